@@ -0,0 +1,87 @@
+use std::{
+    env,
+    fs::File,
+    io::{BufWriter, Write},
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use time::{macros::format_description, Duration, OffsetDateTime};
+use veto::{
+    handler::{self, Handler},
+    settings,
+    testing::{MemoryRepository, MockFirewall},
+};
+
+const LINES: u32 = 1_000_000;
+
+/// Write a synthetic access log, where roughly every 20th line hits one of the blacklists.
+fn generate_log(path: &Path) {
+    let format =
+        format_description!("[day]/[month repr:short]/[year]:[hour]:[minute]:[second] +0000");
+    let start = OffsetDateTime::now_utc() - Duration::days(1);
+    let mut file = BufWriter::new(File::create(path).unwrap());
+
+    for i in 0..LINES {
+        let ip = Ipv4Addr::from(0x0a00_0000 + i % 5000);
+        let time = (start + Duration::milliseconds(i.into()))
+            .format(format)
+            .unwrap();
+        let (path, ua) = if i % 20 == 0 {
+            ("/wp-login.php", "zgrab/0.x")
+        } else {
+            ("/index.html", "Mozilla/5.0 (X11; Linux x86_64)")
+        };
+
+        writeln!(
+            file,
+            r#"{ip} - - [{time}] "GET {path} HTTP/1.1" 200 512 "https://example.com/" "{ua}""#
+        )
+        .unwrap();
+    }
+
+    file.flush().unwrap();
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let path = env::temp_dir().join("veto-bench-handler.log");
+    generate_log(&path);
+
+    let mut settings = settings::load(Some(PathBuf::from("./benches/matcher.toml"))).unwrap();
+    for rule in settings.rules.values_mut() {
+        rule.file.clone_from(&path);
+    }
+
+    let mut g = c.benchmark_group("Handler");
+    g.throughput(Throughput::Elements(LINES.into()));
+    g.sample_size(10);
+    g.bench_function("handle_modified", |b| {
+        b.iter_batched(
+            || {
+                let files = handler::RuleBuilder::new()
+                    .rules(settings.rules.clone())
+                    .limits(settings.limits)
+                    .build()
+                    .unwrap();
+                let handler =
+                    Handler::builder(MemoryRepository::new(), MockFirewall::new()).build();
+                (files, handler)
+            },
+            |(mut files, mut handler)| {
+                for (entry, state) in files.values_mut() {
+                    handler.handle_modified(entry, state).unwrap();
+                }
+                handler
+            },
+            BatchSize::PerIteration,
+        );
+    });
+
+    g.finish();
+
+    std::fs::remove_file(path).ok();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
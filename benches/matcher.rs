@@ -7,7 +7,13 @@ use veto::{handler, matcher::Matcher, settings};
 fn criterion_benchmark(c: &mut Criterion) {
     let matcher = Matcher::with(datetime!(2020-10-04 10:00 UTC));
     let settings = settings::load(Some(PathBuf::from("./benches/matcher.toml"))).unwrap();
-    let entry = handler::prepare_rule("web".to_owned(), settings.rules["web"].clone()).unwrap();
+    let entry = handler::prepare_rule(
+        "web".to_owned(),
+        settings.rules["web"].clone(),
+        &settings.limits,
+        &mut handler::RuleCache::default(),
+    )
+    .unwrap();
     let mut time = OffsetDateTime::UNIX_EPOCH;
     let line = fs::read_to_string("./benches/matcher.txt")
         .unwrap()
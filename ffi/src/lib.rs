@@ -0,0 +1,238 @@
+//! C bindings to veto's filter engine, so other programs can compile rules and match log lines
+//! against them without running the veto daemon. The matching functions are declared in
+//! `veto.h`.
+//!
+//! A rule is not thread safe. Each thread must compile its own rule, or synchronize access to it.
+
+#![deny(rust_2018_idioms, clippy::all, clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+use std::{
+    error::Error,
+    ffi::{c_char, c_int, CStr, CString},
+    fmt::Write,
+    ptr, slice,
+};
+
+use time::OffsetDateTime;
+use veto::{
+    handler::{self, Entry, RuleCache},
+    matcher::Matcher,
+    settings::{Limits, Rule},
+};
+
+/// Buffer size that fits any IP address, including the trailing NUL byte.
+pub const VETO_HOST_LEN: usize = 46;
+
+/// A compiled rule, created with [`veto_rule_new`] and released with [`veto_rule_free`].
+pub struct VetoRule {
+    entry: Entry,
+    matcher: Matcher,
+}
+
+/// Compile a rule from its definition, which has the same TOML format as a rule in the
+/// configuration file.
+///
+/// Returns NULL if the rule is invalid. In that case, an error message is stored in `error` if
+/// it's not NULL, which must be released with [`veto_string_free`].
+///
+/// # Safety
+///
+/// `name` and `definition` must be valid NUL-terminated strings. `error` must be NULL or point to
+/// writable memory for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn veto_rule_new(
+    name: *const c_char,
+    definition: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut VetoRule {
+    match compile(CStr::from_ptr(name), CStr::from_ptr(definition)) {
+        Ok(rule) => Box::into_raw(Box::new(rule)),
+        Err(message) => {
+            if !error.is_null() {
+                *error = CString::new(message).map_or(ptr::null_mut(), CString::into_raw);
+            }
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Release a rule. Passing NULL does nothing.
+///
+/// # Safety
+///
+/// `rule` must be NULL or a rule from [`veto_rule_new`] that wasn't released yet.
+#[no_mangle]
+pub unsafe extern "C" fn veto_rule_free(rule: *mut VetoRule) {
+    if !rule.is_null() {
+        drop(Box::from_raw(rule));
+    }
+}
+
+/// Match a single line of `len` bytes against the rule.
+///
+/// Returns 1 if the client IP of the line should be blocked, and writes it as NUL-terminated
+/// string into `host`. Returns 0 if nothing should be blocked, and -1 if `host_len` is too small
+/// for the IP. A buffer of [`VETO_HOST_LEN`] bytes is always large enough.
+///
+/// # Safety
+///
+/// `rule` must be a valid rule from [`veto_rule_new`]. `line` must point to at least `len`
+/// readable bytes and `host` to at least `host_len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn veto_rule_match(
+    rule: *mut VetoRule,
+    line: *const c_char,
+    len: usize,
+    host: *mut c_char,
+    host_len: usize,
+) -> c_int {
+    let rule = &mut *rule;
+    let Ok(line) = std::str::from_utf8(slice::from_raw_parts(line.cast(), len)) else {
+        return 0;
+    };
+
+    // Lines are matched independently, so they don't need to arrive in order.
+    let mut last_time = OffsetDateTime::UNIX_EPOCH;
+
+    rule.matcher.refresh();
+    let Some(ip) = rule.matcher.find(&rule.entry, &mut last_time, line) else {
+        return 0;
+    };
+
+    let ip = ip.to_string();
+    if ip.len() >= host_len {
+        return -1;
+    }
+
+    ptr::copy_nonoverlapping(ip.as_ptr(), host.cast(), ip.len());
+    *host.add(ip.len()) = 0;
+
+    1
+}
+
+/// Release a string that was returned by any other function. Passing NULL does nothing.
+///
+/// # Safety
+///
+/// `s` must be NULL or a string from this library that wasn't released yet.
+#[no_mangle]
+pub unsafe extern "C" fn veto_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+fn compile(name: &CStr, definition: &CStr) -> Result<VetoRule, String> {
+    let name = name.to_str().map_err(|e| describe(&e))?;
+    let definition = definition.to_str().map_err(|e| describe(&e))?;
+    let rule = basic_toml::from_str::<Rule>(definition).map_err(|e| describe(&e))?;
+
+    let entry = handler::prepare_rule(
+        name.to_owned(),
+        rule,
+        &Limits::default(),
+        &mut RuleCache::default(),
+    )
+    .map_err(|e| describe(&e))?;
+
+    Ok(VetoRule {
+        entry,
+        matcher: Matcher::new(),
+    })
+}
+
+/// Describe the error with all its sources, as C callers can't walk the chain themselves.
+fn describe(error: &dyn Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+
+    while let Some(e) = source {
+        _ = write!(message, ": {e}");
+        source = e.source();
+    }
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_rule(definition: &str) -> Result<*mut VetoRule, String> {
+        let name = CString::new("web").unwrap();
+        let definition = CString::new(definition).unwrap();
+        let mut error = ptr::null_mut();
+
+        let rule =
+            unsafe { veto_rule_new(name.as_ptr(), definition.as_ptr(), ptr::addr_of_mut!(error)) };
+        if rule.is_null() {
+            let message = unsafe { CStr::from_ptr(error) }
+                .to_string_lossy()
+                .into_owned();
+            unsafe { veto_string_free(error) };
+            return Err(message);
+        }
+
+        Ok(rule)
+    }
+
+    fn find(rule: *mut VetoRule, line: &str, host_len: usize) -> (c_int, String) {
+        let mut host = vec![0 as c_char; host_len];
+        let found = unsafe {
+            veto_rule_match(
+                rule,
+                line.as_ptr().cast(),
+                line.len(),
+                host.as_mut_ptr(),
+                host_len,
+            )
+        };
+        let host = if found == 1 {
+            unsafe { CStr::from_ptr(host.as_ptr()) }
+                .to_string_lossy()
+                .into_owned()
+        } else {
+            String::new()
+        };
+
+        (found, host)
+    }
+
+    #[test]
+    fn match_line() {
+        let rule = new_rule(
+            r#"
+                file = "/var/log/access.log"
+                filters = ['^<HOST> - - \[<TIME>\] "GET (?P<path>\S+)']
+                timeout = "1d"
+
+                [blacklists]
+                path = ["/wp-login.php"]
+            "#,
+        )
+        .unwrap();
+
+        let time = OffsetDateTime::now_utc()
+            .format(time::macros::format_description!(
+                "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] +0000"
+            ))
+            .unwrap();
+        let bad = format!(r#"10.0.0.1 - - [{time}] "GET /wp-login.php HTTP/1.1""#);
+        let good = format!(r#"10.0.0.1 - - [{time}] "GET / HTTP/1.1""#);
+
+        assert_eq!((1, "10.0.0.1".to_owned()), find(rule, &bad, VETO_HOST_LEN));
+        assert_eq!((0, String::new()), find(rule, &good, VETO_HOST_LEN));
+        assert_eq!((-1, String::new()), find(rule, &bad, 4));
+
+        unsafe { veto_rule_free(rule) };
+    }
+
+    #[test]
+    fn invalid_rule() {
+        let message = new_rule("file = \"/var/log/access.log\"\nfilters = ['(']\ntimeout = \"1d\"")
+            .unwrap_err();
+
+        assert!(message.starts_with("invalid filter pattern: "), "{message}");
+    }
+}
@@ -0,0 +1,50 @@
+/*
+ * C bindings to the filter engine of veto, to compile rules and match log lines against them.
+ *
+ * A rule is not thread safe. Each thread must compile its own rule, or synchronize access to it.
+ */
+
+#ifndef VETO_H
+#define VETO_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Buffer size that fits any IP address, including the trailing NUL byte. */
+#define VETO_HOST_LEN 46
+
+/* A compiled rule. */
+typedef struct VetoRule VetoRule;
+
+/*
+ * Compile a rule from its definition, which has the same TOML format as a rule in the
+ * configuration file.
+ *
+ * Returns NULL if the rule is invalid. In that case, an error message is stored in `error` if it's
+ * not NULL, which must be released with `veto_string_free`.
+ */
+VetoRule *veto_rule_new(const char *name, const char *definition, char **error);
+
+/* Release a rule. Passing NULL does nothing. */
+void veto_rule_free(VetoRule *rule);
+
+/*
+ * Match a single line of `len` bytes against the rule.
+ *
+ * Returns 1 if the client IP of the line should be blocked, and writes it as NUL-terminated string
+ * into `host`. Returns 0 if nothing should be blocked, and -1 if `host_len` is too small for the
+ * IP. A buffer of `VETO_HOST_LEN` bytes is always large enough.
+ */
+int veto_rule_match(VetoRule *rule, const char *line, size_t len, char *host, size_t host_len);
+
+/* Release a string that was returned by any other function. Passing NULL does nothing. */
+void veto_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,65 @@
+//! Preparation of rules from arbitrary filters and host sources, and matching lines against them.
+//!
+//! The first line of the input is taken as filter pattern, the second one as host source and the
+//! remaining ones as log lines.
+
+#![no_main]
+
+use std::num::NonZeroUsize;
+
+use libfuzzer_sys::fuzz_target;
+use time::{Duration, OffsetDateTime};
+use veto::{
+    handler::{self, RuleCache},
+    matcher::Matcher,
+    settings::{Filter, HostSource, Limits, Protocol, Rule},
+};
+
+fuzz_target!(|data: &str| {
+    let mut lines = data.lines();
+    let (Some(pattern), Some(host)) = (lines.next(), lines.next()) else {
+        return;
+    };
+    let Ok(host) = HostSource::try_from(host.to_owned()) else {
+        return;
+    };
+
+    let rule = Rule {
+        file: "fuzz.log".into(),
+        plugins: Vec::new(),
+        ports: Vec::new(),
+        protocol: Protocol::Tcp,
+        timeout: Duration::MAX,
+        host,
+        time_format: Some("epoch".to_owned()),
+        filters: vec![Filter {
+            pattern: pattern.to_owned(),
+            prefilter: None,
+        }],
+        blacklists: [(
+            "path".to_owned(),
+            ["/admin".to_owned()].into_iter().collect(),
+        )]
+        .into_iter()
+        .collect(),
+    };
+    // Keep huge regexes from slowing down the fuzzer, they're rejected by the size limit.
+    let limits = Limits {
+        regex_size_limit: NonZeroUsize::new(1 << 20),
+        ..Limits::default()
+    };
+
+    let Ok(entry) =
+        handler::prepare_rule("fuzz".to_owned(), rule, &limits, &mut RuleCache::default())
+    else {
+        return;
+    };
+
+    let matcher = Matcher::with(OffsetDateTime::UNIX_EPOCH);
+    let mut last_time = OffsetDateTime::UNIX_EPOCH;
+
+    for line in lines {
+        matcher.find(&entry, &mut last_time, line);
+        matcher.find_analyze(&entry, line);
+    }
+});
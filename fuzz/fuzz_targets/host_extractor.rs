@@ -0,0 +1,55 @@
+//! Extraction of the client IP from matching lines, with each of the built-in host sources.
+
+#![no_main]
+
+use std::num::NonZeroUsize;
+
+use libfuzzer_sys::fuzz_target;
+use time::{Duration, OffsetDateTime};
+use veto::{
+    handler::Handler,
+    pipeline::Pipeline,
+    settings::{Filter, HostSource, Protocol, Rule},
+    testing::{MemoryRepository, MockFirewall},
+};
+
+fuzz_target!(|line: &str| {
+    for (host, pattern) in [
+        (HostSource::Capture, r"<HOST> (?P<time>\d+)"),
+        (HostSource::Leading, r"^<HOST> (?P<time>\d+)"),
+        (
+            HostSource::Group("client".to_owned()),
+            r"(?P<client>\S+) (?P<time>\d+)",
+        ),
+        (HostSource::Column(NonZeroUsize::MIN), r"(?P<time>\d+)"),
+        (HostSource::Json("client.ip".to_owned()), r"(?P<time>\d+)"),
+        (HostSource::Cef("src".to_owned()), r"(?P<time>\d+)"),
+    ] {
+        let handler = Handler::builder(MemoryRepository::new(), MockFirewall::new())
+            .clock(|| OffsetDateTime::UNIX_EPOCH)
+            .build();
+        let mut pipeline = Pipeline::new(handler);
+
+        pipeline
+            .add_rule(
+                "fuzz",
+                Rule {
+                    file: "fuzz.log".into(),
+                    plugins: Vec::new(),
+                    ports: Vec::new(),
+                    protocol: Protocol::Tcp,
+                    timeout: Duration::MAX,
+                    host,
+                    time_format: Some("epoch".to_owned()),
+                    filters: vec![Filter {
+                        pattern: pattern.to_owned(),
+                        prefilter: None,
+                    }],
+                    blacklists: Default::default(),
+                },
+            )
+            .unwrap();
+
+        let _ = pipeline.push_line("fuzz", line);
+    }
+});
@@ -0,0 +1,17 @@
+//! Loading of the default storage from arbitrary file content.
+
+#![no_main]
+
+use std::{env, fs, process};
+
+use libfuzzer_sys::fuzz_target;
+use veto::storage::{self, Backend, TargetRepository};
+
+fuzz_target!(|data: &[u8]| {
+    let path = env::temp_dir().join(format!("veto-fuzz-storage-{}.bin", process::id()));
+    fs::write(&path, data).unwrap();
+
+    let storage = storage::open(Backend::Bincode, Some(path), None).unwrap();
+    storage.records().unwrap();
+    storage.active_records().unwrap();
+});
@@ -0,0 +1,21 @@
+//! Parsing of captured timestamps, with all built-in time formats and custom format descriptions.
+//!
+//! The first line of the input is taken as format description and the rest as timestamp.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use veto::timestamp::{TimeParsers, DEFAULT_FORMAT};
+
+fuzz_target!(|data: &str| {
+    let (format, input) = data.split_once('\n').unwrap_or(("", data));
+    let mut parsers = TimeParsers::default();
+
+    for name in [DEFAULT_FORMAT, "rfc2822", "rfc3339", "syslog", "epoch"] {
+        parsers.get(name).unwrap().parse(input);
+    }
+
+    if let Ok(parser) = parsers.get(format) {
+        parser.parse(input);
+    }
+});
@@ -0,0 +1,100 @@
+//! Python bindings to test rules against log samples, for example in a pytest suite:
+//!
+//! ```python
+//! import veto
+//!
+//! rule = veto.prepare_rule("web", open("web.toml").read())
+//!
+//! def test_blocks_wordpress_scans():
+//!     assert rule.find(SCAN_LINE) == "10.0.0.1"
+//! ```
+
+#![deny(rust_2018_idioms, clippy::all, clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+use std::{error::Error, fmt::Write};
+
+use pyo3::{exceptions::PyValueError, prelude::*};
+use time::OffsetDateTime;
+use veto::{
+    handler::{self, Entry, RuleCache},
+    matcher::Matcher,
+    settings::Limits,
+};
+
+/// A compiled rule, created with `prepare_rule`.
+#[pyclass(unsendable, module = "veto")]
+struct Rule {
+    entry: Entry,
+    matcher: Matcher,
+}
+
+#[pymethods]
+impl Rule {
+    /// Name of the rule.
+    #[getter]
+    fn name(&self) -> &str {
+        &self.entry.name
+    }
+
+    /// Match the line like the daemon would, and return the IP to block or `None`.
+    fn find(&mut self, line: &str) -> Option<String> {
+        // Lines are matched independently, so samples don't need to be in order.
+        let mut last_time = OffsetDateTime::UNIX_EPOCH;
+
+        self.matcher.refresh();
+        self.matcher
+            .find(&self.entry, &mut last_time, line)
+            .map(|ip| ip.to_string())
+    }
+
+    /// Detailed result of each filter for the line, in the same layout as the JSON output of
+    /// `veto analyze --json`.
+    fn analyze(&self, py: Python<'_>, line: &str) -> PyResult<PyObject> {
+        let analysis = self.matcher.find_analyze(&self.entry, line);
+        let json = serde_json::to_string(&analysis).map_err(|e| error(&e))?;
+
+        py.import("json")?
+            .call_method1("loads", (json,))
+            .map(Bound::unbind)
+    }
+}
+
+/// Compile a rule from its definition, which has the same TOML format as a rule in the
+/// configuration file.
+#[pyfunction]
+fn prepare_rule(name: &str, definition: &str) -> PyResult<Rule> {
+    let rule = basic_toml::from_str(definition).map_err(|e| error(&e))?;
+    let entry = handler::prepare_rule(
+        name.to_owned(),
+        rule,
+        &Limits::default(),
+        &mut RuleCache::default(),
+    )
+    .map_err(|e| error(&e))?;
+
+    Ok(Rule {
+        entry,
+        matcher: Matcher::new(),
+    })
+}
+
+/// Turn the error with all its sources into a Python exception.
+fn error(error: &dyn Error) -> PyErr {
+    let mut message = error.to_string();
+    let mut source = error.source();
+
+    while let Some(e) = source {
+        _ = write!(message, ": {e}");
+        source = e.source();
+    }
+
+    PyValueError::new_err(message)
+}
+
+#[pymodule(name = "veto")]
+fn init(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Rule>()?;
+    m.add_function(wrap_pyfunction!(prepare_rule, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,44 @@
+from datetime import datetime, timezone
+
+import pytest
+
+import veto
+
+RULE = r"""
+file = "/var/log/nginx/access.log"
+filters = ['^<HOST> - - \[<TIME>\] "GET (?P<path>\S+)']
+timeout = "1d"
+
+[blacklists]
+path = ["/wp-login.php"]
+"""
+
+
+@pytest.fixture
+def rule():
+    return veto.prepare_rule("web", RULE)
+
+
+def line(path):
+    time = datetime.now(timezone.utc).strftime("%d/%b/%Y:%H:%M:%S +0000")
+    return f'10.0.0.1 - - [{time}] "GET {path} HTTP/1.1"'
+
+
+def test_find(rule):
+    assert rule.name == "web"
+    assert rule.find(line("/wp-login.php")) == "10.0.0.1"
+    assert rule.find(line("/")) is None
+
+
+def test_analyze(rule):
+    analysis = rule.analyze(line("/wp-login.php"))
+    matched = next(iter(analysis["matches"].values()))
+
+    assert matched["host"] == "10.0.0.1"
+    assert matched["captures"]["path"] == "/wp-login.php"
+    assert matched["blacklists"] == {"path": "/wp-login.php"}
+
+
+def test_invalid_rule():
+    with pytest.raises(ValueError, match="invalid filter pattern"):
+        veto.prepare_rule("web", RULE.replace("GET", "("))
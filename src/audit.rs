@@ -0,0 +1,219 @@
+//! Append-only record of every block and unblock, for compliance and reviews after an incident.
+//!
+//! Unlike the storage, which forgets about IPs once they're evicted, the audit log is never
+//! truncated or pruned by veto. Rotating it is left to tools like logrotate.
+
+use std::{
+    fmt::{self, Display},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::Path,
+    thread,
+};
+
+use flume::Receiver;
+use log::warn;
+use parking_lot::Mutex;
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{events::BlockEvent, firewall::Action, settings, Result};
+
+/// Initiator of changes that the running instance does on its own.
+pub const AUTOMATIC: &str = "veto";
+
+/// A single block or unblock.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Entry {
+    #[serde(with = "time::serde::rfc3339")]
+    pub at: OffsetDateTime,
+    pub action: Action,
+    pub ip: IpAddr,
+    /// Name of the rule that the block belongs to.
+    pub rule: String,
+    /// Time until the IP stays blocked, for blocks.
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub until: Option<OffsetDateTime>,
+    /// Why the change was done, like a matched log line or an expired block.
+    pub reason: String,
+    /// Who did the change, either [`AUTOMATIC`] or the user of a manual command.
+    pub initiator: String,
+}
+
+impl Entry {
+    /// Create an entry for a change that the running instance did, from its event.
+    #[must_use]
+    pub fn from_event(event: &BlockEvent, at: OffsetDateTime) -> Self {
+        let (action, ip, rule, until, reason) = match event {
+            BlockEvent::Ban(ban) => (
+                Action::Block,
+                ban.ip,
+                &ban.rule,
+                Some(ban.until),
+                "log line matched the rule",
+            ),
+            BlockEvent::Unban(unban) => (
+                Action::Unblock,
+                unban.ip,
+                &unban.rule,
+                None,
+                "block expired",
+            ),
+        };
+
+        Self {
+            at,
+            action,
+            ip,
+            rule: rule.clone(),
+            until,
+            reason: reason.to_owned(),
+            initiator: AUTOMATIC.to_owned(),
+        }
+    }
+}
+
+/// Single line like `2023-10-01T12:00:00Z block 10.0.0.1 rule=web until=2023-10-01T13:00:00Z
+/// initiator=veto reason="log line matched the rule"`.
+impl Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Fractions of seconds are only kept in the JSON format, to keep the lines short.
+        let format = |time: OffsetDateTime| {
+            time.replace_nanosecond(0)
+                .unwrap_or(time)
+                .format(&Rfc3339)
+                .map_err(|_| fmt::Error)
+        };
+        let action = match self.action {
+            Action::Block => "block",
+            Action::Unblock => "unblock",
+        };
+
+        write!(
+            f,
+            "{} {action} {} rule={}",
+            format(self.at)?,
+            self.ip,
+            self.rule
+        )?;
+        if let Some(until) = self.until {
+            write!(f, " until={}", format(until)?)?;
+        }
+        write!(f, " initiator={} reason={:?}", self.initiator, self.reason)
+    }
+}
+
+/// Audit log file, that entries are only ever appended to.
+pub struct AuditLog {
+    file: Mutex<File>,
+    json: bool,
+}
+
+impl AuditLog {
+    /// Open the audit log at the given location, creating it and its directory if needed.
+    pub fn open(path: &Path, json: bool) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+            json,
+        })
+    }
+
+    /// Open the audit log from the settings, or return [`None`] if it's disabled.
+    pub fn from_settings(settings: &settings::Audit) -> io::Result<Option<Self>> {
+        settings
+            .file
+            .as_deref()
+            .map(|file| Self::open(file, settings.json))
+            .transpose()
+    }
+
+    /// Append the entry as a single line. Each line is written at once, so entries of several
+    /// processes never interleave.
+    pub fn write(&self, entry: &Entry) -> io::Result<()> {
+        let mut line = if self.json {
+            serde_json::to_string(entry)?
+        } else {
+            entry.to_string()
+        };
+        line.push('\n');
+
+        self.file.lock().write_all(line.as_bytes())
+    }
+}
+
+/// Start a background thread that writes all events of a handler to the audit log.
+pub fn log_events(log: AuditLog, events: Receiver<BlockEvent>) -> Result<()> {
+    thread::Builder::new()
+        .name("audit".to_owned())
+        .spawn(move || {
+            for event in events {
+                let entry = Entry::from_event(&event, OffsetDateTime::now_utc());
+                if let Err(e) = log.write(&entry) {
+                    warn!("failed writing audit log entry for {}: {e}", entry.ip);
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use time::macros::datetime;
+
+    use super::*;
+    use crate::events::{BanEvent, UnbanEvent};
+
+    #[test]
+    fn append() {
+        let path = env::temp_dir().join(format!("veto-audit-{}.log", std::process::id()));
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let at = datetime!(2023-10-01 12:00 UTC);
+
+        let ban = Entry::from_event(
+            &BlockEvent::Ban(BanEvent {
+                rule: "web".to_owned(),
+                ip,
+                until: datetime!(2023-10-01 13:00 UTC),
+            }),
+            at,
+        );
+        let unban = Entry::from_event(
+            &BlockEvent::Unban(UnbanEvent {
+                rule: "web".to_owned(),
+                ip,
+            }),
+            at,
+        );
+
+        AuditLog::open(&path, false).unwrap().write(&ban).unwrap();
+        AuditLog::open(&path, true).unwrap().write(&unban).unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        let mut lines = content.lines();
+        assert_eq!(
+            Some(
+                "2023-10-01T12:00:00Z block 10.0.0.1 rule=web until=2023-10-01T13:00:00Z \
+                 initiator=veto reason=\"log line matched the rule\""
+            ),
+            lines.next()
+        );
+        assert_eq!(
+            Some(
+                r#"{"at":"2023-10-01T12:00:00Z","action":"unblock","ip":"10.0.0.1","rule":"web","until":null,"reason":"block expired","initiator":"veto"}"#
+            ),
+            lines.next()
+        );
+    }
+}
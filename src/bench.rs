@@ -0,0 +1,268 @@
+//! Measuring the cost of rules and their filters against sample lines, to find the filters that
+//! slow down processing the most.
+
+use std::{
+    hint,
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+use time::OffsetDateTime;
+
+use crate::{handler::Entry, matcher::Matcher};
+
+/// How many times slower than its siblings a filter must be to be flagged as slow.
+pub const SLOW_FACTOR: f64 = 10.0;
+
+/// Longest time a filter may take for a single crafted line, before it's reported as a risk.
+pub const STRESS_LIMIT: Duration = Duration::from_millis(10);
+
+/// Length of the crafted lines, if the line length isn't limited.
+const STRESS_LINE_LENGTH: usize = 16 * 1024;
+
+/// Patterns that are repeated to craft lines, that let many regexes run far before failing. A
+/// line can start with a valid IP as well, so filters that begin with `<HOST>` get past it.
+const STRESS_PATTERNS: &[&str] = &["a", "0", " ", "a ", "1.", "a:", "%", "\\\"", "-a_0/"];
+
+/// Performance of a whole rule against the sample lines.
+#[derive(Debug)]
+pub struct RuleReport {
+    pub name: String,
+    /// Amount of lines that were processed, including all iterations.
+    pub lines: u64,
+    /// Time to process all lines, the same way as the daemon does.
+    pub elapsed: Duration,
+    /// Cost of each filter, in the same order as the rule's filters.
+    pub filters: Vec<FilterReport>,
+}
+
+impl RuleReport {
+    #[must_use]
+    pub fn lines_per_second(&self) -> f64 {
+        per_second(self.lines, self.elapsed)
+    }
+
+    /// Indices of all filters that are at least [`SLOW_FACTOR`] times slower than the median of
+    /// the other filters. Rules with a single filter have nothing to compare against.
+    pub fn slow_filters(&self) -> impl Iterator<Item = usize> + '_ {
+        self.filters.iter().enumerate().filter_map(|(i, filter)| {
+            let mut others = self
+                .filters
+                .iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .map(|(_, f)| f.per_line())
+                .collect::<Vec<_>>();
+            others.sort_unstable();
+
+            let median = *others.get(others.len() / 2)?;
+            (filter.per_line().as_secs_f64() >= median.as_secs_f64() * SLOW_FACTOR).then_some(i)
+        })
+    }
+}
+
+/// Cost of a single filter, when run against every line on its own.
+#[derive(Debug)]
+pub struct FilterReport {
+    pub pattern: String,
+    pub lines: u64,
+    /// Amount of lines that passed the prefilter and were checked with the regex.
+    pub candidates: u64,
+    /// Amount of lines that the regex matched.
+    pub matches: u64,
+    /// Time spent on the prefilter and regex of all lines.
+    pub elapsed: Duration,
+}
+
+impl FilterReport {
+    /// Average time per line.
+    #[must_use]
+    pub fn per_line(&self) -> Duration {
+        u32::try_from(self.lines)
+            .ok()
+            .and_then(|lines| self.elapsed.checked_div(lines))
+            .unwrap_or_default()
+    }
+
+    #[must_use]
+    pub fn lines_per_second(&self) -> f64 {
+        per_second(self.lines, self.elapsed)
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn per_second(count: u64, elapsed: Duration) -> f64 {
+    count as f64 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+/// Run the rule against all lines, repeated the given amount of times.
+///
+/// The rule is measured as a whole first, and then each filter separately against every line, even
+/// if an earlier filter already matched. Timestamps are never considered outdated, so old samples
+/// can be used.
+#[must_use]
+pub fn run(entry: &Entry, lines: &[String], iterations: u32) -> RuleReport {
+    let matcher = Matcher::with_clock(|| OffsetDateTime::UNIX_EPOCH);
+    let total = lines.len() as u64 * u64::from(iterations);
+
+    let mut locations = entry.capture_locations();
+    let start = Instant::now();
+    for _ in 0..iterations {
+        let mut last_time = OffsetDateTime::UNIX_EPOCH;
+        for line in lines {
+            hint::black_box(matcher.detect_with(entry, &mut locations, &mut last_time, line));
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let filters = entry
+        .matchers
+        .iter()
+        .zip(&entry.rule.filters)
+        .map(|(filter, settings)| {
+            let mut candidates = 0;
+            let mut hits = 0;
+            let mut locations = filter.capture_locations();
+
+            let start = Instant::now();
+            for _ in 0..iterations {
+                for line in lines {
+                    if !filter.is_candidate(line) {
+                        continue;
+                    }
+                    candidates += 1;
+                    if filter.captures_with(&mut locations, line, |_| ()).is_some() {
+                        hits += 1;
+                    }
+                }
+            }
+
+            FilterReport {
+                pattern: settings.pattern.clone(),
+                lines: total,
+                candidates,
+                matches: hits,
+                elapsed: start.elapsed(),
+            }
+        })
+        .collect();
+
+    RuleReport {
+        name: entry.name.clone(),
+        lines: total,
+        elapsed,
+        filters,
+    }
+}
+
+/// Worst case time of a single filter against the crafted lines.
+#[derive(Debug)]
+pub struct StressReport {
+    /// Index of the filter in the rule's filters.
+    pub index: usize,
+    pub pattern: String,
+    /// Longest time that a single line took.
+    pub worst: Duration,
+}
+
+impl StressReport {
+    /// Whether the filter takes longer than [`STRESS_LIMIT`] for any line.
+    #[must_use]
+    pub fn is_risky(&self) -> bool {
+        self.worst > STRESS_LIMIT
+    }
+}
+
+/// Lines of the given length, crafted to be expensive to match for many regexes.
+#[must_use]
+pub fn crafted_lines(length: usize) -> Vec<String> {
+    let fill = |prefix: &str, pattern: &str| {
+        let mut line = prefix.to_owned();
+        while line.len() < length {
+            line.push_str(pattern);
+        }
+        line.truncate(length);
+        line
+    };
+
+    STRESS_PATTERNS
+        .iter()
+        .flat_map(|pattern| [fill("", pattern), fill("192.0.2.1 ", pattern)])
+        .collect()
+}
+
+/// Time each filter of the rule against lines crafted to be expensive, as long as the longest
+/// lines that are processed.
+///
+/// The regexes are run without their prefilters, as an attacker can easily include the required
+/// literals in a line.
+#[must_use]
+pub fn stress(entry: &Entry, max_line_length: Option<NonZeroUsize>) -> Vec<StressReport> {
+    let lines = crafted_lines(max_line_length.map_or(STRESS_LINE_LENGTH, NonZeroUsize::get));
+
+    entry
+        .matchers
+        .iter()
+        .zip(&entry.rule.filters)
+        .enumerate()
+        .map(|(index, (filter, settings))| StressReport {
+            index,
+            pattern: settings.pattern.clone(),
+            worst: lines
+                .iter()
+                .map(|line| {
+                    let start = Instant::now();
+                    hint::black_box(filter.regex.is_match(line));
+                    start.elapsed()
+                })
+                .max()
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(pattern: &str, nanos: u64) -> FilterReport {
+        FilterReport {
+            pattern: pattern.to_owned(),
+            lines: 10,
+            candidates: 10,
+            matches: 0,
+            elapsed: Duration::from_nanos(nanos * 10),
+        }
+    }
+
+    #[test]
+    fn slow_filters() {
+        let report = |filters| RuleReport {
+            name: "test".to_owned(),
+            lines: 10,
+            elapsed: Duration::ZERO,
+            filters,
+        };
+
+        let single = report(vec![filter("a", 1_000_000)]);
+        assert_eq!(0, single.slow_filters().count());
+
+        let mixed = report(vec![
+            filter("a", 100),
+            filter("b", 150),
+            filter("c", 5_000),
+            filter("d", 120),
+        ]);
+        assert_eq!(vec![2], mixed.slow_filters().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn crafted() {
+        let lines = crafted_lines(100);
+        assert_eq!(STRESS_PATTERNS.len() * 2, lines.len());
+        assert!(lines.iter().all(|line| line.len() == 100));
+        assert!(lines.iter().any(|line| line.starts_with("192.0.2.1 aaa")));
+
+        assert!(crafted_lines(4).iter().all(|line| line.len() == 4));
+    }
+}
@@ -0,0 +1,182 @@
+//! Circuit breaker that pauses blocking, when the state of veto suggests that it would block the
+//! wrong IPs, until an operator resumes it with `veto resume`.
+//!
+//! Detection keeps running while paused, so offending IPs are still logged, but not blocked.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+use crate::{handler::Entry, local::LocalAddresses, metrics::RuleMetrics, settings, Result};
+
+/// Time between two checks, which is also the window for the share of matching lines.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Minimum amount of lines within a window, before the share of matching lines is checked, so a
+/// handful of lines on a quiet server can't trip the breaker.
+const MIN_LINES: u64 = 100;
+
+/// Checks for conditions under which blocking should be paused.
+pub struct Breaker {
+    enabled: bool,
+    max_match_percent: u8,
+    config: PathBuf,
+    /// Content of the configuration, as it was loaded.
+    content: Option<Vec<u8>>,
+    rules: Vec<Arc<RuleMetrics>>,
+    /// Amount of lines and matches over all rules at the start of the window.
+    lines: u64,
+    matches: u64,
+    checked: Instant,
+}
+
+impl Breaker {
+    /// Create a breaker for the given rules, watching the configuration at the given location.
+    pub fn new<'a>(
+        settings: &settings::Breaker,
+        config: PathBuf,
+        entries: impl IntoIterator<Item = &'a Entry>,
+    ) -> Self {
+        let mut breaker = Self {
+            enabled: settings.enabled,
+            max_match_percent: settings.max_match_percent,
+            content: None,
+            config,
+            rules: entries.into_iter().map(|e| e.metrics.clone()).collect(),
+            lines: 0,
+            matches: 0,
+            checked: Instant::now(),
+        };
+        breaker.reset();
+        breaker
+    }
+
+    /// Take the current state as the new baseline, like after blocking was resumed.
+    pub fn reset(&mut self) {
+        self.content = fs::read(&self.config).ok();
+        (self.lines, self.matches) = self.totals();
+        self.checked = Instant::now();
+    }
+
+    /// Check all conditions, if the last check was long enough ago, and describe the first one
+    /// that failed. Always passes if the breaker is disabled.
+    pub fn check(&mut self, local: Option<&LocalAddresses>) -> Option<String> {
+        self.check_at(local, Instant::now())
+    }
+
+    fn check_at(&mut self, local: Option<&LocalAddresses>, now: Instant) -> Option<String> {
+        if !self.enabled || now.saturating_duration_since(self.checked) < CHECK_INTERVAL {
+            return None;
+        }
+
+        self.checked = now;
+
+        let (lines, matches) = self.totals();
+        let window = (
+            lines.saturating_sub(self.lines),
+            matches.saturating_sub(self.matches),
+        );
+        (self.lines, self.matches) = (lines, matches);
+
+        if local.is_some_and(LocalAddresses::is_failed) {
+            return Some("the host's own addresses couldn't be detected".to_owned());
+        }
+
+        if fs::read(&self.config).ok() != self.content {
+            return Some(format!(
+                "the configuration at {} changed since it was loaded",
+                self.config.display()
+            ));
+        }
+
+        exceeds_share(window, self.max_match_percent).then(|| {
+            format!(
+                "{} of {} lines within a minute matched, more than {}%",
+                window.1, window.0, self.max_match_percent
+            )
+        })
+    }
+
+    /// Total amount of lines and matches over all rules.
+    fn totals(&self) -> (u64, u64) {
+        self.rules.iter().fold((0, 0), |(lines, matches), rule| {
+            (
+                lines + rule.lines.load(Ordering::Relaxed),
+                matches + rule.matches.load(Ordering::Relaxed),
+            )
+        })
+    }
+}
+
+/// Whether more than the given percentage of lines matched, once enough lines were seen.
+fn exceeds_share((lines, matches): (u64, u64), max_percent: u8) -> bool {
+    lines >= MIN_LINES && matches * 100 > lines * u64::from(max_percent)
+}
+
+/// Location of the file that asks a running instance to resume blocking, next to the storage
+/// file at the given location.
+#[must_use]
+pub fn resume_path(storage: &Path) -> PathBuf {
+    storage.with_extension("resume")
+}
+
+/// Ask the running instance that uses the storage at the given location to resume blocking. It
+/// picks up the request within a minute.
+pub fn request_resume(storage: &Path) -> Result<()> {
+    fs::write(resume_path(storage), "").map_err(Into::into)
+}
+
+/// Whether blocking should be resumed, which removes the request.
+#[must_use]
+pub fn take_resume(storage: &Path) -> bool {
+    fs::remove_file(resume_path(storage)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_share() {
+        assert!(!exceeds_share((50, 50), 50));
+        assert!(!exceeds_share((100, 50), 50));
+        assert!(exceeds_share((100, 51), 50));
+        assert!(!exceeds_share((1000, 1000), 100));
+    }
+
+    #[test]
+    fn config_change() {
+        let dir = std::env::temp_dir().join(format!("veto-breaker-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("config.toml");
+        fs::write(&config, "[rules]\n").unwrap();
+
+        let settings = settings::Breaker {
+            enabled: true,
+            ..settings::Breaker::default()
+        };
+        let mut breaker = Breaker::new(&settings, config.clone(), []);
+
+        // Nothing is checked before the interval passed.
+        fs::write(&config, "whitelist = []\n[rules]\n").unwrap();
+        let at = breaker.checked;
+        assert_eq!(None, breaker.check_at(None, at));
+        let at = breaker.checked + CHECK_INTERVAL;
+        assert!(breaker.check_at(None, at).is_some());
+
+        // The changed configuration is the new baseline once resumed.
+        breaker.reset();
+        let at = breaker.checked + CHECK_INTERVAL;
+        assert_eq!(None, breaker.check_at(None, at));
+
+        let storage = dir.join("storage.bin");
+        assert!(!take_resume(&storage));
+        request_resume(&storage).unwrap();
+        assert!(take_resume(&storage));
+        assert!(!take_resume(&storage));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,429 @@
+//! Verification of search engine crawlers, so aggressive rules don't block them.
+//!
+//! Anyone can claim to be Googlebot in the user agent, so a claim is only trusted after the
+//! verification that the search engines document: the reverse DNS name of the IP must belong to
+//! the search engine's domain, and that name must resolve back to the same IP. The two DNS lookups
+//! run on a background thread, and results are cached.
+
+use std::{
+    io,
+    net::IpAddr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use flume::{Receiver, Sender};
+use log::{debug, warn};
+use parking_lot::{Condvar, Mutex};
+
+use crate::{HashMap, IndexMap};
+
+/// Time that the result of a verification is cached.
+const CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Maximum amount of cached results. Once reached, the oldest result is evicted for each new one.
+const CACHE_SIZE: usize = 10_000;
+/// Longest time that a verification may take, after which the IP counts as not verified.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Maximum amount of verifications that wait for the background thread. Any further claims count
+/// as not verified, until the queue shrinks again.
+const MAX_QUEUED: usize = 1024;
+
+/// A search engine crawler, that is identified by its user agent.
+struct Crawler {
+    /// Part of the user agent that identifies the crawler, compared case-insensitively.
+    agent: &'static str,
+    /// Domains that the reverse DNS names of the crawler's IPs belong to.
+    domains: &'static [&'static str],
+}
+
+const GOOGLE: &[&str] = &["googlebot.com", "google.com", "googleusercontent.com"];
+
+/// Crawlers that document the DNS verification.
+const CRAWLERS: &[Crawler] = &[
+    Crawler {
+        agent: "googlebot",
+        domains: GOOGLE,
+    },
+    Crawler {
+        agent: "adsbot-google",
+        domains: GOOGLE,
+    },
+    Crawler {
+        agent: "mediapartners-google",
+        domains: GOOGLE,
+    },
+    Crawler {
+        agent: "google-inspectiontool",
+        domains: GOOGLE,
+    },
+    Crawler {
+        agent: "bingbot",
+        domains: &["search.msn.com"],
+    },
+    Crawler {
+        agent: "applebot",
+        domains: &["applebot.apple.com"],
+    },
+    Crawler {
+        agent: "yandexbot",
+        domains: &["yandex.ru", "yandex.net", "yandex.com"],
+    },
+    Crawler {
+        agent: "baiduspider",
+        domains: &["baidu.com", "baidu.jp"],
+    },
+];
+
+/// DNS lookups that the verification needs.
+pub trait Resolver: Send + Sync {
+    /// Host name of the IP's PTR record.
+    fn reverse(&self, ip: IpAddr) -> io::Result<String>;
+    /// Addresses that the host name resolves to.
+    fn forward(&self, host: &str) -> io::Result<Vec<IpAddr>>;
+}
+
+/// Resolve names through the system's resolver.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn reverse(&self, ip: IpAddr) -> io::Result<String> {
+        dns_lookup::lookup_addr(&ip)
+    }
+
+    fn forward(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        dns_lookup::lookup_host(host)
+    }
+}
+
+/// The crawler that a user agent claims to be.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Claim(usize);
+
+impl Claim {
+    /// Find the crawler that the user agent claims to be. User agents of any other clients are
+    /// never verified.
+    #[must_use]
+    pub fn from_agent(agent: &str) -> Option<Self> {
+        let agent = agent.to_ascii_lowercase();
+        CRAWLERS
+            .iter()
+            .position(|crawler| agent.contains(crawler.agent))
+            .map(Self)
+    }
+
+    fn crawler(self) -> &'static Crawler {
+        &CRAWLERS[self.0]
+    }
+}
+
+/// Results and running verifications, shared with the background thread.
+#[derive(Default)]
+struct State {
+    /// Result for each IP and the crawler it claimed to be, with the time it was verified. The
+    /// oldest results come first.
+    cache: IndexMap<(IpAddr, Claim), (bool, Instant)>,
+    /// Verifications that wait for the background thread, with the time they were queued.
+    pending: HashMap<(IpAddr, Claim), Instant>,
+}
+
+impl State {
+    fn cached(&self, key: &(IpAddr, Claim)) -> Option<bool> {
+        self.cache
+            .get(key)
+            .filter(|(_, at)| at.elapsed() < CACHE_TTL)
+            .map(|(verified, _)| *verified)
+    }
+
+    /// Cache a new result. Expired results are evicted one by one, oldest first, as well as the
+    /// oldest one if the cache is full.
+    fn insert(&mut self, key: (IpAddr, Claim), verified: bool) {
+        self.pending.remove(&key);
+        self.cache.shift_remove(&key);
+
+        while let Some((_, (_, at))) = self.cache.first() {
+            if at.elapsed() < CACHE_TTL && self.cache.len() < CACHE_SIZE {
+                break;
+            }
+            self.cache.shift_remove_index(0);
+        }
+
+        self.cache.insert(key, (verified, Instant::now()));
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// Notified whenever the background thread finished a verification.
+    finished: Condvar,
+}
+
+/// Verifies crawlers on a background thread and caches the results.
+pub struct Verifier {
+    resolver: Arc<dyn Resolver>,
+    shared: Arc<Shared>,
+    /// Queue of the background thread, which is started with the first verification.
+    queue: Mutex<Option<Sender<(IpAddr, Claim)>>>,
+    /// Channel that receives a message for each finished verification.
+    updates: (Sender<()>, Receiver<()>),
+}
+
+impl Default for Verifier {
+    fn default() -> Self {
+        Self::new(Box::new(SystemResolver))
+    }
+}
+
+impl Verifier {
+    #[must_use]
+    pub fn new(resolver: Box<dyn Resolver>) -> Self {
+        Self {
+            resolver: resolver.into(),
+            shared: Arc::default(),
+            queue: Mutex::default(),
+            updates: flume::bounded(1),
+        }
+    }
+
+    /// Whether the IP belongs to a known crawler, that the user agent claims it to be. This waits
+    /// for the verification, but never longer than its timeout.
+    pub fn is_verified(&self, ip: IpAddr, agent: &str) -> bool {
+        Claim::from_agent(agent).is_some_and(|claim| self.wait(ip, claim))
+    }
+
+    /// Like [`Self::is_verified`], for an already known claim.
+    pub fn wait(&self, ip: IpAddr, claim: Claim) -> bool {
+        let deadline = Instant::now() + LOOKUP_TIMEOUT;
+        if let Some(verified) = self.status(ip, claim) {
+            return verified;
+        }
+
+        let mut state = self.shared.state.lock();
+        while state.pending.contains_key(&(ip, claim)) {
+            if self
+                .shared
+                .finished
+                .wait_until(&mut state, deadline)
+                .timed_out()
+            {
+                break;
+            }
+        }
+        drop(state);
+
+        self.status(ip, claim).unwrap_or_default()
+    }
+
+    /// Result of the verification of the IP, or [`None`] while it's still running. A verification
+    /// is started in the background if there is no result yet. Verifications that take longer
+    /// than their timeout, or can't be queued at all, count as failed.
+    pub fn status(&self, ip: IpAddr, claim: Claim) -> Option<bool> {
+        let key = (ip, claim);
+        let mut state = self.shared.state.lock();
+
+        if let Some(verified) = state.cached(&key) {
+            return Some(verified);
+        }
+
+        if let Some(queued) = state.pending.get(&key) {
+            if queued.elapsed() < LOOKUP_TIMEOUT {
+                return None;
+            }
+
+            debug!(
+                "verification of {ip} as {} timed out",
+                claim.crawler().agent
+            );
+            state.insert(key, false);
+            return Some(false);
+        }
+
+        // Queued while locked, so the background thread can't miss the pending verification.
+        if !self.queue(key) {
+            return Some(false);
+        }
+        state.pending.insert(key, Instant::now());
+
+        None
+    }
+
+    /// Receiver of a message whenever a verification finished, after which [`Self::status`] has
+    /// a new result.
+    #[must_use]
+    pub fn updates(&self) -> Receiver<()> {
+        self.updates.1.clone()
+    }
+
+    /// Send a verification to the background thread, starting it if needed. Returns whether it
+    /// was queued.
+    fn queue(&self, key: (IpAddr, Claim)) -> bool {
+        let mut queue = self.queue.lock();
+
+        if queue.is_none() {
+            let (tx, rx) = flume::bounded(MAX_QUEUED);
+            let resolver = Arc::clone(&self.resolver);
+            let shared = Arc::clone(&self.shared);
+            let updates = self.updates.0.clone();
+
+            let spawned = thread::Builder::new()
+                .name("crawlers".to_owned())
+                .spawn(move || work(&*resolver, &shared, &rx, &updates));
+
+            match spawned {
+                Ok(_) => *queue = Some(tx),
+                Err(e) => {
+                    warn!("failed starting crawler verification: {e}");
+                    return false;
+                }
+            }
+        }
+
+        queue.as_ref().is_some_and(|tx| tx.try_send(key).is_ok())
+    }
+}
+
+/// Run the verifications of the queue, until the verifier is dropped. Verifications that timed
+/// out while waiting in the queue are skipped, as their result isn't awaited anymore.
+fn work(
+    resolver: &dyn Resolver,
+    shared: &Shared,
+    queue: &Receiver<(IpAddr, Claim)>,
+    updates: &Sender<()>,
+) {
+    for key @ (ip, claim) in queue {
+        let queued = shared.state.lock().pending.get(&key).copied();
+        if queued.is_none_or(|queued| queued.elapsed() >= LOOKUP_TIMEOUT) {
+            continue;
+        }
+
+        let verified = verify(resolver, ip, claim.crawler().domains);
+        debug!(
+            "{ip} claims to be {}, verified: {verified}",
+            claim.crawler().agent
+        );
+
+        shared.state.lock().insert(key, verified);
+        shared.finished.notify_all();
+        // A full channel already has a message waiting, that covers this result as well.
+        updates.try_send(()).ok();
+    }
+}
+
+/// Check that the reverse DNS name of the IP is in one of the domains, and resolves back to the
+/// IP.
+fn verify(resolver: &dyn Resolver, ip: IpAddr, domains: &[&str]) -> bool {
+    let Ok(host) = resolver.reverse(ip) else {
+        return false;
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+    let in_domain = domains.iter().any(|domain| {
+        host.strip_suffix(domain)
+            .is_some_and(|sub| sub.ends_with('.'))
+    });
+
+    in_domain
+        && resolver
+            .forward(&host)
+            .is_ok_and(|addrs| addrs.contains(&ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    struct FakeResolver;
+
+    impl Resolver for FakeResolver {
+        fn reverse(&self, ip: IpAddr) -> io::Result<String> {
+            match ip.to_string().as_str() {
+                "66.249.66.1" => Ok("crawl-66-249-66-1.googlebot.com.".to_owned()),
+                // Anyone can set their PTR record to a name of another domain.
+                "10.0.0.2" => Ok("crawl-66-249-66-1.googlebot.com".to_owned()),
+                "10.0.0.3" => Ok("googlebot.com.example.org".to_owned()),
+                _ => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+
+        fn forward(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+            match host {
+                "crawl-66-249-66-1.googlebot.com" => Ok(vec![IpAddr::from([66, 249, 66, 1])]),
+                _ => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+    }
+
+    #[test]
+    fn verify_crawlers() {
+        let verifier = Verifier::new(Box::new(FakeResolver));
+        let agent = "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)";
+
+        assert!(verifier.is_verified([66, 249, 66, 1].into(), agent));
+        assert!(!verifier.is_verified([10, 0, 0, 2].into(), agent));
+        assert!(!verifier.is_verified([10, 0, 0, 3].into(), agent));
+        assert!(!verifier.is_verified([10, 0, 0, 4].into(), agent));
+        assert!(!verifier.is_verified([66, 249, 66, 1].into(), "curl/8.4.0"));
+        assert!(!verifier.is_verified(
+            [66, 249, 66, 1].into(),
+            "Mozilla/5.0 (compatible; bingbot/2.0)"
+        ));
+    }
+
+    /// Resolver that answers reverse lookups only once the test allows it.
+    struct SlowResolver(Receiver<()>);
+
+    impl Resolver for SlowResolver {
+        fn reverse(&self, ip: IpAddr) -> io::Result<String> {
+            self.0.recv().ok();
+            FakeResolver.reverse(ip)
+        }
+
+        fn forward(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+            FakeResolver.forward(host)
+        }
+    }
+
+    #[test]
+    fn verify_in_background() {
+        let (tx, rx) = flume::unbounded();
+        let verifier = Verifier::new(Box::new(SlowResolver(rx)));
+        let updates = verifier.updates();
+        let claim = Claim::from_agent("Googlebot/2.1").unwrap();
+        let ip = IpAddr::from([66, 249, 66, 1]);
+
+        assert_eq!(None, verifier.status(ip, claim));
+        assert_eq!(None, verifier.status(ip, claim));
+
+        tx.send(()).unwrap();
+        updates.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(Some(true), verifier.status(ip, claim));
+
+        assert_eq!(None, verifier.status([10, 0, 0, 2].into(), claim));
+        tx.send(()).unwrap();
+        assert!(!verifier.wait([10, 0, 0, 2].into(), claim));
+    }
+
+    #[test]
+    fn evict_oldest() {
+        let claim = Claim::from_agent("bingbot").unwrap();
+        let key = |i: u32| (IpAddr::from(Ipv4Addr::from(i)), claim);
+        let mut state = State::default();
+
+        for i in 0..=u32::try_from(CACHE_SIZE).unwrap() {
+            state.insert(key(i), true);
+        }
+
+        assert_eq!(CACHE_SIZE, state.cache.len());
+        assert_eq!(None, state.cached(&key(0)));
+        assert_eq!(Some(true), state.cached(&key(1)));
+
+        // Results are moved to the end again, once they're renewed.
+        state.insert(key(1), false);
+        state.insert(key(u32::MAX), true);
+        assert_eq!(Some(false), state.cached(&key(1)));
+        assert_eq!(None, state.cached(&key(2)));
+    }
+}
@@ -0,0 +1,263 @@
+//! Finding out why a filter doesn't match a line, by progressively truncating its pattern until
+//! the remaining part matches.
+
+use regex::Regex;
+use serde::Serialize;
+
+use crate::{handler, settings::HostSource};
+
+/// Explanation of a filter that didn't match a line.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Diagnosis {
+    /// Longest start of the pattern that still matches the line. Groups that are left open by the
+    /// truncation are closed implicitly.
+    pub matched: String,
+    /// The part of the pattern right after the matching start, which likely made the filter fail.
+    pub failed: String,
+    /// Byte offset in the line up to which the matching start of the pattern got.
+    pub offset: usize,
+}
+
+/// Check the filter pattern against the line and explain where it fails, or return [`None`] if it
+/// matches.
+#[must_use]
+pub fn diagnose(pattern: &str, host: &HostSource, line: &str) -> Option<Diagnosis> {
+    let tokens = tokenize(pattern);
+
+    let compile = |end: usize, depth: usize| {
+        let mut prefix = pattern[..end].to_owned();
+        prefix.extend(std::iter::repeat_n(')', depth));
+        Regex::new(&handler::expand_pattern(&prefix, host)).ok()
+    };
+
+    if compile(pattern.len(), 0).is_some_and(|regex| regex.is_match(line)) {
+        return None;
+    }
+
+    // Longer prefixes aren't guaranteed to match less, so each one is checked.
+    let (count, offset) = (1..tokens.len())
+        .rev()
+        .find_map(|count| {
+            let token = &tokens[count - 1];
+            let found = compile(token.end, token.depth)?.find(line)?;
+            Some((count, found.end()))
+        })
+        .unwrap_or_default();
+
+    let end = count.checked_sub(1).map_or(0, |i| tokens[i].end);
+    let failed_end = tokens.get(count).map_or(pattern.len(), |t| t.end);
+
+    Some(Diagnosis {
+        matched: pattern[..end].to_owned(),
+        failed: pattern[end..failed_end].to_owned(),
+        offset,
+    })
+}
+
+/// Smallest part of a pattern that can be cut off, together with its quantifier.
+struct Token {
+    /// Byte offset of the token's end in the pattern.
+    end: usize,
+    /// Amount of groups that are still open after this token.
+    depth: usize,
+}
+
+/// Split a pattern into tokens. Escapes, classes, group openings and placeholders are kept whole,
+/// and quantifiers are attached to the token before them.
+fn tokenize(pattern: &str) -> Vec<Token> {
+    let bytes = pattern.as_bytes();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut depth = 0_usize;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let end = match bytes[i] {
+            b'\\' => escape_end(pattern, i),
+            b'[' => class_end(bytes, i),
+            b'(' => {
+                let end = group_end(bytes, i);
+                if !pattern[i..end].ends_with(')') {
+                    depth += 1;
+                }
+                end
+            }
+            b')' => {
+                depth = depth.saturating_sub(1);
+                i + 1
+            }
+            b'*' | b'+' | b'?' | b'{' if !tokens.is_empty() => {
+                let end = quantifier_end(bytes, i);
+                if let Some(last) = tokens.last_mut().filter(|_| end > i) {
+                    last.end = end;
+                    i = end;
+                    continue;
+                }
+                end.max(i + 1)
+            }
+            b'<' => placeholder_end(bytes, i).unwrap_or(i + 1),
+            _ => i + pattern[i..].chars().next().map_or(1, char::len_utf8),
+        };
+
+        tokens.push(Token { end, depth });
+        i = end;
+    }
+
+    tokens
+}
+
+fn escape_end(pattern: &str, start: usize) -> usize {
+    let bytes = pattern.as_bytes();
+    let Some(c) = pattern[start + 1..].chars().next() else {
+        return bytes.len();
+    };
+    let end = start + 1 + c.len_utf8();
+
+    // Escapes like `\p{L}` or `\x{1F600}` carry their argument in braces.
+    if matches!(c, 'p' | 'P' | 'x' | 'u' | 'U') && bytes.get(end) == Some(&b'{') {
+        return memchr::memchr(b'}', &bytes[end..]).map_or(bytes.len(), |pos| end + pos + 1);
+    }
+    if matches!(c, 'p' | 'P') {
+        return (end + 1).min(bytes.len());
+    }
+
+    end
+}
+
+fn class_end(bytes: &[u8], start: usize) -> usize {
+    let mut i = start + 1;
+    let mut depth = 1;
+
+    if bytes.get(i) == Some(&b'^') {
+        i += 1;
+    }
+    // A closing bracket right at the start is a literal.
+    if bytes.get(i) == Some(&b']') {
+        i += 1;
+    }
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            b'[' => depth += 1,
+            b']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return i + 1;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    bytes.len()
+}
+
+/// End of a group opening like `(`, `(?:` or `(?P<name>`. Flag groups like `(?i)` are returned
+/// whole, including their closing parenthesis.
+fn group_end(bytes: &[u8], start: usize) -> usize {
+    if bytes.get(start + 1) != Some(&b'?') {
+        return start + 1;
+    }
+
+    let mut i = start + 2;
+    while i < bytes.len() {
+        match bytes[i] {
+            b':' | b'>' | b')' => return i + 1,
+            _ => i += 1,
+        }
+    }
+
+    bytes.len()
+}
+
+/// End of a quantifier, including a trailing `?` that makes it lazy. A brace that doesn't form a
+/// valid repetition like `{2}` or `{1,3}` is no quantifier, and `start` is returned.
+fn quantifier_end(bytes: &[u8], start: usize) -> usize {
+    let mut end = if bytes[start] == b'{' {
+        let Some(len) = memchr::memchr(b'}', &bytes[start..]) else {
+            return start;
+        };
+        let inner = &bytes[start + 1..start + len];
+        if inner.is_empty() || !inner.iter().all(|b| b.is_ascii_digit() || *b == b',') {
+            return start;
+        }
+        start + len + 1
+    } else {
+        start + 1
+    };
+
+    if bytes.get(end) == Some(&b'?') {
+        end += 1;
+    }
+
+    end
+}
+
+/// End of a placeholder like `<HOST>`, which consists of uppercase letters and underscores.
+fn placeholder_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let len = memchr::memchr(b'>', &bytes[start..])?;
+    let name = &bytes[start + 1..start + len];
+
+    (!name.is_empty() && name.iter().all(|b| b.is_ascii_uppercase() || *b == b'_'))
+        .then_some(start + len + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(pattern: &str) -> Vec<&str> {
+        let mut start = 0;
+        tokenize(pattern)
+            .into_iter()
+            .map(|token| {
+                let part = &pattern[start..token.end];
+                start = token.end;
+                part
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tokens() {
+        assert_eq!(
+            vec![
+                "^",
+                "<HOST>",
+                " ",
+                "\\[",
+                "[^]\\]]+",
+                "\\]",
+                " ",
+                "(?P<path>",
+                "\\S+?",
+                ")"
+            ],
+            split(r"^<HOST> \[[^]\]]+\] (?P<path>\S+?)")
+        );
+        assert_eq!(
+            vec!["(?i)", "(?:", "a", "|", "b", "){2,3}", "\\p{L}", "x", "{", "y"],
+            split(r"(?i)(?:a|b){2,3}\p{L}x{y")
+        );
+    }
+
+    #[test]
+    fn mismatch() {
+        let pattern = r#"^<HOST> - - \[<TIME>\] "(?P<method>GET) (?P<path>\S+)"#;
+        let line = r#"10.0.0.1 - - [01/Oct/2020:12:00:00 +0000] "POST /login""#;
+
+        assert_eq!(
+            Some(Diagnosis {
+                matched: r#"^<HOST> - - \[<TIME>\] "(?P<method>"#.to_owned(),
+                failed: "G".to_owned(),
+                offset: 43,
+            }),
+            diagnose(pattern, &HostSource::Capture, line)
+        );
+        assert_eq!(
+            None,
+            diagnose(pattern, &HostSource::Capture, &line.replace("POST", "GET"))
+        );
+    }
+}
@@ -0,0 +1,522 @@
+//! Diagnostics of the environment that veto runs in, like missing binaries, kernel modules or
+//! permissions, each with a hint on how to fix it.
+
+use std::{
+    fmt::{self, Display},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::{
+    handler::{self, RuleCache},
+    settings::{self, FirewallBackend, IptablesTarget, Settings},
+};
+
+/// Capability that is needed to change the firewall.
+const CAP_NET_ADMIN: u32 = 12;
+/// Amount of inotify watches below which the limit is likely to be hit by other programs.
+const LOW_WATCHES: u64 = 8192;
+
+/// Severity of a check's result.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// The check couldn't be done, for example on other systems than Linux.
+    Skipped,
+    /// veto can work, but likely runs into problems.
+    Warning,
+    /// veto won't work like this.
+    Error,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::Ok => "ok",
+            Self::Skipped => "skipped",
+            Self::Warning => "warning",
+            Self::Error => "error",
+        })
+    }
+}
+
+/// Result of a single diagnostic check.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub message: String,
+    /// How to fix the problem, if there is one.
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, message: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn ok(name: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(name, Status::Ok, message)
+    }
+
+    fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+/// Run all checks against the configuration and storage at the given locations.
+///
+/// Checks that depend on the configuration, like the required binaries of the firewall, are only
+/// done if the configuration is valid.
+#[must_use]
+pub fn run(config: &Path, storage: &Path) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let settings = match settings::load(Some(config.to_owned())) {
+        Ok(settings) => {
+            checks.push(Check::ok(
+                "configuration",
+                format!("{} is valid", config.display()),
+            ));
+            Some(settings)
+        }
+        Err(e) => {
+            checks.push(
+                Check::new("configuration", Status::Error, e.to_string()).hint(format!(
+                    "fix {} or pass another location with --config",
+                    config.display()
+                )),
+            );
+            None
+        }
+    };
+
+    if let Some(settings) = &settings {
+        checks.extend(check_rules(settings));
+        checks.extend(check_firewall(settings));
+    }
+
+    checks.push(check_inotify(
+        settings.as_ref().map_or(0, |s| s.rules.len()),
+    ));
+    checks.push(check_storage(storage));
+
+    checks
+}
+
+fn check_rules(settings: &Settings) -> Vec<Check> {
+    let mut cache = RuleCache::default();
+
+    settings
+        .rules
+        .iter()
+        .map(|(name, rule)| {
+            let name = format!("rule {name}");
+
+            let missing = match fs::File::open(&rule.file) {
+                Ok(_) => false,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+                Err(e) => {
+                    return Check::new(
+                        name,
+                        Status::Error,
+                        format!("can't read {}: {e}", rule.file.display()),
+                    )
+                    .hint("make sure the log file is readable by veto");
+                }
+            };
+
+            match handler::prepare_rule(name.clone(), rule.clone(), &settings.limits, &mut cache) {
+                Ok(_) if missing => Check::new(
+                    name,
+                    Status::Warning,
+                    format!(
+                        "{} doesn't exist yet, it's watched until it's created",
+                        rule.file.display()
+                    ),
+                )
+                .hint("make sure the path is right and its directory exists"),
+                Ok(_) => Check::ok(name, format!("watching {}", rule.file.display())),
+                Err(e) => Check::new(name, Status::Error, e.to_string())
+                    .hint("check the filters with `veto analyze --debug`"),
+            }
+        })
+        .collect()
+}
+
+fn check_firewall(settings: &Settings) -> Vec<Check> {
+    if settings.firewall.detect_only {
+        return vec![Check::ok(
+            "firewall",
+            "detection only, the firewall isn't changed",
+        )];
+    }
+
+    let mut checks = Vec::new();
+
+    for backend in settings.firewall_backends() {
+        if !backend.is_configured(settings) {
+            checks.push(
+                Check::new(
+                    backend.section(),
+                    Status::Error,
+                    "listed in `firewall.backends`, but not configured",
+                )
+                .hint(format!("add a [{}] section", backend.section())),
+            );
+            continue;
+        }
+
+        checks.extend(check_backend(settings, backend));
+    }
+
+    // The helper changes the firewall instead, so veto itself needs no privileges.
+    if settings
+        .firewall_backends()
+        .iter()
+        .any(|backend| *backend != FirewallBackend::Helper)
+    {
+        checks.push(check_capabilities());
+    }
+
+    // veto still starts without a working firewall, but doesn't block anything.
+    if settings.firewall.fallback == settings::Fallback::DetectOnly {
+        for check in &mut checks {
+            if check.status == Status::Error {
+                check.status = Status::Warning;
+                check
+                    .message
+                    .push_str(", so veto falls back to detection only");
+            }
+        }
+    }
+
+    checks
+}
+
+/// Check the tools that a single firewall needs, which has its settings section.
+fn check_backend(settings: &Settings, backend: FirewallBackend) -> Vec<Check> {
+    match backend {
+        FirewallBackend::Plugin => settings
+            .plugin
+            .iter()
+            .map(|plugin| {
+                which::which(&plugin.command).map_or_else(
+                    |_| {
+                        Check::new(
+                            "plugin",
+                            Status::Error,
+                            format!("{} not found or not executable", plugin.command.display()),
+                        )
+                        .hint("check the `plugin.command` setting")
+                    },
+                    |path| Check::ok("plugin", format!("found at {}", path.display())),
+                )
+            })
+            .collect(),
+        FirewallBackend::Exec => settings
+            .exec
+            .iter()
+            .map(|exec| {
+                // Only the first word is checked, as the commands run in a shell.
+                let missing = [&exec.install, &exec.uninstall]
+                    .into_iter()
+                    .flatten()
+                    .chain([&exec.block, &exec.unblock])
+                    .filter_map(|command| command.split_whitespace().next())
+                    .filter(|program| which::which(program).is_err())
+                    .unique()
+                    .join(", ");
+
+                if missing.is_empty() {
+                    Check::ok("exec", "all commands found")
+                } else {
+                    Check::new("exec", Status::Warning, format!("{missing} not found"))
+                        .hint("check the commands of the `exec` settings")
+                }
+            })
+            .collect(),
+        FirewallBackend::Blackhole => vec![check_binary("ip", "iproute2")],
+        FirewallBackend::Iptables => vec![
+            check_binary("iptables", "iptables"),
+            check_binary("ip6tables", "iptables"),
+        ],
+        FirewallBackend::Tc => check_tc(),
+        FirewallBackend::Helper => settings.helper.iter().map(check_helper).collect(),
+        FirewallBackend::Nginx => settings
+            .nginx
+            .iter()
+            .map(|nginx| {
+                // Only the first word is checked, as the command runs in a shell.
+                let program = nginx.reload.split_whitespace().next().unwrap_or_default();
+
+                if which::which(program).is_ok() {
+                    Check::ok("nginx", format!("reload command {program} found"))
+                } else {
+                    Check::new("nginx", Status::Warning, format!("{program} not found"))
+                        .hint("check the `nginx.reload` setting")
+                }
+            })
+            .collect(),
+        FirewallBackend::Haproxy => settings
+            .haproxy
+            .iter()
+            .map(|haproxy| {
+                if haproxy.socket.exists() {
+                    Check::ok(
+                        "haproxy",
+                        format!("socket found at {}", haproxy.socket.display()),
+                    )
+                } else {
+                    Check::new(
+                        "haproxy",
+                        Status::Error,
+                        format!("socket {} not found", haproxy.socket.display()),
+                    )
+                    .hint(
+                        "open it with `stats socket <path> level admin` in HAProxy's configuration",
+                    )
+                }
+            })
+            .collect(),
+        FirewallBackend::AwsWaf => vec![which::which("aws").map_or_else(
+            |_| {
+                Check::new("aws", Status::Error, "not found").hint(
+                    "install the AWS CLI, and give it credentials that can update the IP sets",
+                )
+            },
+            |path| Check::ok("aws", format!("found at {}", path.display())),
+        )],
+        FirewallBackend::Pf => vec![which::which("pfctl")
+            .or_else(|_| which::which("/sbin/pfctl"))
+            .map_or_else(
+                |_| {
+                    Check::new("pfctl", Status::Error, "not found")
+                        .hint("pf is only available on FreeBSD and OpenBSD")
+                },
+                |path| Check::ok("pfctl", format!("found at {}", path.display())),
+            )],
+        FirewallBackend::Ipset => check_ipset(settings),
+    }
+}
+
+fn check_ipset(settings: &Settings) -> Vec<Check> {
+    let mut checks = [
+        ("ipset", "ipset"),
+        ("iptables", "iptables"),
+        ("ip6tables", "iptables"),
+    ]
+    .into_iter()
+    .map(|(binary, package)| check_binary(binary, package))
+    .collect::<Vec<_>>();
+
+    checks.push(check_module("ip_set", "load it with `modprobe ip_set`"));
+    if matches!(settings.ipset.target, IptablesTarget::Tarpit) {
+        checks.push(check_module(
+            "xt_TARPIT",
+            "install the iptables addons (`xtables-addons-dkms` on Debian) and load it with \
+             `modprobe xt_TARPIT`",
+        ));
+    }
+    if let IptablesTarget::Redirect(port) = settings.ipset.target {
+        if settings.tarpit.port != Some(port) {
+            checks.push(
+                Check::new(
+                    "tarpit",
+                    Status::Warning,
+                    format!(
+                        "connections are redirected to port {port}, but no tarpit listens there"
+                    ),
+                )
+                .hint("set `tarpit.port` to the same port, or run another tarpit on it"),
+            );
+        }
+    }
+
+    checks
+}
+
+fn check_helper(helper: &settings::Helper) -> Check {
+    if helper.socket.exists() {
+        Check::ok(
+            "helper",
+            format!("socket found at {}", helper.socket.display()),
+        )
+    } else {
+        Check::new(
+            "helper",
+            Status::Error,
+            format!("socket {} not found", helper.socket.display()),
+        )
+        .hint("start the helper with `veto helper` as root")
+    }
+}
+
+fn check_tc() -> Vec<Check> {
+    vec![
+        check_binary("tc", "iproute2"),
+        check_binary("ipset", "ipset"),
+        check_module("ip_set", "load it with `modprobe ip_set`"),
+        check_module("em_ipset", "load it with `modprobe em_ipset`"),
+    ]
+}
+
+fn check_binary(name: &str, package: &str) -> Check {
+    let fallback = PathBuf::from("/usr/sbin").join(name);
+
+    match which::which(name) {
+        Ok(path) => Check::ok(name, format!("found at {}", path.display())),
+        Err(_) if fallback.is_file() => Check::ok(name, format!("found at {}", fallback.display())),
+        Err(_) => Check::new(name, Status::Error, "not found")
+            .hint(format!("install the `{package}` package")),
+    }
+}
+
+fn check_module(name: &str, hint: &str) -> Check {
+    let check = format!("kernel module {name}");
+
+    if cfg!(not(target_os = "linux")) {
+        return Check::new(check, Status::Skipped, "only checked on Linux");
+    }
+
+    // Built-in modules are listed here as well, unlike in `/proc/modules`.
+    if Path::new("/sys/module").join(name).exists() {
+        Check::ok(check, "loaded")
+    } else {
+        Check::new(check, Status::Warning, "not loaded").hint(hint)
+    }
+}
+
+fn check_capabilities() -> Check {
+    let name = "permissions";
+
+    let Ok(status) = fs::read_to_string("/proc/self/status") else {
+        return Check::new(name, Status::Skipped, "can't read the process capabilities");
+    };
+
+    match has_capability(&status, CAP_NET_ADMIN) {
+        Some(true) => Check::ok(name, "allowed to change the firewall"),
+        Some(false) => Check::new(name, Status::Error, "missing the CAP_NET_ADMIN capability")
+            .hint(
+                "run veto as root, or grant the capability, like with \
+                 `AmbientCapabilities=CAP_NET_ADMIN` in a systemd service",
+            ),
+        None => Check::new(name, Status::Skipped, "can't read the process capabilities"),
+    }
+}
+
+/// Check the effective capabilities in the content of `/proc/<pid>/status` for a capability.
+fn has_capability(status: &str, capability: u32) -> Option<bool> {
+    let caps = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?
+        .trim();
+
+    u64::from_str_radix(caps, 16)
+        .ok()
+        .map(|caps| caps & (1 << capability) != 0)
+}
+
+fn check_inotify(files: usize) -> Check {
+    let name = "inotify watches";
+
+    let Some(limit) = fs::read_to_string("/proc/sys/fs/inotify/max_user_watches")
+        .ok()
+        .and_then(|limit| limit.trim().parse::<u64>().ok())
+    else {
+        return Check::new(name, Status::Skipped, "can't read the limit");
+    };
+
+    let hint = "raise the limit with `sysctl fs.inotify.max_user_watches=524288`, and persist it \
+                in /etc/sysctl.d";
+
+    if limit < files as u64 {
+        Check::new(
+            name,
+            Status::Error,
+            format!("limit of {limit} is below the {files} watched files"),
+        )
+        .hint(hint)
+    } else if limit < LOW_WATCHES {
+        Check::new(
+            name,
+            Status::Warning,
+            format!("limit of {limit} is low, and shared with other programs"),
+        )
+        .hint(hint)
+    } else {
+        Check::ok(name, format!("limit of {limit}"))
+    }
+}
+
+fn check_storage(storage: &Path) -> Check {
+    let name = "storage";
+
+    // The storage creates missing directories, so the closest existing one must be writable.
+    let Some(dir) = storage.ancestors().skip(1).find(|dir| dir.is_dir()) else {
+        return Check::new(
+            name,
+            Status::Error,
+            format!("no directory found for {}", storage.display()),
+        );
+    };
+
+    let probe = dir.join(format!(".veto-doctor-{}", std::process::id()));
+    match fs::write(&probe, []) {
+        Ok(()) => {
+            fs::remove_file(probe).ok();
+            Check::ok(name, format!("{} is writable", dir.display()))
+        }
+        Err(e) => Check::new(
+            name,
+            Status::Error,
+            format!("can't write to {}: {e}", dir.display()),
+        )
+        .hint(
+            "run veto as a user that owns the storage directory, or pass another location with \
+             --storage",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn capabilities() {
+        let status = "Name:\tveto\nCapInh:\t0000000000000000\nCapEff:\t0000000000001000\n";
+
+        assert_eq!(Some(true), has_capability(status, CAP_NET_ADMIN));
+        assert_eq!(Some(false), has_capability(status, 0));
+        assert_eq!(None, has_capability("Name:\tveto\n", CAP_NET_ADMIN));
+    }
+
+    #[test]
+    fn invalid_config() {
+        let dir = env::temp_dir().join(format!("veto-doctor-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("config.toml");
+        fs::write(&config, "[rules.web]\nfile = 5\n").unwrap();
+
+        let checks = run(&config, &dir.join("storage.bin"));
+
+        assert_eq!(Status::Error, checks[0].status);
+        assert!(checks[0].hint.is_some());
+        assert_eq!(Status::Ok, checks.last().unwrap().status);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
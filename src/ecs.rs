@@ -0,0 +1,137 @@
+//! Export of blocks and unblocks as JSON lines in the Elastic Common Schema (ECS), so they can be
+//! shipped to Elasticsearch or Logstash with Filebeat and correlated with other security events.
+//!
+//! Each line is a single document, built from the same [`audit::Entry`] that the audit log uses.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    thread,
+};
+
+use flume::Receiver;
+use log::warn;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{audit, events::BlockEvent, firewall::Action, settings, Result};
+
+/// Version of the schema that the documents follow.
+pub const ECS_VERSION: &str = "8.11.0";
+
+/// Build the ECS document for a block or unblock.
+#[must_use]
+pub fn document(entry: &audit::Entry) -> Value {
+    let format = |time: OffsetDateTime| time.format(&Rfc3339).unwrap_or_default();
+    let (action, kind) = match entry.action {
+        Action::Block => ("block", "denied"),
+        Action::Unblock => ("unblock", "allowed"),
+    };
+
+    let mut event = json!({
+        "kind": "event",
+        "category": ["network", "intrusion_detection"],
+        "type": [kind],
+        "action": action,
+        "module": "veto",
+        "dataset": "veto.blocks",
+        "reason": entry.reason,
+    });
+    if let Some(until) = entry.until {
+        event["end"] = Value::String(format(until));
+    }
+
+    json!({
+        "@timestamp": format(entry.at),
+        "message": format!("{action} {} by rule {}", entry.ip, entry.rule),
+        "ecs": { "version": ECS_VERSION },
+        "event": event,
+        "source": { "ip": entry.ip },
+        "rule": { "name": entry.rule },
+        "user": { "name": entry.initiator },
+        "observer": { "product": "veto", "type": "firewall" },
+    })
+}
+
+/// File that ECS documents are appended to, one per line.
+pub struct EcsLog {
+    file: Mutex<File>,
+}
+
+impl EcsLog {
+    /// Open the file at the given location, creating it and its directory if needed.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Open the file from the settings, or return [`None`] if the export is disabled.
+    pub fn from_settings(settings: &settings::Ecs) -> io::Result<Option<Self>> {
+        settings.file.as_deref().map(Self::open).transpose()
+    }
+
+    /// Append the document of the entry as a single line.
+    pub fn write(&self, entry: &audit::Entry) -> io::Result<()> {
+        let mut line = document(entry).to_string();
+        line.push('\n');
+
+        self.file.lock().write_all(line.as_bytes())
+    }
+}
+
+/// Start a background thread that writes all events of a handler as ECS documents.
+pub fn log_events(log: EcsLog, events: Receiver<BlockEvent>) -> Result<()> {
+    thread::Builder::new()
+        .name("ecs".to_owned())
+        .spawn(move || {
+            for event in events {
+                let entry = audit::Entry::from_event(&event, OffsetDateTime::now_utc());
+                if let Err(e) = log.write(&entry) {
+                    warn!("failed writing ECS document for {}: {e}", entry.ip);
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use time::macros::datetime;
+
+    use super::*;
+    use crate::events::BanEvent;
+
+    #[test]
+    fn ban_document() {
+        let entry = audit::Entry::from_event(
+            &BlockEvent::Ban(BanEvent {
+                rule: "web".to_owned(),
+                ip: IpAddr::from([10, 0, 0, 1]),
+                until: datetime!(2023-10-01 13:00 UTC),
+            }),
+            datetime!(2023-10-01 12:00 UTC),
+        );
+
+        let document = document(&entry);
+
+        assert_eq!("2023-10-01T12:00:00Z", document["@timestamp"]);
+        assert_eq!("block", document["event"]["action"]);
+        assert_eq!(json!(["denied"]), document["event"]["type"]);
+        assert_eq!("2023-10-01T13:00:00Z", document["event"]["end"]);
+        assert_eq!("10.0.0.1", document["source"]["ip"]);
+        assert_eq!("web", document["rule"]["name"]);
+        assert_eq!("veto", document["user"]["name"]);
+    }
+}
@@ -0,0 +1,372 @@
+//! Additional information about IPs, like their host name, network and abuse contact, so operators
+//! can tell at a glance what kind of client was blocked.
+
+use std::{
+    fmt::{self, Display},
+    io::{self, BufRead, BufReader, Write},
+    net::{IpAddr, TcpStream, ToSocketAddrs},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use flume::Receiver;
+use log::{debug, info};
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::{events::BlockEvent, settings, HashMap, Result};
+
+/// Whois server that maps IPs to their network (ASN) and country.
+const CYMRU_SERVER: &str = "whois.cymru.com";
+/// Whois server that refers to the responsible regional registry of an IP.
+const IANA_SERVER: &str = "whois.iana.org";
+
+/// Information found about a single IP. Each field is [`None`] if the lookup failed or is
+/// disabled.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Enrichment {
+    /// Host name from the reverse DNS lookup.
+    pub hostname: Option<String>,
+    /// Two-letter country code of the network.
+    pub country: Option<String>,
+    /// Autonomous system number of the network.
+    pub asn: Option<u32>,
+    /// Name of the network's owner, like a hoster or ISP.
+    pub network: Option<String>,
+    /// Contact for abuse reports.
+    pub abuse: Option<String>,
+}
+
+impl Enrichment {
+    /// Whether nothing was found at all.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Short summary like `host.example.com, AS64496 Example (US), abuse@example.com`, leaving out
+/// anything that wasn't found.
+impl Display for Enrichment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut separator = "";
+        let mut next = || std::mem::replace(&mut separator, ", ");
+
+        if let Some(hostname) = &self.hostname {
+            write!(f, "{}{hostname}", next())?;
+        }
+        if let Some(asn) = self.asn {
+            write!(f, "{}AS{asn}", next())?;
+            if let Some(network) = &self.network {
+                write!(f, " {network}")?;
+            }
+            if let Some(country) = &self.country {
+                write!(f, " ({country})")?;
+            }
+        } else if let Some(country) = &self.country {
+            write!(f, "{}{country}", next())?;
+        }
+        if let Some(abuse) = &self.abuse {
+            write!(f, "{}{abuse}", next())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Source of information about IPs.
+pub trait Lookup: Send + Sync {
+    /// Fill in the information that this source knows about the IP.
+    fn lookup(&self, ip: IpAddr, enrichment: &mut Enrichment) -> io::Result<()>;
+}
+
+/// Shared lookups, like a database that several consumers use.
+impl<T: Lookup + ?Sized> Lookup for Arc<T> {
+    fn lookup(&self, ip: IpAddr, enrichment: &mut Enrichment) -> io::Result<()> {
+        (**self).lookup(ip, enrichment)
+    }
+}
+
+/// Resolve the host name of IPs through the system's resolver.
+pub struct ReverseDns;
+
+impl Lookup for ReverseDns {
+    fn lookup(&self, ip: IpAddr, enrichment: &mut Enrichment) -> io::Result<()> {
+        let hostname = dns_lookup::lookup_addr(&ip)?;
+
+        // Without a PTR record, the IP itself is returned.
+        if hostname.parse::<IpAddr>().is_err() {
+            enrichment.hostname = Some(hostname);
+        }
+
+        Ok(())
+    }
+}
+
+/// Look up the network and country through Team Cymru's whois service, and the abuse contact
+/// through the whois server of the regional registry that the IP belongs to.
+pub struct Whois {
+    timeout: Duration,
+}
+
+impl Whois {
+    #[must_use]
+    pub const fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+
+    fn query(&self, server: &str, query: &str) -> io::Result<String> {
+        let addr = (server, 43)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for server"))?;
+
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.write_all(format!("{query}\r\n").as_bytes())?;
+
+        let mut response = String::new();
+        for line in BufReader::new(stream).lines() {
+            response.push_str(&line?);
+            response.push('\n');
+        }
+
+        Ok(response)
+    }
+}
+
+impl Lookup for Whois {
+    fn lookup(&self, ip: IpAddr, enrichment: &mut Enrichment) -> io::Result<()> {
+        let response = self.query(CYMRU_SERVER, &format!(" -v {ip}"))?;
+        if let Some((asn, country, network)) = parse_cymru(&response) {
+            enrichment.asn = Some(asn);
+            enrichment.country = country;
+            enrichment.network = network;
+        }
+
+        let response = self.query(IANA_SERVER, &ip.to_string())?;
+        if let Some(server) = parse_refer(&response) {
+            let response = self.query(server, &ip.to_string())?;
+            enrichment.abuse = parse_abuse(&response);
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse the verbose response of Team Cymru's whois service, which is a table with a header like
+/// `AS | IP | BGP Prefix | CC | Registry | Allocated | AS Name`.
+fn parse_cymru(response: &str) -> Option<(u32, Option<String>, Option<String>)> {
+    let line = response
+        .lines()
+        .filter(|line| !line.starts_with("AS ") && !line.starts_with("Bulk mode"))
+        .find(|line| line.contains('|'))?;
+    let fields = line.split('|').map(str::trim).collect::<Vec<_>>();
+
+    let asn = fields.first()?.parse().ok()?;
+    let non_empty = |i: usize| {
+        fields
+            .get(i)
+            .filter(|f| !f.is_empty())
+            .map(|&f| f.to_owned())
+    };
+
+    Some((asn, non_empty(3), non_empty(6)))
+}
+
+/// Find the whois server that IANA refers to.
+fn parse_refer(response: &str) -> Option<&str> {
+    response
+        .lines()
+        .find_map(|line| line.strip_prefix("refer:"))
+        .map(str::trim)
+        .filter(|server| !server.is_empty())
+}
+
+/// Find the abuse contact in a registry's response, which each registry formats differently.
+fn parse_abuse(response: &str) -> Option<String> {
+    response.lines().find_map(|line| {
+        // RIPE mentions it in a comment like `% Abuse contact for '...' is 'abuse@...'`.
+        if line.starts_with("% Abuse contact for") {
+            return line.rsplit('\'').nth(1).map(ToOwned::to_owned);
+        }
+
+        let (key, value) = line.split_once(':')?;
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim();
+
+        if value.is_empty() {
+            return None;
+        }
+
+        match key.as_str() {
+            "abuse-mailbox" | "orgabuseemail" => Some(value.to_owned()),
+            _ => None,
+        }
+    })
+}
+
+/// Runs lookups for IPs and caches their results.
+pub struct Enricher {
+    lookups: Vec<Box<dyn Lookup>>,
+    ttl: Duration,
+    cache: Mutex<HashMap<IpAddr, (Instant, Enrichment)>>,
+}
+
+impl Enricher {
+    /// Create an enricher with the lookups that are enabled in the settings.
+    #[must_use]
+    pub fn new(settings: &settings::Enrichment) -> Self {
+        let mut enricher = Self::with_lookups(Vec::new(), settings.cache_ttl.unsigned_abs());
+
+        if settings.rdns {
+            enricher.lookups.push(Box::new(ReverseDns));
+        }
+        if settings.whois {
+            enricher
+                .lookups
+                .push(Box::new(Whois::new(settings.timeout.unsigned_abs())));
+        }
+
+        enricher
+    }
+
+    /// Create an enricher with custom lookups, that are run in the given order.
+    #[must_use]
+    pub fn with_lookups(lookups: Vec<Box<dyn Lookup>>, ttl: Duration) -> Self {
+        Self {
+            lookups,
+            ttl,
+            cache: Mutex::default(),
+        }
+    }
+
+    /// Add a lookup, that runs after all existing ones.
+    pub fn push(&mut self, lookup: Box<dyn Lookup>) {
+        self.lookups.push(lookup);
+    }
+
+    /// Whether any lookups are enabled.
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        !self.lookups.is_empty()
+    }
+
+    /// Get all information about the IP, from the cache if it was looked up recently. Failing
+    /// lookups are skipped.
+    pub fn enrich(&self, ip: IpAddr) -> Enrichment {
+        if let Some((at, enrichment)) = self.cache.lock().get(&ip) {
+            if at.elapsed() < self.ttl {
+                return enrichment.clone();
+            }
+        }
+
+        let mut enrichment = Enrichment::default();
+        for lookup in &self.lookups {
+            if let Err(e) = lookup.lookup(ip, &mut enrichment) {
+                debug!("failed looking up information about {ip}: {e}");
+            }
+        }
+
+        let mut cache = self.cache.lock();
+        cache.retain(|_, (at, _)| at.elapsed() < self.ttl);
+        cache.insert(ip, (Instant::now(), enrichment.clone()));
+
+        enrichment
+    }
+}
+
+/// Start a background thread that logs the details of each newly blocked IP. The lookups are done
+/// on that thread, so they never delay the blocking itself.
+pub fn log_events(enricher: Enricher, events: Receiver<BlockEvent>) -> Result<()> {
+    thread::Builder::new()
+        .name("enrichment".to_owned())
+        .spawn(move || {
+            for event in events {
+                if let BlockEvent::Ban(ban) = event {
+                    let enrichment = enricher.enrich(ban.ip);
+                    if !enrichment.is_empty() {
+                        info!("blocked {} by rule {}: {enrichment}", ban.ip, ban.rule);
+                    }
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+
+    #[test]
+    fn parse_responses() {
+        let cymru =
+            "AS      | IP               | BGP Prefix          | CC | Registry | Allocated  | AS \
+             Name\n15169   | 8.8.8.8          | 8.8.8.0/24          | US | arin     | 1992-12-01 \
+             | GOOGLE, US\n";
+        assert_eq!(
+            Some((15169, Some("US".to_owned()), Some("GOOGLE, US".to_owned()))),
+            parse_cymru(cymru)
+        );
+
+        assert_eq!(
+            Some("whois.ripe.net"),
+            parse_refer("% IANA WHOIS server\n\nrefer:        whois.ripe.net\n")
+        );
+
+        assert_eq!(
+            Some("abuse@example.com".to_owned()),
+            parse_abuse("% Abuse contact for '192.0.2.0 - 192.0.2.255' is 'abuse@example.com'\n")
+        );
+        assert_eq!(
+            Some("abuse@example.org".to_owned()),
+            parse_abuse("OrgName: Example\nOrgAbuseEmail:  abuse@example.org\n")
+        );
+        assert_eq!(None, parse_abuse("remarks: nothing here\n"));
+    }
+
+    #[test]
+    fn cache() {
+        struct Counting(AtomicUsize);
+
+        impl Lookup for &'static Counting {
+            fn lookup(&self, _ip: IpAddr, enrichment: &mut Enrichment) -> io::Result<()> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                enrichment.hostname = Some("host.example.com".to_owned());
+                Ok(())
+            }
+        }
+
+        static COUNTING: Counting = Counting(AtomicUsize::new(0));
+
+        let enricher = Enricher::with_lookups(vec![Box::new(&COUNTING)], Duration::from_secs(60));
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        assert_eq!(
+            Some("host.example.com"),
+            enricher.enrich(ip).hostname.as_deref()
+        );
+        assert!(!enricher.enrich(ip).is_empty());
+        assert_eq!(1, COUNTING.0.load(Ordering::Relaxed));
+
+        let enrichment = Enrichment {
+            hostname: None,
+            country: Some("US".to_owned()),
+            asn: Some(64496),
+            network: Some("EXAMPLE".to_owned()),
+            abuse: Some("abuse@example.com".to_owned()),
+        };
+        assert_eq!(
+            "AS64496 EXAMPLE (US), abuse@example.com",
+            enrichment.to_string()
+        );
+    }
+}
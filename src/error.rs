@@ -0,0 +1,168 @@
+use std::{io, path::PathBuf};
+
+use thiserror::Error;
+
+/// Result type of the library, with [`Error`] as default error type.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// All errors that can occur while using the library.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The settings file couldn't be read.
+    #[error("failed reading settings file {path:?}")]
+    ReadSettings {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// The settings file isn't valid TOML or contains invalid values.
+    #[error("invalid settings")]
+    Settings(#[from] basic_toml::Error),
+    /// A log file of a rule couldn't be opened.
+    #[error("failed opening log file {path:?}")]
+    LogFile {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// A log line isn't valid UTF-8.
+    #[error("invalid UTF-8 in log line")]
+    Utf8(#[from] std::str::Utf8Error),
+    /// The regex of a filter is invalid or exceeds the size limit.
+    #[error("invalid filter pattern")]
+    Pattern(#[from] regex::Error),
+    /// The regex of a filter couldn't be parsed for literal extraction.
+    #[error("invalid filter pattern")]
+    PatternSyntax(#[source] Box<regex_syntax::Error>),
+    /// A prefilter or blacklist couldn't be compiled.
+    #[error("failed building word matcher")]
+    WordMatcher(#[from] aho_corasick::BuildError),
+    /// A binary that the firewall depends on couldn't be found.
+    #[error("cannot find binary path of '{0}'")]
+    MissingBinary(String),
+    /// The time format of a rule is neither registered nor a valid format description.
+    #[error("unknown time format '{format}'")]
+    TimeFormat {
+        format: String,
+        #[source]
+        source: Option<time::error::InvalidFormatDescription>,
+    },
+    /// A line was pushed for a rule that doesn't exist.
+    #[error("rule '{0}' doesn't exist")]
+    UnknownRule(String),
+    /// A rule refers to a host extractor that wasn't registered.
+    #[error("host extractor '{0}' doesn't exist")]
+    UnknownHostExtractor(String),
+    /// A plugin module couldn't be loaded, or plugins aren't supported by this build.
+    #[error("failed loading plugin {path:?}")]
+    Plugin {
+        path: PathBuf,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// A firewall command couldn't be started.
+    #[error("failed running {program}")]
+    Spawn {
+        program: String,
+        #[source]
+        source: io::Error,
+    },
+    /// A firewall command ran, but reported a failure.
+    #[error("failed {action}: {stderr}")]
+    Command {
+        /// Description of what the command tried to do.
+        action: &'static str,
+        /// Error output of the command.
+        stderr: String,
+    },
+    /// The kernel rejected a netlink request of the firewall.
+    #[error("failed {action}")]
+    Netlink {
+        /// Description of what the request tried to do.
+        action: &'static str,
+        #[source]
+        source: io::Error,
+    },
+    /// The firewall can't hold any more entries, like an ipset table that reached its `maxelem`.
+    #[error("firewall is full")]
+    FirewallFull,
+    /// The storage reached its limit and all its entries are still actively blocked.
+    #[error("storage limit of {0} entries reached, but all entries are still active")]
+    StorageFull(usize),
+    /// The thread running firewall commands in the background stopped.
+    #[error("firewall worker stopped unexpectedly")]
+    WorkerStopped,
+    /// The storage couldn't be persisted.
+    #[error("failed persisting storage")]
+    Persist(#[from] bincode::Error),
+    /// The storage backend isn't supported by this build.
+    #[error("storage backend '{0}' requires the `{0}` feature")]
+    UnsupportedStorage(crate::storage::Backend),
+    /// Entries got lost while copying them to another storage.
+    #[error("expected {expected} entries after migrating the storage, but found {found}")]
+    Migration { expected: usize, found: usize },
+    /// A query of the `SQLite` storage failed.
+    #[cfg(feature = "sqlite")]
+    #[error("sqlite storage failed")]
+    Sqlite(#[from] rusqlite::Error),
+    /// A `GeoIP` database or the Tor exit list couldn't be downloaded.
+    #[cfg(any(feature = "geoip", feature = "tor"))]
+    #[error("download failed")]
+    Download(#[source] Box<ureq::Error>),
+    /// A `GeoIP` database is invalid or can't be read.
+    #[cfg(feature = "geoip")]
+    #[error("invalid GeoIP database")]
+    GeoIpDatabase(#[from] maxminddb::MaxMindDBError),
+    /// A downloaded `GeoIP` database doesn't match its published checksum.
+    #[error("checksum mismatch, expected {expected} but found {found}")]
+    Checksum { expected: String, found: String },
+    /// The Tor exit list is enabled, but not supported by this build.
+    #[error("the Tor exit list requires the `tor` feature")]
+    UnsupportedTor,
+    /// The `GeoIP` settings are incomplete, or `GeoIP` isn't supported by this build.
+    #[error("invalid GeoIP settings: {0}")]
+    GeoIpSettings(&'static str),
+    /// Watching the log files for changes failed.
+    #[error("failed watching log files")]
+    Watch(#[from] notify::Error),
+    /// The metrics endpoint returned an unexpected response.
+    #[error("metrics request failed: {0}")]
+    Metrics(String),
+    /// Any other I/O error.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// Error of a custom firewall or repository implementation.
+    #[error(transparent)]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl Error {
+    /// Whether the error was caused by missing privileges, like firewall commands that lack the
+    /// `CAP_NET_ADMIN` capability.
+    #[must_use]
+    pub fn is_permission_denied(&self) -> bool {
+        match self {
+            Self::Spawn { source, .. } | Self::Netlink { source, .. } | Self::Io(source) => {
+                source.kind() == io::ErrorKind::PermissionDenied
+            }
+            Self::Command { stderr, .. } => ["Operation not permitted", "Permission denied"]
+                .iter()
+                .any(|message| stderr.contains(message)),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(any(feature = "geoip", feature = "tor"))]
+impl From<ureq::Error> for Error {
+    fn from(value: ureq::Error) -> Self {
+        Self::Download(Box::new(value))
+    }
+}
+
+impl From<regex_syntax::Error> for Error {
+    fn from(value: regex_syntax::Error) -> Self {
+        Self::PatternSyntax(Box::new(value))
+    }
+}
@@ -0,0 +1,85 @@
+//! Events about blocked and unblocked IPs, that library users and integrations can subscribe to.
+
+use std::net::IpAddr;
+
+use flume::{Receiver, Sender};
+use time::OffsetDateTime;
+
+/// An IP was newly blocked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BanEvent {
+    /// Name of the rule that matched.
+    pub rule: String,
+    /// The blocked IP address.
+    pub ip: IpAddr,
+    /// Time until the IP stays blocked.
+    pub until: OffsetDateTime,
+}
+
+/// An IP was unblocked, after its block timed out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnbanEvent {
+    /// Name of the rule that originally blocked the IP.
+    pub rule: String,
+    /// The unblocked IP address.
+    pub ip: IpAddr,
+}
+
+/// Any change to the blocked IPs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockEvent {
+    Ban(BanEvent),
+    Unban(UnbanEvent),
+}
+
+/// Distributes events to all subscribers, each with their own channel.
+#[derive(Debug, Default)]
+pub struct EventBus {
+    subscribers: Vec<Sender<BlockEvent>>,
+}
+
+impl EventBus {
+    /// Receive all events from now on. The subscription ends once the receiver is dropped.
+    ///
+    /// The channel is unbounded, so the receiver should be drained continuously.
+    pub fn subscribe(&mut self) -> Receiver<BlockEvent> {
+        let (tx, rx) = flume::unbounded();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// Amount of active subscriptions.
+    #[must_use]
+    pub const fn subscribers(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    /// Send the event to all subscribers, removing the ones that went away.
+    pub(crate) fn publish(&mut self, event: &BlockEvent) {
+        self.subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn publish() {
+        let mut bus = EventBus::default();
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+        drop(second);
+
+        let event = BlockEvent::Unban(UnbanEvent {
+            rule: "web".to_owned(),
+            ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+        });
+        bus.publish(&event);
+
+        assert_eq!(Ok(event), first.try_recv());
+        assert_eq!(1, bus.subscribers());
+    }
+}
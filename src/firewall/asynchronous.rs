@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use super::{Firewall, OwnedTarget, Target};
+use crate::Result;
+
+/// Async variant of the [`Firewall`] trait, for firewalls that are controlled over the network
+/// and shouldn't block a thread while waiting for a response.
+#[async_trait]
+pub trait AsyncFirewall: Send + Sync {
+    /// Setup the firewall for usage. See [`Firewall::install`].
+    async fn install(&self) -> Result<()>;
+    /// Remove any changes from [`Self::install`]. See [`Firewall::uninstall`].
+    async fn uninstall(&self) -> Result<()>;
+    /// Block requests from the given target. See [`Firewall::block`].
+    async fn block(&self, target: &Target<'_>) -> Result<()>;
+    /// Remove the target from the firewall. See [`Firewall::unblock`].
+    async fn unblock(&self, target: &Target<'_>) -> Result<()>;
+    /// Block several targets at once, which by default blocks each target individually.
+    async fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        for target in targets {
+            self.block(target).await?;
+        }
+        Ok(())
+    }
+    /// Unblock several targets at once, which by default unblocks each target individually.
+    async fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        for target in targets {
+            self.unblock(target).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Adapter that turns any [`Firewall`] into an [`AsyncFirewall`], by running its commands on a
+/// thread pool for blocking operations. It works with any async runtime.
+pub struct FromSync<F>(Arc<F>);
+
+impl<F> FromSync<F>
+where
+    F: Firewall + Send + Sync + 'static,
+{
+    pub fn new(firewall: F) -> Self {
+        Self(Arc::new(firewall))
+    }
+
+    async fn run(&self, f: impl FnOnce(&F) -> Result<()> + Send + 'static) -> Result<()> {
+        let firewall = Arc::clone(&self.0);
+        blocking::unblock(move || f(&firewall)).await
+    }
+
+    async fn run_all(
+        &self,
+        targets: &[Target<'_>],
+        f: fn(&F, &[Target<'_>]) -> Result<()>,
+    ) -> Result<()> {
+        let targets = OwnedTarget::from_slice(targets);
+        self.run(move |firewall| {
+            let targets = targets.iter().map(OwnedTarget::borrow).collect::<Vec<_>>();
+            f(firewall, &targets)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl<F> AsyncFirewall for FromSync<F>
+where
+    F: Firewall + Send + Sync + 'static,
+{
+    async fn install(&self) -> Result<()> {
+        self.run(F::install).await
+    }
+
+    async fn uninstall(&self) -> Result<()> {
+        self.run(F::uninstall).await
+    }
+
+    async fn block(&self, target: &Target<'_>) -> Result<()> {
+        self.run_all(std::slice::from_ref(target), F::block_all)
+            .await
+    }
+
+    async fn unblock(&self, target: &Target<'_>) -> Result<()> {
+        self.run_all(std::slice::from_ref(target), F::unblock_all)
+            .await
+    }
+
+    async fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.run_all(targets, F::block_all).await
+    }
+
+    async fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.run_all(targets, F::unblock_all).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{settings::Protocol, testing::MockFirewall};
+
+    #[test]
+    fn from_sync() {
+        let firewall = FromSync::new(MockFirewall::new());
+        let targets = [
+            Target {
+                ip: "10.0.0.1".parse().unwrap(),
+                ports: &[],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            },
+            Target {
+                ip: "10.0.0.2".parse().unwrap(),
+                ports: &[80],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            },
+        ];
+
+        futures_lite::future::block_on(async {
+            firewall.block_all(&targets).await.unwrap();
+            assert_eq!(2, firewall.0.blocked().len());
+
+            firewall.unblock(&targets[0]).await.unwrap();
+            let blocked = firewall.0.blocked();
+            assert_eq!(1, blocked.len());
+            assert!(blocked.contains(&targets[1].ip));
+        });
+    }
+}
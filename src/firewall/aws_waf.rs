@@ -0,0 +1,305 @@
+use std::{
+    io,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+use ipnetwork::IpNetwork;
+use itertools::Itertools;
+use log::warn;
+use serde::Deserialize;
+
+use super::{check, command_line, find_binary, run, Action, Firewall, Target};
+use crate::{
+    settings::{AwsWaf as Settings, WafIpSet},
+    Error, Result,
+};
+
+/// Maximum amount of addresses in a single IP set, as limited by AWS.
+const MAX_ADDRESSES: usize = 10_000;
+/// Maximum attempts to update a set, which fails if someone else changed it in the meantime.
+const MAX_ATTEMPTS: usize = 3;
+
+/// Firewall that blocks IPs at the edge, by keeping them in IP sets of AWS WAF.
+///
+/// The sets must already exist and be referenced by a blocking rule of a web ACL. Each change
+/// replaces the whole address list of a set, guarded by the lock token of the last read, so
+/// changes of others in the meantime are never overwritten.
+pub struct AwsWaf {
+    aws_path: PathBuf,
+    settings: Settings,
+}
+
+/// Current content of an IP set, as returned by `aws wafv2 get-ip-set`.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Response {
+    #[serde(rename = "IPSet")]
+    ip_set: Content,
+    lock_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Content {
+    addresses: Vec<IpNetwork>,
+}
+
+impl AwsWaf {
+    pub fn new(settings: Settings) -> Result<Self> {
+        Ok(Self {
+            aws_path: find_binary("aws", "/usr/local/bin/aws")?,
+            settings,
+        })
+    }
+
+    /// All configured sets, with the address family they hold.
+    fn sets(&self) -> impl Iterator<Item = (&WafIpSet, bool)> {
+        let ipv6 = self.settings.ipv6.as_ref().map(|set| (set, false));
+        std::iter::once((&self.settings.ipv4, true)).chain(ipv6)
+    }
+
+    /// Command for an operation like `get-ip-set` on the set.
+    fn set_cmd(&self, operation: &str, set: &WafIpSet) -> Command {
+        let mut cmd = Command::new(&self.aws_path);
+        cmd.args(["wafv2", operation, "--name", &set.name, "--id", &set.id])
+            .args(["--scope", &self.settings.scope.to_string()]);
+
+        if let Some(region) = &self.settings.region {
+            cmd.args(["--region", region]);
+        }
+
+        cmd.args(["--output", "json"]);
+        cmd
+    }
+
+    /// Current addresses and lock token of the set.
+    fn get(&self, set: &WafIpSet) -> Result<Response> {
+        let output = run(&mut self.set_cmd("get-ip-set", set))?;
+        check(&output, "reading AWS WAF IP set")?;
+
+        Ok(serde_json::from_slice(&output.stdout).map_err(io::Error::from)?)
+    }
+
+    fn update(&self, set: &WafIpSet, addresses: &[IpNetwork], lock_token: &str) -> Result<Output> {
+        let addresses = serde_json::to_string(addresses).map_err(io::Error::from)?;
+
+        run(self.set_cmd("update-ip-set", set).args([
+            "--addresses",
+            &addresses,
+            "--lock-token",
+            lock_token,
+        ]))
+    }
+
+    /// Apply the change to the addresses of the set, and retry if someone else changed the set
+    /// between reading and updating it.
+    fn modify(&self, set: &WafIpSet, change: impl Fn(&mut Vec<IpNetwork>)) -> Result<()> {
+        for attempt in 1..=MAX_ATTEMPTS {
+            let Response {
+                ip_set: Content { mut addresses },
+                lock_token,
+            } = self.get(set)?;
+
+            let before = addresses.len();
+            change(&mut addresses);
+            if addresses.len() == before {
+                return Ok(());
+            }
+
+            if addresses.len() > MAX_ADDRESSES {
+                return Err(Error::Command {
+                    action: "updating AWS WAF IP set",
+                    stderr: format!("more than {MAX_ADDRESSES} addresses"),
+                });
+            }
+
+            let output = self.update(set, &addresses, &lock_token)?;
+            if attempt < MAX_ATTEMPTS
+                && String::from_utf8_lossy(&output.stderr).contains("WAFOptimisticLockException")
+            {
+                warn!("AWS WAF IP set {} changed concurrently, retrying", set.name);
+                continue;
+            }
+
+            return check(&output, "updating AWS WAF IP set");
+        }
+
+        Ok(())
+    }
+
+    /// Apply the action to each set, for the targets of its address family.
+    fn apply(&self, action: Action, targets: &[Target<'_>]) -> Result<()> {
+        if self.settings.ipv6.is_none() && targets.iter().any(|target| target.ip.is_ipv6()) {
+            warn!("no AWS WAF IP set for IPv6 addresses configured, skipping them");
+        }
+
+        for (set, v4) in self.sets() {
+            let networks = networks(targets, v4);
+            if !networks.is_empty() {
+                self.modify(set, |addresses| change(addresses, action, &networks))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn members(&self) -> Result<Vec<IpNetwork>> {
+        let mut ips = Vec::new();
+        for (set, _) in self.sets() {
+            ips.extend(self.get(set)?.ip_set.addresses);
+        }
+
+        Ok(ips)
+    }
+}
+
+impl Firewall for AwsWaf {
+    /// Make sure that all sets exist. The web ACL rules that reference them are left to the
+    /// user, as their priority and action depend on the rest of the ACL.
+    fn install(&self) -> Result<()> {
+        for (set, _) in self.sets() {
+            self.get(set)?;
+        }
+
+        Ok(())
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        for (set, _) in self.sets() {
+            self.modify(set, Vec::clear)?;
+        }
+
+        Ok(())
+    }
+
+    fn block(&self, target: &Target<'_>) -> Result<()> {
+        self.apply(Action::Block, std::slice::from_ref(target))
+    }
+
+    fn unblock(&self, target: &Target<'_>) -> Result<()> {
+        self.apply(Action::Unblock, std::slice::from_ref(target))
+    }
+
+    fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.apply(Action::Block, targets)
+    }
+
+    fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.apply(Action::Unblock, targets)
+    }
+
+    fn is_installed(&self) -> Result<Option<bool>> {
+        self.install().map(|()| Some(true))
+    }
+
+    fn is_blocked(&self, target: &Target<'_>) -> Result<Option<bool>> {
+        Ok(Some(self.members()?.contains(&target.ip)))
+    }
+
+    fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+        self.members().map(Some)
+    }
+
+    fn describe(&self, action: Action, targets: &[Target<'_>]) -> Option<Vec<String>> {
+        let verb = match action {
+            Action::Block => "add",
+            Action::Unblock => "remove",
+        };
+
+        Some(
+            self.sets()
+                .filter_map(|(set, v4)| {
+                    let networks = networks(targets, v4);
+                    (!networks.is_empty()).then(|| {
+                        format!(
+                            "{} --addresses <current addresses> --lock-token <current token> # {verb} {}",
+                            command_line(&self.set_cmd("update-ip-set", set)),
+                            networks.iter().join(" ")
+                        )
+                    })
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Networks of the targets of one address family.
+fn networks(targets: &[Target<'_>], v4: bool) -> Vec<IpNetwork> {
+    targets
+        .iter()
+        .filter(|target| target.ip.is_ipv4() == v4)
+        .map(|target| target.ip)
+        .collect()
+}
+
+/// Add the networks to the addresses of a set, or remove them, keeping any other addresses.
+fn change(addresses: &mut Vec<IpNetwork>, action: Action, networks: &[IpNetwork]) {
+    match action {
+        Action::Block => {
+            for network in networks {
+                if !addresses.contains(network) {
+                    addresses.push(*network);
+                }
+            }
+        }
+        Action::Unblock => addresses.retain(|address| !networks.contains(address)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Protocol;
+
+    #[test]
+    fn change_addresses() {
+        let response = serde_json::from_str::<Response>(
+            r#"{
+                "IPSet": {
+                    "Name": "veto",
+                    "Id": "a1b2c3d4-5678-90ab-cdef-EXAMPLE11111",
+                    "ARN": "arn:aws:wafv2:eu-central-1:123456789012:regional/ipset/veto/a1b2c3d4",
+                    "Description": "",
+                    "IPAddressVersion": "IPV4",
+                    "Addresses": ["10.0.0.1/32", "192.168.0.0/16"]
+                },
+                "LockToken": "0674c84b-0304-47fe-8728-c6bff46af8fc"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!("0674c84b-0304-47fe-8728-c6bff46af8fc", response.lock_token);
+
+        let targets = [
+            Target {
+                ip: "10.0.0.1".parse().unwrap(),
+                ports: &[],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            },
+            Target {
+                ip: "10.0.0.2".parse().unwrap(),
+                ports: &[],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            },
+            Target {
+                ip: "2001:db8::1".parse().unwrap(),
+                ports: &[],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            },
+        ];
+        let networks = networks(&targets, true);
+
+        let mut addresses = response.ip_set.addresses;
+        change(&mut addresses, Action::Block, &networks);
+        assert_eq!(
+            "10.0.0.1/32 192.168.0.0/16 10.0.0.2/32",
+            addresses.iter().join(" ")
+        );
+
+        change(&mut addresses, Action::Unblock, &networks);
+        assert_eq!("192.168.0.0/16", addresses.iter().join(" "));
+    }
+}
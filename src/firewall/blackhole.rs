@@ -0,0 +1,248 @@
+use std::{
+    fmt::Write as _,
+    io::Write as _,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use ipnetwork::IpNetwork;
+use log::warn;
+
+use super::{check, find_binary, run, Action, Firewall, Target};
+use crate::{settings::Blackhole as Settings, Error, Result};
+
+/// Routing protocol that the routes are tagged with, so they can be told apart from all other
+/// routes. The number isn't assigned to any routing daemon.
+const PROTOCOL: &str = "249";
+
+/// Firewall that adds a blackhole route for each blocked IP, for hosts where the packet filter is
+/// managed by other software and only routing changes are acceptable.
+///
+/// Packets from a blocked IP still arrive, but all answers are dropped, so no connection can be
+/// established. This always affects all ports. Without any firewall rules, nothing has to be
+/// installed.
+pub struct Blackhole {
+    ip_path: PathBuf,
+    settings: Settings,
+}
+
+impl Blackhole {
+    pub fn new(settings: Settings) -> Result<Self> {
+        if cfg!(not(target_os = "linux")) {
+            warn!("The blackhole firewall is only supported on Linux systems");
+        }
+
+        Ok(Self {
+            ip_path: find_binary("ip", "/usr/sbin/ip")?,
+            settings,
+        })
+    }
+
+    /// Arguments that select the routes of veto, after the destination.
+    fn route_args(&self) -> Vec<String> {
+        let mut args = vec!["proto".to_owned(), PROTOCOL.to_owned()];
+        if let Some(table) = self.settings.table {
+            args.extend(["table".to_owned(), table.to_string()]);
+        }
+
+        args
+    }
+
+    /// Input for `ip -batch`, with one route command for each target. The address family is
+    /// derived from each IP.
+    fn batch_input(&self, command: &str, targets: &[Target<'_>]) -> String {
+        let args = self.route_args().join(" ");
+        let mut input = String::new();
+
+        for target in targets {
+            _ = writeln!(
+                input,
+                "route {command} blackhole {} {args}",
+                target.address()
+            );
+        }
+
+        input
+    }
+
+    /// Run all route commands in a single process. With `-force`, the remaining commands still
+    /// run if one fails, like when deleting a route that is already gone.
+    fn batch(&self, command: &str, targets: &[Target<'_>]) -> Result<()> {
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        let mut child = Command::new(&self.ip_path)
+            .args(["-force", "-batch", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|source| Error::Spawn {
+                program: self.ip_path.display().to_string(),
+                source,
+            })?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(self.batch_input(command, targets).as_bytes())?;
+        }
+
+        let output = child.wait_with_output()?;
+
+        // Deleting routes that don't exist is fine, as the IP isn't blocked either way.
+        if command == "del" && is_missing_route(&String::from_utf8_lossy(&output.stderr)) {
+            return Ok(());
+        }
+
+        check(&output, "changing blackhole routes")
+    }
+
+    fn family_cmd(&self, family: &str, command: &str) -> Command {
+        let mut cmd = Command::new(&self.ip_path);
+        cmd.args([family, "route", command, "type", "blackhole"])
+            .args(self.route_args());
+        cmd
+    }
+}
+
+impl Firewall for Blackhole {
+    fn install(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        for family in ["-4", "-6"] {
+            let output = run(&mut self.family_cmd(family, "flush"))?;
+            check(&output, "flushing blackhole routes")?;
+        }
+
+        Ok(())
+    }
+
+    fn block(&self, target: &Target<'_>) -> Result<()> {
+        self.block_all(std::slice::from_ref(target))
+    }
+
+    fn unblock(&self, target: &Target<'_>) -> Result<()> {
+        self.unblock_all(std::slice::from_ref(target))
+    }
+
+    fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.batch("replace", targets)
+    }
+
+    fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.batch("del", targets)
+    }
+
+    /// Always installed, as there is nothing to install.
+    fn is_installed(&self) -> Result<Option<bool>> {
+        Ok(Some(true))
+    }
+
+    fn is_blocked(&self, target: &Target<'_>) -> Result<Option<bool>> {
+        Ok(self.list_blocked()?.map(|ips| ips.contains(&target.ip)))
+    }
+
+    fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+        let mut ips = Vec::new();
+
+        for family in ["-4", "-6"] {
+            let output = run(&mut self.family_cmd(family, "show"))?;
+            check(&output, "listing blackhole routes")?;
+
+            ips.extend(parse_routes(&String::from_utf8_lossy(&output.stdout)));
+        }
+
+        Ok(Some(ips))
+    }
+
+    fn describe(&self, action: Action, targets: &[Target<'_>]) -> Option<Vec<String>> {
+        if targets.is_empty() {
+            return Some(Vec::new());
+        }
+
+        let command = match action {
+            Action::Block => "replace",
+            Action::Unblock => "del",
+        };
+
+        Some(vec![format!(
+            "{} -force -batch - <<EOF\n{}EOF",
+            self.ip_path.display(),
+            self.batch_input(command, targets)
+        )])
+    }
+}
+
+/// Get the networks of a route listing like `blackhole 10.0.0.1 proto 249`. Host routes are listed
+/// without a prefix.
+fn parse_routes(output: &str) -> Vec<IpNetwork> {
+    output
+        .lines()
+        .filter_map(|l| l.strip_prefix("blackhole ")?.split_whitespace().next())
+        .filter_map(|destination| destination.parse().ok())
+        .collect()
+}
+
+/// Whether the error output only reports routes that don't exist.
+fn is_missing_route(stderr: &str) -> bool {
+    stderr
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .all(|l| l.contains("No such process") || l.starts_with("Command failed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Protocol;
+
+    #[test]
+    fn routes() {
+        let blackhole = Blackhole {
+            ip_path: PathBuf::from("/usr/sbin/ip"),
+            settings: Settings { table: Some(100) },
+        };
+        let targets = [
+            Target {
+                ip: "10.0.0.1".parse().unwrap(),
+                ports: &[22],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            },
+            Target {
+                ip: "2001:db8::1".parse().unwrap(),
+                ports: &[],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            },
+        ];
+
+        assert_eq!(
+            "route replace blackhole 10.0.0.1 proto 249 table 100\n\
+             route replace blackhole 2001:db8::1 proto 249 table 100\n",
+            blackhole.batch_input("replace", &targets)
+        );
+
+        assert_eq!(
+            vec![
+                "10.0.0.1".parse::<IpNetwork>().unwrap(),
+                "10.1.0.0/16".parse().unwrap(),
+                "2001:db8::1".parse().unwrap()
+            ],
+            parse_routes(
+                "blackhole 10.0.0.1 proto 249 \n\
+                 blackhole 10.1.0.0/16 proto 249 \n\
+                 blackhole 2001:db8::1 dev lo proto 249 metric 1024 pref medium\n"
+            )
+        );
+
+        assert!(is_missing_route(
+            "RTNETLINK answers: No such process\nCommand failed -:1\n"
+        ));
+        assert!(!is_missing_route(
+            "RTNETLINK answers: Operation not permitted\n"
+        ));
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    fmt::Write as _,
+    io::Write as _,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use ipnetwork::IpNetwork;
+use itertools::Itertools;
+use log::warn;
+
+use super::{check, find_binary, run};
+use crate::{Error, Result};
+
+const NAME: &str = concat!(env!("CARGO_PKG_NAME"), "_country");
+const NAME_V6: &str = concat!(env!("CARGO_PKG_NAME"), "_country_v6");
+/// Maximum amount of networks in a set, well above the size of even the largest countries.
+const MAX_ELEMENTS: usize = 1 << 20;
+
+/// Networks that are always allowed, as they never belong to any country, like local networks.
+const RESERVED: &[(IpAddr, u8)] = &[
+    (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8),
+    (IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)), 8),
+    (IpAddr::V4(Ipv4Addr::new(169, 254, 0, 0)), 16),
+    (IpAddr::V4(Ipv4Addr::new(172, 16, 0, 0)), 12),
+    (IpAddr::V4(Ipv4Addr::new(192, 168, 0, 0)), 16),
+    (IpAddr::V6(Ipv6Addr::LOCALHOST), 128),
+    (IpAddr::V6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0)), 7),
+    (IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0)), 10),
+];
+
+/// Sets of networks that are the only ones allowed to connect to some ports, like the networks of
+/// a few countries. Everyone else is dropped by an iptables rule.
+///
+/// The sets are replaced atomically through a temporary set, so there is no moment in which
+/// allowed clients are dropped.
+pub struct CountrySets {
+    ipset_path: PathBuf,
+    iptables_path: PathBuf,
+    ip6tables_path: PathBuf,
+    ports: String,
+}
+
+impl CountrySets {
+    pub fn new(ports: &[u16]) -> Result<Self> {
+        Ok(Self {
+            ipset_path: find_binary("ipset", "/usr/sbin/ipset")?,
+            iptables_path: find_binary("iptables", "/usr/sbin/iptables")?,
+            ip6tables_path: find_binary("ip6tables", "/usr/sbin/ip6tables")?,
+            ports: ports.iter().join(","),
+        })
+    }
+
+    /// Replace the allowed networks, and start dropping everyone else if not done yet. The
+    /// networks are aggregated first, and the reserved networks are always added.
+    pub fn apply(&self, networks: Vec<IpNetwork>) -> Result<usize> {
+        let mut networks = networks;
+        networks.extend(
+            RESERVED
+                .iter()
+                .filter_map(|&(ip, prefix)| IpNetwork::new(ip, prefix).ok()),
+        );
+        let networks = aggregate(networks);
+
+        let mut child = Command::new(&self.ipset_path)
+            .args(["restore", "-exist"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|source| Error::Spawn {
+                program: self.ipset_path.display().to_string(),
+                source,
+            })?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(restore_input(&networks).as_bytes())?;
+        }
+
+        let output = child.wait_with_output()?;
+        check(&output, "replacing country networks")?;
+
+        self.install_for(NAME, &self.iptables_path)?;
+        self.install_for(NAME_V6, &self.ip6tables_path)?;
+
+        Ok(networks.len())
+    }
+
+    /// Stop dropping anyone and remove the sets.
+    pub fn uninstall(&self) -> Result<()> {
+        self.uninstall_for(NAME, &self.iptables_path)?;
+        self.uninstall_for(NAME_V6, &self.ip6tables_path)?;
+
+        Ok(())
+    }
+
+    /// Arguments of the iptables rule, after the action and chain.
+    fn rule_args<'a>(&'a self, name: &'a str) -> [&'a str; 12] {
+        [
+            "-p",
+            "tcp",
+            "-m",
+            "multiport",
+            "--dports",
+            &self.ports,
+            "-m",
+            "set",
+            "!",
+            "--match-set",
+            name,
+            "src",
+        ]
+    }
+
+    fn install_for(&self, name: &str, iptables: &Path) -> Result<()> {
+        let output = run(Command::new(iptables).arg("-S"))?;
+        check(&output, "listing iptables rules")?;
+
+        let rule = format!("-A INPUT {} -j DROP", self.rule_args(name).join(" "));
+        if String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .any(|l| l == rule)
+        {
+            return Ok(());
+        }
+
+        let output = run(Command::new(iptables)
+            .args(["-I", "INPUT"])
+            .args(self.rule_args(name))
+            .args(["-j", "DROP"]))?;
+        check(&output, "adding iptables rule")
+    }
+
+    fn uninstall_for(&self, name: &str, iptables: &Path) -> Result<()> {
+        loop {
+            let output = run(Command::new(iptables)
+                .args(["-D", "INPUT"])
+                .args(self.rule_args(name))
+                .args(["-j", "DROP"]))?;
+
+            if !output.status.success() {
+                break;
+            }
+        }
+
+        let output = run(Command::new(&self.ipset_path).args(["destroy", name]))?;
+        if !output.status.success() {
+            warn!(
+                "failed deleting ipset table {name}: {}",
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Input for `ipset restore`, that fills a temporary set for each family and swaps it with the
+/// current one.
+fn restore_input(networks: &[IpNetwork]) -> String {
+    let mut input = String::new();
+
+    for (name, family, v4) in [(NAME, "inet", true), (NAME_V6, "inet6", false)] {
+        let temp = format!("{name}_tmp");
+
+        for set in [name, &temp] {
+            _ = writeln!(
+                input,
+                "create {set} hash:net family {family} maxelem {MAX_ELEMENTS}"
+            );
+        }
+        _ = writeln!(input, "flush {temp}");
+
+        for network in networks.iter().filter(|network| network.is_ipv4() == v4) {
+            _ = writeln!(input, "add {temp} {network}");
+        }
+
+        _ = writeln!(input, "swap {temp} {name}");
+        _ = writeln!(input, "destroy {temp}");
+    }
+
+    input
+}
+
+/// Merge the networks into as few as possible, by dropping networks that are part of another one,
+/// and joining neighbors into their parent network.
+fn aggregate(networks: Vec<IpNetwork>) -> Vec<IpNetwork> {
+    // Networks as start address and prefix, with IPv4 addresses mapped into IPv6, so both
+    // families sort and merge the same way.
+    let mut ranges = networks
+        .into_iter()
+        .map(|network| match network {
+            IpNetwork::V4(network) => (
+                u128::from(network.network().to_ipv6_mapped()),
+                network.prefix() + 96,
+            ),
+            IpNetwork::V6(network) => (u128::from(network.network()), network.prefix()),
+        })
+        .collect::<Vec<_>>();
+    ranges.sort_unstable();
+
+    let mut merged = Vec::<(u128, u8)>::with_capacity(ranges.len());
+
+    for (mut start, mut prefix) in ranges {
+        if merged
+            .last()
+            .is_some_and(|&(last, last_prefix)| contains(last, last_prefix, start))
+        {
+            continue;
+        }
+
+        // Join with the previous network as long as both are halves of the same parent.
+        while let Some(&(last, last_prefix)) = merged.last() {
+            if last_prefix != prefix || prefix == 0 || prefix == 96 {
+                break;
+            }
+
+            let parent = prefix - 1;
+            if mask(last, parent) != mask(start, parent) {
+                break;
+            }
+
+            merged.pop();
+            start = mask(last, parent);
+            prefix = parent;
+        }
+
+        merged.push((start, prefix));
+    }
+
+    merged
+        .into_iter()
+        .filter_map(|(start, prefix)| {
+            let ip = Ipv6Addr::from(start);
+            match ip.to_ipv4_mapped() {
+                Some(ip) if prefix >= 96 => IpNetwork::new(ip.into(), prefix - 96).ok(),
+                _ => IpNetwork::new(ip.into(), prefix).ok(),
+            }
+        })
+        .collect()
+}
+
+const fn mask(start: u128, prefix: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        start & (u128::MAX << (128 - prefix))
+    }
+}
+
+const fn contains(start: u128, prefix: u8, ip: u128) -> bool {
+    mask(ip, prefix) == start
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregate_networks() {
+        let networks = [
+            "10.0.1.0/24",
+            "10.0.0.0/24",
+            "10.0.0.128/25",
+            "10.0.2.0/23",
+            "10.0.5.0/24",
+            "2001:db8::/33",
+            "2001:db8:8000::/33",
+        ]
+        .into_iter()
+        .map(|n| n.parse().unwrap())
+        .collect();
+
+        assert_eq!(
+            vec![
+                "10.0.0.0/22".parse::<IpNetwork>().unwrap(),
+                "10.0.5.0/24".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap(),
+            ],
+            aggregate(networks)
+        );
+
+        let input = restore_input(&["10.0.0.0/22".parse().unwrap()]);
+        assert!(input.contains("add veto_country_tmp 10.0.0.0/22\n"));
+        assert!(input.contains("swap veto_country_v6_tmp veto_country_v6\n"));
+    }
+}
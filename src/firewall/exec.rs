@@ -0,0 +1,127 @@
+use std::process::Command;
+
+use itertools::Itertools;
+
+use super::{check, run, Action, Firewall, Target};
+use crate::{settings::Exec as Settings, Result};
+
+/// Firewall that runs user-supplied shell commands, to integrate with tools like ufw or csf
+/// without writing a plugin.
+///
+/// Each command is a template, that is run with `sh -c` after replacing the placeholders `{ip}`,
+/// `{ports}` and `{protocol}`. The ports are comma separated, and empty if all ports are blocked.
+/// The protocol is one of `tcp`, `udp` or `all`. Commands that exit with a non-zero code are
+/// treated as failed.
+pub struct Exec {
+    settings: Settings,
+}
+
+impl Exec {
+    #[must_use]
+    pub const fn new(settings: Settings) -> Self {
+        Self { settings }
+    }
+
+    fn run(template: &str, target: Option<&Target<'_>>, action: &'static str) -> Result<()> {
+        check(&run(&mut shell(&expand(template, target)))?, action)
+    }
+}
+
+impl Firewall for Exec {
+    fn install(&self) -> Result<()> {
+        self.settings.install.as_deref().map_or(Ok(()), |install| {
+            Self::run(install, None, "running install command")
+        })
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        self.settings
+            .uninstall
+            .as_deref()
+            .map_or(Ok(()), |uninstall| {
+                Self::run(uninstall, None, "running uninstall command")
+            })
+    }
+
+    fn block(&self, target: &Target<'_>) -> Result<()> {
+        Self::run(&self.settings.block, Some(target), "running block command")
+    }
+
+    fn unblock(&self, target: &Target<'_>) -> Result<()> {
+        Self::run(
+            &self.settings.unblock,
+            Some(target),
+            "running unblock command",
+        )
+    }
+
+    fn describe(&self, action: Action, targets: &[Target<'_>]) -> Option<Vec<String>> {
+        let template = match action {
+            Action::Block => &self.settings.block,
+            Action::Unblock => &self.settings.unblock,
+        };
+
+        Some(
+            targets
+                .iter()
+                .map(|target| expand(template, Some(target)))
+                .collect(),
+        )
+    }
+}
+
+/// Command that runs the line in the shell.
+pub(super) fn shell(line: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", line]);
+    cmd
+}
+
+/// Replace the placeholders of the template with the values of the target. Only parsed IPs and
+/// ports are inserted, so they can't inject anything into the shell.
+fn expand(template: &str, target: Option<&Target<'_>>) -> String {
+    let Some(target) = target else {
+        return template.to_owned();
+    };
+
+    template
+        .replace("{ip}", &target.address())
+        .replace("{ports}", &target.ports.iter().join(","))
+        .replace("{protocol}", &target.protocol.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Protocol;
+
+    #[test]
+    fn expand_templates() {
+        let target = Target {
+            ip: "2001:db8::1".parse().unwrap(),
+            ports: &[80, 443],
+            protocol: Protocol::Udp,
+            timeout: None,
+        };
+
+        assert_eq!(
+            "ufw insert 1 deny from 2001:db8::1 to any port 80,443 proto udp",
+            expand(
+                "ufw insert 1 deny from {ip} to any port {ports} proto {protocol}",
+                Some(&target)
+            )
+        );
+        assert_eq!("csf -d {ip}", expand("csf -d {ip}", None));
+
+        let exec = Exec::new(Settings {
+            install: Some("true".to_owned()),
+            uninstall: None,
+            block: "test {ports} = 80,443".to_owned(),
+            unblock: "exit 1".to_owned(),
+        });
+        exec.install().unwrap();
+        exec.uninstall().unwrap();
+        exec.block(&target).unwrap();
+        assert!(exec.unblock(&target).is_err());
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    time::Duration,
+};
+
+use ipnetwork::IpNetwork;
+use itertools::Itertools;
+
+use super::{Action, Firewall, Target};
+use crate::{settings::HaProxy as Settings, Error, Result};
+
+/// Maximum amount of commands per request. `HAProxy` reads each request into a fixed buffer, which
+/// is 16 KiB by default.
+const BATCH_SIZE: usize = 100;
+
+/// Time to wait for `HAProxy` to accept or answer a request.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Firewall that adds blocked IPs to an ACL of `HAProxy` through its runtime API, for layer 7
+/// deployments where veto can't change the packet filter.
+///
+/// The ACL is referenced by the file that `HAProxy` loaded it from, like with
+/// `tcp-request connection reject if { src -f /etc/haproxy/veto.acl }`. Changes only live in the
+/// memory of `HAProxy` and the file is never written, so it should stay empty. Ports and protocols
+/// don't apply, as `HAProxy` rejects the whole connection.
+pub struct HaProxy {
+    settings: Settings,
+}
+
+impl HaProxy {
+    #[must_use]
+    pub const fn new(settings: Settings) -> Self {
+        Self { settings }
+    }
+
+    /// Commands that change the ACL for all targets, split into requests of [`BATCH_SIZE`]
+    /// commands each.
+    fn requests(&self, command: &str, targets: &[Target<'_>]) -> Vec<String> {
+        targets
+            .chunks(BATCH_SIZE)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .map(|target| {
+                        format!("{command} acl {} {}", self.settings.acl, target.address())
+                    })
+                    .join(";")
+            })
+            .collect()
+    }
+
+    /// Send a single request over a new connection, and return the answer. `HAProxy` closes the
+    /// connection once all commands of the request are done.
+    fn request(&self, request: &str) -> Result<String> {
+        let mut stream = UnixStream::connect(&self.settings.socket)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(b"\n")?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+
+        Ok(response)
+    }
+
+    /// Run the command for all targets. Changes don't print anything on success, so any output is
+    /// an error.
+    fn change(&self, command: &str, targets: &[Target<'_>], action: &'static str) -> Result<()> {
+        for request in self.requests(command, targets) {
+            let response = self.request(&request)?;
+            let errors = response
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty())
+                // Deleting entries that don't exist is fine, as the IP isn't blocked either way.
+                .filter(|l| command != "del" || *l != "Key not found.")
+                .join("\n");
+
+            if !errors.is_empty() {
+                return Err(Error::Command {
+                    action,
+                    stderr: errors,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Entries of the ACL, or the error that `HAProxy` answered with, like for an unknown ACL.
+    fn show(&self) -> Result<Vec<IpNetwork>> {
+        let response = self.request(&format!("show acl {}", self.settings.acl))?;
+
+        parse_acl(&response).map_err(|message| Error::Command {
+            action: "listing HAProxy ACL",
+            stderr: message.to_owned(),
+        })
+    }
+}
+
+impl Firewall for HaProxy {
+    /// Check that the ACL exists, as it can only be created by loading its file in `HAProxy`.
+    fn install(&self) -> Result<()> {
+        self.show().map(|_| ())
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        let response = self.request(&format!("clear acl {}", self.settings.acl))?;
+
+        if response.trim().is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Command {
+                action: "clearing HAProxy ACL",
+                stderr: response.trim().to_owned(),
+            })
+        }
+    }
+
+    fn block(&self, target: &Target<'_>) -> Result<()> {
+        self.block_all(std::slice::from_ref(target))
+    }
+
+    fn unblock(&self, target: &Target<'_>) -> Result<()> {
+        self.unblock_all(std::slice::from_ref(target))
+    }
+
+    fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.change("add", targets, "adding IPs to HAProxy ACL")
+    }
+
+    fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.change("del", targets, "deleting IPs from HAProxy ACL")
+    }
+
+    fn is_installed(&self) -> Result<Option<bool>> {
+        match self.show() {
+            Ok(_) => Ok(Some(true)),
+            Err(Error::Command { .. }) => Ok(Some(false)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn is_blocked(&self, target: &Target<'_>) -> Result<Option<bool>> {
+        Ok(self.list_blocked()?.map(|ips| ips.contains(&target.ip)))
+    }
+
+    fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+        self.show().map(Some)
+    }
+
+    fn describe(&self, action: Action, targets: &[Target<'_>]) -> Option<Vec<String>> {
+        let command = match action {
+            Action::Block => "add",
+            Action::Unblock => "del",
+        };
+
+        Some(
+            self.requests(command, targets)
+                .into_iter()
+                .map(|request| format!("send to {}: {request}", self.settings.socket.display()))
+                .collect(),
+        )
+    }
+}
+
+/// Get the networks of an ACL listing, with one entry per line like `0x55d3c8a0 10.0.0.1`. Any
+/// other line is an error message.
+fn parse_acl(output: &str) -> Result<Vec<IpNetwork>, &str> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|l| match l.split_once(' ') {
+            Some((id, pattern)) if id.starts_with("0x") => Ok(pattern.parse().ok()),
+            _ => Err(l),
+        })
+        .filter_map(Result::transpose)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, io::BufRead, io::BufReader, os::unix::net::UnixListener, thread};
+
+    use super::*;
+    use crate::settings::Protocol;
+
+    #[test]
+    fn runtime_api() {
+        let socket = env::temp_dir().join(format!("veto-haproxy-{}.sock", std::process::id()));
+        _ = fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        // Answers like HAProxy, and returns the requests it received.
+        let server = thread::spawn(move || {
+            let answers = [
+                "",
+                "Key not found.\n",
+                "0x55d3c8a0 10.0.0.1\n0x55d3c8b0 10.1.0.0/16\n",
+            ];
+            answers
+                .into_iter()
+                .map(|answer| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let mut request = String::new();
+                    BufReader::new(&stream).read_line(&mut request).unwrap();
+                    stream.write_all(answer.as_bytes()).unwrap();
+                    request
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let haproxy = HaProxy::new(Settings {
+            socket: socket.clone(),
+            acl: "veto.acl".to_owned(),
+        });
+        let targets = [
+            Target {
+                ip: "10.0.0.1".parse().unwrap(),
+                ports: &[80],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            },
+            Target {
+                ip: "10.1.0.0/16".parse().unwrap(),
+                ports: &[],
+                protocol: Protocol::All,
+                timeout: None,
+            },
+        ];
+
+        haproxy.block_all(&targets).unwrap();
+        haproxy.unblock(&targets[0]).unwrap();
+        assert_eq!(
+            Some(vec![
+                "10.0.0.1".parse().unwrap(),
+                "10.1.0.0/16".parse().unwrap()
+            ]),
+            haproxy.list_blocked().unwrap()
+        );
+
+        assert_eq!(
+            vec![
+                "add acl veto.acl 10.0.0.1;add acl veto.acl 10.1.0.0/16\n",
+                "del acl veto.acl 10.0.0.1\n",
+                "show acl veto.acl\n",
+            ],
+            server.join().unwrap()
+        );
+        fs::remove_file(socket).unwrap();
+
+        assert_eq!(
+            Err("Unknown ACL identifier. Please use #<id> or <file>."),
+            parse_acl("Unknown ACL identifier. Please use #<id> or <file>.\n")
+        );
+    }
+}
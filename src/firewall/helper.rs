@@ -0,0 +1,493 @@
+use std::{
+    error::Error as StdError,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    iter,
+    os::unix::net::{UnixListener, UnixStream},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use ipnetwork::IpNetwork;
+use itertools::Itertools;
+use log::warn;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::{Action, Firewall, OwnedTarget, Target};
+use crate::{settings::Helper as Settings, Error, Result};
+
+/// Time to wait for the helper to answer, which includes running the firewall commands. The helper
+/// waits as long for the next request, before it closes the connection.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum amount of connections that the helper serves at the same time. Any further connection is
+/// closed right away.
+const MAX_CONNECTIONS: usize = 16;
+
+/// Maximum length of a single request in bytes.
+const MAX_LINE: usize = 64 * 1024;
+
+/// Maximum amount of targets per request, which keeps requests well below [`MAX_LINE`].
+const BATCH_SIZE: usize = 100;
+
+/// Firewall that hands all changes to a privileged helper process over a Unix socket, so veto
+/// itself can run without any privileges.
+///
+/// The helper is veto itself, started with `veto helper`. It applies the changes to the firewall
+/// that its own configuration selects, and does nothing else, so the daemon with its log parsing
+/// and network facing parts doesn't need to run as root. Requests and responses are single lines
+/// of JSON, like for plugins, and each request uses its own connection.
+///
+/// As anything that reaches the helper can change the firewall, it only answers the configured
+/// user and root, never blocks networks that would cut off the host, and refuses to install or
+/// uninstall the firewall unless that's allowed in the settings. Instead, it installs the firewall
+/// itself when it starts.
+pub struct Helper {
+    settings: Settings,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    Install,
+    Uninstall,
+    Block {
+        targets: Vec<OwnedTarget>,
+    },
+    Unblock {
+        targets: Vec<OwnedTarget>,
+    },
+    IsInstalled,
+    ListBlocked,
+    Describe {
+        action: Action,
+        targets: Vec<OwnedTarget>,
+    },
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct Response {
+    ok: bool,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    error: String,
+    /// Answer of [`Request::IsInstalled`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    installed: Option<bool>,
+    /// Answer of [`Request::ListBlocked`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blocked: Option<Vec<IpNetwork>>,
+    /// Answer of [`Request::Describe`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    commands: Option<Vec<String>>,
+}
+
+impl Helper {
+    #[must_use]
+    pub const fn new(settings: Settings) -> Self {
+        Self { settings }
+    }
+
+    /// Answer requests of the listener's connections with the firewall, until the listener fails.
+    /// Each connection is served on its own thread, but only one request at a time changes the
+    /// firewall.
+    pub fn serve(&self, listener: &UnixListener, firewall: impl Firewall + Send) -> Result<()> {
+        let uid = user_id(&self.settings.user)?;
+        if !self.settings.allow_install {
+            firewall.install()?;
+        }
+        if cfg!(not(target_os = "linux")) {
+            warn!(
+                "The helper can't check who connects on this system, only the socket permissions"
+            );
+        }
+
+        let firewall = Mutex::new(firewall);
+        let connections = AtomicUsize::new(0);
+
+        thread::scope(|scope| {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("failed accepting helper connection: {e}");
+                        continue;
+                    }
+                };
+
+                match peer_id(&stream) {
+                    Ok(Some(peer)) if peer != uid && peer != 0 => {
+                        warn!("refused helper connection of user {peer}");
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("failed checking helper connection: {e}");
+                        continue;
+                    }
+                }
+
+                if connections.fetch_add(1, Ordering::AcqRel) >= MAX_CONNECTIONS {
+                    connections.fetch_sub(1, Ordering::AcqRel);
+                    warn!("refused helper connection, as {MAX_CONNECTIONS} are open already");
+                    continue;
+                }
+
+                let (firewall, connections) = (&firewall, &connections);
+                let allow_install = self.settings.allow_install;
+                scope.spawn(move || {
+                    if let Err(e) = handle(&stream, firewall, allow_install) {
+                        warn!("failed serving helper connection: {e}");
+                    }
+                    connections.fetch_sub(1, Ordering::AcqRel);
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Send a single request to the helper and wait for its response.
+    fn request(&self, request: &Request, action: &'static str) -> Result<Response> {
+        let mut line = serde_json::to_string(request).map_err(io::Error::from)?;
+        line.push('\n');
+
+        let stream = UnixStream::connect(&self.settings.socket)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        (&stream).write_all(line.as_bytes())?;
+
+        line.clear();
+        if BufReader::new(&stream).read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "helper closed the connection without answering",
+            )
+            .into());
+        }
+
+        let response = serde_json::from_str::<Response>(&line).map_err(io::Error::from)?;
+        if response.ok {
+            return Ok(response);
+        }
+
+        Err(Error::Command {
+            action,
+            stderr: response.error,
+        })
+    }
+}
+
+impl Firewall for Helper {
+    /// Install the firewall through the helper if allowed. Otherwise, the helper installed it
+    /// already when it started.
+    fn install(&self) -> Result<()> {
+        if !self.settings.allow_install {
+            return Ok(());
+        }
+
+        self.request(&Request::Install, "installing firewall through helper")
+            .map(|_| ())
+    }
+
+    /// Uninstall the firewall through the helper if allowed. Otherwise, the firewall stays as it
+    /// is, with all blocks in place.
+    fn uninstall(&self) -> Result<()> {
+        if !self.settings.allow_install {
+            return Ok(());
+        }
+
+        self.request(&Request::Uninstall, "uninstalling firewall through helper")
+            .map(|_| ())
+    }
+
+    fn block(&self, target: &Target<'_>) -> Result<()> {
+        self.block_all(std::slice::from_ref(target))
+    }
+
+    fn unblock(&self, target: &Target<'_>) -> Result<()> {
+        self.unblock_all(std::slice::from_ref(target))
+    }
+
+    fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        for chunk in targets.chunks(BATCH_SIZE) {
+            let targets = OwnedTarget::from_slice(chunk);
+            self.request(&Request::Block { targets }, "blocking IPs through helper")?;
+        }
+
+        Ok(())
+    }
+
+    fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        for chunk in targets.chunks(BATCH_SIZE) {
+            let targets = OwnedTarget::from_slice(chunk);
+            self.request(
+                &Request::Unblock { targets },
+                "unblocking IPs through helper",
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn is_installed(&self) -> Result<Option<bool>> {
+        self.request(&Request::IsInstalled, "checking firewall through helper")
+            .map(|response| response.installed)
+    }
+
+    fn is_blocked(&self, target: &Target<'_>) -> Result<Option<bool>> {
+        Ok(self.list_blocked()?.map(|ips| ips.contains(&target.ip)))
+    }
+
+    fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+        self.request(&Request::ListBlocked, "listing blocked IPs through helper")
+            .map(|response| response.blocked)
+    }
+
+    fn describe(&self, action: Action, targets: &[Target<'_>]) -> Option<Vec<String>> {
+        let targets = OwnedTarget::from_slice(targets);
+
+        self.request(
+            &Request::Describe { action, targets },
+            "describing changes through helper",
+        )
+        .ok()
+        .and_then(|response| response.commands)
+    }
+}
+
+/// Answer all requests of a single connection, until the client closes it or stays idle for too
+/// long. Requests over [`MAX_LINE`] end the connection.
+fn handle(
+    stream: &UnixStream,
+    firewall: &Mutex<impl Firewall>,
+    allow_install: bool,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+
+    while (&mut reader).take(MAX_LINE as u64).read_line(&mut line)? > 0 {
+        let too_long = !line.ends_with('\n') && line.len() >= MAX_LINE;
+
+        let response = if too_long {
+            Response {
+                error: format!("invalid request: longer than {MAX_LINE} bytes"),
+                ..Response::default()
+            }
+        } else {
+            match serde_json::from_str::<Request>(&line) {
+                Ok(request) => match check(&request, allow_install) {
+                    Ok(()) => answer(&*firewall.lock(), request),
+                    Err(error) => Response {
+                        error,
+                        ..Response::default()
+                    },
+                },
+                Err(e) => Response {
+                    error: format!("invalid request: {e}"),
+                    ..Response::default()
+                },
+            }
+        };
+
+        let mut output = serde_json::to_string(&response).map_err(io::Error::from)?;
+        output.push('\n');
+        (&*stream).write_all(output.as_bytes())?;
+
+        if too_long {
+            break;
+        }
+        line.clear();
+    }
+
+    Ok(())
+}
+
+/// Refuse requests that clients aren't allowed to send, as the helper must not be a way to take
+/// down the host.
+fn check(request: &Request, allow_install: bool) -> Result<(), String> {
+    match request {
+        Request::Install | Request::Uninstall if !allow_install => Err(
+            "installing or uninstalling the firewall through the helper isn't allowed".to_owned(),
+        ),
+        Request::Block { targets } | Request::Unblock { targets } => targets
+            .iter()
+            .find(|target| !is_allowed(target.ip))
+            .map_or(Ok(()), |target| {
+                Err(format!(
+                    "refusing to change {} through the helper",
+                    target.ip
+                ))
+            }),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the network can be blocked without cutting off the host. Unspecified and loopback
+/// addresses, and networks shorter than `/8` for IPv4 or `/32` for IPv6 are refused.
+fn is_allowed(network: IpNetwork) -> bool {
+    let min_prefix = match network {
+        IpNetwork::V4(_) => 8,
+        IpNetwork::V6(_) => 32,
+    };
+
+    network.prefix() >= min_prefix && !network.ip().is_unspecified() && !network.ip().is_loopback()
+}
+
+/// Resolve the user, which is either a numeric ID or a name from `/etc/passwd`.
+fn user_id(user: &str) -> Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+
+    fs::read_to_string("/etc/passwd")?
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.split(':');
+            (fields.next() == Some(user))
+                .then(|| fields.nth(1)?.parse().ok())
+                .flatten()
+        })
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("unknown user `{user}`")).into()
+        })
+}
+
+/// User ID of the process on the other end of the connection, if the system can tell.
+#[cfg(target_os = "linux")]
+fn peer_id(stream: &UnixStream) -> io::Result<Option<u32>> {
+    let cred = rustix::net::sockopt::socket_peercred(stream)?;
+    Ok(Some(cred.uid.as_raw()))
+}
+
+#[cfg(not(target_os = "linux"))]
+#[allow(clippy::unnecessary_wraps)]
+const fn peer_id(_stream: &UnixStream) -> io::Result<Option<u32>> {
+    Ok(None)
+}
+
+/// Run the request on the firewall. Errors are sent with their whole chain of causes, as the
+/// client can't see the helper's logs.
+fn answer(firewall: &impl Firewall, request: Request) -> Response {
+    let result = match request {
+        Request::Install => firewall.install().map(|()| Response::default()),
+        Request::Uninstall => firewall.uninstall().map(|()| Response::default()),
+        Request::Block { targets } => firewall
+            .block_all(&borrow(&targets))
+            .map(|()| Response::default()),
+        Request::Unblock { targets } => firewall
+            .unblock_all(&borrow(&targets))
+            .map(|()| Response::default()),
+        Request::IsInstalled => firewall.is_installed().map(|installed| Response {
+            installed,
+            ..Response::default()
+        }),
+        Request::ListBlocked => firewall.list_blocked().map(|blocked| Response {
+            blocked,
+            ..Response::default()
+        }),
+        Request::Describe { action, targets } => Ok(Response {
+            commands: firewall.describe(action, &borrow(&targets)),
+            ..Response::default()
+        }),
+    };
+
+    match result {
+        Ok(response) => Response {
+            ok: true,
+            ..response
+        },
+        Err(e) => Response {
+            error: iter::successors(Some(&e as &dyn StdError), |e| (*e).source()).join(": "),
+            ..Response::default()
+        },
+    }
+}
+
+fn borrow(targets: &[OwnedTarget]) -> Vec<Target<'_>> {
+    targets.iter().map(OwnedTarget::borrow).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, os::unix::fs::MetadataExt, sync::Arc};
+
+    use super::*;
+    use crate::{firewall::Noop, settings::Protocol};
+
+    fn send(socket: &std::path::Path, request: &str) -> String {
+        let mut stream = UnixStream::connect(socket).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line).unwrap();
+        line
+    }
+
+    #[test]
+    fn round_trip() {
+        let socket = env::temp_dir().join(format!("veto-helper-{}.sock", std::process::id()));
+        _ = fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        let helper = Arc::new(Helper::new(Settings {
+            socket: socket.clone(),
+            user: fs::metadata(&socket).unwrap().uid().to_string(),
+            allow_install: false,
+        }));
+        let server = Arc::clone(&helper);
+        thread::spawn(move || server.serve(&listener, Noop));
+
+        let target = Target {
+            ip: "10.0.0.1".parse().unwrap(),
+            ports: &[80, 443],
+            protocol: Protocol::Tcp,
+            timeout: Some(Duration::from_secs(60)),
+        };
+
+        helper.install().unwrap();
+        helper.block(&target).unwrap();
+        helper.unblock(&target).unwrap();
+        assert_eq!(None, helper.list_blocked().unwrap());
+        assert_eq!(Some(Vec::new()), helper.describe(Action::Block, &[target]));
+
+        assert!(send(&socket, "{\"op\":\"reboot\"}\n")
+            .starts_with(r#"{"ok":false,"error":"invalid request: unknown variant `reboot`"#));
+        assert!(send(&socket, "{\"op\":\"uninstall\"}\n").starts_with(r#"{"ok":false"#));
+        assert!(send(
+            &socket,
+            r#"{"op":"block","targets":[{"ip":"0.0.0.0/0","ports":[],"protocol":"all","timeout":null}]}
+"#
+        )
+        .starts_with(r#"{"ok":false,"error":"refusing to change 0.0.0.0/0"#));
+        assert!(send(&socket, &format!("{}\n", "x".repeat(MAX_LINE))).contains("longer than"));
+
+        fs::remove_file(socket).unwrap();
+    }
+
+    #[test]
+    fn allowed_targets() {
+        for network in ["10.0.0.1", "10.0.0.0/8", "2001:db8::/32"] {
+            assert!(is_allowed(network.parse().unwrap()), "{network}");
+        }
+        for network in [
+            "0.0.0.0",
+            "127.0.0.1",
+            "10.0.0.0/7",
+            "::",
+            "::1",
+            "2001:db8::/31",
+        ] {
+            assert!(!is_allowed(network.parse().unwrap()), "{network}");
+        }
+    }
+}
@@ -1,16 +1,28 @@
 use std::{
-    net::IpAddr,
+    collections::{BTreeSet, HashSet},
+    fmt::Write as _,
+    io::Write as _,
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
 };
 
-use anyhow::{ensure, Context, Result};
+use ipnetwork::IpNetwork;
+use itertools::Itertools;
 use log::warn;
 
-use super::{find_binary, Firewall, Target};
-use crate::settings::IpSet as Settings;
+#[cfg(all(feature = "netlink", target_os = "linux"))]
+use super::netlink;
+use super::{check, find_binary, run, Action, Firewall, Target};
+use crate::{
+    settings::{IpSet as Settings, IptablesTarget, Protocol, Rule},
+    Error, Result,
+};
+
+/// Longest timeout that ipset supports, in seconds. Longer blocks are added without a timeout.
+const MAX_TIMEOUT: u64 = 2_147_483;
 
-const DEFAULT_CHAINS: &[&str] = &["INPUT", "FORWARD"];
+/// Tables that the rules of the sets can be in, depending on the target.
+const TABLES: [&str; 3] = ["filter", "nat", "raw"];
 
 pub struct IpSet {
     name: &'static str,
@@ -19,10 +31,15 @@ pub struct IpSet {
     iptables_path: PathBuf,
     ip6tables_path: PathBuf,
     settings: Settings,
+    /// Protocols and ports that the iptables rules of the sets cover, one rule per chain for each.
+    matches: Vec<(Protocol, Vec<u16>)>,
+    /// Socket to change and list the sets without running `ipset`, if supported by this build.
+    #[cfg(all(feature = "netlink", target_os = "linux"))]
+    netlink: Option<netlink::Socket>,
 }
 
 impl IpSet {
-    pub fn new(settings: Settings) -> Result<Self> {
+    pub fn new<'a>(settings: Settings, rules: impl IntoIterator<Item = &'a Rule>) -> Result<Self> {
         if cfg!(not(target_os = "linux")) {
             warn!("The ipset firewall is only supported on Linux systems");
             warn!("Instead you will see commands that would be run instead");
@@ -36,177 +53,379 @@ impl IpSet {
             iptables_path: find_binary("iptables", "/usr/sbin/iptables")?,
             ip6tables_path: find_binary("ip6tables", "/usr/sbin/ip6tables")?,
             settings,
+            matches: matches(rules),
+            #[cfg(all(feature = "netlink", target_os = "linux"))]
+            netlink: netlink::Socket::new()
+                .inspect_err(|e| warn!("failed opening netlink socket, using ipset instead: {e}"))
+                .ok(),
         })
     }
 
-    fn install_for(&self, name: &str, iptables: &Path, family: &str, output: &str) -> Result<()> {
-        if !output.lines().any(|l| l == name) {
-            let output = Command::new(&self.ipset_path)
-                .args(["create", name, "hash:ip", "family", family])
-                .output()
-                .context("failed running ipset")?;
-
-            ensure!(
-                output.status.success(),
-                "failed creating new ipset table: {}",
-                String::from_utf8_lossy(&output.stderr)
+    /// Table and chains that the rules go into, which depend on the target.
+    fn chains(&self) -> (&'static str, Vec<&str>) {
+        match self.settings.target {
+            // Redirects are only possible before routing, so only incoming connections are
+            // affected.
+            IptablesTarget::Redirect(_) => ("nat", vec!["PREROUTING"]),
+            // The raw table comes before connection tracking, and its PREROUTING chain sees all
+            // incoming packets, including forwarded ones.
+            IptablesTarget::RawDrop => ("raw", vec!["PREROUTING"]),
+            _ => (
+                "filter",
+                self.settings.chains.iter().map(String::as_str).collect(),
+            ),
+        }
+    }
+
+    /// The iptables rules for a chain, one for each protocol and ports, as `iptables -S` lists
+    /// them.
+    fn rules(&self, chain: &str, name: &str) -> Vec<String> {
+        self.matches
+            .iter()
+            .map(|(protocol, ports)| {
+                format!(
+                    "-A {chain} {}",
+                    self.rule_args(name, *protocol, ports).join(" ")
+                )
+            })
+            .collect()
+    }
+
+    /// Arguments of the iptables rule for a set, that follow the chain. Rules for all protocols
+    /// match any packet of the blocked IPs, regardless of the ports.
+    fn rule_args(&self, name: &str, protocol: Protocol, ports: &[u16]) -> Vec<String> {
+        let mut args = set_match(name, protocol, ports);
+
+        if let IptablesTarget::RateLimit(limit) = self.settings.target {
+            // Only new connections count, so open ones keep working. Each set gets its own table of
+            // counters, named after it.
+            args.extend(
+                [
+                    "-m",
+                    "conntrack",
+                    "--ctstate",
+                    "NEW",
+                    "-m",
+                    "hashlimit",
+                    "--hashlimit-above",
+                    &rate(limit.get()),
+                    "--hashlimit-mode",
+                    "srcip",
+                    "--hashlimit-name",
+                    name,
+                ]
+                .map(ToOwned::to_owned),
             );
         }
 
-        let output = Command::new(iptables)
-            .arg("-S")
-            .output()
-            .context("failed running iptables")?;
+        args.push("-j".to_owned());
+        args.extend(self.settings.target.to_args());
+        args
+    }
 
-        ensure!(
-            output.status.success(),
-            "failed listing iptables rules: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
+    /// Installed if each chain has a rule for the set and each protocol and ports. The rules
+    /// aren't compared as a whole, as iptables lists some of them differently, like with the
+    /// defaults of hashlimit.
+    fn is_installed_for(&self, name: &str, iptables: &Path, sets: &str) -> Result<bool> {
+        if !sets.lines().any(|l| l == name) {
+            return Ok(false);
+        }
 
-        let output = String::from_utf8(output.stdout)?;
+        let (table, chains) = self.chains();
+        let output = run(Command::new(iptables).args(["-t", table, "-S"]))?;
+        check(&output, "listing iptables rules")?;
+
+        let output = String::from_utf8_lossy(&output.stdout);
+
+        Ok(chains.iter().all(|chain| {
+            self.matches.iter().all(|(protocol, ports)| {
+                let args = set_match(name, *protocol, ports);
+                set_rules(&output, name).any(|rule| {
+                    rule_chain(rule) == Some(*chain)
+                        && rule
+                            .split_whitespace()
+                            .skip(2)
+                            .take(args.len())
+                            .eq(args.iter().map(String::as_str))
+                })
+            })
+        }))
+    }
 
-        for chain in DEFAULT_CHAINS {
-            let rule = format!(
-                "-A {} -p tcp -m multiport --dports 80,443 -m set --match-set {} src -j {}",
-                chain, name, self.settings.target
-            );
+    /// Arguments to create a set of the given name and family.
+    fn create_args(&self, name: &str, family: &str) -> Vec<String> {
+        let mut args = ["create", name, "hash:net", "family", family]
+            .map(ToOwned::to_owned)
+            .to_vec();
+        if let Some(hashsize) = self.settings.hashsize {
+            args.extend(["hashsize".to_owned(), hashsize.to_string()]);
+        }
+        if let Some(maxelem) = self.settings.maxelem {
+            args.extend(["maxelem".to_owned(), maxelem.to_string()]);
+        }
+        if self.settings.timeouts {
+            // Entries without a timeout of their own never expire.
+            args.extend(["timeout".to_owned(), "0".to_owned()]);
+        }
+
+        args
+    }
+
+    /// Set that holds targets of the address family of the target.
+    const fn set_name(&self, target: &Target<'_>) -> &'static str {
+        match target.ip {
+            IpNetwork::V4(_) => self.name,
+            IpNetwork::V6(_) => self.name_v6,
+        }
+    }
 
-            if !output.lines().any(|l| l == rule) {
-                let output = Command::new(iptables)
-                    .args([
-                        "-I",
-                        chain,
-                        "-p",
-                        "tcp",
-                        "-m",
-                        "multiport",
-                        "--dports",
-                        "80,443",
-                        "-m",
-                        "set",
-                        "--match-set",
-                        name,
-                        "src",
-                        "-j",
-                    ])
-                    .args(self.settings.target.to_args())
-                    .output()?;
-
-                ensure!(
-                    output.status.success(),
-                    "failed adding iptables rule: {}",
-                    String::from_utf8_lossy(&output.stderr)
-                );
+    /// Timeout to add the target with, in seconds, if timeouts are enabled.
+    fn timeout(&self, target: &Target<'_>) -> Option<u64> {
+        let timeout = target.timeout.filter(|_| self.settings.timeouts)?;
+        let secs = timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0);
+
+        (secs <= MAX_TIMEOUT).then_some(secs.max(1))
+    }
+
+    /// Bring an existing set in line with the settings. Sets of the `hash:ip` type, that older
+    /// versions created, can't hold networks and are removed together with their rules. Sets
+    /// without the needed timeout support or with another maximum size are swapped with a new
+    /// one. Either way, the entries are lost but restored from the storage right after the
+    /// installation.
+    ///
+    /// Returns whether the set still exists.
+    fn migrate(&self, name: &str, iptables: &Path, family: &str) -> Result<bool> {
+        let output = run(Command::new(&self.ipset_path).args(["list", "-t", name]))?;
+        check(&output, "listing ipset table header")?;
+
+        let output = String::from_utf8_lossy(&output.stdout);
+
+        if !output.lines().any(|l| l == "Type: hash:net") {
+            warn!("recreating ipset table {name} to support blocking networks");
+            self.uninstall_for(name, iptables)?;
+            return Ok(false);
+        }
+
+        let header = output
+            .lines()
+            .find_map(|l| l.strip_prefix("Header: "))
+            .unwrap_or_default();
+        let missing_timeouts = self.settings.timeouts && header_value(header, "timeout").is_none();
+        let other_maxelem = self
+            .settings
+            .maxelem
+            .is_some_and(|maxelem| header_value(header, "maxelem") != Some(&maxelem.to_string()));
+
+        if missing_timeouts {
+            warn!("recreating ipset table {name} with timeout support");
+        } else if other_maxelem {
+            warn!("recreating ipset table {name} with a new maximum size");
+        } else {
+            return Ok(true);
+        }
+
+        let temp = format!("{name}_tmp");
+        let output = run(Command::new(&self.ipset_path).args(self.create_args(&temp, family)))?;
+        check(&output, "creating new ipset table")?;
+
+        let output = run(Command::new(&self.ipset_path).args(["swap", &temp, name]))?;
+        check(&output, "swapping ipset tables")?;
+
+        let output = run(Command::new(&self.ipset_path).args(["destroy", &temp]))?;
+        check(&output, "deleting ipset table")?;
+
+        Ok(true)
+    }
+
+    /// Create the set if needed, and make sure each chain has exactly the rules for it, that the
+    /// settings describe. Rules of the set from other settings, like another target, chain or
+    /// protocol, are removed.
+    fn install_for(&self, name: &str, iptables: &Path, family: &str, output: &str) -> Result<()> {
+        let exists = output.lines().any(|l| l == name) && self.migrate(name, iptables, family)?;
+        if !exists {
+            let output = run(Command::new(&self.ipset_path).args(self.create_args(name, family)))?;
+            check(&output, "creating new ipset table")?;
+        }
+
+        let (table, chains) = self.chains();
+        let wanted = chains
+            .iter()
+            .flat_map(|chain| self.rules(chain, name))
+            .collect::<Vec<_>>();
+        let mut present = HashSet::new();
+
+        for current in TABLES {
+            let Some(output) = list_rules(iptables, current)? else {
+                continue;
+            };
+
+            for rule in set_rules(&output, name) {
+                // Duplicates are removed as well, so each chain keeps a single rule.
+                if current == table && wanted.iter().any(|w| w == rule) && !present.contains(rule) {
+                    present.insert(rule.to_owned());
+                } else {
+                    delete_rule(iptables, current, rule)?;
+                }
+            }
+        }
+
+        let args = chains
+            .iter()
+            .cartesian_product(&self.matches)
+            .map(|(chain, (protocol, ports))| (chain, self.rule_args(name, *protocol, ports)));
+
+        for (rule, (chain, args)) in wanted.iter().zip(args) {
+            if !present.contains(rule) {
+                let output = run(Command::new(iptables)
+                    .args(["-t", table, "-I", chain])
+                    .args(args))?;
+                check(&output, "adding iptables rule")?;
             }
         }
 
         Ok(())
     }
 
+    /// Remove every rule that matches against the set, in any table, and the set itself. This
+    /// includes rules of earlier settings, which would keep the set from being deleted.
     fn uninstall_for(&self, name: &str, iptables: &Path) -> Result<()> {
-        for chain in DEFAULT_CHAINS {
-            loop {
-                let output = Command::new(iptables)
-                    .args([
-                        "-D",
-                        chain,
-                        "-p",
-                        "tcp",
-                        "-m",
-                        "multiport",
-                        "--dports",
-                        "80,443",
-                        "-m",
-                        "set",
-                        "--match-set",
-                        name,
-                        "src",
-                        "-j",
-                    ])
-                    .args(self.settings.target.to_args())
-                    .output()
-                    .context("failed running iptables")?;
-
-                if !output.status.success() {
-                    let stderr = String::from_utf8_lossy(&output.stderr);
-                    if !stderr.starts_with("iptables: Bad rule ")
-                        && !stderr.starts_with("ip6tables: Bad rule ")
-                        && !stderr.starts_with("iptables: No chain/target/match by that name.")
-                        && !stderr.starts_with("ip6tables: No chain/target/match by that name.")
-                    {
-                        warn!("failed deleting iptables rule: {}", stderr);
-                    }
-                    break;
+        for table in TABLES {
+            let Some(output) = list_rules(iptables, table)? else {
+                continue;
+            };
+
+            for rule in set_rules(&output, name) {
+                if let Err(e) = delete_rule(iptables, table, rule) {
+                    warn!("failed deleting iptables rule: {e}");
                 }
             }
         }
 
-        let output = Command::new(&self.ipset_path)
-            .args(["destroy", name])
-            .output()
-            .context("failed running ipset")?;
+        let output = run(Command::new(&self.ipset_path).args(["destroy", name]))?;
+        check(&output, "deleting ipset table")
+    }
 
-        ensure!(
-            output.status.success(),
-            "failed deleting ipset table: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
+    /// Entries of the targets, for changes through netlink.
+    #[cfg(all(feature = "netlink", target_os = "linux"))]
+    fn entries(&self, targets: &[Target<'_>]) -> Vec<netlink::Entry<'static>> {
+        targets
+            .iter()
+            .map(|target| netlink::Entry {
+                set: self.set_name(target),
+                network: target.ip,
+                timeout: self.timeout(target),
+            })
+            .collect()
+    }
 
-        Ok(())
+    /// Command that adds the target to or deletes it from its set.
+    fn target_cmd(&self, command: &str, target: &Target<'_>) -> Command {
+        let mut cmd = Command::new(&self.ipset_path);
+        cmd.args([command, self.set_name(target), &target.address()]);
+
+        // Existing entries get the new timeout, like when a block was extended.
+        if command == "add" {
+            if let Some(timeout) = self.timeout(target) {
+                cmd.args(["timeout", &timeout.to_string(), "-exist"]);
+            }
+        }
+
+        cmd
     }
 
-    fn block_for(&self, name: &str, ip: &str) -> Result<()> {
-        let output = Command::new(&self.ipset_path)
-            .args(["add", name, ip])
-            .output()
-            .context("failed running ipset")?;
+    fn block_for(&self, target: &Target<'_>) -> Result<()> {
+        let output = run(&mut self.target_cmd("add", target))?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
 
-        if !output.status.success() {
-            let message = String::from_utf8_lossy(&output.stderr);
-            ensure!(
-                is_expected_error(&message, RunType::Add),
-                "failed adding IP to ipset table: {}",
-                message
-            );
+        if is_expected_error(&stderr, RunType::Add) {
+            return Ok(());
+        }
+        if is_full(&stderr) {
+            return Err(Error::FirewallFull);
         }
 
-        Ok(())
+        check(&output, "adding IP to ipset table")
     }
 
-    fn unblock_for(&self, name: &str, ip: &str) -> Result<()> {
-        let output = Command::new(&self.ipset_path)
-            .args(["del", name, ip])
-            .output()
-            .context("failed running ipset")?;
+    fn unblock_for(&self, target: &Target<'_>) -> Result<()> {
+        let output = run(&mut self.target_cmd("del", target))?;
 
-        if !output.status.success() {
-            let message = String::from_utf8_lossy(&output.stderr);
-            ensure!(
-                is_expected_error(&message, RunType::Delete),
-                "failed deleting IP from ipset table: {}",
-                message
-            );
+        if is_expected_error(&String::from_utf8_lossy(&output.stderr), RunType::Delete) {
+            return Ok(());
         }
 
-        Ok(())
+        check(&output, "deleting IP from ipset table")
+    }
+
+    /// All networks in the set of the given name.
+    fn members(&self, name: &str) -> Result<Vec<IpNetwork>> {
+        #[cfg(all(feature = "netlink", target_os = "linux"))]
+        if let Some(netlink) = &self.netlink {
+            return netlink.list(name);
+        }
+
+        let output = run(Command::new(&self.ipset_path).args(["list", name, "-output", "plain"]))?;
+        check(&output, "listing ipset entries")?;
+
+        Ok(parse_members(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// Input for `ipset restore`, with one line of the given command for each target.
+    fn restore_input(&self, command: &str, targets: &[Target<'_>]) -> String {
+        let mut input = String::new();
+        for target in targets {
+            let name = self.set_name(target);
+            _ = write!(input, "{command} {name} {}", target.address());
+            if command == "add" {
+                if let Some(timeout) = self.timeout(target) {
+                    _ = write!(input, " timeout {timeout}");
+                }
+            }
+            input.push('\n');
+        }
+
+        input
+    }
+
+    /// Run a single `ipset restore` with the given command for all targets, which is much faster
+    /// than spawning a separate process for each of them. Already added or deleted entries are
+    /// ignored, thanks to the `-exist` flag.
+    fn restore(&self, command: &str, targets: &[Target<'_>]) -> Result<()> {
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        let input = self.restore_input(command, targets);
+        let mut child = Command::new(&self.ipset_path)
+            .args(["restore", "-exist"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|source| Error::Spawn {
+                program: self.ipset_path.display().to_string(),
+                source,
+            })?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input.as_bytes())?;
+        }
+
+        let output = child.wait_with_output()?;
+        if is_full(&String::from_utf8_lossy(&output.stderr)) {
+            return Err(Error::FirewallFull);
+        }
+
+        check(&output, "restoring ipset entries")
     }
 }
 
 impl Firewall for IpSet {
     fn install(&self) -> Result<()> {
-        let output = Command::new(&self.ipset_path)
-            .args(["list", "-n"])
-            .output()
-            .context("failed running ipset")?;
-
-        ensure!(
-            output.status.success(),
-            "failed listing ipset table names: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
+        let output = run(Command::new(&self.ipset_path).args(["list", "-n"]))?;
+        check(&output, "listing ipset table names")?;
 
-        let output = String::from_utf8(output.stdout)?;
+        let output = String::from_utf8_lossy(&output.stdout);
 
         self.install_for(self.name, &self.iptables_path, "inet", &output)?;
         self.install_for(self.name_v6, &self.ip6tables_path, "inet6", &output)?;
@@ -222,20 +441,190 @@ impl Firewall for IpSet {
     }
 
     fn block(&self, target: &Target<'_>) -> Result<()> {
-        match target.ip {
-            IpAddr::V4(ip) => self.block_for(self.name, &ip.to_string()),
-            IpAddr::V6(ip) => self.block_for(self.name_v6, &ip.to_string()),
+        #[cfg(all(feature = "netlink", target_os = "linux"))]
+        if self.netlink.is_some() {
+            return self.block_all(std::slice::from_ref(target));
         }
+
+        self.block_for(target)
     }
 
     fn unblock(&self, target: &Target<'_>) -> Result<()> {
-        match target.ip {
-            IpAddr::V4(ip) => self.unblock_for(self.name, &ip.to_string()),
-            IpAddr::V6(ip) => self.unblock_for(self.name_v6, &ip.to_string()),
+        #[cfg(all(feature = "netlink", target_os = "linux"))]
+        if self.netlink.is_some() {
+            return self.unblock_all(std::slice::from_ref(target));
+        }
+
+        self.unblock_for(target)
+    }
+
+    fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        #[cfg(all(feature = "netlink", target_os = "linux"))]
+        if let Some(netlink) = &self.netlink {
+            return netlink.add(&self.entries(targets));
+        }
+
+        self.restore("add", targets)
+    }
+
+    fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        #[cfg(all(feature = "netlink", target_os = "linux"))]
+        if let Some(netlink) = &self.netlink {
+            return netlink.del(&self.entries(targets));
         }
+
+        self.restore("del", targets)
+    }
+
+    fn is_installed(&self) -> Result<Option<bool>> {
+        let output = run(Command::new(&self.ipset_path).args(["list", "-n"]))?;
+        check(&output, "listing ipset table names")?;
+
+        let output = String::from_utf8_lossy(&output.stdout);
+
+        Ok(Some(
+            self.is_installed_for(self.name, &self.iptables_path, &output)?
+                && self.is_installed_for(self.name_v6, &self.ip6tables_path, &output)?,
+        ))
+    }
+
+    fn is_blocked(&self, target: &Target<'_>) -> Result<Option<bool>> {
+        Ok(Some(
+            self.members(self.set_name(target))?.contains(&target.ip),
+        ))
+    }
+
+    fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+        let mut ips = self.members(self.name)?;
+        ips.extend(self.members(self.name_v6)?);
+
+        Ok(Some(ips))
+    }
+
+    fn describe(&self, action: Action, targets: &[Target<'_>]) -> Option<Vec<String>> {
+        if targets.is_empty() {
+            return Some(Vec::new());
+        }
+
+        let command = match action {
+            Action::Block => "add",
+            Action::Unblock => "del",
+        };
+
+        Some(vec![format!(
+            "{} restore -exist <<EOF\n{}EOF",
+            self.ipset_path.display(),
+            self.restore_input(command, targets)
+        )])
     }
 }
 
+/// List the rules of the table, or [`None`] if the table isn't available, like the `raw` table
+/// without its kernel module.
+fn list_rules(iptables: &Path, table: &str) -> Result<Option<String>> {
+    let output = run(Command::new(iptables).args(["-t", table, "-S"]))?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+/// Protocols and ports of the rules, without duplicates. Ports don't apply to all protocols, and
+/// without any rules, TCP is matched on all ports, like for manual blocks without a rule.
+fn matches<'a>(rules: impl IntoIterator<Item = &'a Rule>) -> Vec<(Protocol, Vec<u16>)> {
+    let mut matches = rules
+        .into_iter()
+        .map(|rule| match rule.protocol {
+            Protocol::All => (Protocol::All, Vec::new()),
+            protocol => (
+                protocol,
+                rule.ports.iter().copied().sorted().dedup().collect(),
+            ),
+        })
+        .collect::<BTreeSet<_>>();
+
+    if matches.is_empty() {
+        matches.insert((Protocol::Tcp, Vec::new()));
+    }
+
+    matches.into_iter().collect()
+}
+
+/// Arguments that match packets from IPs in the set, for the protocol and destination ports.
+fn set_match(name: &str, protocol: Protocol, ports: &[u16]) -> Vec<String> {
+    let mut args = Vec::new();
+
+    if protocol != Protocol::All {
+        args.extend(["-p".to_owned(), protocol.to_string()]);
+        if !ports.is_empty() {
+            args.extend(
+                ["-m", "multiport", "--dports", &ports.iter().join(",")].map(ToOwned::to_owned),
+            );
+        }
+    }
+
+    args.extend(["-m", "set", "--match-set", name, "src"].map(ToOwned::to_owned));
+    args
+}
+
+/// Rules of an `iptables -S` listing, that match against the set.
+fn set_rules<'a>(output: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> {
+    output.lines().filter(move |l| {
+        l.starts_with("-A ")
+            && l.split_whitespace()
+                .tuple_windows()
+                .any(|(option, set)| option == "--match-set" && set == name)
+    })
+}
+
+/// Chain of a rule, as `iptables -S` lists it.
+fn rule_chain(rule: &str) -> Option<&str> {
+    rule.strip_prefix("-A ")?.split_whitespace().next()
+}
+
+/// Delete a rule, as `iptables -S` lists it.
+fn delete_rule(iptables: &Path, table: &str, rule: &str) -> Result<()> {
+    let output = run(Command::new(iptables)
+        .args(["-t", table, "-D"])
+        .args(rule.split_whitespace().skip(1)))?;
+    check(&output, "deleting iptables rule")
+}
+
+/// Get the networks of a set listing, which follow the header one per line, possibly followed by
+/// options. Single hosts are listed without a prefix.
+pub(super) fn parse_members(output: &str) -> Vec<IpNetwork> {
+    output
+        .lines()
+        .skip_while(|l| *l != "Members:")
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().next()?.parse().ok())
+        .collect()
+}
+
+/// Value of an option in the header of a set listing, like `65536` for `maxelem`.
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    header
+        .split_whitespace()
+        .skip_while(|word| *word != key)
+        .nth(1)
+}
+
+/// Rate of connections per minute, as `iptables -S` lists it. Rates of full connections per
+/// second are listed per second instead.
+fn rate(per_minute: u32) -> String {
+    if per_minute % 60 == 0 {
+        format!("{}/sec", per_minute / 60)
+    } else {
+        format!("{per_minute}/min")
+    }
+}
+
+/// Whether the error output reports a set that reached its maximum size.
+fn is_full(stderr: &str) -> bool {
+    stderr.contains("Hash is full, cannot add more elements")
+}
+
 #[derive(Copy, Clone)]
 enum RunType {
     Add,
@@ -256,3 +645,225 @@ fn is_expected_error(message: &str, ty: RunType) -> bool {
 
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroU32, time::Duration};
+
+    use super::*;
+    use crate::{firewall::command_line, settings::Protocol};
+
+    #[test]
+    fn members() {
+        let output = "\
+            Name: veto\n\
+            Type: hash:net\n\
+            Header: family inet hashsize 1024 maxelem 65536\n\
+            Number of entries: 3\n\
+            Members:\n\
+            10.0.0.1\n\
+            10.0.0.2 timeout 0\n\
+            10.1.0.0/16\n";
+
+        assert_eq!(
+            vec![
+                "10.0.0.1".parse::<IpNetwork>().unwrap(),
+                "10.0.0.2".parse().unwrap(),
+                "10.1.0.0/16".parse().unwrap()
+            ],
+            parse_members(output)
+        );
+        assert!(parse_members("Name: veto\nMembers:\n").is_empty());
+    }
+
+    /// Firewall with the given settings, that only runs the binaries.
+    fn ipset(settings: Settings) -> IpSet {
+        IpSet {
+            name: "veto",
+            name_v6: "veto_v6",
+            ipset_path: PathBuf::from("/usr/sbin/ipset"),
+            iptables_path: PathBuf::from("/usr/sbin/iptables"),
+            ip6tables_path: PathBuf::from("/usr/sbin/ip6tables"),
+            settings,
+            matches: vec![(Protocol::Tcp, vec![80, 443])],
+            #[cfg(all(feature = "netlink", target_os = "linux"))]
+            netlink: None,
+        }
+    }
+
+    #[test]
+    fn chains() {
+        let ipset = |target, chains: Vec<String>| {
+            ipset(Settings {
+                target,
+                chains,
+                ..Settings::default()
+            })
+        };
+
+        assert_eq!(
+            ("filter", vec!["INPUT", "FORWARD"]),
+            ipset(IptablesTarget::Drop, Settings::default().chains).chains()
+        );
+
+        let custom = ipset(IptablesTarget::Drop, vec!["f2b-web".to_owned()]);
+        assert_eq!(("filter", vec!["f2b-web"]), custom.chains());
+        assert_eq!(
+            vec!["-A f2b-web -p tcp -m multiport --dports 80,443 -m set --match-set veto src -j DROP"],
+            custom.rules("f2b-web", "veto")
+        );
+
+        assert_eq!(
+            ("nat", vec!["PREROUTING"]),
+            ipset(IptablesTarget::Redirect(2222), vec!["f2b-web".to_owned()]).chains()
+        );
+
+        let limited = ipset(
+            IptablesTarget::RateLimit(NonZeroU32::new(30).unwrap()),
+            Settings::default().chains,
+        );
+        assert_eq!(("filter", vec!["INPUT", "FORWARD"]), limited.chains());
+        assert_eq!(
+            vec![
+                "-A INPUT -p tcp -m multiport --dports 80,443 -m set --match-set veto src -m \
+                 conntrack --ctstate NEW -m hashlimit --hashlimit-above 30/min --hashlimit-mode \
+                 srcip --hashlimit-name veto -j DROP"
+            ],
+            limited.rules("INPUT", "veto")
+        );
+        assert_eq!("2/sec", rate(120));
+
+        assert_eq!(
+            ("raw", vec!["PREROUTING"]),
+            ipset(IptablesTarget::RawDrop, vec!["f2b-web".to_owned()]).chains()
+        );
+    }
+
+    #[test]
+    fn rule_protocols() {
+        let rule = |protocol, ports| {
+            basic_toml::from_str::<Rule>(&format!(
+                "file = \"/var/log/app.log\"\ntimeout = \"1h\"\nprotocol = \"{protocol}\"\n\
+                 ports = {ports:?}"
+            ))
+            .unwrap()
+        };
+        let rules = [
+            rule(Protocol::Tcp, vec![443, 80]),
+            rule(Protocol::Tcp, vec![80, 443, 80]),
+            rule(Protocol::Udp, vec![53]),
+            rule(Protocol::Udp, vec![]),
+            rule(Protocol::All, vec![22]),
+        ];
+
+        let ipset = IpSet {
+            matches: matches(&rules),
+            ..ipset(Settings::default())
+        };
+        assert_eq!(
+            vec![
+                "-A INPUT -p tcp -m multiport --dports 80,443 -m set --match-set veto src -j DROP",
+                "-A INPUT -p udp -m set --match-set veto src -j DROP",
+                "-A INPUT -p udp -m multiport --dports 53 -m set --match-set veto src -j DROP",
+                "-A INPUT -m set --match-set veto src -j DROP",
+            ],
+            ipset.rules("INPUT", "veto")
+        );
+
+        assert_eq!(vec![(Protocol::Tcp, vec![])], matches([]));
+    }
+
+    #[test]
+    fn listed_rules() {
+        let output = "\
+            -P INPUT ACCEPT\n\
+            -A INPUT -p tcp -m multiport --dports 80,443 -m set --match-set veto src -m conntrack \
+            --ctstate NEW -m hashlimit --hashlimit-above 30/min --hashlimit-burst 5 \
+            --hashlimit-mode srcip --hashlimit-name veto -j DROP\n\
+            -A f2b-web -p tcp -m multiport --dports 80,443 -m set --match-set veto src -j DROP\n\
+            -A INPUT -p tcp -m multiport --dports 80,443 -m set --match-set veto_v6 src -j DROP\n";
+
+        let rules = set_rules(output, "veto").collect::<Vec<_>>();
+        assert_eq!(2, rules.len());
+        assert_eq!(
+            vec![Some("INPUT"), Some("f2b-web")],
+            rules
+                .iter()
+                .map(|rule| rule_chain(rule))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, set_rules(output, "veto_v6").count());
+    }
+
+    #[test]
+    fn timeouts() {
+        let ipset = ipset(Settings {
+            timeouts: true,
+            ..Settings::default()
+        });
+        let targets = [
+            Target {
+                ip: "10.0.0.1".parse().unwrap(),
+                ports: &[],
+                protocol: Protocol::Tcp,
+                timeout: Some(Duration::from_millis(3_600_500)),
+            },
+            Target {
+                ip: "2001:db8::1".parse().unwrap(),
+                ports: &[],
+                protocol: Protocol::Tcp,
+                timeout: Some(Duration::from_secs(365 * 24 * 60 * 60)),
+            },
+        ];
+
+        assert_eq!(
+            vec!["create", "veto", "hash:net", "family", "inet", "timeout", "0"],
+            ipset.create_args("veto", "inet")
+        );
+        assert_eq!(
+            "/usr/sbin/ipset add veto 10.0.0.1 timeout 3601 -exist",
+            command_line(&ipset.target_cmd("add", &targets[0]))
+        );
+        assert_eq!(
+            "/usr/sbin/ipset del veto_v6 2001:db8::1",
+            command_line(&ipset.target_cmd("del", &targets[1]))
+        );
+        assert_eq!(
+            "add veto 10.0.0.1 timeout 3601\nadd veto_v6 2001:db8::1\n",
+            ipset.restore_input("add", &targets)
+        );
+        assert_eq!(
+            "del veto 10.0.0.1\ndel veto_v6 2001:db8::1\n",
+            ipset.restore_input("del", &targets)
+        );
+    }
+
+    #[test]
+    fn capacity() {
+        let ipset = ipset(Settings {
+            hashsize: NonZeroU32::new(4096),
+            maxelem: NonZeroU32::new(1_000_000),
+            ..Settings::default()
+        });
+
+        assert_eq!(
+            vec![
+                "create", "veto", "hash:net", "family", "inet", "hashsize", "4096", "maxelem",
+                "1000000"
+            ],
+            ipset.create_args("veto", "inet")
+        );
+
+        let header = "family inet hashsize 1024 maxelem 65536 timeout 0 bucketsize 12";
+        assert_eq!(Some("65536"), header_value(header, "maxelem"));
+        assert_eq!(Some("0"), header_value(header, "timeout"));
+        assert_eq!(None, header_value("family inet", "timeout"));
+
+        assert!(is_full(
+            "ipset v7.17: Error in line 1: Hash is full, cannot add more elements\n"
+        ));
+        assert!(!is_full(
+            "ipset v7.17: The set with the given name does not exist\n"
+        ));
+    }
+}
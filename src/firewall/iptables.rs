@@ -4,32 +4,36 @@ use std::{
     process::Command,
 };
 
-use anyhow::{ensure, Result};
 use itertools::Itertools;
 use log::debug;
 
-use super::{find_binary, Firewall, Target};
+use super::{check, command_line, find_binary, run, Action, Firewall, Target};
+use crate::{
+    settings::{IpTables as Settings, Protocol, RejectWith},
+    Result,
+};
 
 pub struct IpTables {
     name: &'static str,
     iptables_path: PathBuf,
     ip6tables_path: PathBuf,
+    settings: Settings,
 }
 
 impl IpTables {
-    #[allow(dead_code)]
-    pub fn new() -> Result<Self> {
+    pub fn new(settings: Settings) -> Result<Self> {
         Ok(Self {
             name: env!("CARGO_PKG_NAME"),
             iptables_path: find_binary("iptables", "/usr/sbin/iptables")?,
             ip6tables_path: find_binary("ip6tables", "/usr/sbin/ip6tables")?,
+            settings,
         })
     }
 
-    fn block_args(cmd: &mut Command, target: &Target<'_>) {
-        cmd.args(["-s", &target.ip.to_string(), "-p", "tcp"]);
+    fn block_args(&self, cmd: &mut Command, target: &Target<'_>) {
+        cmd.args(["-s", &target.address(), "-p", &target.protocol.to_string()]);
 
-        if !target.ports.is_empty() {
+        if !target.ports.is_empty() && target.protocol != Protocol::All {
             cmd.args([
                 "-m",
                 "multiport",
@@ -38,7 +42,40 @@ impl IpTables {
             ]);
         }
 
-        cmd.args(["-j", "REJECT", "--reject-with", "tcp-reset"]);
+        // Only TCP connections can be reset, all other packets are answered with the default ICMP
+        // error instead.
+        let reject_with = self.settings.reject_with;
+        cmd.args(["-j", "REJECT"]);
+        if target.protocol == Protocol::Tcp || reject_with != RejectWith::TcpReset {
+            cmd.args(["--reject-with", reject_with.name(target.ip.is_ipv6())]);
+        }
+    }
+
+    /// Command that changes or checks the rule of the target, with the given operation like `-I`.
+    fn target_cmd(&self, operation: &str, target: &Target<'_>) -> Command {
+        let mut cmd = Command::new(self.select_cmd(target.ip.ip()));
+        cmd.args([operation, self.name]);
+        self.block_args(&mut cmd, target);
+        cmd
+    }
+
+    /// Arguments of the commands that set up the chain. Packets that aren't blocked return to the
+    /// `INPUT` chain, so any later rules still apply to them.
+    fn install_args(&self) -> [Vec<&str>; 3] {
+        [
+            vec!["-N", self.name],
+            vec!["-A", self.name, "-j", "RETURN"],
+            vec!["-I", "INPUT", "-j", self.name],
+        ]
+    }
+
+    /// Arguments of the commands that remove the chain again.
+    fn uninstall_args(&self) -> [Vec<&str>; 3] {
+        [
+            vec!["-D", "INPUT", "-j", self.name],
+            vec!["-F", self.name],
+            vec!["-X", self.name],
+        ]
     }
 
     fn select_cmd(&self, ip: IpAddr) -> &Path {
@@ -51,11 +88,7 @@ impl IpTables {
 
 impl Firewall for IpTables {
     fn install(&self) -> Result<()> {
-        let cmds = &[
-            vec!["-N", self.name],
-            vec!["-A", self.name, "-j", "ACCEPT"],
-            vec!["-I", "INPUT", "-p", "tcp", "-j", self.name],
-        ];
+        let cmds = &self.install_args();
 
         for args in cmds {
             let mut cmd = Command::new(&self.iptables_path);
@@ -64,10 +97,7 @@ impl Firewall for IpTables {
             if cfg!(debug_assertions) {
                 debug!("install: {:?}", cmd);
             } else {
-                ensure!(
-                    cmd.status()?.success(),
-                    "Failed running iptables to install rule chain"
-                );
+                check(&run(&mut cmd)?, "installing iptables rule chain")?;
             }
         }
 
@@ -78,10 +108,7 @@ impl Firewall for IpTables {
             if cfg!(debug_assertions) {
                 debug!("install: {:?}", cmd);
             } else {
-                ensure!(
-                    cmd.status()?.success(),
-                    "Failed running ip6tables to install rule chain"
-                );
+                check(&run(&mut cmd)?, "installing ip6tables rule chain")?;
             }
         }
 
@@ -89,11 +116,7 @@ impl Firewall for IpTables {
     }
 
     fn uninstall(&self) -> Result<()> {
-        let cmds = &[
-            vec!["-D", "INPUT", "-p", "tcp", "-j", self.name],
-            vec!["-F", self.name],
-            vec!["-X", self.name],
-        ];
+        let cmds = &self.uninstall_args();
 
         for args in cmds {
             let mut cmd = Command::new(&self.iptables_path);
@@ -102,10 +125,7 @@ impl Firewall for IpTables {
             if cfg!(debug_assertions) {
                 debug!("uninstall: {:?}", cmd);
             } else {
-                ensure!(
-                    cmd.status()?.success(),
-                    "Failed running iptables to uninstall rule chain"
-                );
+                check(&run(&mut cmd)?, "uninstalling iptables rule chain")?;
             }
         }
 
@@ -116,10 +136,7 @@ impl Firewall for IpTables {
             if cfg!(debug_assertions) {
                 debug!("uninstall: {:?}", cmd);
             } else {
-                ensure!(
-                    cmd.status()?.success(),
-                    "Failed running ip6tables to uninstall rule chain"
-                );
+                check(&run(&mut cmd)?, "uninstalling ip6tables rule chain")?;
             }
         }
 
@@ -127,40 +144,149 @@ impl Firewall for IpTables {
     }
 
     fn block(&self, target: &Target<'_>) -> Result<()> {
-        let mut cmd = Command::new(self.select_cmd(target.ip));
-
-        cmd.args(["-I", self.name]);
-
-        Self::block_args(&mut cmd, target);
+        let mut cmd = self.target_cmd("-I", target);
 
         if cfg!(debug_assertions) {
             debug!("block: {:?}", cmd);
         } else {
-            ensure!(
-                cmd.status()?.success(),
-                "Failed running iptables to block target"
-            );
+            check(&run(&mut cmd)?, "blocking target with iptables")?;
         }
 
         Ok(())
     }
 
     fn unblock(&self, target: &Target<'_>) -> Result<()> {
-        let mut cmd = Command::new(self.select_cmd(target.ip));
-
-        cmd.args(["-D", self.name]);
-
-        Self::block_args(&mut cmd, target);
+        let mut cmd = self.target_cmd("-D", target);
 
         if cfg!(debug_assertions) {
             debug!("block: {:?}", cmd);
         } else {
-            ensure!(
-                cmd.status()?.success(),
-                "Failed running iptables to unblock target"
-            );
+            check(&run(&mut cmd)?, "unblocking target with iptables")?;
         }
 
         Ok(())
     }
+
+    fn is_installed(&self) -> Result<Option<bool>> {
+        if cfg!(debug_assertions) {
+            return Ok(None);
+        }
+
+        let rule = format!("-A INPUT -j {}", self.name);
+
+        for path in [&self.iptables_path, &self.ip6tables_path] {
+            let output = run(Command::new(path).args(["-S", "INPUT"]))?;
+            check(&output, "listing iptables rules")?;
+
+            if !String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|l| l == rule)
+            {
+                return Ok(Some(false));
+            }
+        }
+
+        Ok(Some(true))
+    }
+
+    fn is_blocked(&self, target: &Target<'_>) -> Result<Option<bool>> {
+        if cfg!(debug_assertions) {
+            return Ok(None);
+        }
+
+        let mut cmd = self.target_cmd("-C", target);
+
+        // Checking fails with a non-zero exit code if the rule doesn't exist.
+        Ok(Some(run(&mut cmd)?.status.success()))
+    }
+
+    fn describe(&self, action: Action, targets: &[Target<'_>]) -> Option<Vec<String>> {
+        let operation = match action {
+            Action::Block => "-I",
+            Action::Unblock => "-D",
+        };
+
+        Some(
+            targets
+                .iter()
+                .map(|target| command_line(&self.target_cmd(operation, target)))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iptables(reject_with: RejectWith) -> IpTables {
+        IpTables {
+            name: "veto",
+            iptables_path: "iptables".into(),
+            ip6tables_path: "ip6tables".into(),
+            settings: Settings { reject_with },
+        }
+    }
+
+    #[test]
+    fn install_args() {
+        let iptables = iptables(RejectWith::TcpReset);
+
+        assert_eq!(
+            [
+                vec!["-N", "veto"],
+                vec!["-A", "veto", "-j", "RETURN"],
+                vec!["-I", "INPUT", "-j", "veto"],
+            ],
+            iptables.install_args()
+        );
+        assert_eq!(
+            [
+                vec!["-D", "INPUT", "-j", "veto"],
+                vec!["-F", "veto"],
+                vec!["-X", "veto"],
+            ],
+            iptables.uninstall_args()
+        );
+    }
+
+    #[test]
+    fn reject_with() {
+        let targets = [
+            Target {
+                ip: "10.0.0.1".parse().unwrap(),
+                ports: &[80, 443],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            },
+            Target {
+                ip: "2001:db8::1".parse().unwrap(),
+                ports: &[53],
+                protocol: Protocol::Udp,
+                timeout: None,
+            },
+        ];
+
+        assert_eq!(
+            Some(vec![
+                "iptables -I veto -s 10.0.0.1 -p tcp -m multiport --dports 80,443 -j REJECT \
+                 --reject-with tcp-reset"
+                    .to_owned(),
+                "ip6tables -I veto -s 2001:db8::1 -p udp -m multiport --dports 53 -j REJECT"
+                    .to_owned(),
+            ]),
+            iptables(RejectWith::TcpReset).describe(Action::Block, &targets)
+        );
+        assert_eq!(
+            Some(vec![
+                "iptables -D veto -s 10.0.0.1 -p tcp -m multiport --dports 80,443 -j REJECT \
+                 --reject-with icmp-admin-prohibited"
+                    .to_owned(),
+                "ip6tables -D veto -s 2001:db8::1 -p udp -m multiport --dports 53 -j REJECT \
+                 --reject-with icmp6-adm-prohibited"
+                    .to_owned(),
+            ]),
+            iptables(RejectWith::IcmpAdminProhibited).describe(Action::Unblock, &targets)
+        );
+    }
 }
@@ -1,22 +1,162 @@
-use std::{net::IpAddr, path::PathBuf};
+use std::{
+    ffi::OsStr,
+    iter,
+    path::PathBuf,
+    process::{Command, Output},
+    time::Duration,
+};
 
-use anyhow::Result;
+use ipnetwork::IpNetwork;
+use itertools::Itertools;
+use log::warn;
+use serde::{Deserialize, Serialize, Serializer};
+use time::OffsetDateTime;
 
-pub use self::{ipset::IpSet, iptables::IpTables};
+#[cfg(feature = "async")]
+pub use self::asynchronous::{AsyncFirewall, FromSync};
+pub use self::{
+    aws_waf::AwsWaf,
+    blackhole::Blackhole,
+    country::CountrySets,
+    exec::Exec,
+    haproxy::HaProxy,
+    helper::Helper,
+    ipset::IpSet,
+    iptables::IpTables,
+    multi::Multi,
+    nginx::Nginx,
+    noop::Noop,
+    pf::Pf,
+    plugin::Plugin,
+    rate_limit::RateLimited,
+    reconcile::{reconcile, Reconciliation},
+    tc::Tc,
+    verify::{verify, Outcome, Step, TEST_IP},
+    worker::Worker,
+};
+use crate::{settings::Protocol, Error, Result};
 
+#[cfg(feature = "async")]
+mod asynchronous;
+mod aws_waf;
+mod blackhole;
+mod country;
+mod exec;
+mod haproxy;
+mod helper;
 mod ipset;
 mod iptables;
+mod multi;
+#[cfg(all(feature = "netlink", target_os = "linux"))]
+mod netlink;
+mod nginx;
+mod noop;
+mod pf;
+mod plugin;
+mod rate_limit;
+mod reconcile;
+mod tc;
+mod verify;
+mod worker;
 
-/// Information to block a specific IP on the firewall.
+/// Information to block a specific IP, or a whole network, on the firewall.
+#[derive(Serialize)]
 pub struct Target<'a> {
-    /// IP address to block requests from.
-    pub ip: IpAddr,
+    /// Network to block requests from, which is a single IP for most targets.
+    #[serde(serialize_with = "serialize_address")]
+    pub ip: IpNetwork,
     /// Optional list of ports that the access is blocked for. If the list is empty, then all ports
     /// are blocked.
     pub ports: &'a [u16],
+    /// Protocol that the access is blocked for. The ports are ignored if all protocols are blocked.
+    pub protocol: Protocol,
+    /// Time after which the firewall can unblock the IP by itself, if it supports that. Veto still
+    /// unblocks it as usual, so this only matters if veto stops unexpectedly.
+    #[serde(skip)]
+    pub timeout: Option<Duration>,
+}
+
+impl Target<'_> {
+    /// Address of the target as firewalls expect it, which is the bare IP for single hosts and the
+    /// network in CIDR notation otherwise.
+    #[must_use]
+    pub fn address(&self) -> String {
+        address(self.ip)
+    }
+}
+
+/// Format the network as bare IP if it only holds a single host, like `10.0.0.1` instead of
+/// `10.0.0.1/32`.
+fn address(network: IpNetwork) -> String {
+    if network.prefix() == max_prefix(network) {
+        network.ip().to_string()
+    } else {
+        network.to_string()
+    }
+}
+
+const fn max_prefix(network: IpNetwork) -> u8 {
+    match network {
+        IpNetwork::V4(_) => 32,
+        IpNetwork::V6(_) => 128,
+    }
+}
+
+/// Serialize single hosts as bare IP, so plugins keep receiving the same format for them.
+fn serialize_address<S: Serializer>(network: &IpNetwork, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&address(*network))
+}
+
+/// Time that is left until the block ends, or [`None`] if it already ended.
+#[must_use]
+pub fn remaining(until: OffsetDateTime, now: OffsetDateTime) -> Option<Duration> {
+    (until - now).try_into().ok()
+}
+
+/// Change to the firewall, that is done for a set of targets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Block,
+    Unblock,
+}
+
+/// Owned variant of [`Target`], that can be sent to other threads or processes.
+#[derive(Deserialize, Serialize)]
+struct OwnedTarget {
+    ip: IpNetwork,
+    ports: Vec<u16>,
+    protocol: Protocol,
+    timeout: Option<Duration>,
+}
+
+impl OwnedTarget {
+    fn from_slice(targets: &[Target<'_>]) -> Vec<Self> {
+        targets
+            .iter()
+            .map(|t| Self {
+                ip: t.ip,
+                ports: t.ports.to_owned(),
+                protocol: t.protocol,
+                timeout: t.timeout,
+            })
+            .collect()
+    }
+
+    fn borrow(&self) -> Target<'_> {
+        Target {
+            ip: self.ip,
+            ports: &self.ports,
+            protocol: self.protocol,
+            timeout: self.timeout,
+        }
+    }
 }
 
 /// A firewall can block and unblock requests from certain IPs.
+///
+/// The trait is object safe, so implementations can be selected at runtime as a `Box<dyn
+/// Firewall>`.
 pub trait Firewall {
     /// Setup the firewall for usage. This usually installs filters needed for easy IP blocking.
     fn install(&self) -> Result<()>;
@@ -26,22 +166,145 @@ pub trait Firewall {
     fn block(&self, target: &Target<'_>) -> Result<()>;
     /// Remove an entry from the firewall.
     fn unblock(&self, target: &Target<'_>) -> Result<()>;
+    /// Add several entries to the firewall at once. Implementations can override this to submit
+    /// all entries in a single operation, which by default blocks each target individually. A
+    /// failing target doesn't keep the others from being blocked, and the first error is returned.
+    fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        each_target(targets, "blocking", |target| self.block(target))
+    }
+    /// Remove several entries from the firewall at once. Like [`Self::block_all`], this unblocks
+    /// each target individually by default.
+    fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        each_target(targets, "unblocking", |target| self.unblock(target))
+    }
+    /// Check whether the changes of [`Self::install`] are in place, by listing the firewall's
+    /// current state. Returns [`None`] if the firewall can't tell, which is the default.
+    fn is_installed(&self) -> Result<Option<bool>> {
+        Ok(None)
+    }
+    /// Check whether the target is currently blocked, by listing the firewall's current state.
+    /// Like [`Self::is_installed`], this returns [`None`] by default.
+    fn is_blocked(&self, _target: &Target<'_>) -> Result<Option<bool>> {
+        Ok(None)
+    }
+    /// List the networks of all targets that are currently blocked. Like [`Self::is_installed`],
+    /// this returns [`None`] by default.
+    fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+        Ok(None)
+    }
+    /// Describe the commands that [`Self::block_all`] or [`Self::unblock_all`] would run for the
+    /// targets, without running them. Returns [`None`] if the firewall can't tell, which is the
+    /// default.
+    fn describe(&self, _action: Action, _targets: &[Target<'_>]) -> Option<Vec<String>> {
+        None
+    }
+}
+
+impl<F: Firewall + ?Sized> Firewall for Box<F> {
+    fn install(&self) -> Result<()> {
+        (**self).install()
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        (**self).uninstall()
+    }
+
+    fn block(&self, target: &Target<'_>) -> Result<()> {
+        (**self).block(target)
+    }
+
+    fn unblock(&self, target: &Target<'_>) -> Result<()> {
+        (**self).unblock(target)
+    }
+
+    fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        (**self).block_all(targets)
+    }
+
+    fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        (**self).unblock_all(targets)
+    }
+
+    fn is_installed(&self) -> Result<Option<bool>> {
+        (**self).is_installed()
+    }
+
+    fn is_blocked(&self, target: &Target<'_>) -> Result<Option<bool>> {
+        (**self).is_blocked(target)
+    }
+
+    fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+        (**self).list_blocked()
+    }
+
+    fn describe(&self, action: Action, targets: &[Target<'_>]) -> Option<Vec<String>> {
+        (**self).describe(action, targets)
+    }
+}
+
+/// Run the change for every target, regardless of whether any of them fails.
+fn each_target(
+    targets: &[Target<'_>],
+    action: &str,
+    change: impl Fn(&Target<'_>) -> Result<()>,
+) -> Result<()> {
+    let mut first = None;
+
+    for target in targets {
+        if let Err(e) = change(target) {
+            warn!("failed {action} {}: {e}", target.address());
+            first.get_or_insert(e);
+        }
+    }
+
+    first.map_or(Ok(()), Err)
+}
+
+/// Run the command to completion and collect its output.
+fn run(command: &mut Command) -> Result<Output> {
+    command.output().map_err(|source| Error::Spawn {
+        program: command.get_program().to_string_lossy().into_owned(),
+        source,
+    })
+}
+
+/// Format the program and arguments of a command as a single line, like it's typed in a shell.
+fn command_line(command: &Command) -> String {
+    iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(OsStr::to_string_lossy)
+        .join(" ")
+}
+
+/// Turn a failed command into an error, describing the action that it tried to do.
+fn check(output: &Output, action: &'static str) -> Result<()> {
+    if output.status.success() {
+        return Ok(());
+    }
+
+    Err(Error::Command {
+        action,
+        stderr: String::from_utf8_lossy(&output.stderr)
+            .trim_end()
+            .to_owned(),
+    })
 }
 
 #[cfg(target_os = "linux")]
 fn find_binary(name: &str, default: &str) -> Result<PathBuf> {
     use std::{fs, os::unix::fs::MetadataExt};
 
-    use anyhow::ensure;
-
     if let Ok(path) = which::which(name) {
         return Ok(path);
     }
 
+    #[allow(clippy::manual_is_variant_and)]
     let meta = fs::metadata(default)
         .map(|meta| meta.is_file() && meta.mode() & 0o111 != 0)
         .unwrap_or_default();
-    ensure!(meta, "cannot find binary path of '{}'", name);
+    if !meta {
+        return Err(Error::MissingBinary(name.to_owned()));
+    }
 
     Ok(PathBuf::from(default))
 }
@@ -51,3 +314,59 @@ fn find_binary(name: &str, default: &str) -> Result<PathBuf> {
 fn find_binary(_name: &str, default: &str) -> Result<PathBuf> {
     Ok(PathBuf::from(default))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_all_tries_every_target() {
+        struct Picky(parking_lot::Mutex<Vec<IpNetwork>>);
+
+        impl Firewall for Picky {
+            fn install(&self) -> Result<()> {
+                Ok(())
+            }
+
+            fn uninstall(&self) -> Result<()> {
+                Ok(())
+            }
+
+            fn block(&self, target: &Target<'_>) -> Result<()> {
+                if target.ip.prefix() == 0 {
+                    return Err(Error::Command {
+                        action: "blocking",
+                        stderr: "too wide".to_owned(),
+                    });
+                }
+
+                self.0.lock().push(target.ip);
+                Ok(())
+            }
+
+            fn unblock(&self, _target: &Target<'_>) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        let target = |ip: &str| Target {
+            ip: ip.parse().unwrap(),
+            ports: &[],
+            protocol: Protocol::Tcp,
+            timeout: None,
+        };
+
+        let firewall = Picky(parking_lot::Mutex::default());
+        let result =
+            firewall.block_all(&[target("10.0.0.1"), target("0.0.0.0/0"), target("10.0.0.2")]);
+
+        assert!(matches!(result, Err(Error::Command { .. })));
+        assert_eq!(
+            vec![
+                "10.0.0.1".parse::<IpNetwork>().unwrap(),
+                "10.0.0.2".parse().unwrap()
+            ],
+            *firewall.0.lock()
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::HashSet;
+
+use ipnetwork::IpNetwork;
+use log::warn;
+
+use super::{Action, Firewall, Target};
+use crate::Result;
+
+/// Firewall that applies every change to several firewalls at once, like a local ipset together
+/// with AWS WAF.
+///
+/// Each firewall is changed even if another one failed before, so a broken firewall doesn't stop
+/// the others from blocking. Failures are logged, and the first one is returned once all firewalls
+/// ran, so the whole change is retried later. All firewalls should therefore accept the same change
+/// twice.
+pub struct Multi<F> {
+    firewalls: Vec<(String, F)>,
+}
+
+impl<F: Firewall> Multi<F> {
+    /// Combine the firewalls, each with a name that identifies it in logs.
+    #[must_use]
+    pub const fn new(firewalls: Vec<(String, F)>) -> Self {
+        Self { firewalls }
+    }
+
+    /// Run the change on all firewalls, regardless of whether any of them fails.
+    fn each(&self, action: &str, change: impl Fn(&F) -> Result<()>) -> Result<()> {
+        let mut first = None;
+
+        for (name, firewall) in &self.firewalls {
+            if let Err(e) = change(firewall) {
+                warn!("failed {action} with the {name} firewall: {e}");
+                first.get_or_insert(e);
+            }
+        }
+
+        first.map_or(Ok(()), Err)
+    }
+
+    /// Combine the answers of all firewalls that can tell, which is only true if all of them
+    /// agree.
+    fn all(&self, check: impl Fn(&F) -> Result<Option<bool>>) -> Result<Option<bool>> {
+        let mut result = None;
+
+        for (_, firewall) in &self.firewalls {
+            if let Some(value) = check(firewall)? {
+                result = Some(result.unwrap_or(true) && value);
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+impl<F: Firewall> Firewall for Multi<F> {
+    fn install(&self) -> Result<()> {
+        self.each("installing", Firewall::install)
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        self.each("uninstalling", Firewall::uninstall)
+    }
+
+    fn block(&self, target: &Target<'_>) -> Result<()> {
+        self.each("blocking", |firewall| firewall.block(target))
+    }
+
+    fn unblock(&self, target: &Target<'_>) -> Result<()> {
+        self.each("unblocking", |firewall| firewall.unblock(target))
+    }
+
+    fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.each("blocking", |firewall| firewall.block_all(targets))
+    }
+
+    fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.each("unblocking", |firewall| firewall.unblock_all(targets))
+    }
+
+    fn is_installed(&self) -> Result<Option<bool>> {
+        self.all(Firewall::is_installed)
+    }
+
+    fn is_blocked(&self, target: &Target<'_>) -> Result<Option<bool>> {
+        self.all(|firewall| firewall.is_blocked(target))
+    }
+
+    /// Only the networks that all firewalls which can list them have blocked, so a network that
+    /// is missing from any of them is blocked again.
+    fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+        let mut result = None::<Vec<IpNetwork>>;
+
+        for (_, firewall) in &self.firewalls {
+            let Some(listed) = firewall.list_blocked()? else {
+                continue;
+            };
+
+            result = Some(match result {
+                Some(mut blocked) => {
+                    let listed = listed.into_iter().collect::<HashSet<_>>();
+                    blocked.retain(|network| listed.contains(network));
+                    blocked
+                }
+                None => listed,
+            });
+        }
+
+        Ok(result)
+    }
+
+    fn describe(&self, action: Action, targets: &[Target<'_>]) -> Option<Vec<String>> {
+        self.firewalls
+            .iter()
+            .filter_map(|(_, firewall)| firewall.describe(action, targets))
+            .reduce(|mut all, commands| {
+                all.extend(commands);
+                all
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        settings::Protocol,
+        testing::{FirewallCall, MockFirewall},
+        Error,
+    };
+
+    /// Firewall that records all calls, but fails to block anything if broken.
+    struct Flaky {
+        broken: bool,
+        mock: MockFirewall,
+    }
+
+    impl Firewall for Flaky {
+        fn install(&self) -> Result<()> {
+            self.mock.install()
+        }
+
+        fn uninstall(&self) -> Result<()> {
+            self.mock.uninstall()
+        }
+
+        fn block(&self, target: &Target<'_>) -> Result<()> {
+            if self.broken {
+                return Err(Error::Command {
+                    action: "adding IP",
+                    stderr: "broken".to_owned(),
+                });
+            }
+
+            self.mock.block(target)
+        }
+
+        fn unblock(&self, target: &Target<'_>) -> Result<()> {
+            self.mock.unblock(target)
+        }
+
+        fn is_installed(&self) -> Result<Option<bool>> {
+            self.mock.is_installed()
+        }
+
+        fn is_blocked(&self, target: &Target<'_>) -> Result<Option<bool>> {
+            self.mock.is_blocked(target)
+        }
+
+        fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+            self.mock.list_blocked()
+        }
+    }
+
+    #[test]
+    fn isolate_failures() {
+        let multi = Multi::new(
+            [("broken", true), ("working", false)]
+                .into_iter()
+                .map(|(name, broken)| {
+                    let mock = MockFirewall::new();
+                    (name.to_owned(), Flaky { broken, mock })
+                })
+                .collect(),
+        );
+        let target = Target {
+            ip: "10.0.0.1".parse().unwrap(),
+            ports: &[22],
+            protocol: Protocol::Tcp,
+            timeout: None,
+        };
+
+        multi.install().unwrap();
+        assert_eq!(Some(true), multi.is_installed().unwrap());
+
+        let err = multi.block_all(std::slice::from_ref(&target)).unwrap_err();
+        assert!(matches!(err, Error::Command { stderr, .. } if stderr == "broken"));
+        assert_eq!(
+            FirewallCall::Block {
+                ip: target.ip,
+                ports: vec![22]
+            },
+            multi.firewalls[1].1.mock.calls()[1]
+        );
+
+        // Missing from the broken firewall, so it's blocked again during reconciliation.
+        assert_eq!(Some(Vec::new()), multi.list_blocked().unwrap());
+        assert_eq!(Some(false), multi.is_blocked(&target).unwrap());
+
+        multi.uninstall().unwrap();
+        assert_eq!(Some(false), multi.is_installed().unwrap());
+        assert_eq!(None, multi.describe(Action::Block, &[target]));
+    }
+}
@@ -0,0 +1,576 @@
+//! Changes to ipset sets over netlink, without running the `ipset` binary for each batch of IPs.
+//!
+//! The messages are encoded by hand on top of a raw rustix socket. The netlink crates like `mnl`
+//! only handle the generic message framing and bind to the C library, and `nftnl` covers nftables
+//! but not ipset, so the ipset commands and attributes would still have to be encoded here. The few
+//! messages that veto sends follow the layout of `include/uapi/linux/netfilter/ipset/ip_set.h`, and
+//! the tests compare them byte by byte against a known encoding.
+
+use std::{
+    io,
+    net::IpAddr,
+    os::fd::OwnedFd,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use ipnetwork::IpNetwork;
+use rustix::net::{
+    self, netlink, netlink::SocketAddrNetlink, AddressFamily, RecvFlags, SendFlags, SocketFlags,
+    SocketType,
+};
+
+use crate::{Error, Result};
+
+/// Netfilter subsystem of ipset, in the upper byte of the message type.
+const NFNL_SUBSYS_IPSET: u16 = 6;
+/// Oldest protocol version of ipset, which all kernels with ipset support accept.
+const IPSET_PROTOCOL: u8 = 6;
+
+const IPSET_CMD_LIST: u8 = 7;
+const IPSET_CMD_ADD: u8 = 9;
+const IPSET_CMD_DEL: u8 = 10;
+
+const IPSET_ATTR_PROTOCOL: u16 = 1;
+const IPSET_ATTR_SETNAME: u16 = 2;
+const IPSET_ATTR_DATA: u16 = 7;
+const IPSET_ATTR_ADT: u16 = 8;
+const IPSET_ATTR_IP: u16 = 1;
+const IPSET_ATTR_CIDR: u16 = 3;
+const IPSET_ATTR_TIMEOUT: u16 = 6;
+const IPSET_ATTR_IPADDR_IPV4: u16 = 1;
+const IPSET_ATTR_IPADDR_IPV6: u16 = 2;
+
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_F_NET_BYTEORDER: u16 = 1 << 14;
+
+const NLM_F_REQUEST: u16 = 0x01;
+const NLM_F_ACK: u16 = 0x04;
+const NLM_F_DUMP: u16 = 0x300;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+
+const AF_INET: u8 = 2;
+const AF_INET6: u8 = 10;
+
+/// Amount of entries that are sent at once, which keeps each batch well below the default socket
+/// buffer size.
+const BATCH_SIZE: usize = 256;
+/// First error code that ipset defines on top of the regular errno values.
+const IPSET_ERR_PRIVATE: i32 = 4096;
+/// Error of sets that reached their maximum amount of entries.
+const IPSET_ERR_HASH_FULL: i32 = 4352;
+
+/// Entry to add to or delete from a set.
+pub struct Entry<'a> {
+    pub set: &'a str,
+    pub network: IpNetwork,
+    /// Seconds until the kernel removes the entry, if the set supports timeouts.
+    pub timeout: Option<u64>,
+}
+
+/// Socket that talks to the ipset subsystem of the kernel directly, the same way the `ipset`
+/// binary does.
+///
+/// Entries are changed in batches of many messages per system call, and only failures are answered
+/// by the kernel, except for the last message of each batch. Like `ipset -exist`, adding existing
+/// entries and deleting missing ones is not an error.
+pub struct Socket {
+    fd: OwnedFd,
+    seq: AtomicU32,
+}
+
+impl Socket {
+    pub fn new() -> Result<Self> {
+        let fd = net::socket_with(
+            AddressFamily::NETLINK,
+            SocketType::RAW,
+            SocketFlags::CLOEXEC,
+            Some(netlink::NETFILTER),
+        )
+        .map_err(io::Error::from)?;
+        net::bind(&fd, &SocketAddrNetlink::new(0, 0)).map_err(io::Error::from)?;
+
+        Ok(Self {
+            fd,
+            seq: AtomicU32::new(1),
+        })
+    }
+
+    pub fn add(&self, entries: &[Entry<'_>]) -> Result<()> {
+        self.change(IPSET_CMD_ADD, entries, "adding IPs to ipset table")
+    }
+
+    pub fn del(&self, entries: &[Entry<'_>]) -> Result<()> {
+        self.change(IPSET_CMD_DEL, entries, "deleting IPs from ipset table")
+    }
+
+    /// All networks in the set of the given name.
+    pub fn list(&self, set: &str) -> Result<Vec<IpNetwork>> {
+        let action = "listing ipset entries";
+        let seq = self.next_seq(1);
+        let mut msg = Message::new(IPSET_CMD_LIST, NLM_F_DUMP, seq, 0);
+        msg.attr(IPSET_ATTR_SETNAME, &set_name(set));
+        self.send(&msg.finish(), action)?;
+
+        let mut networks = Vec::new();
+        let mut buf = vec![0; 64 * 1024];
+
+        loop {
+            for (kind, msg_seq, payload) in messages(self.recv(&mut buf, action)?) {
+                if msg_seq != seq {
+                    continue;
+                }
+
+                match kind {
+                    NLMSG_DONE => return Ok(networks),
+                    NLMSG_ERROR => {
+                        let code = error_code(payload);
+                        if code != 0 {
+                            return Err(error(action, code));
+                        }
+                    }
+                    _ => networks.extend(parse_list(payload)),
+                }
+            }
+        }
+    }
+
+    /// Send the command for all entries, and wait until the kernel processed them. All entries are
+    /// sent even if some fail, and the first failure is returned afterwards.
+    fn change(&self, command: u8, entries: &[Entry<'_>], action: &'static str) -> Result<()> {
+        let mut first = None;
+
+        for chunk in entries.chunks(BATCH_SIZE) {
+            #[allow(clippy::cast_possible_truncation)]
+            let seq = self.next_seq(chunk.len() as u32);
+
+            self.send(&batch(command, seq, chunk), action)?;
+            first = first.or(self.wait(seq, chunk.len() - 1, action)?);
+        }
+
+        first.map_or(Ok(()), Err)
+    }
+
+    /// Read the answers to a batch, until the last message of it was acknowledged. Returns the
+    /// first error that the kernel reported for any of the messages.
+    fn wait(&self, seq: u32, last: usize, action: &'static str) -> Result<Option<Error>> {
+        let mut first = None;
+        let mut buf = vec![0; 64 * 1024];
+
+        loop {
+            for (kind, msg_seq, payload) in messages(self.recv(&mut buf, action)?) {
+                let index = msg_seq.wrapping_sub(seq) as usize;
+                if kind != NLMSG_ERROR || index > last {
+                    continue;
+                }
+
+                let code = error_code(payload);
+                if code != 0 {
+                    first.get_or_insert_with(|| error(action, code));
+                }
+                if index == last {
+                    return Ok(first);
+                }
+            }
+        }
+    }
+
+    /// Reserve sequence numbers for the given amount of messages, returning the first one.
+    fn next_seq(&self, count: u32) -> u32 {
+        self.seq.fetch_add(count, Ordering::Relaxed)
+    }
+
+    fn send(&self, buf: &[u8], action: &'static str) -> Result<()> {
+        net::sendto(
+            &self.fd,
+            buf,
+            SendFlags::empty(),
+            &SocketAddrNetlink::new(0, 0),
+        )
+        .map_err(|e| Error::Netlink {
+            action,
+            source: e.into(),
+        })?;
+
+        Ok(())
+    }
+
+    fn recv<'b>(&self, buf: &'b mut [u8], action: &'static str) -> Result<&'b [u8]> {
+        let (len, _) =
+            net::recv(&self.fd, &mut *buf, RecvFlags::empty()).map_err(|e| Error::Netlink {
+                action,
+                source: e.into(),
+            })?;
+
+        Ok(&buf[..len])
+    }
+}
+
+/// Netlink message that is built up attribute by attribute.
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(command: u8, flags: u16, seq: u32, family: u8) -> Self {
+        let mut buf = Vec::with_capacity(80);
+        // The length is filled in once all attributes are added.
+        buf.extend_from_slice(&0_u32.to_ne_bytes());
+        buf.extend_from_slice(&((NFNL_SUBSYS_IPSET << 8) | u16::from(command)).to_ne_bytes());
+        buf.extend_from_slice(&(NLM_F_REQUEST | flags).to_ne_bytes());
+        buf.extend_from_slice(&seq.to_ne_bytes());
+        // The port ID of the socket is assigned by the kernel.
+        buf.extend_from_slice(&0_u32.to_ne_bytes());
+        // Netfilter header of address family, version and resource ID.
+        buf.extend_from_slice(&[family, 0, 0, 0]);
+
+        let mut msg = Self { buf };
+        msg.attr(IPSET_ATTR_PROTOCOL, &[IPSET_PROTOCOL]);
+        msg
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn attr(&mut self, kind: u16, payload: &[u8]) {
+        self.buf
+            .extend_from_slice(&((4 + payload.len()) as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(payload);
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    /// Start a nested attribute, that ends with [`Self::end_nested`] at the returned offset.
+    fn begin_nested(&mut self, kind: u16) -> usize {
+        let start = self.buf.len();
+        self.buf.extend_from_slice(&0_u16.to_ne_bytes());
+        self.buf
+            .extend_from_slice(&(kind | NLA_F_NESTED).to_ne_bytes());
+        start
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn end_nested(&mut self, start: usize) {
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// Messages that add or delete all entries, with consecutive sequence numbers starting at `seq`.
+fn batch(command: u8, seq: u32, entries: &[Entry<'_>]) -> Vec<u8> {
+    let last = entries.len().saturating_sub(1);
+    let mut buf = Vec::with_capacity(entries.len() * 80);
+
+    for (i, entry) in entries.iter().enumerate() {
+        // Only the last message is acknowledged, which tells that all others are done.
+        let flags = if i == last { NLM_F_ACK } else { 0 };
+        #[allow(clippy::cast_possible_truncation)]
+        buf.extend(entry_message(
+            command,
+            flags,
+            seq.wrapping_add(i as u32),
+            entry,
+        ));
+    }
+
+    buf
+}
+
+/// Message that adds or deletes a single entry.
+fn entry_message(command: u8, flags: u16, seq: u32, entry: &Entry<'_>) -> Vec<u8> {
+    let family = match entry.network {
+        IpNetwork::V4(_) => AF_INET,
+        IpNetwork::V6(_) => AF_INET6,
+    };
+
+    let mut msg = Message::new(command, flags, seq, family);
+    msg.attr(IPSET_ATTR_SETNAME, &set_name(entry.set));
+
+    let data = msg.begin_nested(IPSET_ATTR_DATA);
+    let ip = msg.begin_nested(IPSET_ATTR_IP);
+    match entry.network.ip() {
+        IpAddr::V4(ip) => msg.attr(IPSET_ATTR_IPADDR_IPV4 | NLA_F_NET_BYTEORDER, &ip.octets()),
+        IpAddr::V6(ip) => msg.attr(IPSET_ATTR_IPADDR_IPV6 | NLA_F_NET_BYTEORDER, &ip.octets()),
+    }
+    msg.end_nested(ip);
+    msg.attr(IPSET_ATTR_CIDR, &[entry.network.prefix()]);
+    if let Some(timeout) = entry.timeout {
+        let timeout = u32::try_from(timeout).unwrap_or(u32::MAX);
+        msg.attr(
+            IPSET_ATTR_TIMEOUT | NLA_F_NET_BYTEORDER,
+            &timeout.to_be_bytes(),
+        );
+    }
+    msg.end_nested(data);
+
+    msg.finish()
+}
+
+/// Name of a set, as the kernel expects it with a trailing NUL byte.
+fn set_name(name: &str) -> Vec<u8> {
+    let mut name = name.as_bytes().to_vec();
+    name.push(0);
+    name
+}
+
+/// Round up to the 4 byte alignment of netlink messages and attributes.
+const fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Split the received messages into their type, sequence number and payload after the headers.
+fn messages(mut buf: &[u8]) -> Vec<(u16, u32, &[u8])> {
+    let mut messages = Vec::new();
+
+    while buf.len() >= 16 {
+        let len = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        if len < 16 || len > buf.len() {
+            break;
+        }
+
+        let kind = u16::from_ne_bytes([buf[4], buf[5]]);
+        let seq = u32::from_ne_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        messages.push((kind, seq, &buf[16..len]));
+
+        buf = &buf[align(len).min(buf.len())..];
+    }
+
+    messages
+}
+
+/// Split attributes into their type, without the flags, and payload.
+fn attributes(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attributes = Vec::new();
+
+    while buf.len() >= 4 {
+        let len = usize::from(u16::from_ne_bytes([buf[0], buf[1]]));
+        if len < 4 || len > buf.len() {
+            break;
+        }
+
+        let kind = u16::from_ne_bytes([buf[2], buf[3]]) & !(NLA_F_NESTED | NLA_F_NET_BYTEORDER);
+        attributes.push((kind, &buf[4..len]));
+
+        buf = &buf[align(len).min(buf.len())..];
+    }
+
+    attributes
+}
+
+/// Error code of an error message, which is zero for acknowledgements.
+fn error_code(payload: &[u8]) -> i32 {
+    payload.get(..4).map_or(0, |code| {
+        i32::from_ne_bytes([code[0], code[1], code[2], code[3]])
+    })
+}
+
+/// Networks of a single message of a set listing. The entries are grouped in the ADT attribute,
+/// while other messages only describe the set itself.
+fn parse_list(payload: &[u8]) -> Vec<IpNetwork> {
+    // Skip the netfilter header before the attributes.
+    let Some(attrs) = payload.get(4..) else {
+        return Vec::new();
+    };
+
+    attributes(attrs)
+        .into_iter()
+        .filter(|(kind, _)| *kind == IPSET_ATTR_ADT)
+        .flat_map(|(_, adt)| attributes(adt))
+        .filter(|(kind, _)| *kind == IPSET_ATTR_DATA)
+        .filter_map(|(_, data)| parse_entry(data))
+        .collect()
+}
+
+fn parse_entry(data: &[u8]) -> Option<IpNetwork> {
+    let mut ip = None;
+    let mut prefix = None;
+
+    for (kind, payload) in attributes(data) {
+        match kind {
+            IPSET_ATTR_IP => {
+                ip = attributes(payload)
+                    .into_iter()
+                    .find_map(|(kind, addr)| match kind {
+                        IPSET_ATTR_IPADDR_IPV4 => {
+                            Some(IpAddr::from(<[u8; 4]>::try_from(addr).ok()?))
+                        }
+                        IPSET_ATTR_IPADDR_IPV6 => {
+                            Some(IpAddr::from(<[u8; 16]>::try_from(addr).ok()?))
+                        }
+                        _ => None,
+                    });
+            }
+            IPSET_ATTR_CIDR => prefix = payload.first().copied(),
+            _ => {}
+        }
+    }
+
+    let ip = ip?;
+    let prefix = prefix.unwrap_or(match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    });
+
+    IpNetwork::new(ip, prefix).ok()
+}
+
+/// Error for a negative error code of the kernel, which is either an errno or specific to ipset.
+fn error(action: &'static str, code: i32) -> Error {
+    let code = -code;
+    if code == IPSET_ERR_HASH_FULL {
+        return Error::FirewallFull;
+    }
+
+    let source = if code >= IPSET_ERR_PRIVATE {
+        io::Error::other(match code {
+            4097 => "invalid ipset request".to_owned(),
+            4102 => "set type doesn't match".to_owned(),
+            4104 => "invalid network prefix".to_owned(),
+            4106 => "address family doesn't match the set".to_owned(),
+            4107 => "set has no timeout support".to_owned(),
+            _ => format!("ipset error {code}"),
+        })
+    } else {
+        io::Error::from_raw_os_error(code)
+    };
+
+    Error::Netlink { action, source }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    #[test]
+    fn encode_and_parse() {
+        let entry = Entry {
+            set: "veto",
+            network: "10.1.0.0/16".parse().unwrap(),
+            timeout: Some(60),
+        };
+        let msg = entry_message(IPSET_CMD_ADD, NLM_F_ACK, 7, &entry);
+
+        let [(kind, seq, payload)] = messages(&msg)[..] else {
+            panic!("expected a single message");
+        };
+        assert_eq!((0x0609, 7), (kind, seq));
+        assert_eq!(&[AF_INET, 0, 0, 0], &payload[..4]);
+
+        let attrs = attributes(&payload[4..]);
+        assert_eq!((IPSET_ATTR_PROTOCOL, &[6][..]), attrs[0]);
+        assert_eq!((IPSET_ATTR_SETNAME, &b"veto\0"[..]), attrs[1]);
+
+        let data = attributes(attrs[2].1);
+        assert_eq!(
+            vec![(IPSET_ATTR_IPADDR_IPV4, &[10, 1, 0, 0][..])],
+            attributes(data[0].1)
+        );
+        assert_eq!((IPSET_ATTR_CIDR, &[16][..]), data[1]);
+        assert_eq!((IPSET_ATTR_TIMEOUT, &[0, 0, 0, 60][..]), data[2]);
+
+        // A listing has the same layout as the kernel sends it, with all entries in one attribute.
+        let mut msg = Message::new(IPSET_CMD_LIST, 0, 7, AF_INET6);
+        msg.attr(IPSET_ATTR_SETNAME, &set_name("veto_v6"));
+        let adt = msg.begin_nested(IPSET_ATTR_ADT);
+        for (ip, prefix) in [
+            (Ipv6Addr::LOCALHOST, None),
+            (Ipv6Addr::UNSPECIFIED, Some(64)),
+        ] {
+            let data = msg.begin_nested(IPSET_ATTR_DATA);
+            let nested = msg.begin_nested(IPSET_ATTR_IP);
+            msg.attr(IPSET_ATTR_IPADDR_IPV6 | NLA_F_NET_BYTEORDER, &ip.octets());
+            msg.end_nested(nested);
+            if let Some(prefix) = prefix {
+                msg.attr(IPSET_ATTR_CIDR, &[prefix]);
+            }
+            msg.end_nested(data);
+        }
+        msg.end_nested(adt);
+
+        let msg = msg.finish();
+        assert_eq!(
+            vec![
+                "::1".parse::<IpNetwork>().unwrap(),
+                "::/64".parse().unwrap()
+            ],
+            parse_list(messages(&msg)[0].2)
+        );
+        assert!(parse_entry(&[]).is_none());
+
+        assert!(error("adding IPs to ipset table", -1).is_permission_denied());
+        assert!(matches!(
+            error("adding IPs to ipset table", -IPSET_ERR_HASH_FULL),
+            Error::FirewallFull
+        ));
+    }
+
+    /// Headers and attribute lengths are in native byte order, so the expected bytes are those of
+    /// a little endian machine.
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn encode_batch() {
+        let entries = [
+            Entry {
+                set: "veto",
+                network: "10.0.0.1/32".parse().unwrap(),
+                timeout: Some(60),
+            },
+            Entry {
+                set: "veto_v6",
+                network: "2001:db8::/32".parse().unwrap(),
+                timeout: None,
+            },
+        ];
+
+        #[rustfmt::skip]
+        let add: &[u8] = &[
+            // Message header: length, IPSET_CMD_ADD, NLM_F_REQUEST, sequence, port ID.
+            72, 0, 0, 0,  9, 6,  1, 0,  1, 0, 0, 0,  0, 0, 0, 0,
+            // Netfilter header: AF_INET, version, resource ID.
+            2, 0, 0, 0,
+            // IPSET_ATTR_PROTOCOL.
+            5, 0, 1, 0,  6, 0, 0, 0,
+            // IPSET_ATTR_SETNAME.
+            9, 0, 2, 0,  b'v', b'e', b't', b'o',  0, 0, 0, 0,
+            // IPSET_ATTR_DATA, nested.
+            32, 0, 7, 0x80,
+            // IPSET_ATTR_IP, nested, with IPSET_ATTR_IPADDR_IPV4 in network byte order.
+            12, 0, 1, 0x80,  8, 0, 1, 0x40,  10, 0, 0, 1,
+            // IPSET_ATTR_CIDR.
+            5, 0, 3, 0,  32, 0, 0, 0,
+            // IPSET_ATTR_TIMEOUT, in network byte order.
+            8, 0, 6, 0x40,  0, 0, 0, 60,
+
+            // Message header: length, IPSET_CMD_ADD, NLM_F_REQUEST | NLM_F_ACK, sequence, port ID.
+            76, 0, 0, 0,  9, 6,  5, 0,  2, 0, 0, 0,  0, 0, 0, 0,
+            // Netfilter header: AF_INET6, version, resource ID.
+            10, 0, 0, 0,
+            // IPSET_ATTR_PROTOCOL.
+            5, 0, 1, 0,  6, 0, 0, 0,
+            // IPSET_ATTR_SETNAME, which needs no padding.
+            12, 0, 2, 0,  b'v', b'e', b't', b'o',  b'_', b'v', b'6', 0,
+            // IPSET_ATTR_DATA, nested.
+            36, 0, 7, 0x80,
+            // IPSET_ATTR_IP, nested, with IPSET_ATTR_IPADDR_IPV6 in network byte order.
+            24, 0, 1, 0x80,  20, 0, 2, 0x40,
+            0x20, 0x01, 0x0d, 0xb8,  0, 0, 0, 0,  0, 0, 0, 0,  0, 0, 0, 0,
+            // IPSET_ATTR_CIDR.
+            5, 0, 3, 0,  32, 0, 0, 0,
+        ];
+        assert_eq!(add, batch(IPSET_CMD_ADD, 1, &entries));
+
+        // A single entry is the last one of its batch, and deleting only differs in the command.
+        let mut del = add[..72].to_vec();
+        del[4] = IPSET_CMD_DEL;
+        del[6] |= 4;
+        assert_eq!(del, batch(IPSET_CMD_DEL, 1, &entries[..1]));
+        assert!(batch(IPSET_CMD_DEL, 1, &[]).is_empty());
+    }
+}
@@ -0,0 +1,233 @@
+use std::{collections::BTreeSet, fmt::Write as _, fs, io};
+
+use ipnetwork::IpNetwork;
+use parking_lot::Mutex;
+
+use super::{address, check, exec::shell, run, Action, Firewall, Target};
+use crate::{settings::Nginx as Settings, Result};
+
+/// First line of the deny file, so nobody edits it by hand.
+const HEADER: &str = "# Managed by veto, any changes are overwritten.\n";
+
+/// Firewall that writes a `deny <ip>;` line for each blocked IP into a file and reloads nginx, for
+/// hosts where only the nginx configuration can be changed.
+///
+/// The file is meant to be included by the nginx configuration, like with
+/// `include /etc/nginx/veto.conf;` in the `http` block. Ports and protocols don't apply, as nginx
+/// denies all requests of the IP with a `403 Forbidden` error.
+pub struct Nginx {
+    settings: Settings,
+    /// Guards the file, so concurrent changes don't overwrite each other.
+    lock: Mutex<()>,
+}
+
+impl Nginx {
+    #[must_use]
+    pub const fn new(settings: Settings) -> Self {
+        Self {
+            settings,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Networks that the file currently denies. A missing file denies nothing.
+    fn read(&self) -> Result<BTreeSet<IpNetwork>> {
+        match fs::read_to_string(&self.settings.file) {
+            Ok(content) => Ok(parse_denies(&content)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace the file at once, so nginx never loads a partially written one.
+    fn write(&self, networks: &BTreeSet<IpNetwork>) -> Result<()> {
+        let temp = self.settings.file.with_extension("tmp");
+
+        fs::write(&temp, render(networks))?;
+        fs::rename(temp, &self.settings.file)?;
+
+        Ok(())
+    }
+
+    fn reload(&self) -> Result<()> {
+        check(&run(&mut shell(&self.settings.reload))?, "reloading nginx")
+    }
+
+    /// Apply the change to the denied networks, and only write the file and reload nginx if
+    /// anything changed.
+    fn update(&self, change: impl FnOnce(&mut BTreeSet<IpNetwork>) -> bool) -> Result<()> {
+        let _lock = self.lock.lock();
+
+        let mut networks = self.read()?;
+        if !change(&mut networks) {
+            return Ok(());
+        }
+
+        self.write(&networks)?;
+        self.reload()
+    }
+}
+
+impl Firewall for Nginx {
+    /// Create the file if it's missing, so the configuration can include it.
+    fn install(&self) -> Result<()> {
+        if !self.settings.file.exists() {
+            self.write(&BTreeSet::new())?;
+        }
+
+        Ok(())
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        self.update(|networks| {
+            let changed = !networks.is_empty();
+            networks.clear();
+            changed
+        })
+    }
+
+    fn block(&self, target: &Target<'_>) -> Result<()> {
+        self.block_all(std::slice::from_ref(target))
+    }
+
+    fn unblock(&self, target: &Target<'_>) -> Result<()> {
+        self.unblock_all(std::slice::from_ref(target))
+    }
+
+    fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.update(|networks| {
+            let before = networks.len();
+            networks.extend(targets.iter().map(|target| target.ip));
+            networks.len() != before
+        })
+    }
+
+    fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.update(|networks| {
+            let before = networks.len();
+            for target in targets {
+                networks.remove(&target.ip);
+            }
+            networks.len() != before
+        })
+    }
+
+    fn is_installed(&self) -> Result<Option<bool>> {
+        Ok(Some(self.settings.file.exists()))
+    }
+
+    fn is_blocked(&self, target: &Target<'_>) -> Result<Option<bool>> {
+        Ok(Some(self.read()?.contains(&target.ip)))
+    }
+
+    fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+        Ok(Some(self.read()?.into_iter().collect()))
+    }
+
+    fn describe(&self, action: Action, targets: &[Target<'_>]) -> Option<Vec<String>> {
+        if targets.is_empty() {
+            return Some(Vec::new());
+        }
+
+        let change = match action {
+            Action::Block => "add to",
+            Action::Unblock => "remove from",
+        };
+        let file = self.settings.file.display();
+
+        Some(
+            targets
+                .iter()
+                .map(|target| format!("{change} {file}: deny {};", target.address()))
+                .chain([self.settings.reload.clone()])
+                .collect(),
+        )
+    }
+}
+
+/// Content of the file, with one `deny` directive per network.
+fn render(networks: &BTreeSet<IpNetwork>) -> String {
+    let mut content = HEADER.to_owned();
+    for network in networks {
+        _ = writeln!(content, "deny {};", address(*network));
+    }
+
+    content
+}
+
+/// Get the networks of all `deny` directives in the file. Single hosts are listed without a prefix.
+fn parse_denies(content: &str) -> BTreeSet<IpNetwork> {
+    content
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("deny ")?.strip_suffix(';'))
+        .filter_map(|network| network.trim().parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf};
+
+    use super::*;
+    use crate::settings::Protocol;
+
+    fn target(ip: &str) -> Target<'static> {
+        Target {
+            ip: ip.parse().unwrap(),
+            ports: &[80],
+            protocol: Protocol::Tcp,
+            timeout: None,
+        }
+    }
+
+    #[test]
+    fn deny_file() {
+        let dir = env::temp_dir().join(format!("veto-nginx-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("veto.conf");
+        let reloads = dir.join("reloads");
+
+        let nginx = Nginx::new(Settings {
+            file: file.clone(),
+            reload: format!("echo reload >> {}", reloads.display()),
+        });
+
+        nginx.install().unwrap();
+        assert_eq!(HEADER, fs::read_to_string(&file).unwrap());
+
+        nginx
+            .block_all(&[target("10.0.0.1"), target("2001:db8::/32")])
+            .unwrap();
+        // Already denied, so neither the file nor nginx is touched.
+        nginx.block(&target("10.0.0.1")).unwrap();
+        nginx.unblock(&target("10.0.0.2")).unwrap();
+
+        assert_eq!(
+            format!("{HEADER}deny 10.0.0.1;\ndeny 2001:db8::/32;\n"),
+            fs::read_to_string(&file).unwrap()
+        );
+        assert_eq!(Some(true), nginx.is_blocked(&target("10.0.0.1")).unwrap());
+
+        nginx.uninstall().unwrap();
+        assert_eq!(Some(Vec::new()), nginx.list_blocked().unwrap());
+        assert_eq!("reload\nreload\n", fs::read_to_string(&reloads).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn describe() {
+        let nginx = Nginx::new(Settings {
+            file: PathBuf::from("/etc/nginx/veto.conf"),
+            reload: "nginx -s reload".to_owned(),
+        });
+
+        assert_eq!(
+            Some(vec![
+                "remove from /etc/nginx/veto.conf: deny 10.0.0.1;".to_owned(),
+                "nginx -s reload".to_owned(),
+            ]),
+            nginx.describe(Action::Unblock, &[target("10.0.0.1")])
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use log::debug;
+
+use super::{Action, Firewall, Target};
+use crate::Result;
+
+/// Firewall that doesn't block anything, for running veto in detection-only mode.
+///
+/// Blocks are still recorded in the storage and published as events, so other systems can
+/// enforce them instead. This doesn't require any privileges, unlike the real firewalls.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Noop;
+
+impl Firewall for Noop {
+    fn install(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn block(&self, target: &Target<'_>) -> Result<()> {
+        debug!("Detected {}, not blocking it", target.ip);
+        Ok(())
+    }
+
+    fn unblock(&self, _target: &Target<'_>) -> Result<()> {
+        Ok(())
+    }
+
+    fn describe(&self, _action: Action, _targets: &[Target<'_>]) -> Option<Vec<String>> {
+        Some(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{macros::datetime, Duration};
+
+    use super::*;
+    use crate::{
+        events::{BanEvent, BlockEvent},
+        handler::Handler,
+        pipeline::Pipeline,
+        settings::{Filter, HostSource, Protocol, Rule},
+        testing::MemoryRepository,
+        IndexMap, IndexSet,
+    };
+
+    #[test]
+    fn detect_only() {
+        let mut pipeline = Pipeline::new(
+            Handler::builder(MemoryRepository::new(), Noop)
+                .clock(|| datetime!(2023-10-01 12:00 UTC))
+                .build(),
+        );
+
+        pipeline
+            .add_rule(
+                "app",
+                Rule {
+                    file: "app".into(),
+                    filters: vec![Filter {
+                        pattern: r"^\[<TIME>\] <HOST> (?P<event>.+)$".to_owned(),
+                        prefilter: None,
+                    }],
+                    plugins: Vec::new(),
+                    ports: Vec::new(),
+                    protocol: Protocol::Tcp,
+                    timeout: Duration::hours(1),
+                    blacklists: IndexMap::from_iter([(
+                        "event".to_owned(),
+                        IndexSet::from_iter(["failed login".to_owned()]),
+                    )]),
+                    host: HostSource::Capture,
+                    time_format: None,
+                    stall_timeout: None,
+                    max_bans_per_minute: None,
+                    reputation_timeout: None,
+                    tor_timeout: None,
+                    crawler_group: None,
+                },
+            )
+            .unwrap();
+
+        let events = pipeline.handler_mut().events.subscribe();
+        let ban = pipeline
+            .push_line("app", "[01/Oct/2023:11:59:00 +0000] 10.0.0.1 failed login")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            Ok(BlockEvent::Ban(BanEvent {
+                rule: "app".to_owned(),
+                ip: ban.ip,
+                until: ban.until,
+            })),
+            events.try_recv()
+        );
+    }
+}
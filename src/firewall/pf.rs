@@ -0,0 +1,227 @@
+use std::{
+    io::Write as _,
+    path::PathBuf,
+    process::{Command, Stdio},
+    slice,
+};
+
+use ipnetwork::IpNetwork;
+use itertools::Itertools;
+use log::warn;
+
+use super::{check, command_line, find_binary, run, Action, Firewall, Target};
+use crate::{settings::Pf as Settings, Error, Result};
+
+/// Firewall for FreeBSD and OpenBSD, that puts blocked IPs into a pf table.
+///
+/// The table and the rule that blocks it live in their own anchor, so the main ruleset is never
+/// touched. It only has to reference the anchor once with `anchor "veto"`. Unlike ipset, a single
+/// table holds both IPv4 and IPv6 addresses.
+pub struct Pf {
+    name: &'static str,
+    pfctl_path: PathBuf,
+    settings: Settings,
+}
+
+impl Pf {
+    pub fn new(settings: Settings) -> Result<Self> {
+        if cfg!(not(any(target_os = "freebsd", target_os = "openbsd"))) {
+            warn!("The pf firewall is only supported on FreeBSD and OpenBSD systems");
+        }
+
+        Ok(Self {
+            name: env!("CARGO_PKG_NAME"),
+            pfctl_path: find_binary("pfctl", "/sbin/pfctl")?,
+            settings,
+        })
+    }
+
+    /// Rules that are loaded into the anchor.
+    fn rules(&self) -> String {
+        let ports = match self.settings.ports.as_slice() {
+            [] => String::new(),
+            [port] => format!(" port {port}"),
+            ports => format!(" port {{ {} }}", ports.iter().join(" ")),
+        };
+        let proto = if ports.is_empty() { "" } else { " proto tcp" };
+
+        format!(
+            "table <{name}> persist\nblock drop in quick{proto} from <{name}> to any{ports}\n",
+            name = self.name
+        )
+    }
+
+    /// Command that runs a table operation like `add`, for the IPs of all targets.
+    fn table_cmd(&self, operation: &str, targets: &[Target<'_>]) -> Command {
+        let mut cmd = Command::new(&self.pfctl_path);
+        cmd.args(["-a", self.name, "-t", self.name, "-T", operation])
+            .args(targets.iter().map(Target::address));
+        cmd
+    }
+
+    /// All networks in the table.
+    fn members(&self) -> Result<Vec<IpNetwork>> {
+        let output = run(&mut self.table_cmd("show", &[]))?;
+        check(&output, "listing pf table entries")?;
+
+        Ok(parse_table(&String::from_utf8_lossy(&output.stdout)))
+    }
+}
+
+impl Firewall for Pf {
+    fn install(&self) -> Result<()> {
+        let mut child = Command::new(&self.pfctl_path)
+            .args(["-a", self.name, "-f", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|source| Error::Spawn {
+                program: self.pfctl_path.display().to_string(),
+                source,
+            })?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(self.rules().as_bytes())?;
+        }
+
+        let output = child.wait_with_output()?;
+        check(&output, "loading pf anchor rules")
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        let output = run(Command::new(&self.pfctl_path).args(["-a", self.name, "-F", "rules"]))?;
+        check(&output, "flushing pf anchor rules")?;
+
+        let output = run(&mut self.table_cmd("kill", &[]))?;
+        check(&output, "deleting pf table")
+    }
+
+    fn block(&self, target: &Target<'_>) -> Result<()> {
+        self.block_all(slice::from_ref(target))
+    }
+
+    fn unblock(&self, target: &Target<'_>) -> Result<()> {
+        self.unblock_all(slice::from_ref(target))
+    }
+
+    /// Add all IPs with a single command. IPs that are already in the table are skipped by pfctl.
+    fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        check(
+            &run(&mut self.table_cmd("add", targets))?,
+            "adding IPs to pf table",
+        )
+    }
+
+    /// Delete all IPs with a single command. IPs that aren't in the table are skipped by pfctl.
+    fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        check(
+            &run(&mut self.table_cmd("delete", targets))?,
+            "deleting IPs from pf table",
+        )
+    }
+
+    fn is_installed(&self) -> Result<Option<bool>> {
+        let output = run(Command::new(&self.pfctl_path).args(["-a", self.name, "-s", "rules"]))?;
+        check(&output, "listing pf anchor rules")?;
+
+        let table = format!("<{}>", self.name);
+
+        Ok(Some(String::from_utf8_lossy(&output.stdout).lines().any(
+            |l| l.starts_with("block drop in quick") && l.contains(&table),
+        )))
+    }
+
+    fn is_blocked(&self, target: &Target<'_>) -> Result<Option<bool>> {
+        Ok(Some(self.members()?.contains(&target.ip)))
+    }
+
+    fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+        self.members().map(Some)
+    }
+
+    fn describe(&self, action: Action, targets: &[Target<'_>]) -> Option<Vec<String>> {
+        if targets.is_empty() {
+            return Some(Vec::new());
+        }
+
+        let operation = match action {
+            Action::Block => "add",
+            Action::Unblock => "delete",
+        };
+
+        Some(vec![command_line(&self.table_cmd(operation, targets))])
+    }
+}
+
+/// Get the networks of a table listing, which has one indented entry per line. Single hosts are
+/// listed without a prefix.
+fn parse_table(output: &str) -> Vec<IpNetwork> {
+    output
+        .lines()
+        .filter_map(|l| l.trim().parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Protocol;
+
+    #[test]
+    fn rules_and_table() {
+        let pf = |ports: &[u16]| Pf {
+            name: "veto",
+            pfctl_path: PathBuf::from("/sbin/pfctl"),
+            settings: Settings {
+                ports: ports.to_owned(),
+            },
+        };
+
+        assert_eq!(
+            "table <veto> persist\nblock drop in quick proto tcp from <veto> to any port { 80 443 }\n",
+            pf(&[80, 443]).rules()
+        );
+        assert_eq!(
+            "table <veto> persist\nblock drop in quick from <veto> to any\n",
+            pf(&[]).rules()
+        );
+
+        let targets = [
+            Target {
+                ip: "10.0.0.1".parse().unwrap(),
+                ports: &[],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            },
+            Target {
+                ip: "2001:db8::1".parse().unwrap(),
+                ports: &[],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            },
+        ];
+        assert_eq!(
+            Some(vec![
+                "/sbin/pfctl -a veto -t veto -T add 10.0.0.1 2001:db8::1".to_owned()
+            ]),
+            pf(&[22]).describe(Action::Block, &targets)
+        );
+
+        assert_eq!(
+            vec![
+                "10.0.0.1".parse::<IpNetwork>().unwrap(),
+                "2001:db8::1".parse().unwrap()
+            ],
+            parse_table("   10.0.0.1\n   2001:db8::1\n")
+        );
+    }
+}
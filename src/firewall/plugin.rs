@@ -0,0 +1,263 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    process::{Child, ChildStdout, Command, Stdio},
+};
+
+use log::warn;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::{Action, Firewall, Target};
+use crate::{settings::Plugin as Settings, Error, Result};
+
+/// Firewall that delegates all work to a long-running external process, so integrations can be
+/// written in any language.
+///
+/// Requests are written to the plugin's standard input as one JSON object per line, with the
+/// operation in the `op` field:
+///
+/// ```json
+/// {"op":"install"}
+/// {"op":"uninstall"}
+/// {"op":"block","targets":[{"ip":"10.0.0.1","ports":[80,443],"protocol":"tcp"}]}
+/// {"op":"unblock","targets":[{"ip":"10.0.0.1","ports":[80,443],"protocol":"tcp"}]}
+/// ```
+///
+/// The IP is either a single address, or a whole network in CIDR notation like `10.1.0.0/16`. An
+/// empty port list means all ports, and the protocol is one of `tcp`, `udp` or `all`. The plugin
+/// must answer each request with a single line on its standard output, either `{"ok":true}` or
+/// `{"ok":false,"error":"<message>"}`. Standard error is passed through, for the plugin's own
+/// logging. Once veto shuts down, the plugin's standard input is closed and it is expected to exit.
+pub struct Plugin {
+    program: String,
+    process: Mutex<Process>,
+}
+
+struct Process {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request<'a> {
+    Install,
+    Uninstall,
+    Block { targets: &'a [Target<'a>] },
+    Unblock { targets: &'a [Target<'a>] },
+}
+
+#[derive(Deserialize)]
+struct Response {
+    ok: bool,
+    #[serde(default)]
+    error: String,
+}
+
+impl Plugin {
+    /// Start the plugin process, which keeps running until this firewall is dropped.
+    pub fn new(settings: &Settings) -> Result<Self> {
+        let program = settings.command.display().to_string();
+
+        let mut child = Command::new(&settings.command)
+            .args(&settings.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|source| Error::Spawn {
+                program: program.clone(),
+                source,
+            })?;
+
+        let stdout = child
+            .stdout
+            .take()
+            .map(BufReader::new)
+            .ok_or_else(|| io::Error::other("plugin has no standard output"))?;
+
+        Ok(Self {
+            program,
+            process: Mutex::new(Process { child, stdout }),
+        })
+    }
+
+    /// Send a single request and wait for the plugin's response.
+    fn request(&self, request: &Request<'_>, action: &'static str) -> Result<()> {
+        let mut line = serde_json::to_string(request).map_err(io::Error::from)?;
+        line.push('\n');
+
+        self.exchange(&mut line)?;
+
+        let response = serde_json::from_str::<Response>(&line).map_err(io::Error::from)?;
+        if response.ok {
+            return Ok(());
+        }
+
+        Err(Error::Command {
+            action,
+            stderr: response.error,
+        })
+    }
+
+    /// Write the request line to the plugin and replace it with the response line.
+    fn exchange(&self, line: &mut String) -> io::Result<()> {
+        let mut process = self.process.lock();
+
+        let stdin = process
+            .child
+            .stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::other("plugin has no standard input"))?;
+        stdin.write_all(line.as_bytes())?;
+        stdin.flush()?;
+
+        line.clear();
+        let read = process.stdout.read_line(line)?;
+        drop(process);
+
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("plugin {} closed its output", self.program),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        let process = self.process.get_mut();
+
+        // Closing the input signals the plugin to shut down.
+        drop(process.child.stdin.take());
+
+        if let Err(e) = process.child.wait() {
+            warn!("failed waiting for plugin {}: {:?}", self.program, e);
+        }
+    }
+}
+
+impl Firewall for Plugin {
+    fn install(&self) -> Result<()> {
+        self.request(&Request::Install, "installing plugin firewall")
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        self.request(&Request::Uninstall, "uninstalling plugin firewall")
+    }
+
+    fn block(&self, target: &Target<'_>) -> Result<()> {
+        self.block_all(std::slice::from_ref(target))
+    }
+
+    fn unblock(&self, target: &Target<'_>) -> Result<()> {
+        self.unblock_all(std::slice::from_ref(target))
+    }
+
+    fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        self.request(&Request::Block { targets }, "blocking IPs with plugin")
+    }
+
+    fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        self.request(&Request::Unblock { targets }, "unblocking IPs with plugin")
+    }
+
+    fn describe(&self, action: Action, targets: &[Target<'_>]) -> Option<Vec<String>> {
+        if targets.is_empty() {
+            return Some(Vec::new());
+        }
+
+        let request = match action {
+            Action::Block => Request::Block { targets },
+            Action::Unblock => Request::Unblock { targets },
+        };
+
+        serde_json::to_string(&request)
+            .ok()
+            .map(|request| vec![format!("send to {}: {request}", self.program)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Protocol;
+
+    /// Answers every request successfully, except for unblocking.
+    const SCRIPT: &str = r#"
+        while read -r line; do
+            case "$line" in
+                *'"op":"unblock"'*) echo '{"ok":false,"error":"not supported"}' ;;
+                *) echo '{"ok":true}' ;;
+            esac
+        done
+    "#;
+
+    #[test]
+    fn protocol() {
+        let plugin = Plugin::new(&Settings {
+            command: "sh".into(),
+            args: vec!["-c".to_owned(), SCRIPT.to_owned()],
+        })
+        .unwrap();
+
+        let target = Target {
+            ip: "10.0.0.1".parse().unwrap(),
+            ports: &[80],
+            protocol: Protocol::Tcp,
+            timeout: None,
+        };
+
+        plugin.install().unwrap();
+        plugin.block(&target).unwrap();
+
+        let err = plugin.unblock(&target).unwrap_err();
+        assert!(matches!(err, Error::Command { stderr, .. } if stderr == "not supported"));
+
+        assert_eq!(
+            Some(vec![
+                r#"send to sh: {"op":"block","targets":[{"ip":"10.0.0.1","ports":[80],"protocol":"tcp"}]}"#
+                    .to_owned()
+            ]),
+            plugin.describe(Action::Block, &[target])
+        );
+    }
+
+    #[test]
+    fn request_format() {
+        let targets = [
+            Target {
+                ip: "10.0.0.1".parse().unwrap(),
+                ports: &[80, 443],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            },
+            Target {
+                ip: "10.1.0.0/16".parse().unwrap(),
+                ports: &[],
+                protocol: Protocol::All,
+                timeout: None,
+            },
+        ];
+
+        assert_eq!(
+            r#"{"op":"block","targets":[{"ip":"10.0.0.1","ports":[80,443],"protocol":"tcp"},{"ip":"10.1.0.0/16","ports":[],"protocol":"all"}]}"#,
+            serde_json::to_string(&Request::Block { targets: &targets }).unwrap()
+        );
+        assert_eq!(
+            r#"{"op":"install"}"#,
+            serde_json::to_string(&Request::Install).unwrap()
+        );
+    }
+}
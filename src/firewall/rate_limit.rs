@@ -0,0 +1,105 @@
+use std::{
+    num::NonZeroU32,
+    thread,
+    time::{Duration, Instant},
+};
+
+use ipnetwork::IpNetwork;
+use log::debug;
+use parking_lot::Mutex;
+
+use super::{Action, Firewall, Target};
+use crate::Result;
+
+/// Wrapper around another [`Firewall`] that limits the amount of commands per second, so a flood
+/// of matches can't spawn an unbounded amount of firewall processes.
+///
+/// Commands exceeding the limit are queued by blocking the caller until the command can be run.
+pub struct RateLimited<F> {
+    inner: F,
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl<F: Firewall> RateLimited<F> {
+    /// Wrap the firewall with the given limit of commands per second. No limit is applied if it's
+    /// [`None`].
+    pub fn new(inner: F, limit: Option<NonZeroU32>) -> Self {
+        Self {
+            inner,
+            interval: limit.map_or(Duration::ZERO, |limit| Duration::from_secs(1) / limit.get()),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until the next command is allowed to run. The slot is reserved while holding the lock,
+    /// but the waiting happens after releasing it, so other callers can reserve their slots
+    /// meanwhile.
+    fn acquire(&self) {
+        if self.interval.is_zero() {
+            return;
+        }
+
+        let start = {
+            let mut next = self.next.lock();
+            let start = (*next).max(Instant::now());
+            *next = start + self.interval;
+            start
+        };
+
+        let delay = start.saturating_duration_since(Instant::now());
+        if !delay.is_zero() {
+            debug!(
+                "rate limit reached, delaying firewall command by {:?}",
+                delay
+            );
+            thread::sleep(delay);
+        }
+    }
+}
+
+impl<F: Firewall> Firewall for RateLimited<F> {
+    fn install(&self) -> Result<()> {
+        self.inner.install()
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        self.inner.uninstall()
+    }
+
+    fn block(&self, target: &Target<'_>) -> Result<()> {
+        self.acquire();
+        self.inner.block(target)
+    }
+
+    fn unblock(&self, target: &Target<'_>) -> Result<()> {
+        self.acquire();
+        self.inner.unblock(target)
+    }
+
+    fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.acquire();
+        self.inner.block_all(targets)
+    }
+
+    fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.acquire();
+        self.inner.unblock_all(targets)
+    }
+
+    fn is_installed(&self) -> Result<Option<bool>> {
+        self.inner.is_installed()
+    }
+
+    fn is_blocked(&self, target: &Target<'_>) -> Result<Option<bool>> {
+        self.inner.is_blocked(target)
+    }
+
+    fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+        self.inner.list_blocked()
+    }
+
+    fn describe(&self, action: Action, targets: &[Target<'_>]) -> Option<Vec<String>> {
+        self.inner.describe(action, targets)
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::HashSet;
+
+use ipnetwork::IpNetwork;
+
+use super::{Firewall, Target};
+use crate::{settings::Protocol, Result};
+
+/// Outcome of [`reconcile`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Reconciliation {
+    /// Networks that were blocked on the firewall without being expected, which were unblocked.
+    pub removed: Vec<IpNetwork>,
+    /// Amount of expected targets that weren't blocked yet, and were blocked again.
+    pub restored: usize,
+    /// Networks of expected targets that still aren't blocked after blocking them again.
+    pub failed: Vec<IpNetwork>,
+}
+
+/// Bring the firewall in line with the targets that are expected to be blocked, like the active
+/// storage entries on startup.
+///
+/// Blocks that are left over from a crash or were added by hand are removed, and missing targets
+/// are blocked again. Afterwards, the firewall is checked once more for targets that failed to be
+/// blocked. Leftovers are unblocked without any ports, as only their IPs are known.
+///
+/// If the firewall can't list its blocked IPs, all targets are blocked without any checks, which
+/// makes them neither removed nor failed.
+pub fn reconcile<F>(firewall: &F, targets: &[Target<'_>]) -> Result<Reconciliation>
+where
+    F: Firewall + ?Sized,
+{
+    let Some(listed) = firewall.list_blocked()? else {
+        firewall.block_all(targets)?;
+        return Ok(Reconciliation {
+            restored: targets.len(),
+            ..Reconciliation::default()
+        });
+    };
+
+    let listed = listed.into_iter().collect::<HashSet<_>>();
+    let expected = targets.iter().map(|t| t.ip).collect::<HashSet<_>>();
+
+    let mut removed = listed.difference(&expected).copied().collect::<Vec<_>>();
+    removed.sort_unstable();
+    if !removed.is_empty() {
+        firewall.unblock_all(
+            &removed
+                .iter()
+                .map(|&ip| Target {
+                    ip,
+                    ports: &[],
+                    protocol: Protocol::Tcp,
+                    timeout: None,
+                })
+                .collect::<Vec<_>>(),
+        )?;
+    }
+
+    let missing = targets
+        .iter()
+        .filter(|t| !listed.contains(&t.ip))
+        .map(|t| Target {
+            ip: t.ip,
+            ports: t.ports,
+            protocol: t.protocol,
+            timeout: t.timeout,
+        })
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return Ok(Reconciliation {
+            removed,
+            ..Reconciliation::default()
+        });
+    }
+
+    firewall.block_all(&missing)?;
+
+    let listed = firewall
+        .list_blocked()?
+        .unwrap_or_default()
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let failed = missing
+        .iter()
+        .map(|t| t.ip)
+        .filter(|ip| !listed.contains(ip))
+        .collect();
+
+    Ok(Reconciliation {
+        removed,
+        restored: missing.len(),
+        failed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FirewallCall, MockFirewall};
+
+    #[test]
+    fn both_directions() {
+        let ip = |s: &str| s.parse::<IpNetwork>().unwrap();
+        let firewall = MockFirewall::new();
+        firewall
+            .block_all(&[
+                Target {
+                    ip: ip("10.0.0.1"),
+                    ports: &[],
+                    protocol: Protocol::Tcp,
+                    timeout: None,
+                },
+                Target {
+                    ip: ip("10.0.0.9"),
+                    ports: &[],
+                    protocol: Protocol::Tcp,
+                    timeout: None,
+                },
+            ])
+            .unwrap();
+
+        let targets = [
+            Target {
+                ip: ip("10.0.0.1"),
+                ports: &[22],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            },
+            Target {
+                ip: ip("10.0.0.2"),
+                ports: &[22],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            },
+        ];
+
+        assert_eq!(
+            Reconciliation {
+                removed: vec![ip("10.0.0.9")],
+                restored: 1,
+                failed: Vec::new(),
+            },
+            reconcile(&firewall, &targets).unwrap()
+        );
+        assert_eq!(
+            vec![
+                FirewallCall::Unblock {
+                    ip: ip("10.0.0.9"),
+                    ports: Vec::new()
+                },
+                FirewallCall::Block {
+                    ip: ip("10.0.0.2"),
+                    ports: vec![22]
+                },
+            ],
+            firewall.calls()[2..]
+        );
+
+        // Nothing to do once both sides agree.
+        assert_eq!(
+            Reconciliation::default(),
+            reconcile(&firewall, &targets).unwrap()
+        );
+        assert_eq!(4, firewall.calls().len());
+    }
+
+    #[test]
+    fn failed_blocks() {
+        struct Broken(MockFirewall);
+
+        impl Firewall for Broken {
+            fn install(&self) -> Result<()> {
+                self.0.install()
+            }
+
+            fn uninstall(&self) -> Result<()> {
+                self.0.uninstall()
+            }
+
+            fn block(&self, _target: &Target<'_>) -> Result<()> {
+                Ok(())
+            }
+
+            fn unblock(&self, target: &Target<'_>) -> Result<()> {
+                self.0.unblock(target)
+            }
+
+            fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+                self.0.list_blocked()
+            }
+        }
+
+        let ip = "10.0.0.1".parse().unwrap();
+        let outcome = reconcile(
+            &Broken(MockFirewall::new()),
+            &[Target {
+                ip,
+                ports: &[],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            }],
+        );
+
+        assert_eq!(vec![ip], outcome.unwrap().failed);
+    }
+}
@@ -0,0 +1,271 @@
+use std::{
+    fmt::Write as _,
+    io::Write as _,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use ipnetwork::IpNetwork;
+use log::warn;
+
+use super::{check, find_binary, ipset::parse_members, run, Action, Firewall, Target};
+use crate::{settings::Tc as Settings, Error, Result};
+
+/// Sets of the throttled IPs, one per address family like the ipset firewall uses.
+const SETS: [(&str, &str, &str); 2] = [
+    (concat!(env!("CARGO_PKG_NAME"), "_tc"), "inet", "ip"),
+    (concat!(env!("CARGO_PKG_NAME"), "_tc_v6"), "inet6", "ipv6"),
+];
+
+/// Class that all traffic to throttled IPs goes into.
+const CLASS: &str = "1:1";
+
+/// Firewall that throttles offending IPs through a slow traffic control class, instead of blocking
+/// them.
+///
+/// This gives borderline abusive clients a slow lane, while they can still reach the server. The
+/// IPs are kept in ipsets, which the `ipset` ematch of tc filters matches against. The root qdisc
+/// of the interface is replaced with an HTB qdisc, that sends all other traffic through without
+/// shaping. Only outgoing traffic can be shaped, so answers to the clients are slowed down, which
+/// slows down their connections as a whole. Ports and protocols don't apply.
+#[allow(clippy::struct_field_names)]
+pub struct Tc {
+    tc_path: PathBuf,
+    ipset_path: PathBuf,
+    settings: Settings,
+}
+
+impl Tc {
+    pub fn new(settings: Settings) -> Result<Self> {
+        if cfg!(not(target_os = "linux")) {
+            warn!("The tc firewall is only supported on Linux systems");
+        }
+
+        Ok(Self {
+            tc_path: find_binary("tc", "/usr/sbin/tc")?,
+            ipset_path: find_binary("ipset", "/usr/sbin/ipset")?,
+            settings,
+        })
+    }
+
+    /// Arguments of all tc commands that set up the qdisc, class and filters.
+    fn install_args(&self) -> Vec<Vec<String>> {
+        let dev = self.settings.interface.as_str();
+        let rate = self.settings.rate.as_str();
+        let args = |args: &[&str]| args.iter().map(ToString::to_string).collect::<Vec<_>>();
+
+        let mut commands = vec![
+            // Without a default class, unclassified traffic is sent without any shaping.
+            args(&[
+                "qdisc", "replace", "dev", dev, "root", "handle", "1:", "htb",
+            ]),
+            args(&[
+                "class", "replace", "dev", dev, "parent", "1:", "classid", CLASS, "htb", "rate",
+                rate, "ceil", rate,
+            ]),
+        ];
+
+        for (prio, (set, _, protocol)) in ["1", "2"].into_iter().zip(SETS) {
+            commands.push(args(&[
+                "filter",
+                "replace",
+                "dev",
+                dev,
+                "parent",
+                "1:",
+                "protocol",
+                protocol,
+                "prio",
+                prio,
+                "handle",
+                "1",
+                "basic",
+                "match",
+                &format!("ipset({set} dst)"),
+                "classid",
+                CLASS,
+            ]));
+        }
+
+        commands
+    }
+
+    /// Input for `ipset restore`, with one command per target into the set of its family.
+    fn restore_input(command: &str, targets: &[Target<'_>]) -> String {
+        let mut input = String::new();
+
+        for target in targets {
+            let set = match target.ip {
+                IpNetwork::V4(_) => SETS[0].0,
+                IpNetwork::V6(_) => SETS[1].0,
+            };
+            _ = writeln!(input, "{command} {set} {}", target.address());
+        }
+
+        input
+    }
+
+    /// Run all set changes in a single process. With `-exist`, adding IPs that are already
+    /// throttled and deleting ones that aren't, is fine.
+    fn restore(&self, command: &str, targets: &[Target<'_>]) -> Result<()> {
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        let mut child = Command::new(&self.ipset_path)
+            .args(["restore", "-exist"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|source| Error::Spawn {
+                program: self.ipset_path.display().to_string(),
+                source,
+            })?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(Self::restore_input(command, targets).as_bytes())?;
+        }
+
+        check(&child.wait_with_output()?, "changing throttled IPs")
+    }
+}
+
+impl Firewall for Tc {
+    fn install(&self) -> Result<()> {
+        for (set, family, _) in SETS {
+            let output = run(Command::new(&self.ipset_path)
+                .args(["create", set, "hash:net", "family", family, "-exist"]))?;
+            check(&output, "creating ipset table")?;
+        }
+
+        for args in self.install_args() {
+            let output = run(Command::new(&self.tc_path).args(args))?;
+            check(&output, "setting up traffic control")?;
+        }
+
+        Ok(())
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        // Removing the root qdisc removes the class and filters as well, and restores the default
+        // qdisc of the interface.
+        let output = run(Command::new(&self.tc_path).args([
+            "qdisc",
+            "del",
+            "dev",
+            &self.settings.interface,
+            "root",
+        ]))?;
+        check(&output, "removing traffic control")?;
+
+        for (set, _, _) in SETS {
+            let output = run(Command::new(&self.ipset_path).args(["destroy", set]))?;
+            check(&output, "deleting ipset table")?;
+        }
+
+        Ok(())
+    }
+
+    fn block(&self, target: &Target<'_>) -> Result<()> {
+        self.block_all(std::slice::from_ref(target))
+    }
+
+    fn unblock(&self, target: &Target<'_>) -> Result<()> {
+        self.unblock_all(std::slice::from_ref(target))
+    }
+
+    fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.restore("add", targets)
+    }
+
+    fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.restore("del", targets)
+    }
+
+    fn is_blocked(&self, target: &Target<'_>) -> Result<Option<bool>> {
+        Ok(self.list_blocked()?.map(|ips| ips.contains(&target.ip)))
+    }
+
+    fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+        let mut ips = Vec::new();
+
+        for (set, _, _) in SETS {
+            let output = run(Command::new(&self.ipset_path).args(["list", set]))?;
+            check(&output, "listing ipset entries")?;
+
+            ips.extend(parse_members(&String::from_utf8_lossy(&output.stdout)));
+        }
+
+        Ok(Some(ips))
+    }
+
+    fn describe(&self, action: Action, targets: &[Target<'_>]) -> Option<Vec<String>> {
+        if targets.is_empty() {
+            return Some(Vec::new());
+        }
+
+        let command = match action {
+            Action::Block => "add",
+            Action::Unblock => "del",
+        };
+
+        Some(vec![format!(
+            "{} restore -exist <<EOF\n{}EOF",
+            self.ipset_path.display(),
+            Self::restore_input(command, targets)
+        )])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Protocol;
+
+    #[test]
+    fn commands() {
+        let tc = Tc {
+            tc_path: PathBuf::from("/usr/sbin/tc"),
+            ipset_path: PathBuf::from("/usr/sbin/ipset"),
+            settings: Settings {
+                interface: "eth0".to_owned(),
+                rate: "64kbit".to_owned(),
+            },
+        };
+
+        assert_eq!(
+            vec![
+                "qdisc replace dev eth0 root handle 1: htb",
+                "class replace dev eth0 parent 1: classid 1:1 htb rate 64kbit ceil 64kbit",
+                "filter replace dev eth0 parent 1: protocol ip prio 1 handle 1 basic match \
+                 ipset(veto_tc dst) classid 1:1",
+                "filter replace dev eth0 parent 1: protocol ipv6 prio 2 handle 1 basic match \
+                 ipset(veto_tc_v6 dst) classid 1:1",
+            ],
+            tc.install_args()
+                .into_iter()
+                .map(|args| args.join(" "))
+                .collect::<Vec<_>>()
+        );
+
+        let targets = [
+            Target {
+                ip: "10.0.0.1".parse().unwrap(),
+                ports: &[80],
+                protocol: Protocol::Tcp,
+                timeout: None,
+            },
+            Target {
+                ip: "2001:db8::/32".parse().unwrap(),
+                ports: &[],
+                protocol: Protocol::All,
+                timeout: None,
+            },
+        ];
+        assert_eq!(
+            "add veto_tc 10.0.0.1\nadd veto_tc_v6 2001:db8::/32\n",
+            Tc::restore_input("add", &targets)
+        );
+    }
+}
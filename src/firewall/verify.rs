@@ -0,0 +1,204 @@
+use std::{
+    fmt::{self, Display},
+    net::{IpAddr, Ipv4Addr},
+};
+
+use super::{Firewall, Target};
+use crate::{settings::Protocol, Result};
+
+/// Address that is blocked during verification. It's reserved for documentation (TEST-NET-1 of
+/// RFC 5737), so blocking it never affects real traffic.
+pub const TEST_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+/// Single step of the firewall verification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    Install,
+    CheckInstalled,
+    Block,
+    CheckBlocked,
+    Unblock,
+    CheckUnblocked,
+    Uninstall,
+    CheckUninstalled,
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Install => f.write_str("install firewall rules"),
+            Self::CheckInstalled => f.write_str("check that the rules are installed"),
+            Self::Block => write!(f, "block {TEST_IP}"),
+            Self::CheckBlocked => write!(f, "check that {TEST_IP} is blocked"),
+            Self::Unblock => write!(f, "unblock {TEST_IP}"),
+            Self::CheckUnblocked => write!(f, "check that {TEST_IP} is unblocked"),
+            Self::Uninstall => f.write_str("uninstall firewall rules"),
+            Self::CheckUninstalled => f.write_str("check that the rules are removed"),
+        }
+    }
+}
+
+/// Result of a single verification step.
+#[derive(Debug)]
+pub enum Outcome {
+    Passed,
+    /// The firewall can't list its state, so the check couldn't be done.
+    Unverified,
+    /// The step was skipped, because an earlier one failed.
+    Skipped,
+    Failed(String),
+}
+
+impl Outcome {
+    #[must_use]
+    pub const fn is_failed(&self) -> bool {
+        matches!(self, Self::Failed(_))
+    }
+}
+
+/// Run through installing the firewall, blocking and unblocking the [`TEST_IP`], and uninstalling
+/// it again, checking the firewall's state after each change.
+///
+/// Each step is passed to the report function together with its outcome. After a failure, the
+/// remaining checks are skipped, but unblocking and uninstalling are still done to clean up.
+/// Returns whether all steps succeeded.
+pub fn verify<F>(firewall: &F, mut report: impl FnMut(Step, &Outcome)) -> bool
+where
+    F: Firewall + ?Sized,
+{
+    let target = Target {
+        ip: TEST_IP.into(),
+        ports: &[],
+        protocol: Protocol::Tcp,
+        timeout: None,
+    };
+    let mut failed = false;
+
+    let mut run = |step: Step, cleanup: bool, action: &dyn Fn() -> Result<Option<bool>>| {
+        let outcome = if failed && !cleanup {
+            Outcome::Skipped
+        } else {
+            match action() {
+                Ok(Some(true)) => Outcome::Passed,
+                Ok(Some(false)) => Outcome::Failed("the firewall state doesn't match".to_owned()),
+                Ok(None) => Outcome::Unverified,
+                Err(e) => Outcome::Failed(e.to_string()),
+            }
+        };
+
+        failed |= outcome.is_failed();
+        report(step, &outcome);
+    };
+    let done = |result: Result<()>| result.map(|()| Some(true));
+
+    run(Step::Install, false, &|| done(firewall.install()));
+    run(Step::CheckInstalled, false, &|| firewall.is_installed());
+    run(Step::Block, false, &|| done(firewall.block(&target)));
+    run(Step::CheckBlocked, false, &|| firewall.is_blocked(&target));
+    run(Step::Unblock, true, &|| done(firewall.unblock(&target)));
+    run(Step::CheckUnblocked, false, &|| {
+        firewall.is_blocked(&target).map(|b| b.map(|b| !b))
+    });
+    run(Step::Uninstall, true, &|| done(firewall.uninstall()));
+    run(Step::CheckUninstalled, false, &|| {
+        firewall.is_installed().map(|i| i.map(|i| !i))
+    });
+
+    !failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FirewallCall, MockFirewall};
+
+    fn outcomes(firewall: &impl Firewall) -> (bool, Vec<(Step, String)>) {
+        let mut steps = Vec::new();
+        let passed = verify(firewall, |step, outcome| {
+            steps.push((step, format!("{outcome:?}")));
+        });
+
+        (passed, steps)
+    }
+
+    #[test]
+    fn all_passed() {
+        let firewall = MockFirewall::new();
+        let (passed, steps) = outcomes(&firewall);
+
+        assert!(passed);
+        assert!(steps.iter().all(|(_, outcome)| outcome == "Passed"));
+        assert_eq!(
+            vec![
+                FirewallCall::Install,
+                FirewallCall::Block {
+                    ip: TEST_IP.into(),
+                    ports: Vec::new()
+                },
+                FirewallCall::Unblock {
+                    ip: TEST_IP.into(),
+                    ports: Vec::new()
+                },
+                FirewallCall::Uninstall,
+            ],
+            firewall.calls()
+        );
+    }
+
+    #[test]
+    fn block_not_applied() {
+        /// Firewall that accepts blocks, but never actually applies them.
+        struct Broken(MockFirewall);
+
+        impl Firewall for Broken {
+            fn install(&self) -> Result<()> {
+                self.0.install()
+            }
+
+            fn uninstall(&self) -> Result<()> {
+                self.0.uninstall()
+            }
+
+            fn block(&self, _target: &Target<'_>) -> Result<()> {
+                Ok(())
+            }
+
+            fn unblock(&self, target: &Target<'_>) -> Result<()> {
+                self.0.unblock(target)
+            }
+
+            fn is_installed(&self) -> Result<Option<bool>> {
+                self.0.is_installed()
+            }
+
+            fn is_blocked(&self, target: &Target<'_>) -> Result<Option<bool>> {
+                self.0.is_blocked(target)
+            }
+        }
+
+        let firewall = Broken(MockFirewall::new());
+        let (passed, steps) = outcomes(&firewall);
+
+        assert!(!passed);
+        assert_eq!(
+            vec![
+                (Step::Install, "Passed"),
+                (Step::CheckInstalled, "Passed"),
+                (Step::Block, "Passed"),
+                (
+                    Step::CheckBlocked,
+                    "Failed(\"the firewall state doesn't match\")"
+                ),
+                (Step::Unblock, "Passed"),
+                (Step::CheckUnblocked, "Skipped"),
+                (Step::Uninstall, "Passed"),
+                (Step::CheckUninstalled, "Skipped"),
+            ],
+            steps
+                .iter()
+                .map(|(step, outcome)| (*step, outcome.as_str()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(FirewallCall::Uninstall), firewall.0.calls().pop());
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    mem,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use flume::{Receiver, RecvTimeoutError, Sender};
+use ipnetwork::IpNetwork;
+use log::{debug, error, info, warn};
+
+use super::{Firewall, OwnedTarget, Target};
+use crate::{Error, Result};
+
+/// Maximum amount of attempts for a failed command, before giving up on it.
+const MAX_ATTEMPTS: u32 = 5;
+/// Delay before the first retry of a failed command, which doubles with each further attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Delay between retests of the firewall, while it lacks the privileges to run commands.
+const PERMISSION_RETEST_DELAY: Duration = Duration::from_secs(30);
+/// Minimum time between repeated errors about missing privileges.
+const PERMISSION_REPORT_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Wrapper around another [`Firewall`] that runs all commands on a dedicated thread.
+///
+/// Blocking and unblocking only queues the targets and returns immediately, so a slow or hanging
+/// firewall command never stalls the caller. Failed commands are retried with an increasing delay.
+/// Installing, uninstalling and listing wait for all previously queued commands and report their
+/// result.
+///
+/// Commands that fail for missing privileges, like after a container restart dropped the
+/// `CAP_NET_ADMIN` capability, are never given up on. Instead, all commands are held back and the
+/// firewall is retested periodically, until the privileges are back.
+pub struct Worker {
+    tx: Option<Sender<Command>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+enum Command {
+    Install(Sender<Result<()>>),
+    Uninstall(Sender<Result<()>>),
+    List(Sender<Result<Option<Vec<IpNetwork>>>>),
+    Block(Vec<OwnedTarget>),
+    Unblock(Vec<OwnedTarget>),
+}
+
+/// Missing privileges of the firewall, during which commands are held back and only one of them
+/// is tried again periodically, instead of failing one after another.
+#[derive(Default)]
+struct Denied {
+    /// When the firewall is tested again with the next command, while it lacks privileges.
+    retest: Option<Instant>,
+    /// When the missing privileges were last reported as error.
+    reported: Option<Instant>,
+}
+
+impl Denied {
+    /// Time until which commands are held back, if privileges are missing and the next retest
+    /// isn't due yet.
+    fn hold_until(&self, now: Instant) -> Option<Instant> {
+        self.retest.filter(|&at| at > now)
+    }
+
+    fn deny(&mut self, action: &str, held: usize, e: &Error) {
+        let now = Instant::now();
+        self.retest = Some(now + PERMISSION_RETEST_DELAY);
+
+        if self
+            .reported
+            .is_some_and(|at| now < at + PERMISSION_REPORT_INTERVAL)
+        {
+            debug!("still missing privileges for {action}: {e:?}");
+            return;
+        }
+
+        self.reported = Some(now);
+        error!(
+            "failed {action} for missing privileges, veto must run as root or have the \
+             CAP_NET_ADMIN capability. IPs aren't blocked or unblocked until that's fixed, \
+             {held} commands are held back and retried every {PERMISSION_RETEST_DELAY:?}: {e:?}"
+        );
+    }
+
+    fn allow(&mut self, retries: &mut [Retry]) {
+        if self.retest.take().is_none() {
+            return;
+        }
+
+        self.reported = None;
+        info!(
+            "firewall privileges are back, resuming with {} held back commands",
+            retries.len()
+        );
+
+        let now = Instant::now();
+        for retry in retries {
+            retry.at = retry.at.min(now);
+        }
+    }
+}
+
+/// A block or unblock command, waiting for its next attempt.
+struct Retry {
+    block: bool,
+    targets: Vec<OwnedTarget>,
+    /// Amount of failed attempts so far.
+    attempt: u32,
+    at: Instant,
+}
+
+impl Worker {
+    /// Move the firewall onto a new worker thread.
+    pub fn new<F>(firewall: F) -> Result<Self>
+    where
+        F: Firewall + Send + 'static,
+    {
+        let (tx, rx) = flume::unbounded();
+        let handle = thread::Builder::new()
+            .name("firewall".to_owned())
+            .spawn(move || run(&firewall, &rx))?;
+
+        Ok(Self {
+            tx: Some(tx),
+            handle: Some(handle),
+        })
+    }
+
+    /// Amount of commands that are waiting to be run. Commands that wait for a retry aren't
+    /// included.
+    #[must_use]
+    pub fn queued(&self) -> usize {
+        self.tx.as_ref().map_or(0, Sender::len)
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        self.tx
+            .as_ref()
+            .ok_or(Error::WorkerStopped)?
+            .send(command)
+            .map_err(|_| Error::WorkerStopped)
+    }
+
+    fn request<T>(&self, command: impl FnOnce(Sender<Result<T>>) -> Command) -> Result<T> {
+        let (tx, rx) = flume::bounded(1);
+        self.send(command(tx))?;
+        rx.recv().map_err(|_| Error::WorkerStopped)?
+    }
+}
+
+impl Firewall for Worker {
+    fn install(&self) -> Result<()> {
+        self.request(Command::Install)
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        self.request(Command::Uninstall)
+    }
+
+    fn block(&self, target: &Target<'_>) -> Result<()> {
+        self.block_all(std::slice::from_ref(target))
+    }
+
+    fn unblock(&self, target: &Target<'_>) -> Result<()> {
+        self.unblock_all(std::slice::from_ref(target))
+    }
+
+    fn block_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.send(Command::Block(OwnedTarget::from_slice(targets)))
+    }
+
+    fn unblock_all(&self, targets: &[Target<'_>]) -> Result<()> {
+        self.send(Command::Unblock(OwnedTarget::from_slice(targets)))
+    }
+
+    fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+        self.request(Command::List)
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // Closing the channel lets the worker finish all queued commands and stop.
+        self.tx.take();
+
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+fn run(firewall: &impl Firewall, rx: &Receiver<Command>) {
+    let mut retries = Vec::<Retry>::new();
+    let mut denied = Denied::default();
+
+    loop {
+        let command = retries.iter().map(|r| r.at).min().map_or_else(
+            || rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            |at| rx.recv_deadline(at),
+        );
+
+        match command {
+            Ok(Command::Install(reply)) => {
+                reply.send(firewall.install()).ok();
+            }
+            Ok(Command::Uninstall(reply)) => {
+                reply.send(firewall.uninstall()).ok();
+            }
+            Ok(Command::List(reply)) => {
+                reply.send(firewall.list_blocked()).ok();
+            }
+            Ok(Command::Block(targets)) => {
+                let retry = Retry::new(true, targets);
+                execute(firewall, retry, &mut retries, &mut denied);
+            }
+            Ok(Command::Unblock(targets)) => {
+                let retry = Retry::new(false, targets);
+                execute(firewall, retry, &mut retries, &mut denied);
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let now = Instant::now();
+                let (due, pending) = mem::take(&mut retries)
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|r| r.at <= now);
+                retries = pending;
+
+                for retry in due {
+                    execute(firewall, retry, &mut retries, &mut denied);
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    if !retries.is_empty() {
+        warn!(
+            "dropping {} failed firewall commands on shutdown",
+            retries.len()
+        );
+    }
+}
+
+impl Retry {
+    /// First attempt of a new command.
+    fn new(block: bool, targets: Vec<OwnedTarget>) -> Self {
+        Self {
+            block,
+            targets,
+            attempt: 0,
+            at: Instant::now(),
+        }
+    }
+}
+
+fn execute(firewall: &impl Firewall, retry: Retry, retries: &mut Vec<Retry>, denied: &mut Denied) {
+    if let Some(at) = denied.hold_until(Instant::now()) {
+        retries.push(Retry { at, ..retry });
+        return;
+    }
+
+    let Retry {
+        block,
+        targets,
+        attempt,
+        ..
+    } = retry;
+    let attempt = attempt + 1;
+
+    let borrowed = targets.iter().map(OwnedTarget::borrow).collect::<Vec<_>>();
+
+    let result = if block {
+        firewall.block_all(&borrowed)
+    } else {
+        firewall.unblock_all(&borrowed)
+    };
+
+    let action = if block { "blocking" } else { "unblocking" };
+
+    match result {
+        Ok(()) => {
+            debug!("finished {} {} targets", action, targets.len());
+            denied.allow(retries);
+        }
+        // Missing privileges aren't the command's fault, so they don't count as failed attempt.
+        Err(e) if e.is_permission_denied() => {
+            denied.deny(action, retries.len() + 1, &e);
+            retries.push(Retry {
+                block,
+                targets,
+                attempt: attempt - 1,
+                at: denied.retest.unwrap_or_else(Instant::now),
+            });
+        }
+        // Retrying can't help, until enough IPs were unblocked to make room again.
+        Err(Error::FirewallFull) => error!(
+            "failed {} {} targets, as the firewall is full. Raise its maximum size, like \
+             `ipset.maxelem`, or shorten the timeouts of the rules",
+            action,
+            targets.len()
+        ),
+        Err(e) if attempt < MAX_ATTEMPTS => {
+            let delay = RETRY_DELAY * 2_u32.pow(attempt - 1);
+            warn!(
+                "failed {} {} targets (attempt {}), retrying in {:?}: {:?}",
+                action,
+                targets.len(),
+                attempt,
+                delay,
+                e
+            );
+
+            retries.push(Retry {
+                block,
+                targets,
+                attempt,
+                at: Instant::now() + delay,
+            });
+        }
+        Err(e) => warn!(
+            "failed {} {} targets after {} attempts, giving up: {:?}",
+            action,
+            targets.len(),
+            attempt,
+            e
+        ),
+    }
+}
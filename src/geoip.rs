@@ -0,0 +1,388 @@
+//! Country lookups in a local `GeoIP` database in the `MaxMind` DB format, that can be downloaded
+//! from `MaxMind` or DB-IP and refreshed in the background, without a separate cron job.
+//!
+//! A new database is verified and written next to the current file before replacing it, and the
+//! loaded database is only swapped once the new one could be opened, so lookups always see a
+//! complete database.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use flate2::read::GzDecoder;
+use ipnetwork::{IpNetwork, Ipv6Network};
+use log::{info, warn};
+use maxminddb::{geoip2, Reader};
+use parking_lot::RwLock;
+use sha2::{Digest, Sha256};
+use time::{Date, OffsetDateTime};
+
+use crate::{
+    enrich::{Enrichment, Lookup},
+    settings::{self, GeoIpProvider},
+    Error, Result,
+};
+
+const MAXMIND_URL: &str = "https://download.maxmind.com/app/geoip_download";
+const DBIP_URL: &str = "https://download.db-ip.com/free";
+/// Maximum time for a single download.
+const TIMEOUT: Duration = Duration::from_secs(300);
+/// Maximum size of a download, well above the size of the free city databases.
+const MAX_SIZE: u64 = 512 * 1024 * 1024;
+/// Time between two checks whether the database is outdated, which is also the time until a
+/// failed download is retried.
+const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+const IPV4_ROOT: &str = "0.0.0.0/0";
+const IPV6_ROOT: &str = "::/0";
+/// Subtrees of IPv6 databases that contain the IPv4 space again, as IPv4-compatible, IPv4-mapped
+/// and 6to4 addresses.
+const IPV4_ALIASES: &[&str] = &["::/96", "::ffff:0:0/96", "2002::/16"];
+
+/// Loaded database, that is replaced as a whole on updates.
+type SharedReader = Arc<RwLock<Option<Arc<Reader<Vec<u8>>>>>>;
+
+/// `GeoIP` database that is shared between threads, and can be replaced while it's in use.
+#[derive(Clone, Default)]
+pub struct Database {
+    reader: SharedReader,
+}
+
+impl Database {
+    /// Load the database at the given location. It stays empty if the file doesn't exist yet,
+    /// like before the first download.
+    pub fn open(path: &Path) -> Result<Self> {
+        let database = Self::default();
+
+        match fs::read(path) {
+            Ok(buf) => database.swap(Reader::from_source(buf)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(database)
+    }
+
+    /// Replace the current database.
+    pub fn swap(&self, reader: Reader<Vec<u8>>) {
+        *self.reader.write() = Some(Arc::new(reader));
+    }
+
+    /// Whether a database is loaded.
+    #[must_use]
+    pub fn is_loaded(&self) -> bool {
+        self.reader.read().is_some()
+    }
+
+    /// All networks of the given countries. IPv4 networks that an IPv6 database contains several
+    /// times under different prefixes, like for 6to4, are only returned once.
+    pub fn networks(&self, countries: &[String]) -> Result<Vec<IpNetwork>> {
+        let Some(reader) = self.reader.read().clone() else {
+            return Ok(Vec::new());
+        };
+
+        let mut networks = Vec::new();
+        for root in [IPV4_ROOT, IPV6_ROOT] {
+            let root = root.parse().map_err(io::Error::other)?;
+
+            for item in reader.within::<geoip2::Country<'_>>(root)? {
+                let item = item?;
+                let matches = item
+                    .info
+                    .country
+                    .and_then(|country| country.iso_code)
+                    .is_some_and(|code| countries.iter().any(|c| c.eq_ignore_ascii_case(code)));
+
+                if matches && !is_ipv4_alias(item.ip_net) {
+                    networks.push(item.ip_net);
+                }
+            }
+        }
+
+        Ok(networks)
+    }
+
+    /// Two-letter country code of the IP, if the database contains it.
+    #[must_use]
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let reader = self.reader.read().clone()?;
+        let record = reader.lookup::<geoip2::Country<'_>>(ip).ok()?;
+
+        record
+            .country
+            .and_then(|country| country.iso_code)
+            .map(ToOwned::to_owned)
+    }
+}
+
+/// Fills in the country, unless another lookup already found it.
+impl Lookup for Database {
+    fn lookup(&self, ip: IpAddr, enrichment: &mut Enrichment) -> io::Result<()> {
+        if enrichment.country.is_none() {
+            enrichment.country = self.country(ip);
+        }
+
+        Ok(())
+    }
+}
+
+/// Downloads a new database from the provider, once the current file is older than the refresh
+/// interval.
+pub struct Updater {
+    provider: GeoIpProvider,
+    license_key: Option<String>,
+    edition: String,
+    path: PathBuf,
+    refresh: Duration,
+    agent: ureq::Agent,
+    database: Database,
+}
+
+impl Updater {
+    /// Create an updater for the database from the settings, or return [`None`] if downloads are
+    /// disabled.
+    pub fn from_settings(settings: &settings::GeoIp, database: Database) -> Result<Option<Self>> {
+        let (Some(path), Some(provider)) = (&settings.file, settings.provider) else {
+            return Ok(None);
+        };
+
+        if provider == GeoIpProvider::MaxMind && settings.license_key.is_none() {
+            return Err(Error::GeoIpSettings(
+                "downloads from MaxMind require a license key",
+            ));
+        }
+
+        Ok(Some(Self {
+            provider,
+            license_key: settings.license_key.clone(),
+            edition: settings
+                .edition
+                .clone()
+                .unwrap_or_else(|| provider.default_edition().to_owned()),
+            path: path.clone(),
+            refresh: settings.refresh.unsigned_abs(),
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build(),
+            database,
+        }))
+    }
+
+    /// Whether the database file is missing, or older than the refresh interval.
+    #[must_use]
+    pub fn is_outdated(&self, now: SystemTime) -> bool {
+        fs::metadata(&self.path)
+            .and_then(|meta| meta.modified())
+            .map_or(true, |modified| {
+                now.duration_since(modified).unwrap_or_default() >= self.refresh
+            })
+    }
+
+    /// Download and verify a new database, then replace the file and the loaded database with it.
+    pub fn update(&self) -> Result<()> {
+        let buf = match self.provider {
+            GeoIpProvider::MaxMind => self.download_maxmind()?,
+            GeoIpProvider::DbIp => self.download_dbip(OffsetDateTime::now_utc().date())?,
+        };
+
+        // Make sure the database can be read, before it replaces the current one.
+        Reader::from_source(buf.as_slice())?;
+        persist(&self.path, &buf)?;
+        self.database.swap(Reader::from_source(buf)?);
+
+        Ok(())
+    }
+
+    fn download(&self, url: &str) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.agent
+            .get(url)
+            .call()?
+            .into_reader()
+            .take(MAX_SIZE)
+            .read_to_end(&mut buf)?;
+
+        Ok(buf)
+    }
+
+    fn download_maxmind(&self) -> Result<Vec<u8>> {
+        let url = format!(
+            "{MAXMIND_URL}?edition_id={}&license_key={}&suffix=tar.gz",
+            self.edition,
+            self.license_key.as_deref().unwrap_or_default(),
+        );
+
+        let archive = self.download(&url)?;
+        let checksum = self.download(&format!("{url}.sha256"))?;
+        // The checksum file has the same format as the output of `sha256sum`.
+        let expected = String::from_utf8_lossy(&checksum)
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+
+        verify(&archive, &expected)?;
+        unpack(&archive)
+    }
+
+    /// DB-IP publishes a new database each month, so the one of the previous month is taken, if
+    /// the current one isn't available yet.
+    fn download_dbip(&self, today: Date) -> Result<Vec<u8>> {
+        let archive = match self.download(&dbip_url(&self.edition, today)) {
+            Err(Error::Download(e)) if matches!(*e, ureq::Error::Status(404, _)) => {
+                self.download(&dbip_url(&self.edition, previous_month(today)))?
+            }
+            result => result?,
+        };
+
+        let mut buf = Vec::new();
+        GzDecoder::new(archive.as_slice()).read_to_end(&mut buf)?;
+
+        Ok(buf)
+    }
+}
+
+/// Whether the IPv6 network is part of a subtree that only repeats the IPv4 space.
+fn is_ipv4_alias(network: IpNetwork) -> bool {
+    let IpNetwork::V6(network) = network else {
+        return false;
+    };
+
+    IPV4_ALIASES.iter().any(|alias| {
+        alias
+            .parse::<Ipv6Network>()
+            .is_ok_and(|alias| alias.contains(network.network()))
+    })
+}
+
+fn dbip_url(edition: &str, date: Date) -> String {
+    format!(
+        "{DBIP_URL}/dbip-{edition}-{}-{:02}.mmdb.gz",
+        date.year(),
+        u8::from(date.month())
+    )
+}
+
+fn previous_month(date: Date) -> Date {
+    let first = date.replace_day(1).unwrap_or(date);
+    first.previous_day().unwrap_or(first)
+}
+
+/// Compare the SHA-256 checksum of the data with the expected one in hex.
+fn verify(data: &[u8], expected: &str) -> Result<()> {
+    let found = format!("{:x}", Sha256::digest(data));
+
+    if found.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(Error::Checksum {
+            expected: expected.to_owned(),
+            found,
+        })
+    }
+}
+
+/// Extract the database from a gzipped tar archive, which contains it in a directory named after
+/// the edition and release date.
+fn unpack(archive: &[u8]) -> Result<Vec<u8>> {
+    let mut archive = tar::Archive::new(GzDecoder::new(archive));
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.extension().is_some_and(|ext| ext == "mmdb") {
+            let mut buf = Vec::new();
+            entry.read_to_end(&mut buf)?;
+            return Ok(buf);
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::InvalidData, "archive contains no database").into())
+}
+
+/// Write the data to a temporary file next to the target first, so the target is replaced
+/// atomically.
+fn persist(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+
+    let temp = path.with_extension("mmdb.tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+
+    fs::rename(temp, path)
+}
+
+/// Start a background thread that downloads a new database, whenever the current one is
+/// outdated. The callback is called after each successful update.
+pub fn refresh_database(
+    updater: Updater,
+    on_update: impl Fn(&Database) + Send + 'static,
+) -> Result<()> {
+    thread::Builder::new()
+        .name("geoip".to_owned())
+        .spawn(move || loop {
+            if updater.is_outdated(SystemTime::now()) {
+                match updater.update() {
+                    Ok(()) => {
+                        info!("updated GeoIP database at {}", updater.path.display());
+                        on_update(&updater.database);
+                    }
+                    Err(e) => warn!("failed updating GeoIP database: {e}"),
+                }
+            }
+
+            thread::sleep(CHECK_INTERVAL.min(updater.refresh));
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::GzEncoder, Compression};
+    use time::macros::date;
+
+    use super::*;
+
+    #[test]
+    fn archives() {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        for (path, data) in [
+            ("GeoLite2-Country_20231003/LICENSE.txt", &b"license"[..]),
+            (
+                "GeoLite2-Country_20231003/GeoLite2-Country.mmdb",
+                b"database",
+            ),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append_data(&mut header, path, data).unwrap();
+        }
+        let archive = builder.into_inner().unwrap().finish().unwrap();
+
+        assert_eq!(b"database", unpack(&archive).unwrap().as_slice());
+
+        let checksum = format!("{:X}", Sha256::digest(&archive));
+        assert!(verify(&archive, &checksum).is_ok());
+        assert!(matches!(
+            verify(b"other", &checksum),
+            Err(Error::Checksum { .. })
+        ));
+    }
+
+    #[test]
+    fn dbip_urls() {
+        assert_eq!(
+            "https://download.db-ip.com/free/dbip-country-lite-2023-10.mmdb.gz",
+            dbip_url("country-lite", date!(2023 - 10 - 01))
+        );
+        assert_eq!(date!(2022 - 12 - 31), previous_month(date!(2023 - 01 - 15)));
+    }
+}
@@ -1,41 +1,164 @@
 use std::{
-    fs::File,
+    collections::hash_map::Entry as MapEntry,
     hash::BuildHasher,
-    io::{prelude::*, BufReader, Lines},
+    mem,
     net::IpAddr,
-    path::PathBuf,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
 };
 
 use aho_corasick::AhoCorasick;
-use anyhow::Result;
 use ipnetwork::IpNetwork;
 use log::{debug, info, warn};
-use regex::Regex;
-use time::OffsetDateTime;
+use regex::{CaptureLocations, Regex, RegexBuilder};
+use regex_syntax::hir::{literal::Extractor, Hir, HirKind};
+use time::{Duration, OffsetDateTime};
 
+pub use self::builder::{HandlerBuilder, RuleBuilder};
+use self::limit::BanLimiter;
 use crate::{
-    firewall::{Firewall, Target},
+    events::{BanEvent, BlockEvent, EventBus, UnbanEvent},
+    firewall::{self, Firewall, Target},
+    host::{HostExtractor, HostExtractors},
+    local::LocalAddresses,
     matcher::Matcher,
+    metrics::RuleMetrics,
     notifier::{Event, EventType},
-    settings::Rule,
-    storage::TargetRepository,
-    HashMap, IndexMap,
+    progress::{Progress, Reporter},
+    reputation::Reputation,
+    settings::{self, HostSource, Limits, Protocol, Rule},
+    storage::{BanRecord, TargetRepository},
+    tailer::Tailer,
+    timestamp::{TimeParser, TimeParsers, DEFAULT_FORMAT},
+    tor::ExitList,
+    Error, HashMap, IndexMap, IndexSet, Result,
 };
 
+mod builder;
+mod limit;
+
 pub struct Entry {
     pub name: String,
-    pub matchers: Vec<Regex>,
+    pub matchers: Vec<Filter>,
     pub blacklists: IndexMap<String, AhoCorasick>,
+    /// Parser for the timestamps that the filters capture.
+    pub time_parser: Arc<dyn TimeParser>,
+    /// Extractor for the client IP of matching lines.
+    pub host_extractor: Arc<dyn HostExtractor>,
+    #[cfg(feature = "wasm")]
+    pub plugins: Vec<crate::plugin::Plugin>,
     pub rule: Rule,
+    pub metrics: Arc<RuleMetrics>,
+}
+
+impl Entry {
+    /// Capture locations of each filter, that can be reused for many lines with
+    /// [`Matcher::find_with`].
+    #[must_use]
+    pub fn capture_locations(&self) -> Vec<CaptureLocations> {
+        self.matchers
+            .iter()
+            .map(Filter::capture_locations)
+            .collect()
+    }
+
+    /// Approximate amount of heap memory used by the Aho-Corasick automata of this entry's
+    /// prefilters and blacklists, in bytes. The memory of compiled regexes isn't included, but is
+    /// bounded by the regex size limit.
+    #[must_use]
+    pub fn memory_usage(&self) -> usize {
+        let prefilters = self
+            .matchers
+            .iter()
+            .filter_map(|f| f.prefilter.as_ref())
+            .map(AhoCorasick::memory_usage);
+        let blacklists = self.blacklists.values().map(AhoCorasick::memory_usage);
+
+        prefilters.chain(blacklists).sum()
+    }
+}
+
+pub struct Filter {
+    pub regex: Regex,
+    /// Literals of which at least one must be contained in a line for the regex to match.
+    pub prefilter: Option<AhoCorasick>,
+    /// Indices of all named capture groups of the regex.
+    groups: HashMap<String, usize>,
+}
+
+impl Filter {
+    fn new(regex: Regex, prefilter: Option<AhoCorasick>) -> Self {
+        let groups = regex
+            .capture_names()
+            .enumerate()
+            .filter_map(|(i, name)| name.map(|name| (name.to_owned(), i)))
+            .collect();
+
+        Self {
+            regex,
+            prefilter,
+            groups,
+        }
+    }
+
+    /// Capture locations for [`Self::captures_with`], that can be reused for many lines.
+    #[must_use]
+    pub fn capture_locations(&self) -> CaptureLocations {
+        self.regex.capture_locations()
+    }
+
+    /// Run the regex against the line and pass a lookup function for named capture groups to the
+    /// given function, if it matched. The capture locations must come from
+    /// [`Self::capture_locations`] of this filter, and are reused instead of allocating the full
+    /// captures with their group names for each line.
+    #[inline]
+    pub fn captures_with<'l, T>(
+        &self,
+        locations: &mut CaptureLocations,
+        line: &'l str,
+        f: impl FnOnce(&dyn Fn(&str) -> Option<&'l str>) -> T,
+    ) -> Option<T> {
+        self.regex.captures_read(locations, line)?;
+
+        let group = |name: &str| {
+            self.groups
+                .get(name)
+                .and_then(|&i| locations.get(i))
+                .map(|(start, end)| &line[start..end])
+        };
+
+        Some(f(&group))
+    }
+
+    /// Quickly check whether the line can possibly match the regex, without running it.
+    #[inline]
+    #[must_use]
+    pub fn is_candidate(&self, line: &str) -> bool {
+        self.prefilter
+            .as_ref()
+            .is_none_or(|prefilter| prefilter.is_match(line))
+    }
 }
 
 pub struct State {
-    lines: Option<Lines<BufReader<File>>>,
+    tailer: Tailer,
     pub time: OffsetDateTime,
+    /// Reports progress through the file's existing content, until it's fully scanned.
+    scan: Option<Reporter>,
+}
+
+impl State {
+    /// Approximate amount of heap memory used for reading lines, in bytes.
+    #[must_use]
+    pub fn memory_usage(&self) -> usize {
+        self.tailer.memory_usage()
+    }
 }
 
 static RULE_REGEXS: phf::Map<&str, &str> = phf::phf_map! {
-    "<HOST>" => r"(?P<host>(?:[0-9]{1,3}\.){3}[0-9]{1,3}|(?:[a-fA-F0-9]{0,4}:){1,}[a-fA-F0-9]{1,4})",
+    "<HOST>" => r"(?P<host>(?:[0-9]{1,3}\.){3}[0-9]{1,3}|(?:[a-fA-F0-9]{0,4}:){1,}[a-fA-F0-9]{1,4}(?:%[0-9a-zA-Z._-]+)?)",
     "<TIME>" => r"(?P<time>[0-9]{2}/[a-zA-Z]{3}/[0-9]{4}(?::[0-9]{2}){3} \+[0-9]{4})",
     "<TIME_RFC2822>" => r"(?P<time_rfc2822>[a-zA-Z]{3}, [0-9]{1,2} [a-zA-Z]{3} [0-9]{4} [0-9]{2}(?::[0-9]{2}){2} [\+-][0-9]{4})",
     "<TIME_RFC3339>" => r"(?P<time_rfc3339>[0-9]{4}(?:-[0-9]{2}){2}T[0-9]{2}(?::[0-9]{2}){2}[\+-][0-9]{2}:[0-9]{2})",
@@ -43,11 +166,92 @@ static RULE_REGEXS: phf::Map<&str, &str> = phf::phf_map! {
     "<VERSION>" => r"(?P<version>HTTP/[1-9](?:\.[0-9])?)",
 };
 
+/// Replacement of the `<HOST>` placeholder, if the host is taken from the start of the line.
+const LEADING_HOST_REGEX: &str = r"\S+";
+
+/// Amount of lines after which the matcher's clock is refreshed during long scans.
+const CLOCK_REFRESH_LINES: usize = 1024;
+/// Maximum amount of targets that are sent to the firewall in a single batch.
+const MAX_BATCH_SIZE: usize = 1024;
+/// Minimum difference between the wall clock and the monotonic clock, that counts as clock jump.
+const CLOCK_JUMP_THRESHOLD: Duration = Duration::MINUTE;
+
 pub struct Handler<TR, F> {
     pub whitelist: Vec<IpNetwork>,
+    /// Addresses of the host itself, which are implicitly whitelisted if set.
+    pub local: Option<LocalAddresses>,
+    pub matcher: Matcher,
     pub storage: TR,
     pub firewall: F,
     pub last_unblock: OffsetDateTime,
+    /// Maximum random duration added to each block's timeout.
+    pub unblock_jitter: Duration,
+    /// Maximum amount of IPs to unblock in a single [`Self::handle_unblock`] call.
+    pub max_unblocks: Option<NonZeroUsize>,
+    pub hooks: Hooks,
+    /// Subscriptions to blocks and unblocks.
+    pub events: EventBus,
+    /// IPs that are believed to be blocked on the firewall, with the time until they stay blocked.
+    /// Further offenses of these only extend the block in the storage, without calling the
+    /// firewall again.
+    pub blocked: HashMap<IpAddr, OffsetDateTime>,
+    /// IPs whose block was held back by the ban limits, with the log file of the rule that found
+    /// them. They're blocked in order, as soon as the limits allow it.
+    pub deferred: IndexMap<IpAddr, PathBuf>,
+    /// Limits for the amount of new blocks per minute.
+    ban_limit: BanLimiter,
+    /// Only detect offending IPs without blocking them, like after the breaker tripped.
+    pub paused: bool,
+    /// Reputation of the offending IPs, which decides whether a rule's `reputation_timeout`
+    /// applies.
+    pub reputation: Option<Reputation>,
+    /// Known Tor exit nodes, that rules block with their `tor_timeout`, or not at all if they're
+    /// exempt.
+    pub tor: Option<ExitList>,
+    /// Whether the firewall unblocks IPs by itself once their timeout passed, so extended blocks
+    /// must be sent to it again with the new timeout.
+    pub firewall_timeouts: bool,
+    /// Detection of jumps of the wall clock, if enabled.
+    clock_watch: Option<ClockWatch>,
+}
+
+/// Detects jumps of the wall clock, like a large NTP step or a manual change, by comparing how far
+/// it moved with the monotonic clock.
+///
+/// Blocks end at wall clock times, as these are persisted in the storage. Without this, a jump
+/// would end every block at once, or extend all of them.
+struct ClockWatch {
+    wall: OffsetDateTime,
+    monotonic: Instant,
+}
+
+impl ClockWatch {
+    fn new(wall: OffsetDateTime) -> Self {
+        Self {
+            wall,
+            monotonic: Instant::now(),
+        }
+    }
+
+    /// Difference between the wall clock and where the monotonic clock says it should be, if it
+    /// reaches [`CLOCK_JUMP_THRESHOLD`]. The comparison starts over from the given time either way.
+    fn jump(&mut self, wall: OffsetDateTime) -> Option<Duration> {
+        let expected = self.wall + self.monotonic.elapsed();
+        *self = Self::new(wall);
+
+        let jump = wall - expected;
+        (jump.abs() >= CLOCK_JUMP_THRESHOLD).then_some(jump)
+    }
+}
+
+/// Callback that receives the rule name and IP address of a block or unblock.
+pub type Hook = Box<dyn FnMut(&str, IpAddr) + Send>;
+
+/// Optional callbacks that are called whenever the handler blocks or unblocks an IP.
+#[derive(Default)]
+pub struct Hooks {
+    pub on_block: Option<Hook>,
+    pub on_unblock: Option<Hook>,
 }
 
 impl<TR, F> Handler<TR, F>
@@ -55,6 +259,11 @@ where
     TR: TargetRepository,
     F: Firewall,
 {
+    /// Start building a new handler with the given storage and firewall.
+    pub fn builder(storage: TR, firewall: F) -> HandlerBuilder<TR, F> {
+        HandlerBuilder::new(storage, firewall)
+    }
+
     pub fn handle_event(
         &mut self,
         files: &mut HashMap<PathBuf, (Entry, State)>,
@@ -66,105 +275,484 @@ where
             return Ok(());
         };
 
-        match event.ty {
+        let result = match event.ty {
             EventType::Modified => {
                 debug!("modified");
-                self.handle_modified(entry, state)?;
-            }
-            EventType::Removed => {
-                debug!("removed");
-                state.lines.take();
+                self.handle_modified(entry, state)
             }
             EventType::Created => {
-                debug!("created");
-                let file = File::open(event.path)?;
-                let file = BufReader::new(file);
-                state.lines.replace(file.lines());
+                if state.tailer.is_pending() {
+                    info!("rule {}: {:?} was created", entry.name, event.path);
+                }
+                state.tailer.handle(&event.ty)?;
+                // The file may already have content, like when it was moved into place.
+                self.handle_modified(entry, state)
             }
-        }
+            ty @ EventType::Removed => state.tailer.handle(&ty),
+        };
 
-        Ok(())
+        self.release_crawlers(|path| files.get(path).map(|(entry, _)| entry));
+        self.release_deferred(|path| files.get(path).map(|(entry, _)| entry));
+
+        result
     }
 
-    #[allow(clippy::unused_self)]
-    pub fn check_lines(&self, entry: &Entry, state: &mut State) -> Option<IpAddr> {
-        let State { lines, time } = state;
+    pub fn check_lines(&mut self, entry: &Entry, state: &mut State) -> Option<IpAddr> {
+        let State { tailer, time, scan } = state;
 
-        let lines = match lines {
-            Some(l) => l,
-            None => return None,
-        };
+        self.matcher.refresh();
 
-        let matcher = Matcher::new();
+        let mut count = 0_usize;
+        let mut locations = entry.capture_locations();
 
-        for line in lines {
-            let line = match line {
-                Ok(l) => l,
+        while let Some(found) =
+            tailer.next_with(|line| self.matcher.find_with(entry, &mut locations, time, line))
+        {
+            match found {
+                Ok(Some(addr)) => return Some(addr),
+                Ok(None) => {}
                 Err(e) => {
                     warn!("error reading line: {:?}", e);
                     return None;
                 }
-            };
+            }
 
-            if let Some(addr) = matcher.find(entry, time, &line) {
-                return Some(addr);
+            count += 1;
+            if count % CLOCK_REFRESH_LINES == 0 {
+                self.matcher.refresh();
+                report_scan(entry, tailer, scan);
             }
         }
 
+        report_scan(entry, tailer, scan);
+
         None
     }
 
     pub fn handle_modified(&mut self, entry: &Entry, state: &mut State) -> Result<()> {
+        let mut targets = Vec::new();
+
         while let Some(addr) = self.check_lines(entry, state) {
-            if self.whitelist.iter().any(|wl| wl.contains(addr)) {
-                info!("skipping whitelisted {}", addr);
-                continue;
+            self.collect_offense(entry, addr, &mut targets)?;
+        }
+
+        self.block_all(&entry.name, &targets);
+
+        Ok(())
+    }
+
+    /// Handle an offense that wasn't found in a log line, like a connection to the honeypot, and
+    /// block the IP on the firewall if needed.
+    pub fn handle_offense(&mut self, entry: &Entry, addr: IpAddr) -> Result<()> {
+        self.handle_offenses(entry, [addr])
+    }
+
+    /// Like [`Self::handle_offense`], but for many IPs at once, which are blocked on the firewall
+    /// in batches.
+    pub fn handle_offenses(
+        &mut self,
+        entry: &Entry,
+        addrs: impl IntoIterator<Item = IpAddr>,
+    ) -> Result<()> {
+        let mut targets = Vec::new();
+
+        for addr in addrs {
+            self.collect_offense(entry, addr, &mut targets)?;
+        }
+
+        self.block_all(&entry.name, &targets);
+
+        Ok(())
+    }
+
+    /// Record an offense of the IP, and add it to the targets if it must be newly blocked. Full
+    /// batches of targets are blocked right away. If recording fails, the collected targets are
+    /// blocked before returning the error, as they're already remembered as blocked.
+    fn collect_offense<'e>(
+        &mut self,
+        entry: &'e Entry,
+        addr: IpAddr,
+        targets: &mut Vec<Target<'e>>,
+    ) -> Result<()> {
+        match self.record_offense(entry, addr) {
+            Ok(Some(until)) => targets.push(self.target(entry, addr, until)),
+            Ok(None) => {}
+            Err(e) => {
+                self.block_all(&entry.name, targets);
+                targets.clear();
+                return Err(e);
+            }
+        }
+
+        if targets.len() >= MAX_BATCH_SIZE {
+            self.block_all(&entry.name, targets);
+            targets.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Store a new offense of the IP, that was found by the given rule. If the IP must be newly
+    /// blocked on the firewall, the time until it stays blocked is returned.
+    pub(crate) fn record_offense(
+        &mut self,
+        entry: &Entry,
+        addr: IpAddr,
+    ) -> Result<Option<OffsetDateTime>> {
+        if self.whitelist.iter().any(|wl| wl.contains(addr)) {
+            info!("skipping whitelisted {}", addr);
+            return Ok(None);
+        }
+
+        if self.tor.as_ref().is_some_and(|tor| tor.is_exempt(addr)) {
+            info!("rule {}: skipping {}, a Tor exit node", entry.name, addr);
+            return Ok(None);
+        }
+
+        self.reconcile_clock()?;
+
+        if let Some(local) = &mut self.local {
+            local.refresh_if_due();
+            if local.contains(addr) {
+                warn!(
+                    "rule {}: skipping {}, an address of this host",
+                    entry.name, addr
+                );
+                return Ok(None);
             }
+        }
+
+        if self.paused {
+            warn!(
+                "rule {}: detected {}, but blocking is paused",
+                entry.name, addr
+            );
+            return Ok(None);
+        }
+
+        let now = self.matcher.current_time();
+
+        let bad_reputation = self
+            .reputation
+            .as_mut()
+            .is_some_and(|reputation| reputation.record_offense(addr, now));
+        let exit = self.tor.as_ref().is_some_and(|tor| tor.contains(addr));
+        if bad_reputation || exit {
+            debug!(
+                "rule {}: {} has a bad reputation: {bad_reputation}, is a Tor exit: {exit}",
+                entry.name, addr
+            );
+        }
+
+        // The longest of the special timeouts applies, if any.
+        let timeout = [
+            entry.rule.reputation_timeout.filter(|_| bad_reputation),
+            entry.rule.tor_timeout.filter(|_| exit),
+        ]
+        .into_iter()
+        .flatten()
+        .max()
+        .unwrap_or(entry.rule.timeout);
+
+        let until = now.saturating_add(timeout.saturating_add(self.jitter(addr)));
 
-            let now = OffsetDateTime::now_utc();
+        match self.storage.upsert(addr, until, &entry.rule.file) {
+            Ok(_) => {}
+            Err(Error::StorageFull(max)) => {
+                warn!(
+                    "rule {}: not blocking {addr}, as the storage limit of {max} entries is \
+                     reached and all of them are still blocked",
+                    entry.name
+                );
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        }
+
+        if let Some(blocked) = self
+            .blocked
+            .get_mut(&addr)
+            .filter(|blocked| **blocked >= now)
+        {
+            *blocked = until;
+
+            if self.firewall_timeouts {
+                let target = self.target(entry, addr, until);
+                if let Err(e) = self.firewall.block(&target) {
+                    warn!(
+                        "rule {}: failed extending block of {addr}: {e:?}",
+                        entry.name
+                    );
+                }
+            }
+
+            return Ok(None);
+        }
+
+        if !self
+            .ban_limit
+            .try_acquire(&entry.name, entry.rule.max_bans_per_minute, now)
+        {
+            self.defer(entry, addr);
+            return Ok(None);
+        }
+
+        self.deferred.shift_remove(&addr);
+        self.ban(entry, addr, until);
+
+        Ok(Some(until))
+    }
+
+    /// Remember the IP as blocked, and tell the hooks and subscribers about it. Blocking it on the
+    /// firewall is left to the caller, so it can be done in batches.
+    fn ban(&mut self, entry: &Entry, addr: IpAddr, until: OffsetDateTime) {
+        self.blocked.insert(addr, until);
+
+        info!("rule {}: blocking {}", entry.name, addr);
+
+        if let Some(hook) = &mut self.hooks.on_block {
+            hook(&entry.name, addr);
+        }
+
+        self.events.publish(&BlockEvent::Ban(BanEvent {
+            rule: entry.name.clone(),
+            ip: addr,
+            until,
+        }));
+    }
+
+    /// Queue the block of an IP, as the ban limits are reached. Only the first block that is
+    /// queued after the queue was empty is reported, to not flood the log.
+    fn defer(&mut self, entry: &Entry, addr: IpAddr) {
+        if self.deferred.is_empty() {
+            warn!(
+                "rule {}: ban limit reached, queueing further blocks",
+                entry.name
+            );
+        }
+
+        if self
+            .deferred
+            .insert(addr, entry.rule.file.clone())
+            .is_none()
+        {
+            debug!("rule {}: queueing block of {}", entry.name, addr);
+        }
+    }
+
+    /// Block queued IPs, as far as the ban limits allow it and blocking isn't paused. IPs whose
+    /// block ended while waiting are dropped, while IPs without a known rule are kept.
+    pub(crate) fn release_deferred<'e>(&mut self, rules: impl Fn(&Path) -> Option<&'e Entry>) {
+        if self.paused || self.deferred.is_empty() {
+            return;
+        }
+
+        let now = self.matcher.current_time();
+        let mut targets = Vec::new();
+        let mut kept = IndexMap::default();
+
+        for (addr, path) in mem::take(&mut self.deferred) {
+            let Some(entry) = rules(&path) else {
+                kept.insert(addr, path);
+                continue;
+            };
+
+            let until = match self.storage.record(addr) {
+                Ok(Some(record)) if record.active && record.until >= now => record.until,
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!("failed loading storage entry of {}: {:?}", addr, e);
+                    kept.insert(addr, path);
+                    continue;
+                }
+            };
 
             if !self
-                .storage
-                .upsert(addr, now + entry.rule.timeout, &entry.rule.file)?
+                .ban_limit
+                .try_acquire(&entry.name, entry.rule.max_bans_per_minute, now)
             {
-                info!("rule {}: blocking {}", entry.name, addr);
+                kept.insert(addr, path);
+                continue;
+            }
 
-                let target = &Target {
-                    ip: addr,
-                    ports: &entry.rule.ports,
-                };
-                if let Err(e) = self.firewall.block(target) {
-                    warn!("rule: {}: failed blocking {}: {:?}", entry.name, addr, e);
-                }
+            self.ban(entry, addr, until);
+            targets.push(self.target(entry, addr, until));
+        }
+
+        self.deferred = kept;
+
+        for chunk in targets.chunks(MAX_BATCH_SIZE) {
+            if let Err(e) = self.firewall.block_all(chunk) {
+                warn!("failed blocking {} queued targets: {:?}", chunk.len(), e);
+                self.forget(chunk);
+            }
+        }
+
+        if !targets.is_empty() && self.deferred.is_empty() {
+            info!("all queued blocks are applied");
+        }
+    }
+
+    /// Block IPs that claimed to be crawlers, once their verification failed or timed out. IPs
+    /// without a known rule are dropped.
+    pub fn release_crawlers<'e>(&mut self, rules: impl Fn(&Path) -> Option<&'e Entry>) {
+        for (addr, path) in self.matcher.unverified_crawlers() {
+            let Some(entry) = rules(&path) else {
+                continue;
+            };
+
+            debug!(
+                "rule {}: {} failed the crawler verification",
+                entry.name, addr
+            );
+            if let Err(e) = self.handle_offense(entry, addr) {
+                warn!("rule {}: failed blocking {}: {:?}", entry.name, addr, e);
             }
         }
+    }
+
+    /// Move all blocks by the amount that the wall clock jumped, if it did, so they keep their
+    /// remaining time instead of ending early or late.
+    fn reconcile_clock(&mut self) -> Result<()> {
+        let now = self.matcher.current_time();
+        let Some(jump) = self.clock_watch.as_mut().and_then(|watch| watch.jump(now)) else {
+            return Ok(());
+        };
+
+        warn!(
+            "system clock jumped by {}, moving all blocks by the same amount",
+            jump
+        );
+
+        for record in self.storage.records()? {
+            if record.active {
+                let until = record.until.saturating_add(jump);
+                self.storage.restore(BanRecord { until, ..record })?;
+            }
+        }
+
+        for until in self.blocked.values_mut() {
+            *until = until.saturating_add(jump);
+        }
+        self.last_unblock = self.last_unblock.saturating_add(jump);
 
         Ok(())
     }
 
+    /// Random duration up to the configured maximum jitter.
+    fn jitter(&self, addr: IpAddr) -> Duration {
+        if self.unblock_jitter.is_zero() {
+            return Duration::ZERO;
+        }
+
+        // Each new instance is seeded differently, which makes the hash a cheap random number.
+        let random = ahash::RandomState::new().hash_one(addr);
+
+        #[allow(clippy::cast_precision_loss)]
+        let fraction = random as f64 / u64::MAX as f64;
+
+        self.unblock_jitter * fraction
+    }
+
+    /// Target to block the IP on the firewall with, until the given time.
+    fn target<'e>(&self, entry: &'e Entry, addr: IpAddr, until: OffsetDateTime) -> Target<'e> {
+        Target {
+            ip: addr.into(),
+            ports: &entry.rule.ports,
+            protocol: entry.rule.protocol,
+            timeout: firewall::remaining(until, self.matcher.current_time()),
+        }
+    }
+
+    /// Block the targets on the firewall. If that fails, they're no longer remembered as blocked,
+    /// so the next offense of each IP tries again.
+    pub(crate) fn block_all(&mut self, name: &str, targets: &[Target<'_>]) {
+        if targets.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.firewall.block_all(targets) {
+            warn!(
+                "rule {}: failed blocking {} targets: {:?}",
+                name,
+                targets.len(),
+                e
+            );
+            self.forget(targets);
+        }
+    }
+
+    /// Forget that the targets are blocked, after blocking them on the firewall failed.
+    fn forget(&mut self, targets: &[Target<'_>]) {
+        for target in targets {
+            self.blocked.remove(&target.ip.ip());
+        }
+    }
+
     pub fn handle_unblock(&mut self, files: &HashMap<PathBuf, (Entry, State)>) -> Result<()> {
-        let now = OffsetDateTime::now_utc();
+        self.unblock_with(|path| files.get(path).map(|(entry, _)| entry))
+    }
+
+    /// Unblock all outdated IPs, looking up the rule that blocked them by the path of its log
+    /// file. IPs without a known rule are kept.
+    pub fn unblock_with<'e>(&mut self, rules: impl Fn(&Path) -> Option<&'e Entry>) -> Result<()> {
+        self.reconcile_clock()?;
+        self.release_crawlers(&rules);
+        self.release_deferred(&rules);
+        let now = self.matcher.current_time();
 
         if self.last_unblock < now {
-            self.storage.iter_outdated(|addr, path| {
-                let (entry, _) = if let Some(e) = files.get(path) {
+            let mut targets = Vec::new();
+
+            let max = self.max_unblocks.map_or(usize::MAX, NonZeroUsize::get);
+
+            self.storage.iter_outdated(&mut |addr, path| {
+                let entry = if let Some(e) = rules(path) {
                     e
                 } else {
                     return Ok(false);
                 };
 
+                // Keep the remaining entries for the next run, once the limit is reached.
+                if targets.len() >= max {
+                    return Ok(false);
+                }
+
+                // Queued blocks never reached the firewall, so there is nothing to unblock.
+                if self.deferred.shift_remove(&addr).is_some() {
+                    return Ok(true);
+                }
+
                 info!("rule {}: unblocking {}", entry.name, addr);
+                self.blocked.remove(&addr);
+
+                if let Some(hook) = &mut self.hooks.on_unblock {
+                    hook(&entry.name, addr);
+                }
 
-                let target = &Target {
+                self.events.publish(&BlockEvent::Unban(UnbanEvent {
+                    rule: entry.name.clone(),
                     ip: addr,
+                }));
+
+                targets.push(Target {
+                    ip: addr.into(),
                     ports: &entry.rule.ports,
-                };
-                if let Err(e) = self.firewall.unblock(target) {
-                    warn!("failed unblocking {}: {}", addr, e);
-                }
+                    protocol: entry.rule.protocol,
+                    timeout: None,
+                });
                 Ok(true)
             })?;
 
+            for chunk in targets.chunks(MAX_BATCH_SIZE) {
+                if let Err(e) = self.firewall.unblock_all(chunk) {
+                    warn!("failed unblocking {} targets: {}", chunk.len(), e);
+                }
+            }
+
+            // IPs that were unblocked elsewhere, like through the `unban` command, are dropped
+            // once their block would have ended, so they're blocked again on the next offense.
+            self.blocked.retain(|_, until| *until >= now);
             self.last_unblock = now;
         }
 
@@ -172,8 +760,110 @@ where
     }
 }
 
+/// Cache of compiled regexes and Aho-Corasick automata, keyed by their source.
+///
+/// Compiling is the most expensive part of preparing rules, so keeping a cache around allows to
+/// reuse everything that didn't change when rules are prepared again, for example after the
+/// configuration was reloaded. Both types are reference counted internally, so sharing them
+/// between rules is cheap.
+///
+/// The cache also holds the time parsers and host extractors that rules refer to by their
+/// `time_format` and `host`, where custom ones can be registered.
+#[derive(Default)]
+pub struct RuleCache {
+    filters: HashMap<(settings::Filter, HostSource, Option<NonZeroUsize>), Cached<CompiledFilter>>,
+    blacklists: HashMap<Vec<String>, Cached<AhoCorasick>>,
+    time_parsers: TimeParsers,
+    host_extractors: HostExtractors,
+}
+
+/// Compiled regex of a filter, together with its prefilter.
+type CompiledFilter = (Regex, Option<AhoCorasick>);
+
+struct Cached<T> {
+    value: T,
+    /// Whether the value was used since the last [`RuleCache::sweep`].
+    used: bool,
+}
+
+impl RuleCache {
+    /// Remove all entries that weren't used since the last call, to release the memory of rules
+    /// that no longer exist. This should be called after a full round of preparing rules.
+    pub fn sweep(&mut self) {
+        self.filters.retain(|_, c| mem::take(&mut c.used));
+        self.blacklists.retain(|_, c| mem::take(&mut c.used));
+    }
+
+    /// Total amount of cached regexes and automata.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.filters.len() + self.blacklists.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Make a custom time parser available to rules, under the given `time_format` name.
+    pub fn register_time_parser(
+        &mut self,
+        name: impl Into<String>,
+        parser: impl TimeParser + 'static,
+    ) {
+        self.time_parsers.register(name, parser);
+    }
+
+    /// Make a custom host extractor available to rules, under the given `host` name.
+    pub fn register_host_extractor(
+        &mut self,
+        name: impl Into<String>,
+        extractor: impl HostExtractor + 'static,
+    ) {
+        self.host_extractors.register(name, extractor);
+    }
+
+    fn filter(
+        &mut self,
+        filter: &settings::Filter,
+        host: &HostSource,
+        limits: &Limits,
+    ) -> Result<Filter> {
+        let key = (filter.clone(), host.clone(), limits.regex_size_limit);
+        let cached = match self.filters.entry(key) {
+            MapEntry::Occupied(e) => e.into_mut(),
+            MapEntry::Vacant(e) => e.insert(Cached {
+                value: compile_filter(filter, host, limits)?,
+                used: false,
+            }),
+        };
+        cached.used = true;
+
+        let (regex, prefilter) = cached.value.clone();
+        Ok(Filter::new(regex, prefilter))
+    }
+
+    fn blacklist(&mut self, words: &IndexSet<String>) -> Result<AhoCorasick> {
+        let key = words.iter().cloned().collect::<Vec<_>>();
+        let cached = match self.blacklists.entry(key) {
+            MapEntry::Occupied(e) => e.into_mut(),
+            MapEntry::Vacant(e) => e.insert(Cached {
+                value: AhoCorasick::builder()
+                    .ascii_case_insensitive(true)
+                    .build(words)?,
+                used: false,
+            }),
+        };
+        cached.used = true;
+
+        Ok(cached.value.clone())
+    }
+}
+
 pub fn prepare_rules<S>(
     rules: HashMap<String, Rule, S>,
+    limits: &Limits,
+    cache: &mut RuleCache,
 ) -> Result<HashMap<PathBuf, (Entry, State), S>>
 where
     S: BuildHasher + Default,
@@ -181,69 +871,262 @@ where
     let mut files = HashMap::with_hasher(S::default());
 
     for (name, mut rule) in rules {
-        rule.file = rule.file.canonicalize()?;
+        rule.file = resolve_file(&rule.file)?;
 
-        let file = File::open(&rule.file)?;
-        let buf = BufReader::new(file);
-        let lines = Some(buf.lines());
+        let max_line_length = limits.max_line_length.map_or(usize::MAX, NonZeroUsize::get);
+        let tailer = Tailer::open_or_pending(rule.file.clone())?.with_max_length(max_line_length);
         let time = OffsetDateTime::UNIX_EPOCH;
 
+        let scan = if tailer.is_pending() {
+            warn!(
+                "rule {}: {:?} doesn't exist yet, waiting for it to be created",
+                name, rule.file
+            );
+            None
+        } else {
+            Some(Reporter::default())
+        };
+
         files.insert(
             rule.file.clone(),
-            (prepare_rule(name, rule)?, State { lines, time }),
+            (
+                prepare_rule(name, rule, limits, cache)?,
+                State { tailer, time, scan },
+            ),
         );
     }
 
     Ok(files)
 }
 
-pub fn prepare_rule(name: String, rule: Rule) -> Result<Entry> {
+/// Get the absolute path of a log file, as the rules identify their files with it.
+///
+/// Only the directory is resolved, but not the file itself, so a symlink keeps being followed when
+/// it's pointed to another file, like the `current.log` of many logging systems. This also allows
+/// files that don't exist yet, as long as their directory exists so their creation can be watched.
+pub fn resolve_file(path: &Path) -> Result<PathBuf> {
+    let error = |source| Error::LogFile {
+        path: path.to_owned(),
+        source,
+    };
+
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        return path.canonicalize().map_err(error);
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+
+    Ok(dir.canonicalize().map_err(error)?.join(name))
+}
+
+/// Update the rule's progress through the existing content of its file, and log it whenever a
+/// report is due. Once the scan is done, the total time is logged instead.
+fn report_scan(entry: &Entry, tailer: &Tailer, scan: &mut Option<Reporter>) {
+    let Some(reporter) = scan else {
+        return;
+    };
+
+    if let Some((position, total)) = tailer.scan_progress() {
+        entry.metrics.record_scan(position as u64, total as u64);
+
+        if reporter.due() {
+            info!(
+                "rule {}: scanning {:?}, {}",
+                entry.name,
+                tailer.path(),
+                Progress {
+                    processed: position as u64,
+                    total: Some(total as u64),
+                    matches: entry.metrics.matches.load(Ordering::Relaxed),
+                    elapsed: reporter.elapsed(),
+                }
+            );
+        }
+    } else {
+        entry.metrics.finish_scan();
+        info!(
+            "rule {}: finished scanning {:?} in {:?}",
+            entry.name,
+            tailer.path(),
+            reporter.elapsed()
+        );
+        *scan = None;
+    }
+}
+
+/// Prepare a rule without any filters, for blocks that don't come from a log file, like the ones
+/// of the honeypot. The path only identifies the blocks of the rule in the storage.
+pub fn prepare_builtin(name: &str, path: &str, timeout: Duration) -> Result<Entry> {
+    let rule = Rule {
+        file: path.into(),
+        plugins: Vec::new(),
+        ports: Vec::new(),
+        protocol: Protocol::Tcp,
+        timeout,
+        host: HostSource::default(),
+        time_format: None,
+        stall_timeout: None,
+        max_bans_per_minute: None,
+        reputation_timeout: None,
+        crawler_group: None,
+        tor_timeout: None,
+        filters: Vec::new(),
+        blacklists: IndexMap::default(),
+    };
+
+    prepare_rule(
+        name.to_owned(),
+        rule,
+        &Limits::default(),
+        &mut RuleCache::default(),
+    )
+}
+
+pub fn prepare_rule(
+    name: String,
+    rule: Rule,
+    limits: &Limits,
+    cache: &mut RuleCache,
+) -> Result<Entry> {
     let matchers = rule
         .filters
         .iter()
-        .map(|f| {
-            let f = RULE_REGEXS
-                .entries()
-                .fold(f.clone(), |f, (k, r)| f.replace(k, r));
-            Regex::new(&f).map_err(Into::into)
-        })
+        .map(|filter| cache.filter(filter, &rule.host, limits))
         .collect::<Result<_>>()?;
 
     let blacklists = rule
         .blacklists
         .iter()
-        .map(|(k, v)| {
-            Ok((
-                k.clone(),
-                AhoCorasick::builder()
-                    .ascii_case_insensitive(true)
-                    .build(v)?,
-            ))
-        })
+        .map(|(k, v)| Ok((k.clone(), cache.blacklist(v)?)))
+        .collect::<Result<_>>()?;
+
+    #[cfg(feature = "wasm")]
+    let plugins = rule
+        .plugins
+        .iter()
+        .map(|path| crate::plugin::Plugin::load(path))
         .collect::<Result<_>>()?;
 
+    #[cfg(not(feature = "wasm"))]
+    if let Some(path) = rule.plugins.first() {
+        return Err(Error::Plugin {
+            path: path.clone(),
+            source: "plugins require the `wasm` feature".into(),
+        });
+    }
+
+    let time_parser = cache
+        .time_parsers
+        .get(rule.time_format.as_deref().unwrap_or(DEFAULT_FORMAT))?;
+    let host_extractor = cache.host_extractors.get(&rule.host)?;
+
+    let metrics = Arc::new(RuleMetrics::new(rule.filters.len()));
+
     Ok(Entry {
         name,
         matchers,
         blacklists,
+        time_parser,
+        host_extractor,
+        #[cfg(feature = "wasm")]
+        plugins,
         rule,
+        metrics,
     })
 }
 
+fn compile_filter(
+    filter: &settings::Filter,
+    host: &HostSource,
+    limits: &Limits,
+) -> Result<CompiledFilter> {
+    let pattern = expand_pattern(&filter.pattern, host);
+
+    let mut builder = RegexBuilder::new(&pattern);
+    if let Some(limit) = limits.regex_size_limit {
+        builder.size_limit(limit.get()).dfa_size_limit(limit.get());
+    }
+    let regex = builder.build()?;
+
+    let prefilter = match &filter.prefilter {
+        Some(literal) => Some(AhoCorasick::new([literal])?),
+        None => required_literals(&pattern)?
+            .map(AhoCorasick::new)
+            .transpose()?,
+    };
+
+    Ok((regex, prefilter))
+}
+
+/// Replace all placeholders like `<HOST>` in a filter pattern with their regex.
+#[must_use]
+pub fn expand_pattern(pattern: &str, host: &HostSource) -> String {
+    let mut pattern = pattern.to_owned();
+    if *host == HostSource::Leading {
+        pattern = pattern.replace("<HOST>", LEADING_HOST_REGEX);
+    }
+
+    RULE_REGEXS
+        .entries()
+        .fold(pattern, |f, (k, r)| f.replace(k, r))
+}
+
+/// Extract a set of literals from the pattern, of which at least one must be part of any match.
+///
+/// Every part of a top-level concatenation must match for the whole pattern to match, so the
+/// literal prefixes of the part with the longest (and fewest) literals are picked. If no part
+/// has a finite set of non-empty literals, [`None`] is returned.
+fn required_literals(pattern: &str) -> Result<Option<Vec<Vec<u8>>>> {
+    let hir = regex_syntax::parse(pattern)?;
+    let parts = match hir.kind() {
+        HirKind::Concat(parts) => parts.as_slice(),
+        _ => std::slice::from_ref(&hir),
+    };
+
+    let extractor = Extractor::new();
+
+    Ok(parts
+        .iter()
+        .map(|part: &Hir| extractor.extract(part))
+        .filter_map(|seq| {
+            let min_len = seq.min_literal_len().filter(|&len| len > 0)?;
+            let literals = seq.literals()?;
+            Some((min_len, literals.len(), seq))
+        })
+        .max_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)))
+        .and_then(|(_, _, seq)| {
+            seq.literals()
+                .map(|lits| lits.iter().map(|l| l.as_bytes().to_vec()).collect())
+        }))
+}
+
 #[cfg(test)]
 mod tests {
     use time::{
         format_description::well_known::{Rfc2822, Rfc3339},
         macros::datetime,
+        Date,
     };
 
+    use std::sync::atomic::AtomicI64;
+
     use super::*;
+    use crate::testing::{FirewallCall, MemoryRepository, MockFirewall};
 
     #[test]
     fn valid_host_match() {
         let r = Regex::new(RULE_REGEXS["<HOST>"]).unwrap();
         assert!(r.is_match("127.0.0.1"));
         assert!(r.is_match("::1"));
+        assert_eq!(
+            Some("fe80::1%eth0"),
+            r.captures("fe80::1%eth0 - -")
+                .and_then(|c| c.name("host"))
+                .map(|m| m.as_str())
+        );
     }
 
     #[test]
@@ -299,4 +1182,545 @@ mod tests {
         assert!(r.is_match("HTTP/1.1"));
         assert!(r.is_match("HTTP/2"));
     }
+
+    /// Rule that finds the IP at the start of each line, with an empty log file at a path that is
+    /// unique to the test and process.
+    fn test_rule(name: &str) -> (Rule, PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("veto-test-{}-{name}.log", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+
+        let rule = Rule {
+            file: path.clone(),
+            filters: vec![settings::Filter {
+                pattern: "^<HOST>".to_owned(),
+                prefilter: None,
+            }],
+            timeout: Duration::hours(1),
+            ..Rule::default()
+        };
+
+        (rule, path)
+    }
+
+    #[test]
+    fn filter_is_sync() {
+        fn assert_sync<T: Send + Sync>() {}
+        assert_sync::<Filter>();
+    }
+
+    #[test]
+    fn required_literals_of_filter() {
+        let pattern = RULE_REGEXS.entries().fold(
+            r#"^<HOST> - - \[<TIME>\] "<METHOD> (?P<path>/.*) HTTP"#.to_owned(),
+            |f, (k, r)| f.replace(k, r),
+        );
+
+        let literals = required_literals(&pattern).unwrap().unwrap();
+        assert_eq!(vec![b" - - [".to_vec()], literals);
+
+        assert_eq!(None, required_literals(r"^\d+$").unwrap());
+        assert_eq!(
+            Some(vec![b"GET".to_vec(), b"HEAD".to_vec()]),
+            required_literals(r"\w+ (GET|HEAD)").unwrap()
+        );
+    }
+
+    #[test]
+    fn rule_cache_reuse() {
+        let (rule, path) = test_rule("rule-cache-reuse");
+        std::fs::remove_file(path).ok();
+        let rule = Rule {
+            filters: vec![settings::Filter {
+                pattern: r"^<HOST> (?P<path>\S+)$".to_owned(),
+                prefilter: None,
+            }],
+            blacklists: IndexMap::from_iter([(
+                "path".to_owned(),
+                IndexSet::from_iter(["/wp-login.php".to_owned()]),
+            )]),
+            ..rule
+        };
+        let limits = Limits::default();
+        let mut cache = RuleCache::default();
+
+        prepare_rule("a".to_owned(), rule.clone(), &limits, &mut cache).unwrap();
+        prepare_rule("b".to_owned(), rule, &limits, &mut cache).unwrap();
+        assert_eq!(2, cache.len());
+
+        cache.sweep();
+        assert_eq!(2, cache.len());
+
+        cache.sweep();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn leading_host_match() {
+        let (rule, path) = test_rule("leading-host-match");
+        std::fs::remove_file(path).ok();
+        let rule = Rule {
+            filters: vec![settings::Filter {
+                pattern: r#"^<HOST> - - \[<TIME>\] "GET (?P<path>\S+)"#.to_owned(),
+                prefilter: None,
+            }],
+            timeout: Duration::days(1),
+            blacklists: IndexMap::from_iter([(
+                "path".to_owned(),
+                IndexSet::from_iter(["/wp-login.php".to_owned()]),
+            )]),
+            host: HostSource::Leading,
+            ..rule
+        };
+        let entry = prepare_rule(
+            "web".to_owned(),
+            rule,
+            &Limits::default(),
+            &mut RuleCache::default(),
+        )
+        .unwrap();
+        let matcher = Matcher::with(datetime!(2020-07-04 12:00 UTC));
+        let mut last_time = OffsetDateTime::UNIX_EPOCH;
+
+        let found = matcher.find(
+            &entry,
+            &mut last_time,
+            r#"2001:db8::1 - - [04/Jul/2020:11:22:33 +0000] "GET /wp-login.php HTTP/1.1""#,
+        );
+        assert_eq!(Some("2001:db8::1".parse().unwrap()), found);
+    }
+
+    #[test]
+    fn block_with_hook() {
+        let (rule, path) = test_rule("block-with-hook");
+        std::fs::write(
+            &path,
+            "10.0.0.1 - - [01/Oct/2023:11:59:00 +0000] \"GET /wp-login.php HTTP/1.1\"\n",
+        )
+        .unwrap();
+
+        let rule = Rule {
+            filters: vec![settings::Filter {
+                pattern: r#"^<HOST> - - \[<TIME>\] "GET (?P<path>\S+)"#.to_owned(),
+                prefilter: None,
+            }],
+            ports: vec![443],
+            timeout: Duration::days(1),
+            blacklists: IndexMap::from_iter([(
+                "path".to_owned(),
+                IndexSet::from_iter(["/wp-login.php".to_owned()]),
+            )]),
+            ..rule
+        };
+        let mut files = RuleBuilder::new().rule("web", rule).build().unwrap();
+
+        let (tx, rx) = flume::unbounded();
+        let mut handler = Handler::builder(MemoryRepository::new(), MockFirewall::new())
+            .clock(|| datetime!(2023-10-01 12:00 UTC))
+            .on_block(move |rule, addr| tx.send((rule.to_owned(), addr)).unwrap())
+            .build();
+
+        for (entry, state) in files.values_mut() {
+            handler.handle_modified(entry, state).unwrap();
+        }
+
+        std::fs::remove_file(path).ok();
+
+        let addr = "10.0.0.1".parse::<IpAddr>().unwrap();
+        assert_eq!(
+            vec![FirewallCall::Block {
+                ip: addr.into(),
+                ports: vec![443]
+            }],
+            handler.firewall.calls()
+        );
+        assert_eq!(Some(("web".to_owned(), addr)), rx.try_recv().ok());
+        assert_eq!(1, handler.storage.count());
+    }
+
+    #[test]
+    fn clock_jump() {
+        let start = datetime!(2023-10-01 12:00 UTC);
+        let mut watch = ClockWatch::new(start);
+
+        assert_eq!(None, watch.jump(start + Duration::seconds(1)));
+        let jump = watch.jump(start - Duration::hours(2)).unwrap();
+        assert!((jump + Duration::hours(2)).abs() < Duration::seconds(2));
+    }
+
+    #[test]
+    fn repeated_offense() {
+        static NOW: AtomicI64 = AtomicI64::new(0);
+
+        let (rule, path) = test_rule("repeated-offense");
+        let files = RuleBuilder::new().rule("web", rule).build().unwrap();
+        std::fs::remove_file(path).ok();
+        let (entry, _) = files.values().next().unwrap();
+
+        NOW.store(
+            datetime!(2023-10-01 12:00 UTC).unix_timestamp(),
+            Ordering::Relaxed,
+        );
+        let mut handler = Handler::builder(MemoryRepository::new(), MockFirewall::new())
+            .clock(|| OffsetDateTime::from_unix_timestamp(NOW.load(Ordering::Relaxed)).unwrap())
+            .build();
+
+        let addr = "10.0.0.1".parse().unwrap();
+        assert!(handler.record_offense(entry, addr).unwrap().is_some());
+        assert!(handler.record_offense(entry, addr).unwrap().is_none());
+
+        NOW.store(
+            datetime!(2023-10-01 14:00 UTC).unix_timestamp(),
+            Ordering::Relaxed,
+        );
+        handler.matcher.refresh();
+        handler.unblock_with(|_| Some(entry)).unwrap();
+        assert!(handler.blocked.is_empty());
+
+        // Once unblocked, the next offense must reach the firewall again.
+        assert!(handler.record_offense(entry, addr).unwrap().is_some());
+    }
+
+    #[test]
+    fn failed_block() {
+        struct Failing;
+
+        impl Firewall for Failing {
+            fn install(&self) -> crate::Result<()> {
+                Ok(())
+            }
+
+            fn uninstall(&self) -> crate::Result<()> {
+                Ok(())
+            }
+
+            fn block(&self, _target: &Target<'_>) -> crate::Result<()> {
+                Err(crate::Error::Command {
+                    action: "blocking",
+                    stderr: "unavailable".to_owned(),
+                })
+            }
+
+            fn unblock(&self, _target: &Target<'_>) -> crate::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (rule, path) = test_rule("failed-block");
+        let files = RuleBuilder::new().rule("web", rule).build().unwrap();
+        std::fs::remove_file(path).ok();
+        let (entry, _) = files.values().next().unwrap();
+
+        let mut handler = Handler::builder(MemoryRepository::new(), Failing)
+            .clock(|| datetime!(2023-10-01 12:00 UTC))
+            .build();
+
+        let addr = "10.0.0.1".parse().unwrap();
+        handler.handle_offense(entry, addr).unwrap();
+        assert!(handler.blocked.is_empty());
+
+        // The block failed, so the next offense must reach the firewall again.
+        assert!(handler.record_offense(entry, addr).unwrap().is_some());
+    }
+
+    #[test]
+    fn ban_limit() {
+        static NOW: AtomicI64 = AtomicI64::new(0);
+
+        let (rule, path) = test_rule("ban-limit");
+        let rule = Rule {
+            max_bans_per_minute: NonZeroUsize::new(2),
+            ..rule
+        };
+        let files = RuleBuilder::new().rule("web", rule).build().unwrap();
+        std::fs::remove_file(path).ok();
+        let (entry, _) = files.values().next().unwrap();
+
+        NOW.store(
+            datetime!(2023-10-01 12:00 UTC).unix_timestamp(),
+            Ordering::Relaxed,
+        );
+        let mut handler = Handler::builder(MemoryRepository::new(), MockFirewall::new())
+            .clock(|| OffsetDateTime::from_unix_timestamp(NOW.load(Ordering::Relaxed)).unwrap())
+            .build();
+
+        let addrs = ["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.4"]
+            .map(|addr| addr.parse::<IpAddr>().unwrap());
+        let blocked = addrs
+            .iter()
+            .filter(|&&addr| handler.record_offense(entry, addr).unwrap().is_some())
+            .count();
+
+        assert_eq!(2, blocked);
+        assert_eq!(
+            vec![addrs[2], addrs[3]],
+            handler.deferred.keys().copied().collect::<Vec<_>>()
+        );
+
+        // Repeated offenses of queued IPs don't queue them twice.
+        assert!(handler.record_offense(entry, addrs[2]).unwrap().is_none());
+        assert_eq!(2, handler.deferred.len());
+
+        // Nothing is released while the limit is still reached.
+        handler.release_deferred(|_| Some(entry));
+        assert_eq!(2, handler.deferred.len());
+
+        NOW.store(
+            datetime!(2023-10-01 12:01 UTC).unix_timestamp(),
+            Ordering::Relaxed,
+        );
+        handler.matcher.refresh();
+        handler.release_deferred(|_| Some(entry));
+
+        assert!(handler.deferred.is_empty());
+        assert!(addrs.iter().all(|addr| handler.blocked.contains_key(addr)));
+        assert_eq!(
+            vec![
+                FirewallCall::Block {
+                    ip: addrs[2].into(),
+                    ports: Vec::new()
+                },
+                FirewallCall::Block {
+                    ip: addrs[3].into(),
+                    ports: Vec::new()
+                },
+            ],
+            handler.firewall.calls()
+        );
+    }
+
+    #[test]
+    fn huge_timeout() {
+        let (rule, path) = test_rule("huge-timeout");
+        let rule = Rule {
+            timeout: Duration::MAX,
+            ..rule
+        };
+        let files = RuleBuilder::new().rule("web", rule).build().unwrap();
+        std::fs::remove_file(path).ok();
+        let (entry, _) = files.values().next().unwrap();
+
+        let mut handler = Handler::builder(MemoryRepository::new(), MockFirewall::new())
+            .clock(|| datetime!(2023-10-01 12:00 UTC))
+            .build();
+
+        // The block ends at the latest representable time, instead of overflowing.
+        let addr = "10.0.0.1".parse().unwrap();
+        assert!(handler.record_offense(entry, addr).unwrap().is_some());
+        assert_eq!(Date::MAX, handler.blocked[&addr].date());
+    }
+
+    #[test]
+    fn bad_reputation() {
+        let (rule, path) = test_rule("reputation");
+        let rule = Rule {
+            reputation_timeout: Some(Duration::DAY),
+            ..rule
+        };
+        let files = RuleBuilder::new().rule("web", rule).build().unwrap();
+        std::fs::remove_file(path).ok();
+        let (entry, _) = files.values().next().unwrap();
+
+        let reputation = settings::Reputation {
+            file: Some(
+                std::env::temp_dir()
+                    .join(format!("veto-test-{}-reputation.json", std::process::id())),
+            ),
+            threshold: 2.0,
+            ..settings::Reputation::default()
+        };
+        let mut handler = Handler::builder(MemoryRepository::new(), MockFirewall::new())
+            .clock(|| datetime!(2023-10-01 12:00 UTC))
+            .reputation(Reputation::from_settings(reputation, None).unwrap())
+            .build();
+
+        // The first offense gets the regular timeout, and the second one the longer timeout.
+        let addr = "10.0.0.1".parse().unwrap();
+        let until = handler.record_offense(entry, addr).unwrap().unwrap();
+        assert_eq!(datetime!(2023-10-01 13:00 UTC), until);
+
+        handler.record_offense(entry, addr).unwrap();
+        assert_eq!(datetime!(2023-10-02 12:00 UTC), handler.blocked[&addr]);
+    }
+
+    #[test]
+    fn extend_firewall_timeouts() {
+        let (rule, path) = test_rule("firewall-timeouts");
+        let files = RuleBuilder::new().rule("web", rule).build().unwrap();
+        std::fs::remove_file(path).ok();
+        let (entry, _) = files.values().next().unwrap();
+        let addr = "10.0.0.1".parse().unwrap();
+
+        // Extending the block only reaches the firewall if it expires blocks by itself.
+
+        for (enabled, calls) in [(false, 1), (true, 2)] {
+            let mut handler = Handler::builder(MemoryRepository::new(), MockFirewall::new())
+                .clock(|| datetime!(2023-10-01 12:00 UTC))
+                .firewall_timeouts(enabled)
+                .build();
+
+            handler.handle_offense(entry, addr).unwrap();
+            handler.handle_offense(entry, addr).unwrap();
+            assert_eq!(calls, handler.firewall.calls().len());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_symlink() {
+        let dir = std::env::temp_dir().join(format!("veto-resolve-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let link = dir.join("current.log");
+        std::fs::write(dir.join("1.log"), "").unwrap();
+        std::os::unix::fs::symlink(dir.join("1.log"), &link).unwrap();
+
+        let dir = dir.canonicalize().unwrap();
+        assert_eq!(dir.join("current.log"), resolve_file(&link).unwrap());
+        assert_eq!(
+            dir.join("missing.log"),
+            resolve_file(&dir.join("missing.log")).unwrap()
+        );
+        assert!(resolve_file(&dir.join("missing/file.log")).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn deferred_crawler() {
+        struct NoRecords;
+
+        impl crate::crawler::Resolver for NoRecords {
+            fn reverse(&self, _ip: IpAddr) -> std::io::Result<String> {
+                Err(std::io::ErrorKind::NotFound.into())
+            }
+
+            fn forward(&self, _host: &str) -> std::io::Result<Vec<IpAddr>> {
+                Err(std::io::ErrorKind::NotFound.into())
+            }
+        }
+
+        let (rule, path) = test_rule("deferred-crawler");
+        std::fs::remove_file(path).ok();
+        let rule = Rule {
+            filters: vec![settings::Filter {
+                pattern: r#"^<HOST> - - \[<TIME>\] "GET (?P<path>\S+) [^"]*" "(?P<agent>[^"]*)""#
+                    .to_owned(),
+                prefilter: None,
+            }],
+            timeout: Duration::days(1),
+            blacklists: IndexMap::from_iter([(
+                "path".to_owned(),
+                IndexSet::from_iter(["/wp-login.php".to_owned()]),
+            )]),
+            crawler_group: Some("agent".to_owned()),
+            ..rule
+        };
+        let entry = prepare_rule(
+            "web".to_owned(),
+            rule,
+            &Limits::default(),
+            &mut RuleCache::default(),
+        )
+        .unwrap();
+
+        let mut handler = Handler::builder(MemoryRepository::new(), MockFirewall::new())
+            .clock(|| datetime!(2023-10-01 12:00 UTC))
+            .build();
+        handler.matcher = Matcher::with_clock(|| datetime!(2023-10-01 12:00 UTC))
+            .with_resolver(Box::new(NoRecords))
+            .with_deferred_crawlers();
+        let updates = handler.matcher.crawler_updates();
+
+        // The line is skipped while the IP is verified, instead of waiting for the lookups.
+        let line = r#"10.0.0.1 - - [01/Oct/2023:11:59:00 +0000] "GET /wp-login.php HTTP/1.1" "Googlebot/2.1""#;
+        let mut last_time = OffsetDateTime::UNIX_EPOCH;
+        assert_eq!(None, handler.matcher.find(&entry, &mut last_time, line));
+
+        updates
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap();
+        handler.release_crawlers(|_| Some(&entry));
+
+        assert_eq!(
+            vec![FirewallCall::Block {
+                ip: "10.0.0.1".parse().unwrap(),
+                ports: Vec::new()
+            }],
+            handler.firewall.calls()
+        );
+        assert!(handler.matcher.unverified_crawlers().is_empty());
+    }
+
+    #[test]
+    fn failed_offense() {
+        /// Storage that fails to save the second offense.
+        struct FailingStorage {
+            inner: MemoryRepository,
+            upserts: usize,
+        }
+
+        impl TargetRepository for FailingStorage {
+            fn upsert(&mut self, ip: IpAddr, until: OffsetDateTime, file: &Path) -> Result<bool> {
+                self.upserts += 1;
+                if self.upserts == 2 {
+                    return Err(std::io::Error::other("disk full").into());
+                }
+                self.inner.upsert(ip, until, file)
+            }
+
+            fn restore(&mut self, record: BanRecord) -> Result<()> {
+                self.inner.restore(record)
+            }
+
+            fn remove(&mut self, ip: IpAddr) -> Result<()> {
+                self.inner.remove(ip)
+            }
+
+            fn iter_active(&self, f: &mut dyn FnMut(IpAddr, &Path) -> Result<()>) -> Result<()> {
+                self.inner.iter_active(f)
+            }
+
+            fn iter_outdated(
+                &self,
+                f: &mut dyn FnMut(IpAddr, &Path) -> Result<bool>,
+            ) -> Result<()> {
+                self.inner.iter_outdated(f)
+            }
+
+            fn records(&self) -> Result<Vec<BanRecord>> {
+                self.inner.records()
+            }
+
+            fn count(&self) -> usize {
+                self.inner.count()
+            }
+
+            fn memory_usage(&self) -> usize {
+                self.inner.memory_usage()
+            }
+        }
+
+        let entry = prepare_builtin("web", "/var/log/web.log", Duration::hours(1)).unwrap();
+        let storage = FailingStorage {
+            inner: MemoryRepository::new(),
+            upserts: 0,
+        };
+        let mut handler = Handler::builder(storage, MockFirewall::new())
+            .clock(|| datetime!(2023-10-01 12:00 UTC))
+            .build();
+
+        let first = "10.0.0.1".parse().unwrap();
+        let result = handler.handle_offenses(&entry, [first, "10.0.0.2".parse().unwrap()]);
+        assert!(result.is_err());
+
+        // The first IP is remembered as blocked, so it must have reached the firewall as well.
+        assert!(handler.blocked.contains_key(&first));
+        assert_eq!(
+            vec![FirewallCall::Block {
+                ip: first.into(),
+                ports: Vec::new()
+            }],
+            handler.firewall.calls()
+        );
+    }
 }
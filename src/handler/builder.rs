@@ -0,0 +1,254 @@
+use std::{net::IpAddr, num::NonZeroUsize, path::PathBuf};
+
+use ipnetwork::IpNetwork;
+use log::warn;
+use time::{Duration, OffsetDateTime};
+
+use super::{prepare_rules, BanLimiter, ClockWatch, Entry, Handler, Hooks, RuleCache, State};
+use crate::{
+    events::EventBus,
+    firewall::Firewall,
+    local::LocalAddresses,
+    matcher::{Clock, Matcher},
+    reputation::Reputation,
+    settings::{Limits, Rule},
+    storage::TargetRepository,
+    tor::ExitList,
+    HashMap, IndexMap, Result,
+};
+
+/// Builder for a [`Handler`], with sensible defaults for everything except the storage and
+/// firewall.
+pub struct HandlerBuilder<TR, F> {
+    storage: TR,
+    firewall: F,
+    whitelist: Vec<IpNetwork>,
+    whitelist_local: bool,
+    clock: Clock,
+    watch_clock: bool,
+    unblock_delay: Duration,
+    unblock_jitter: Duration,
+    max_unblocks: Option<NonZeroUsize>,
+    max_bans_per_minute: Option<NonZeroUsize>,
+    reputation: Option<Reputation>,
+    tor: Option<ExitList>,
+    firewall_timeouts: bool,
+    hooks: Hooks,
+}
+
+impl<TR, F> HandlerBuilder<TR, F>
+where
+    TR: TargetRepository,
+    F: Firewall,
+{
+    pub fn new(storage: TR, firewall: F) -> Self {
+        Self {
+            storage,
+            firewall,
+            whitelist: Vec::new(),
+            whitelist_local: false,
+            clock: OffsetDateTime::now_utc,
+            watch_clock: true,
+            unblock_delay: Duration::ZERO,
+            unblock_jitter: Duration::ZERO,
+            max_unblocks: None,
+            max_bans_per_minute: None,
+            reputation: None,
+            tor: None,
+            firewall_timeouts: false,
+            hooks: Hooks::default(),
+        }
+    }
+
+    /// IP networks that are never blocked.
+    #[must_use]
+    pub fn whitelist(mut self, whitelist: Vec<IpNetwork>) -> Self {
+        self.whitelist = whitelist;
+        self
+    }
+
+    /// Never block the host's own interface addresses and default gateways, which are detected
+    /// when the handler is built and refreshed periodically. Disabled by default.
+    #[must_use]
+    pub const fn whitelist_local(mut self, enabled: bool) -> Self {
+        self.whitelist_local = enabled;
+        self
+    }
+
+    /// Source of the current time, which defaults to the system clock.
+    ///
+    /// Jumps of the system clock are detected and compensated for, by moving all blocks by the
+    /// same amount. A custom clock turns this off, as it may jump on purpose, like in tests.
+    #[must_use]
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self.watch_clock = false;
+        self
+    }
+
+    /// Time to wait before the first unblock, which is useful to let the initial scan finish
+    /// first. Unblocking is possible right away by default.
+    #[must_use]
+    pub const fn unblock_delay(mut self, delay: Duration) -> Self {
+        self.unblock_delay = delay;
+        self
+    }
+
+    /// Maximum random duration added to each block's timeout.
+    #[must_use]
+    pub const fn unblock_jitter(mut self, jitter: Duration) -> Self {
+        self.unblock_jitter = jitter;
+        self
+    }
+
+    /// Maximum amount of IPs to unblock at once. Unlimited by default.
+    #[must_use]
+    pub const fn max_unblocks(mut self, max: Option<NonZeroUsize>) -> Self {
+        self.max_unblocks = max;
+        self
+    }
+
+    /// Maximum amount of IPs to block per minute over all rules, in addition to the limits of
+    /// each rule. Further blocks are queued until the limits allow them. Unlimited by default.
+    #[must_use]
+    pub const fn max_bans_per_minute(mut self, max: Option<NonZeroUsize>) -> Self {
+        self.max_bans_per_minute = max;
+        self
+    }
+
+    /// Reputation of IPs, for rules that block IPs with a bad reputation for longer. Not tracked
+    /// by default.
+    #[must_use]
+    pub fn reputation(mut self, reputation: Option<Reputation>) -> Self {
+        self.reputation = reputation;
+        self
+    }
+
+    /// Known Tor exit nodes, for rules that treat them differently. Not known by default.
+    #[must_use]
+    pub fn tor_exits(mut self, exits: Option<ExitList>) -> Self {
+        self.tor = exits;
+        self
+    }
+
+    /// Whether the firewall unblocks IPs by itself once the timeout of their target passed, like
+    /// ipset with native timeouts. Extended blocks are sent to the firewall again then. Disabled
+    /// by default.
+    #[must_use]
+    pub const fn firewall_timeouts(mut self, enabled: bool) -> Self {
+        self.firewall_timeouts = enabled;
+        self
+    }
+
+    /// Callback for every newly blocked IP, with the name of the rule that matched.
+    #[must_use]
+    pub fn on_block(mut self, hook: impl FnMut(&str, IpAddr) + Send + 'static) -> Self {
+        self.hooks.on_block = Some(Box::new(hook));
+        self
+    }
+
+    /// Callback for every unblocked IP, with the name of the rule that originally blocked it.
+    #[must_use]
+    pub fn on_unblock(mut self, hook: impl FnMut(&str, IpAddr) + Send + 'static) -> Self {
+        self.hooks.on_unblock = Some(Box::new(hook));
+        self
+    }
+
+    #[must_use]
+    pub fn build(self) -> Handler<TR, F> {
+        let matcher = Matcher::with_clock(self.clock).with_deferred_crawlers();
+        let now = matcher.current_time();
+        let last_unblock = now.saturating_add(self.unblock_delay);
+
+        // Active entries are expected to be blocked already, like veto does on startup, unless
+        // blocking them failed.
+        let blocked = self.storage.active_records().map_or_else(
+            |e| {
+                warn!("failed loading active storage entries: {e}");
+                HashMap::default()
+            },
+            |records| {
+                records
+                    .into_iter()
+                    .filter(|r| r.active)
+                    .map(|r| (r.ip, r.until))
+                    .collect()
+            },
+        );
+
+        Handler {
+            whitelist: self.whitelist,
+            local: self.whitelist_local.then(LocalAddresses::detect),
+            matcher,
+            storage: self.storage,
+            firewall: self.firewall,
+            last_unblock,
+            unblock_jitter: self.unblock_jitter,
+            max_unblocks: self.max_unblocks,
+            hooks: self.hooks,
+            events: EventBus::default(),
+            blocked,
+            deferred: IndexMap::default(),
+            ban_limit: BanLimiter::new(self.max_bans_per_minute),
+            paused: false,
+            reputation: self.reputation,
+            tor: self.tor,
+            firewall_timeouts: self.firewall_timeouts,
+            clock_watch: self.watch_clock.then(|| ClockWatch::new(now)),
+        }
+    }
+}
+
+/// Builder that prepares rules for use with a [`Handler`], by compiling their filters and opening
+/// their log files.
+#[derive(Default)]
+pub struct RuleBuilder<'c> {
+    rules: Vec<(String, Rule)>,
+    limits: Limits,
+    cache: Option<&'c mut RuleCache>,
+}
+
+impl<'c> RuleBuilder<'c> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a single rule. A later rule replaces any earlier one with the same name.
+    #[must_use]
+    pub fn rule(mut self, name: impl Into<String>, rule: Rule) -> Self {
+        self.rules.push((name.into(), rule));
+        self
+    }
+
+    /// Add several rules at once, like the ones from the settings.
+    #[must_use]
+    pub fn rules(mut self, rules: impl IntoIterator<Item = (String, Rule)>) -> Self {
+        self.rules.extend(rules);
+        self
+    }
+
+    /// Limits for line lengths and regex sizes. No limits are applied by default.
+    #[must_use]
+    pub const fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Cache to reuse compiled filters from. A fresh cache is used by default.
+    #[must_use]
+    pub const fn cache(mut self, cache: &'c mut RuleCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Prepare all rules, keyed by the canonical path of their log file.
+    pub fn build(self) -> Result<HashMap<PathBuf, (Entry, State)>> {
+        let rules = self.rules.into_iter().collect::<HashMap<_, _>>();
+
+        match self.cache {
+            Some(cache) => prepare_rules(rules, &self.limits, cache),
+            None => prepare_rules(rules, &self.limits, &mut RuleCache::default()),
+        }
+    }
+}
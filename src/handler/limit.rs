@@ -0,0 +1,108 @@
+use std::{collections::VecDeque, num::NonZeroUsize};
+
+use time::{Duration, OffsetDateTime};
+
+use crate::HashMap;
+
+/// Length of the window that the ban limits apply to.
+const WINDOW: Duration = Duration::MINUTE;
+
+/// Caps the amount of new blocks per minute, over all rules and for each rule on its own.
+///
+/// This is a safety valve against misconfigured filters, that suddenly match lots of legitimate
+/// clients. Only blocks that count towards a limit are remembered, so nothing is kept without any
+/// limits.
+#[derive(Debug, Default)]
+pub(super) struct BanLimiter {
+    max: Option<NonZeroUsize>,
+    global: VecDeque<OffsetDateTime>,
+    rules: HashMap<String, VecDeque<OffsetDateTime>>,
+}
+
+impl BanLimiter {
+    pub fn new(max: Option<NonZeroUsize>) -> Self {
+        Self {
+            max,
+            ..Self::default()
+        }
+    }
+
+    /// Count a new block of the rule, if neither the global limit nor the rule's limit is reached
+    /// within the last minute. Returns whether the block is allowed.
+    pub fn try_acquire(
+        &mut self,
+        rule: &str,
+        rule_max: Option<NonZeroUsize>,
+        now: OffsetDateTime,
+    ) -> bool {
+        if self.max.is_none() && rule_max.is_none() {
+            return true;
+        }
+
+        let global_full = self
+            .max
+            .is_some_and(|max| is_full(&mut self.global, max, now));
+        let rule_full = rule_max
+            .is_some_and(|max| is_full(self.rules.entry(rule.to_owned()).or_default(), max, now));
+
+        if global_full || rule_full {
+            return false;
+        }
+
+        if self.max.is_some() {
+            self.global.push_back(now);
+        }
+        if rule_max.is_some() {
+            self.rules
+                .entry(rule.to_owned())
+                .or_default()
+                .push_back(now);
+        }
+
+        true
+    }
+}
+
+/// Drop the blocks that left the window, and tell whether the remaining ones reach the limit.
+fn is_full(bans: &mut VecDeque<OffsetDateTime>, max: NonZeroUsize, now: OffsetDateTime) -> bool {
+    while bans
+        .front()
+        .is_some_and(|&at| at <= now.saturating_sub(WINDOW))
+    {
+        bans.pop_front();
+    }
+
+    bans.len() >= max.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+
+    #[test]
+    fn limits() {
+        let two = NonZeroUsize::new(2);
+        let three = NonZeroUsize::new(3);
+        let start = datetime!(2023-10-01 12:00 UTC);
+        let mut limiter = BanLimiter::new(three);
+
+        assert!(limiter.try_acquire("web", two, start));
+        assert!(limiter.try_acquire("web", two, start));
+        assert!(!limiter.try_acquire("web", two, start));
+
+        // The global limit is shared between all rules.
+        assert!(limiter.try_acquire("ssh", None, start));
+        assert!(!limiter.try_acquire("ssh", None, start));
+
+        // Blocks leave the window after a minute.
+        let later = start + Duration::MINUTE;
+        assert!(limiter.try_acquire("web", two, later));
+        assert!(limiter.try_acquire("ssh", None, later));
+
+        let mut unlimited = BanLimiter::default();
+        assert!((0..100).all(|_| unlimited.try_acquire("web", None, start)));
+        assert!(unlimited.global.is_empty());
+    }
+}
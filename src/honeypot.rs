@@ -0,0 +1,148 @@
+//! Decoy ports, that block any IP connecting to them.
+//!
+//! No real service listens on these ports, so connections come from scanners looking for open
+//! telnet, RDP or VNC ports. They're blocked right away, before they ever reach the log of a real
+//! service. Connections are closed without sending anything back, and blocks go through the handler
+//! like those of any rule, under the rule name [`RULE_NAME`].
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener},
+    path::Path,
+    thread,
+};
+
+use flume::{Receiver, Sender, TrySendError};
+use log::{debug, info};
+
+use crate::{
+    handler::{prepare_builtin, Entry},
+    settings, Result,
+};
+
+/// Name of the honeypot's rule in logs and events.
+pub const RULE_NAME: &str = "honeypot";
+/// Identifies the honeypot's blocks in the storage, in place of the log file of a rule.
+pub const STORAGE_PATH: &str = "<honeypot>";
+/// Maximum amount of connections waiting to be handled. Any further ones are dropped, so a flood
+/// of connections can't exhaust the memory.
+const QUEUE_SIZE: usize = 1024;
+
+/// Listeners on the decoy ports.
+pub struct Honeypot {
+    /// Rule that blocks the connecting IPs, with the timeout of the settings.
+    pub entry: Entry,
+    /// IPs that connected to any of the ports.
+    pub connections: Receiver<IpAddr>,
+}
+
+impl Honeypot {
+    /// Start listening on all ports of the settings, or return [`None`] if there are none.
+    pub fn start(settings: &settings::Honeypot) -> Result<Option<Self>> {
+        if settings.ports.is_empty() {
+            return Ok(None);
+        }
+
+        let entry = prepare_builtin(RULE_NAME, STORAGE_PATH, settings.timeout)?;
+
+        let (tx, rx) = flume::bounded(QUEUE_SIZE);
+
+        for &port in &settings.ports {
+            let listener = bind(settings.address, port)?;
+            let tx = tx.clone();
+
+            thread::Builder::new()
+                .name(format!("honeypot-{port}"))
+                .spawn(move || accept(&listener, &tx))?;
+        }
+
+        info!("honeypot listening on ports {:?}", settings.ports);
+
+        Ok(Some(Self {
+            entry,
+            connections: rx,
+        }))
+    }
+
+    /// The honeypot's rule, if the path identifies its blocks in the storage.
+    #[must_use]
+    pub fn rule(&self, path: &Path) -> Option<&Entry> {
+        (self.entry.rule.file == path).then_some(&self.entry)
+    }
+}
+
+/// Listen on the port of the given address, or of all IPv4 and IPv6 addresses if not set.
+pub(crate) fn bind(address: Option<IpAddr>, port: u16) -> std::io::Result<TcpListener> {
+    if let Some(address) = address {
+        return TcpListener::bind((address, port));
+    }
+
+    // Listening on IPv6 accepts IPv4 connections as well, unless IPv6 is disabled.
+    TcpListener::bind((Ipv6Addr::UNSPECIFIED, port))
+        .or_else(|_| TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)))
+}
+
+/// Accept connections and pass on the IP of each peer, until the receiver is gone.
+fn accept(listener: &TcpListener, connections: &Sender<IpAddr>) {
+    let port = listener.local_addr().map_or(0, |addr| addr.port());
+
+    for stream in listener.incoming() {
+        // The stream is dropped right away, which closes the connection.
+        let ip = match stream.and_then(|stream| stream.peer_addr()) {
+            Ok(peer) => peer.ip().to_canonical(),
+            Err(e) => {
+                debug!("failed accepting honeypot connection: {e}");
+                continue;
+            }
+        };
+
+        debug!("honeypot connection from {ip} on port {port}");
+
+        match connections.try_send(ip) {
+            Ok(()) | Err(TrySendError::Full(_)) => {}
+            Err(TrySendError::Disconnected(_)) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpStream, time::Duration};
+
+    use ipnetwork::IpNetwork;
+
+    use super::*;
+    use crate::{
+        handler::Handler,
+        testing::{MemoryRepository, MockFirewall},
+    };
+
+    #[test]
+    fn block_connections() {
+        assert!(Honeypot::start(&settings::Honeypot::default())
+            .unwrap()
+            .is_none());
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = flume::bounded(1);
+        thread::spawn(move || accept(&listener, &tx));
+
+        TcpStream::connect(addr).unwrap();
+        let ip = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(IpAddr::from(Ipv4Addr::LOCALHOST), ip);
+
+        let settings = settings::Honeypot {
+            ports: vec![0],
+            address: Some(Ipv4Addr::LOCALHOST.into()),
+            ..settings::Honeypot::default()
+        };
+        let honeypot = Honeypot::start(&settings).unwrap().unwrap();
+        assert!(honeypot.rule(Path::new(STORAGE_PATH)).is_some());
+
+        let mut handler = Handler::builder(MemoryRepository::new(), MockFirewall::new()).build();
+        handler.handle_offense(&honeypot.entry, ip).unwrap();
+
+        assert!(handler.firewall.blocked().contains(&IpNetwork::from(ip)));
+        assert!(handler.storage.until(ip).is_some());
+    }
+}
@@ -0,0 +1,306 @@
+//! Extractors for the client IP of matching log lines, which are selected per rule with its
+//! `host` setting.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv6Addr},
+    num::NonZeroUsize,
+    sync::Arc,
+};
+
+use serde_json::Value;
+
+use crate::{settings::HostSource, Error, HashMap, Result};
+
+/// Name of the capture group that the `<HOST>` placeholder creates.
+pub const HOST_GROUP: &str = "host";
+
+/// A line that matched a filter, together with the capture groups of the filter.
+pub struct HostContext<'a, 'l> {
+    line: &'l str,
+    group: &'a dyn Fn(&str) -> Option<&'l str>,
+}
+
+impl<'a, 'l> HostContext<'a, 'l> {
+    pub(crate) fn new(line: &'l str, group: &'a dyn Fn(&str) -> Option<&'l str>) -> Self {
+        Self { line, group }
+    }
+
+    /// The full log line.
+    #[must_use]
+    pub const fn line(&self) -> &'l str {
+        self.line
+    }
+
+    /// Content of the named capture group, or [`None`] if it didn't participate in the match.
+    #[must_use]
+    pub fn group(&self, name: &str) -> Option<&'l str> {
+        (self.group)(name)
+    }
+}
+
+/// Strategy to find the client IP in a line that matched a filter.
+pub trait HostExtractor: Send + Sync {
+    /// Extract the IP, or return [`None`] if the line doesn't contain a valid one.
+    fn extract(&self, ctx: &HostContext<'_, '_>) -> Option<IpAddr>;
+}
+
+impl<F> HostExtractor for F
+where
+    F: Fn(&HostContext<'_, '_>) -> Option<IpAddr> + Send + Sync,
+{
+    fn extract(&self, ctx: &HostContext<'_, '_>) -> Option<IpAddr> {
+        self(ctx)
+    }
+}
+
+/// Take the IP from a named capture group of the filter.
+pub struct Group(pub String);
+
+impl HostExtractor for Group {
+    fn extract(&self, ctx: &HostContext<'_, '_>) -> Option<IpAddr> {
+        ctx.group(&self.0).and_then(parse_host)
+    }
+}
+
+/// Take the IP from the start of the line, up to the first space.
+pub struct Leading;
+
+impl HostExtractor for Leading {
+    #[inline]
+    fn extract(&self, ctx: &HostContext<'_, '_>) -> Option<IpAddr> {
+        parse_host(leading_host(ctx.line))
+    }
+}
+
+/// Find the client IP at the start of the line, which ends at the first space.
+#[inline]
+fn leading_host(line: &str) -> &str {
+    let end = memchr::memchr(b' ', line.as_bytes()).unwrap_or(line.len());
+    &line[..end]
+}
+
+/// Take the IP from a whitespace separated column of the line, counting from 1.
+pub struct Column(pub NonZeroUsize);
+
+impl HostExtractor for Column {
+    fn extract(&self, ctx: &HostContext<'_, '_>) -> Option<IpAddr> {
+        ctx.line
+            .split_whitespace()
+            .nth(self.0.get() - 1)
+            .and_then(parse_host)
+    }
+}
+
+/// Take the IP from a string field of lines that are JSON objects. Nested fields are separated
+/// by dots, like `client.ip`.
+pub struct Json(Vec<String>);
+
+impl Json {
+    #[must_use]
+    pub fn new(path: &str) -> Self {
+        Self(path.split('.').map(ToOwned::to_owned).collect())
+    }
+}
+
+impl HostExtractor for Json {
+    fn extract(&self, ctx: &HostContext<'_, '_>) -> Option<IpAddr> {
+        let value = serde_json::from_str::<Value>(ctx.line).ok()?;
+
+        self.0
+            .iter()
+            .try_fold(&value, |value, key| value.get(key))?
+            .as_str()
+            .and_then(parse_host)
+    }
+}
+
+/// Take the IP from an extension key of lines in the Common Event Format (CEF), like `src`.
+pub struct Cef(pub String);
+
+impl HostExtractor for Cef {
+    fn extract(&self, ctx: &HostContext<'_, '_>) -> Option<IpAddr> {
+        cef_extension(ctx.line)?
+            .split_whitespace()
+            .filter_map(|pair| pair.split_once('='))
+            .find_map(|(key, value)| (key == self.0).then_some(value))
+            .and_then(parse_host)
+    }
+}
+
+/// Get the extension part of a CEF line, which follows the seven header fields. Pipes within the
+/// header can be escaped with a backslash.
+fn cef_extension(line: &str) -> Option<&str> {
+    let mut fields = 0;
+    let mut escaped = false;
+
+    for (i, b) in line.bytes().enumerate() {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' => escaped = true,
+            b'|' => {
+                fields += 1;
+                if fields == 7 {
+                    return Some(&line[i + 1..]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+/// Parse the IP of a host, which may be written in other forms than the plain address.
+///
+/// IPv6 addresses can be wrapped in brackets, like `[2001:db8::1]` or `[2001:db8::1]:443` with a
+/// port, and link-local ones can have a zone ID, like `fe80::1%eth0`.
+///
+/// The zone ID is dropped, as the firewall blocks an address on all interfaces. Link-local
+/// addresses are otherwise treated like any other address, as an offense from the local network is
+/// still an offense. They can be whitelisted with `fe80::/10` and `169.254.0.0/16` if that's not
+/// wanted.
+#[must_use]
+pub fn parse_host(host: &str) -> Option<IpAddr> {
+    if let Ok(ip) = host.parse() {
+        return Some(ip);
+    }
+
+    let host = match host.strip_prefix('[') {
+        Some(host) => {
+            let (host, rest) = host.split_once(']')?;
+            let port = rest.strip_prefix(':');
+            if !rest.is_empty() && port.is_none_or(|port| port.parse::<u16>().is_err()) {
+                return None;
+            }
+            host
+        }
+        None => host,
+    };
+
+    match host.split_once('%') {
+        Some((host, zone)) if !zone.is_empty() => host.parse::<Ipv6Addr>().ok().map(IpAddr::V6),
+        Some(_) => None,
+        None => host.parse().ok(),
+    }
+}
+
+/// Registry of named host extractors, that rules can refer to in addition to the built-in
+/// [`HostSource`]s.
+#[derive(Clone, Default)]
+pub struct HostExtractors {
+    extractors: HashMap<String, Arc<dyn HostExtractor>>,
+}
+
+impl fmt::Debug for HostExtractors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.extractors.keys()).finish()
+    }
+}
+
+impl HostExtractors {
+    /// Add an extractor under the given name, replacing any existing one.
+    pub fn register(&mut self, name: impl Into<String>, extractor: impl HostExtractor + 'static) {
+        self.extractors.insert(name.into(), Arc::new(extractor));
+    }
+
+    /// Get the extractor for a host source.
+    pub fn get(&self, source: &HostSource) -> Result<Arc<dyn HostExtractor>> {
+        Ok(match source {
+            HostSource::Capture => Arc::new(Group(HOST_GROUP.to_owned())),
+            HostSource::Leading => Arc::new(Leading),
+            HostSource::Group(name) => Arc::new(Group(name.clone())),
+            HostSource::Column(column) => Arc::new(Column(*column)),
+            HostSource::Json(path) => Arc::new(Json::new(path)),
+            HostSource::Cef(key) => Arc::new(Cef(key.clone())),
+            HostSource::Custom(name) => self
+                .extractors
+                .get(name)
+                .cloned()
+                .ok_or_else(|| Error::UnknownHostExtractor(name.clone()))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn extract(source: &HostSource, line: &str) -> Option<IpAddr> {
+        let mut extractors = HostExtractors::default();
+        extractors.register("fixed", |_: &HostContext<'_, '_>| {
+            Some(IpAddr::V4(Ipv4Addr::LOCALHOST))
+        });
+
+        let group = |name: &str| (name == "client").then_some("10.0.0.2");
+        extractors
+            .get(source)
+            .unwrap()
+            .extract(&HostContext::new(line, &group))
+    }
+
+    #[test]
+    fn builtin() {
+        let ip = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+
+        assert_eq!(ip, extract(&HostSource::Leading, "10.0.0.1 - - [...]"));
+        assert_eq!(
+            ip,
+            extract(
+                &HostSource::Column(NonZeroUsize::new(3).unwrap()),
+                "2023-10-01 12:00:00 10.0.0.1 GET /"
+            )
+        );
+        assert_eq!(
+            ip,
+            extract(
+                &HostSource::Json("client.ip".to_owned()),
+                r#"{"client":{"ip":"10.0.0.1"},"path":"/"}"#
+            )
+        );
+        assert_eq!(
+            ip,
+            extract(
+                &HostSource::Cef("src".to_owned()),
+                r"CEF:0|Vendor|Prod\|uct|1.0|100|src=1.1.1.1 login|5|suser=bob src=10.0.0.1"
+            )
+        );
+        assert_eq!(
+            Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))),
+            extract(&HostSource::Group("client".to_owned()), "")
+        );
+        assert_eq!(None, extract(&HostSource::Capture, ""));
+    }
+
+    #[test]
+    fn host_forms() {
+        let ip = |value: &str| value.parse::<IpAddr>().ok();
+
+        assert_eq!(ip("10.0.0.1"), parse_host("10.0.0.1"));
+        assert_eq!(ip("2001:db8::1"), parse_host("[2001:db8::1]"));
+        assert_eq!(ip("2001:db8::1"), parse_host("[2001:db8::1]:443"));
+        assert_eq!(ip("fe80::1"), parse_host("fe80::1%eth0"));
+        assert_eq!(ip("fe80::1"), parse_host("[fe80::1%25]:22"));
+        assert_eq!(ip("10.0.0.1"), parse_host("[10.0.0.1]"));
+
+        assert_eq!(None, parse_host("10.0.0.1%eth0"));
+        assert_eq!(None, parse_host("fe80::1%"));
+        assert_eq!(None, parse_host("[2001:db8::1"));
+        assert_eq!(None, parse_host("[2001:db8::1]:http"));
+        assert_eq!(None, parse_host("2001:db8::1]"));
+    }
+
+    #[test]
+    fn custom() {
+        assert_eq!(
+            Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            extract(&HostSource::Custom("fixed".to_owned()), "")
+        );
+        assert!(matches!(
+            HostExtractors::default().get(&HostSource::Custom("unknown".to_owned())),
+            Err(Error::UnknownHostExtractor(_))
+        ));
+    }
+}
@@ -1,18 +1,58 @@
 #![forbid(unsafe_code)]
 #![deny(rust_2018_idioms, clippy::all, clippy::pedantic)]
 #![warn(clippy::nursery)]
+// Duration and integer helpers like `from_mins` and `is_multiple_of` are left out, as they need a
+// much newer Rust version than the rest of the code.
 #![allow(
+    clippy::duration_suboptimal_units,
+    clippy::manual_is_multiple_of,
     clippy::manual_let_else,
     clippy::missing_errors_doc,
     clippy::module_name_repetitions
 )]
 
+pub use self::error::{Error, Result};
+
+pub mod audit;
+pub mod bench;
+pub mod breaker;
+pub mod crawler;
+pub mod diagnose;
+pub mod doctor;
+pub mod ecs;
+pub mod enrich;
+mod error;
+pub mod events;
 pub mod firewall;
+#[cfg(feature = "geoip")]
+pub mod geoip;
 pub mod handler;
+pub mod honeypot;
+pub mod host;
+pub mod lint;
+pub mod local;
 pub mod matcher;
+pub mod metrics;
+pub mod mqtt;
 pub mod notifier;
+pub mod pipeline;
+#[cfg(feature = "wasm")]
+pub mod plugin;
+pub mod progress;
+pub mod reader;
+pub mod reputation;
+pub mod seek;
 pub mod settings;
+pub mod simulation;
+pub mod statsd;
 pub mod storage;
+pub mod tailer;
+pub mod tarpit;
+pub mod testing;
+pub mod timestamp;
+pub mod tor;
+pub mod watchdog;
+pub mod wizard;
 
 type HashMap<K, V, S = ahash::RandomState> = std::collections::HashMap<K, V, S>;
 type IndexMap<K, V, S = ahash::RandomState> = indexmap::IndexMap<K, V, S>;
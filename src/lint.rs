@@ -0,0 +1,345 @@
+//! Validation of a configuration file, that reports every problem found instead of stopping at the
+//! first one, so a configuration can be fixed before the running instance is restarted with it.
+
+use std::{
+    error::Error as StdError,
+    fmt::{self, Display},
+    fs, io,
+    path::Path,
+};
+
+use serde::Serialize;
+
+use crate::{
+    bench,
+    handler::{self, Entry, RuleCache},
+    settings::{Protocol, Rule, Settings},
+};
+
+/// Severity of a problem in the configuration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The configuration loads, but likely doesn't do what was intended.
+    Warning,
+    /// veto won't start with this configuration.
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::Warning => "warning",
+            Self::Error => "error",
+        })
+    }
+}
+
+/// A single problem found in the configuration.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    pub severity: Severity,
+    /// Where the problem is, like `rules.web.filters`.
+    pub location: String,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(severity: Severity, location: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Check the configuration at the given location for syntax errors, unknown keys, firewalls
+/// without settings, invalid filters and missing log files.
+///
+/// Rules are only checked if the whole file could be parsed, as they can't be read otherwise.
+#[must_use]
+pub fn run(config: &Path) -> Vec<Problem> {
+    let content = match fs::read_to_string(config) {
+        Ok(content) => content,
+        Err(e) => {
+            return vec![Problem::new(
+                Severity::Error,
+                config.display().to_string(),
+                format!("can't read the file: {e}"),
+            )]
+        }
+    };
+
+    let settings = match basic_toml::from_str::<Settings>(&content) {
+        Ok(settings) => settings,
+        Err(e) => return vec![Problem::new(Severity::Error, "", e.to_string())],
+    };
+
+    let mut problems = unknown_keys(&content);
+    problems.extend(check_firewall(&settings));
+    problems.extend(check_rules(&settings));
+    problems
+}
+
+/// Find keys that the settings don't know about, which are silently ignored while loading them.
+/// These are mostly typos, like `timout` instead of `timeout`.
+fn unknown_keys(content: &str) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    // The TOML deserializer can't be wrapped directly, so the content goes through a generic value.
+    let Ok(value) = basic_toml::from_str::<serde_json::Value>(content) else {
+        return problems;
+    };
+
+    let _ = serde_ignored::deserialize::<_, _, Settings>(value, |path| {
+        problems.push(Problem::new(
+            Severity::Warning,
+            path.to_string(),
+            "unknown key, it's ignored",
+        ));
+    });
+
+    problems
+}
+
+/// Find firewall backends without their settings section, which veto can't create.
+fn check_firewall(settings: &Settings) -> Vec<Problem> {
+    settings
+        .firewall
+        .backends
+        .iter()
+        .filter(|backend| !backend.is_configured(settings))
+        .map(|backend| {
+            Problem::new(
+                Severity::Error,
+                "firewall.backends",
+                format!("the {0} firewall needs a [{0}] section", backend.section()),
+            )
+        })
+        .collect()
+}
+
+fn check_rules(settings: &Settings) -> Vec<Problem> {
+    let mut cache = RuleCache::default();
+    let mut problems = Vec::new();
+
+    let mut rules = settings.rules.iter().collect::<Vec<_>>();
+    rules.sort_by_key(|(name, _)| *name);
+
+    for (name, rule) in rules {
+        let location = format!("rules.{name}");
+
+        match fs::File::open(&rule.file) {
+            Ok(_) => {}
+            // Missing files are waited for, as long as their directory exists.
+            Err(e) if e.kind() == io::ErrorKind::NotFound && parent_exists(&rule.file) => {
+                problems.push(Problem::new(
+                    Severity::Warning,
+                    format!("{location}.file"),
+                    format!("{} doesn't exist yet", rule.file.display()),
+                ));
+            }
+            Err(e) => problems.push(Problem::new(
+                Severity::Error,
+                format!("{location}.file"),
+                format!("can't read {}: {e}", rule.file.display()),
+            )),
+        }
+
+        if rule.protocol == Protocol::All && !rule.ports.is_empty() {
+            problems.push(Problem::new(
+                Severity::Warning,
+                format!("{location}.ports"),
+                "ports are ignored, as all protocols are blocked",
+            ));
+        }
+
+        if rule.filters.is_empty() && rule.plugins.is_empty() {
+            problems.push(Problem::new(
+                Severity::Warning,
+                format!("{location}.filters"),
+                "no filters, the rule never matches",
+            ));
+        }
+
+        match handler::prepare_rule(name.clone(), rule.clone(), &settings.limits, &mut cache) {
+            Ok(entry) => {
+                problems.extend(check_blacklists(&entry, &location));
+                problems.extend(check_stress(&entry, &location, settings));
+            }
+            Err(_) => problems.extend(locate_errors(name, rule, settings, &mut cache)),
+        }
+    }
+
+    problems
+}
+
+fn parent_exists(path: &Path) -> bool {
+    path.parent()
+        .is_some_and(|dir| dir.as_os_str().is_empty() || dir.is_dir())
+}
+
+/// Find blacklists of groups that no filter captures, which can never match.
+fn check_blacklists(entry: &Entry, location: &str) -> Vec<Problem> {
+    if entry.matchers.is_empty() {
+        return Vec::new();
+    }
+
+    entry
+        .blacklists
+        .keys()
+        .filter(|group| {
+            !entry.matchers.iter().any(|filter| {
+                filter
+                    .regex
+                    .capture_names()
+                    .any(|name| name == Some(group.as_str()))
+            })
+        })
+        .map(|group| {
+            Problem::new(
+                Severity::Warning,
+                format!("{location}.blacklists.{group}"),
+                "no filter captures this group, so the blacklist never matches",
+            )
+        })
+        .collect()
+}
+
+/// Find filters that take long for lines crafted to be expensive, which an attacker could use to
+/// keep veto busy.
+fn check_stress(entry: &Entry, location: &str, settings: &Settings) -> Vec<Problem> {
+    bench::stress(entry, settings.limits.max_line_length)
+        .into_iter()
+        .filter(bench::StressReport::is_risky)
+        .map(|report| {
+            Problem::new(
+                Severity::Warning,
+                format!("{location}.filters[{}]", report.index),
+                format!(
+                    "takes {:?} for a crafted line, a flood of such lines could pin the CPU",
+                    report.worst
+                ),
+            )
+        })
+        .collect()
+}
+
+/// Prepare each filter on its own and the rest of the rule without filters, to point at every
+/// part of the rule that fails, instead of only the first one.
+fn locate_errors(
+    name: &str,
+    rule: &Rule,
+    settings: &Settings,
+    cache: &mut RuleCache,
+) -> Vec<Problem> {
+    let location = format!("rules.{name}");
+    let mut problems = Vec::new();
+
+    for (i, filter) in rule.filters.iter().enumerate() {
+        let mut single = rule.clone();
+        single.filters = vec![filter.clone()];
+        single.blacklists.clear();
+        single.plugins.clear();
+
+        if let Err(e) = handler::prepare_rule(name.to_owned(), single, &settings.limits, cache) {
+            problems.push(Problem::new(
+                Severity::Error,
+                format!("{location}.filters[{i}]"),
+                error_chain(&e),
+            ));
+        }
+    }
+
+    let mut rest = rule.clone();
+    rest.filters.clear();
+    if let Err(e) = handler::prepare_rule(name.to_owned(), rest, &settings.limits, cache) {
+        problems.push(Problem::new(Severity::Error, location, error_chain(&e)));
+    }
+
+    problems
+}
+
+/// Describe the error together with all its sources, as the top-level message alone is often too
+/// generic, like `invalid filter pattern`.
+fn error_chain(e: &dyn StdError) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+
+    while let Some(e) = source {
+        message.push_str(": ");
+        message.push_str(&e.to_string());
+        source = e.source();
+    }
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn problems() {
+        let dir = env::temp_dir().join(format!("veto-lint-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("config.toml");
+        fs::write(
+            &config,
+            format!(
+                r#"
+            [firewall]
+            backends = ["ipset", "pf"]
+
+            [rules.web]
+            file = "{}"
+            timout = "1h"
+            timeout = "1h"
+            filters = ["^<HOST> (?P<path>\\S+)$"]
+
+            [rules.web.blacklists]
+            path = ["/wp-login.php"]
+            agent = ["curl"]
+
+            [rules.ssh]
+            file = "/nonexistent/auth.log"
+            ports = [22]
+            protocol = "all"
+            timeout = "1h"
+            filters = ["^<HOST> failed$", "^<HOST> (unclosed$"]
+            "#,
+                dir.join("missing.log").display()
+            ),
+        )
+        .unwrap();
+
+        let problems = run(&config);
+        let locations = problems
+            .iter()
+            .map(|p| (p.severity, p.location.as_str()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                (Severity::Warning, "rules.web.timout"),
+                (Severity::Error, "firewall.backends"),
+                (Severity::Error, "rules.ssh.file"),
+                (Severity::Warning, "rules.ssh.ports"),
+                (Severity::Error, "rules.ssh.filters[1]"),
+                (Severity::Warning, "rules.web.file"),
+                (Severity::Warning, "rules.web.blacklists.agent"),
+            ],
+            locations
+        );
+
+        fs::write(&config, "[rules.web]\nfile = 5\n").unwrap();
+        let problems = run(&config);
+        assert_eq!(1, problems.len());
+        assert_eq!(Severity::Error, problems[0].severity);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+//! Addresses of the host itself, which are implicitly whitelisted so veto never blocks the server
+//! or its gateway, for example when a misbehaving local service shows up in a log.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+/// Time after which the addresses are enumerated again, as interfaces can change while running.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// The host's own interface addresses and default gateways.
+#[derive(Debug, Default)]
+pub struct LocalAddresses {
+    addrs: Vec<IpAddr>,
+    refreshed: Option<Instant>,
+    /// Whether the last enumeration failed.
+    failed: bool,
+}
+
+impl LocalAddresses {
+    /// Enumerate the addresses of the host right away.
+    #[must_use]
+    pub fn detect() -> Self {
+        let mut local = Self::default();
+        local.refresh();
+        local
+    }
+
+    /// Enumerate the addresses again, keeping the previous ones if that fails.
+    pub fn refresh(&mut self) {
+        self.refreshed = Some(Instant::now());
+
+        let mut addrs = match if_addrs::get_if_addrs() {
+            Ok(interfaces) => interfaces.into_iter().map(|i| i.ip()).collect::<Vec<_>>(),
+            Err(e) => {
+                warn!("failed listing network interfaces: {e}");
+                self.failed = true;
+                return;
+            }
+        };
+        self.failed = false;
+
+        addrs.extend(gateways());
+        addrs.sort_unstable();
+        addrs.dedup();
+
+        if addrs != self.addrs {
+            debug!("local addresses: {addrs:?}");
+            self.addrs = addrs;
+        }
+    }
+
+    /// Enumerate the addresses again, if the last time was long enough ago.
+    pub fn refresh_if_due(&mut self) {
+        if self
+            .refreshed
+            .is_none_or(|at| at.elapsed() >= REFRESH_INTERVAL)
+        {
+            self.refresh();
+        }
+    }
+
+    /// Whether the IP is one of the host's own addresses or a default gateway.
+    #[must_use]
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.addrs.binary_search(&ip).is_ok()
+    }
+
+    /// Whether the addresses couldn't be enumerated the last time, so the known ones may be
+    /// outdated or missing.
+    #[must_use]
+    pub const fn is_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// All known addresses, in ascending order.
+    #[must_use]
+    pub fn addrs(&self) -> &[IpAddr] {
+        &self.addrs
+    }
+}
+
+/// Default gateways of the host, from the kernel's routing tables.
+#[cfg(target_os = "linux")]
+fn gateways() -> Vec<IpAddr> {
+    let read = |path| std::fs::read_to_string(path).unwrap_or_default();
+
+    let mut gateways = parse_routes(&read("/proc/net/route"));
+    gateways.extend(parse_routes_v6(&read("/proc/net/ipv6_route")));
+    gateways
+}
+
+#[cfg(not(target_os = "linux"))]
+fn gateways() -> Vec<IpAddr> {
+    Vec::new()
+}
+
+/// Find the gateways of the default routes in `/proc/net/route`. Addresses are written as
+/// hexadecimal numbers in the byte order of the host.
+fn parse_routes(content: &str) -> Vec<IpAddr> {
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            let (destination, gateway) = (fields.next()?, fields.next()?);
+            let gateway = u32::from_str_radix(gateway, 16).ok()?;
+
+            (destination == "00000000" && gateway != 0)
+                .then(|| IpAddr::V4(Ipv4Addr::from(gateway.to_ne_bytes())))
+        })
+        .collect()
+}
+
+/// Find the next hops of the default routes in `/proc/net/ipv6_route`. Unlike IPv4, addresses are
+/// written in network byte order.
+fn parse_routes_v6(content: &str) -> Vec<IpAddr> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (destination, prefix, next_hop) = (fields.first()?, fields.get(1)?, fields.get(4)?);
+            let next_hop = u128::from_str_radix(next_hop, 16).ok()?;
+
+            (destination.bytes().all(|b| b == b'0') && *prefix == "00" && next_hop != 0)
+                .then(|| IpAddr::V6(Ipv6Addr::from(next_hop)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The IPv4 table is written in the byte order of the host.
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn default_routes() {
+        let v4 = "\
+            Iface\tDestination\tGateway\tFlags\tRefCnt\tUse\tMetric\tMask\tMTU\tWindow\tIRTT\n\
+            eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0\n\
+            eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n";
+        assert_eq!(
+            vec![IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))],
+            parse_routes(v4)
+        );
+
+        let v6 = "\
+            fe800000000000000000000000000000 40 00000000000000000000000000000000 00 \
+            00000000000000000000000000000000 00000100 00000002 00000000 00000001 eth0\n\
+            00000000000000000000000000000000 00 00000000000000000000000000000000 00 \
+            fe800000000000000000000000000001 00000400 00000001 00000000 00000003 eth0\n";
+        assert_eq!(
+            vec!["fe80::1".parse::<IpAddr>().unwrap()],
+            parse_routes_v6(v6)
+        );
+    }
+
+    #[test]
+    fn loopback() {
+        let local = LocalAddresses::detect();
+        assert!(local.contains(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert!(!local.contains(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1))));
+    }
+}
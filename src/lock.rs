@@ -0,0 +1,67 @@
+//! Lock that keeps several instances from running at once, as they would fight over the same
+//! firewall sets and storage file.
+
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{bail, Context, Result};
+
+/// Exclusive lock of the storage file, held as long as this value is alive. The OS releases it
+/// when the process ends, even if it crashes, so a stale lock file never blocks the next start.
+pub struct InstanceLock {
+    _file: File,
+}
+
+impl InstanceLock {
+    /// Acquire the lock for the storage at the given location, failing right away if another
+    /// instance holds it already.
+    pub fn acquire(storage: &Path) -> Result<Self> {
+        let path = lock_path(storage);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed creating {}", parent.display()))?;
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("failed opening lock file {}", path.display()))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid).ok();
+
+                bail!(
+                    "another instance of veto (PID {}) is already running with the storage at {}",
+                    pid.trim(),
+                    storage.display()
+                );
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("failed locking {}", path.display()))
+            }
+        }
+
+        // The PID is only informational, to point at the running instance.
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", process::id())?;
+
+        Ok(Self { _file: file })
+    }
+}
+
+/// Location of the lock file, next to the storage.
+fn lock_path(storage: &Path) -> PathBuf {
+    storage.with_extension("lock")
+}
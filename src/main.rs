@@ -1,23 +1,71 @@
 #![forbid(unsafe_code)]
 #![deny(rust_2018_idioms, clippy::all, clippy::pedantic)]
 #![warn(clippy::nursery)]
+// See the library for why these are allowed.
+#![allow(clippy::duration_suboptimal_units, clippy::manual_is_multiple_of)]
 
-use std::{env, path::PathBuf, time::Duration as StdDuration};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    env,
+    fs::{self, File},
+    hash::BuildHasher,
+    io::{self, BufRead, BufReader, Write},
+    net::IpAddr,
+    num::NonZeroUsize,
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        net::UnixListener,
+    },
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{atomic::Ordering, Arc},
+    time::Duration as StdDuration,
+};
 
-use anyhow::{Context, Result};
-use clap::{ArgAction, Parser};
+use anyhow::{ensure, Context, Result};
+use clap::{ArgAction, Args, Parser};
 use flume::{select::SelectError, Receiver};
-use log::{info, warn};
-use time::{Duration, OffsetDateTime};
+use indexmap::IndexMap;
+use ipnetwork::IpNetwork;
+use log::{error, info, warn};
+use regex::Regex;
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, Duration, OffsetDateTime};
 use veto::{
+    audit::{self, AuditLog},
+    breaker::{self, Breaker},
+    diagnose::{self, Diagnosis},
+    doctor,
+    ecs::{self, EcsLog},
+    enrich::{self, Enricher, Enrichment},
+    events::EventBus,
     firewall::{self, Firewall},
     handler,
     handler::Handler,
-    matcher::Matcher,
-    notifier, settings, storage,
+    honeypot::{self, Honeypot},
+    lint,
+    local::LocalAddresses,
+    matcher::{Analysis, Matcher},
+    metrics, mqtt, notifier,
+    progress::{Progress, Reporter},
+    reader,
+    reputation::Reputation,
+    seek::{self, TimeLocator},
+    settings::{self, FirewallBackend, Protocol},
+    simulation::{SimulatedBan, Simulation},
+    statsd, storage,
     storage::TargetRepository,
+    tarpit,
+    tor::{self, Tor},
+    watchdog::Watchdog,
+    wizard,
 };
 
+mod lock;
+mod manual;
+mod top;
+
 /// A lightweight, log file based IP blocker with focus on simplicity and speed.
 #[derive(Parser)]
 #[command(about, author, version)]
@@ -35,6 +83,11 @@ struct Opts {
     /// Alternative storage location.
     #[arg(long, env = "VETO_STORAGE")]
     storage: Option<PathBuf>,
+    /// Print the result of commands as JSON instead, including errors.
+    ///
+    /// Supported by all commands except test-firewall, top, bench and wizard.
+    #[arg(long, global = true)]
+    json: bool,
     #[command(subcommand)]
     cmd: Option<Command>,
 }
@@ -43,17 +96,208 @@ struct Opts {
 enum Command {
     /// Remove any leftover firewall rules.
     Uninstall,
+    /// Check the environment for common problems, like missing binaries, kernel modules or
+    /// permissions, and show how to fix them.
+    Doctor,
+    /// Validate the configuration, reporting syntax errors, unknown keys, invalid filters and
+    /// missing log files all at once.
+    ///
+    /// With --watch, the configuration is validated again whenever it's saved, which gives instant
+    /// feedback while editing it, before restarting veto with it.
+    Check {
+        /// Keep running and validate the configuration again on every change.
+        #[arg(long)]
+        watch: bool,
+    },
+    /// Check that the configured firewall works, by blocking and unblocking a test address.
+    ///
+    /// The firewall rules are installed, 192.0.2.1 (reserved for documentation) is blocked and
+    /// unblocked, and the rules are uninstalled again, verifying the firewall state after each
+    /// step. Don't run this while veto is running, as it removes the firewall rules at the end.
+    TestFirewall,
+    /// Show statistics of the running instance, like processed lines and filter latencies.
+    ///
+    /// Requires the metrics endpoint to be enabled in the configuration.
+    Status,
+    /// Show a live dashboard of the running instance, with current bans and rule activity.
+    ///
+    /// Requires the metrics endpoint to be enabled in the configuration.
+    Top,
+    /// List the configured rules with their settings and statistics.
+    ///
+    /// Processed lines and matches are taken from the running instance, and bans from the storage.
+    Rules,
+    /// Print the current counters, like bans, matches and queue depths, in the Prometheus text
+    /// format.
+    ///
+    /// Works without the metrics endpoint, as the running instance saves its counters next to the
+    /// storage regularly. Useful for collecting metrics through cron jobs.
+    Metrics,
+    /// Block IPs manually, as if the rule had blocked them.
+    ///
+    /// The storage and firewall are changed directly, so stop a running instance first, as it
+    /// overwrites the storage with its own copy. Use --dry-run to only show the changes.
+    Ban {
+        /// The IP addresses to block.
+        #[arg(required = true)]
+        ips: Vec<IpAddr>,
+        #[command(flatten)]
+        options: manual::BanOptions,
+    },
+    /// Remove the blocks of IPs, from the storage and the firewall.
+    ///
+    /// Like with ban, stop a running instance first.
+    Unban {
+        /// The IP addresses to unblock.
+        #[arg(required = true)]
+        ips: Vec<IpAddr>,
+        /// Why the IPs are unblocked, which is recorded in the audit log.
+        #[arg(long)]
+        reason: Option<String>,
+        /// Only show the storage changes and firewall commands, without doing them.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Block all IPs of a blocklist file, with one IP per line.
+    ///
+    /// Empty lines and comments starting with `#` are skipped, as well as anything after the IP on
+    /// the same line. Like with ban, stop a running instance first.
+    Import {
+        /// Blocklist file to read, or `-` to read from the standard input.
+        file: PathBuf,
+        #[command(flatten)]
+        options: manual::BanOptions,
+    },
+    /// Block all active IPs of the storage on the firewall again.
+    ///
+    /// Run this at boot, before the service starts, when the firewall state doesn't survive a
+    /// reboot. Together with `firewall.keep_on_shutdown`, previously blocked attackers stay blocked
+    /// during the whole restart.
+    Restore {
+        /// Only show the firewall commands, without doing them.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Resume blocking of the running instance, after the breaker paused it.
+    ///
+    /// The running instance picks up the request within a minute, and takes the current state,
+    /// like the configuration, as new baseline for the breaker.
+    Resume,
+    /// Copy all entries of the storage to another backend, like from bincode to sqlite.
+    ///
+    /// The entries are read from the location given with --storage, or the default one of the
+    /// source backend. Stop a running instance first, and switch `storage.backend` in the
+    /// configuration to the new backend afterwards.
+    MigrateStorage {
+        /// Backend of the existing storage.
+        #[arg(long)]
+        from: storage::Backend,
+        /// Backend to copy the entries to.
+        #[arg(long)]
+        to: storage::Backend,
+        /// Location of the new storage, instead of the default one of its backend.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Show whether an IP is blocked, by which rule and for how long.
+    Explain {
+        /// The IP address to look up.
+        ip: IpAddr,
+    },
     /// Match against a single log line and show statistics.
+    ///
+    /// Without a line, the rule's log file is matched instead and the results of all lines are
+    /// summed up. Use --sample or --since to only match part of large files.
     Analyze {
         /// One of the configured rules to load.
         #[arg(long, short)]
         rule: String,
         /// The log line to match against.
-        line: String,
+        line: Option<String>,
+        /// Show the spans of capture groups, and for filters that don't match, how far their
+        /// pattern got and which part of it likely failed.
+        #[arg(long, conflicts_with = "sample", conflicts_with = "since")]
+        debug: bool,
+        #[command(flatten)]
+        selection: Selection,
+    },
+    /// Replay complete log files through the rules and show which IPs would have been blocked.
+    ///
+    /// Nothing is blocked and the storage isn't touched. Without any files, each rule replays its
+    /// own log file. Otherwise, the given files are replayed through all rules (or only the one
+    /// selected rule). Use `-` to read from stdin.
+    Simulate {
+        /// Only replay through one of the configured rules.
+        #[arg(long, short)]
+        rule: Option<String>,
+        /// Log files to replay instead of the ones of the rules.
+        files: Vec<PathBuf>,
+        #[command(flatten)]
+        selection: Selection,
+    },
+    /// Measure the throughput of rules and the cost of each filter against a sample file.
+    ///
+    /// Filters that are an order of magnitude slower than the other filters of the same rule are
+    /// flagged. Without a sample file, each rule is measured against its own log file.
+    Bench {
+        /// Only measure one of the configured rules.
+        #[arg(long, short)]
+        rule: Option<String>,
+        /// How many times to run through the sample lines.
+        #[arg(long, short = 'n', default_value_t = 3)]
+        iterations: u32,
+        /// Sample log file to measure against, or `-` for stdin.
+        file: Option<PathBuf>,
+    },
+    /// Create a new rule step by step, from example lines of a log file.
+    ///
+    /// Filters are suggested from the picked lines and tested against the whole file, before the
+    /// rule is appended to the configuration.
+    Wizard {
+        /// Sample log file to pick example lines from.
+        file: Option<PathBuf>,
     },
+    /// Run the privileged helper, that changes the firewall on behalf of an unprivileged veto.
+    ///
+    /// The helper listens on the socket of the `helper` settings, and uses the firewall that the
+    /// configuration selects without that section. It needs the privileges to change the firewall,
+    /// while veto itself can run as any user that may access the socket.
+    Helper,
+}
+
+/// Part of a log file to process, for quick iterations on rules with large files.
+#[derive(Args, Clone, Copy, Default)]
+struct Selection {
+    /// Only process this many lines of each file.
+    #[arg(long)]
+    sample: Option<NonZeroUsize>,
+    /// Only process lines from this time on, either as RFC 3339 timestamp or as duration into the
+    /// past, like `2h`.
+    ///
+    /// The rule's filters must capture timestamps. In files, the start is found by bisecting over
+    /// the timestamps of the lines, so older lines aren't read at all.
+    #[arg(long, value_parser = parse_since)]
+    since: Option<OffsetDateTime>,
+}
+
+impl Selection {
+    const fn is_set(&self) -> bool {
+        self.sample.is_some() || self.since.is_some()
+    }
+}
+
+fn parse_since(value: &str) -> Result<OffsetDateTime, String> {
+    if let Ok(duration) = humantime::parse_duration(value) {
+        return Duration::try_from(duration)
+            .map(|duration| OffsetDateTime::now_utc() - duration)
+            .map_err(|e| e.to_string());
+    }
+
+    OffsetDateTime::parse(value, &Rfc3339)
+        .map_err(|_| "expected an RFC 3339 timestamp or a duration like `2h`".to_owned())
 }
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     dotenvy::dotenv().ok();
 
     let opts: Opts = Opts::parse();
@@ -70,73 +314,603 @@ fn main() -> Result<()> {
     pretty_env_logger::init();
 
     if let Some(cmd) = opts.cmd {
-        match cmd {
-            Command::Uninstall => uninstall(opts.config)?,
-            Command::Analyze { rule, line } => analyze(opts.config, &rule, &line)?,
+        return run_command(cmd, opts.config, opts.storage, opts.json);
+    }
+
+    run(opts.config, opts.storage)
+}
+
+/// Bring the firewall in line with the active storage entries, in both directions. Entries that
+/// still aren't blocked afterwards are marked as inactive, so their next offense blocks them again
+/// instead of being taken as already blocked.
+fn reconcile_firewall(
+    firewall: &impl Firewall,
+    storage: &mut dyn TargetRepository,
+    active: &[(&handler::Entry, storage::BanRecord)],
+) {
+    let now = OffsetDateTime::now_utc();
+    let targets = active
+        .iter()
+        .map(|(entry, record)| firewall::Target {
+            ip: record.ip.into(),
+            ports: &entry.rule.ports,
+            protocol: entry.rule.protocol,
+            timeout: firewall::remaining(record.until, now),
+        })
+        .collect::<Vec<_>>();
+
+    let reconciliation = match firewall::reconcile(firewall, &targets) {
+        Ok(reconciliation) => reconciliation,
+        Err(e) => {
+            warn!("failed blocking {} targets: {:?}", targets.len(), e);
+            return;
         }
-        return Ok(());
+    };
+
+    if !reconciliation.removed.is_empty() {
+        warn!(
+            "unblocked {} IPs that aren't blocked in the storage: {:?}",
+            reconciliation.removed.len(),
+            reconciliation.removed
+        );
     }
 
-    let settings = settings::load(opts.config)?;
+    if !reconciliation.failed.is_empty() {
+        error!(
+            "failed blocking {} IPs of the storage, they're blocked again with their next \
+             offense: {:?}",
+            reconciliation.failed.len(),
+            reconciliation.failed
+        );
+    }
 
-    let shutdown = create_shutdown()?;
+    for (_, record) in active {
+        let blocked = !reconciliation.failed.contains(&record.ip.into());
+        if record.active != blocked {
+            let updated = storage::BanRecord {
+                active: blocked,
+                ..record.clone()
+            };
+            if let Err(e) = storage.restore(updated) {
+                warn!(
+                    "failed updating the storage entry of {}: {:?}",
+                    record.ip, e
+                );
+            }
+        }
+    }
+}
+
+/// Warn about filters that take long for crafted lines, as an attacker could flood the logs with
+/// such lines to keep veto busy.
+fn stress_filters<'a>(
+    entries: impl Iterator<Item = &'a handler::Entry>,
+    max_line_length: Option<NonZeroUsize>,
+) {
+    for entry in entries {
+        for report in veto::bench::stress(entry, max_line_length) {
+            if report.is_risky() {
+                warn!(
+                    "rule {}: filter {:?} takes {:?} for a crafted line, a flood of such lines \
+                     could pin the CPU",
+                    entry.name, report.pattern, report.worst
+                );
+            }
+        }
+    }
+}
+
+/// Create the metrics registry for the rules, and serve or send it if enabled.
+fn start_metrics<'a>(
+    settings: &settings::Metrics,
+    statsd: &settings::Statsd,
+    entries: impl Iterator<Item = &'a handler::Entry>,
+) -> Result<Arc<metrics::Registry>> {
+    let registry = Arc::new(metrics::Registry::new(entries.map(|entry| {
+        let filters = entry.rule.filters.iter().map(|f| f.pattern.clone());
+        (entry.name.clone(), filters.collect(), entry.metrics.clone())
+    })));
 
-    let firewall = firewall::IpSet::new(settings.ipset)?;
+    if let Some(addr) = settings.listen {
+        metrics::serve(addr, registry.clone()).context("failed binding metrics endpoint")?;
+    }
 
-    let storage = storage::new_storage(opts.storage);
+    statsd::start(statsd, registry.clone()).context("failed setting up StatsD")?;
 
-    let mut files = handler::prepare_rules(settings.rules)?;
+    Ok(registry)
+}
 
-    let last_unblock = OffsetDateTime::now_utc() + Duration::minutes(1);
+/// Pause blocking if the breaker trips, and resume it once requested with `veto resume`.
+fn check_breaker<TR, F>(breaker: &mut Breaker, handler: &mut Handler<TR, F>, storage: &Path)
+where
+    TR: TargetRepository,
+    F: Firewall,
+{
+    if handler.paused {
+        if breaker::take_resume(storage) {
+            info!("resuming blocking");
+            breaker.reset();
+            handler.paused = false;
+        }
+    } else if let Some(reason) = breaker.check(handler.local.as_ref()) {
+        // Drop any earlier request, so only a resume after the pause counts.
+        _ = breaker::take_resume(storage);
+        error!("pausing blocking until resumed with `veto resume`, as {reason}");
+        handler.paused = true;
+    }
+}
 
-    firewall.install()?;
+/// Lookups in the `GeoIP` database, and the sets of the allowed countries.
+#[derive(Default)]
+struct GeoIp {
+    lookup: Option<Arc<dyn enrich::Lookup>>,
+    countries: Option<Arc<firewall::CountrySets>>,
+}
 
-    storage.iter_active(|addr, file| {
-        if let Some((entry, _)) = files.get(file) {
-            let target = &firewall::Target {
-                ip: addr,
-                ports: &entry.rule.ports,
-            };
-            if let Err(e) = firewall.block(target) {
-                warn!("failed blocking {}: {:?}", addr, e);
-            }
+impl GeoIp {
+    /// Stop dropping the connections of other countries.
+    fn uninstall(&self) -> Result<()> {
+        if let Some(countries) = &self.countries {
+            countries.uninstall()?;
         }
 
         Ok(())
-    })?;
+    }
+}
 
-    let mut handler = Handler {
-        whitelist: settings.whitelist,
-        storage,
-        firewall,
-        last_unblock,
+/// Load the `GeoIP` database, and keep it up to date in the background if a provider is set. The
+/// allowed countries are applied again after each update.
+#[cfg(feature = "geoip")]
+fn start_geoip(settings: &settings::GeoIp, detect_only: bool) -> Result<GeoIp> {
+    let Some(file) = &settings.file else {
+        anyhow::ensure!(
+            settings.allowed_ports.is_empty(),
+            veto::Error::GeoIpSettings("allowed countries require a GeoIP database")
+        );
+        return Ok(GeoIp::default());
+    };
+    anyhow::ensure!(
+        settings.allowed_ports.is_empty() || !settings.allowed_countries.is_empty(),
+        veto::Error::GeoIpSettings("allowed ports require at least one allowed country")
+    );
+    anyhow::ensure!(
+        settings.allowed_ports.len() <= 15,
+        veto::Error::GeoIpSettings("at most 15 allowed ports are supported")
+    );
+
+    let database = veto::geoip::Database::open(file)
+        .with_context(|| format!("failed loading GeoIP database {}", file.display()))?;
+
+    let countries = if settings.allowed_ports.is_empty() || detect_only {
+        None
+    } else {
+        Some(Arc::new(firewall::CountrySets::new(
+            &settings.allowed_ports,
+        )?))
+    };
+
+    let apply = {
+        let countries = countries.clone();
+        let allowed = settings.allowed_countries.clone();
+        move |database: &veto::geoip::Database| {
+            if let Some(countries) = &countries {
+                apply_countries(countries, database, &allowed);
+            }
+        }
     };
 
+    if database.is_loaded() {
+        apply(&database);
+    }
+
+    if let Some(updater) = veto::geoip::Updater::from_settings(settings, database.clone())? {
+        veto::geoip::refresh_database(updater, apply)?;
+    } else if !database.is_loaded() {
+        warn!("GeoIP database {} doesn't exist", file.display());
+    }
+
+    Ok(GeoIp {
+        lookup: Some(Arc::new(database)),
+        countries,
+    })
+}
+
+/// Replace the sets with the current networks of the allowed countries.
+#[cfg(feature = "geoip")]
+fn apply_countries(
+    countries: &firewall::CountrySets,
+    database: &veto::geoip::Database,
+    allowed: &[String],
+) {
+    let result = database
+        .networks(allowed)
+        .and_then(|networks| countries.apply(networks));
+
+    match result {
+        Ok(count) => info!("allowing {count} networks of {}", allowed.join(", ")),
+        Err(e) => warn!("failed applying allowed countries: {e}"),
+    }
+}
+
+#[cfg(not(feature = "geoip"))]
+fn start_geoip(settings: &settings::GeoIp, _: bool) -> Result<GeoIp> {
+    anyhow::ensure!(
+        settings.file.is_none() && settings.allowed_ports.is_empty(),
+        veto::Error::GeoIpSettings("GeoIP lookups require the `geoip` feature")
+    );
+
+    Ok(GeoIp::default())
+}
+
+/// Show the blocks that are still active from the last run in the metrics.
+fn restore_activity(
+    registry: &metrics::Registry,
+    firewall_name: String,
+    active: &[(&handler::Entry, storage::BanRecord)],
+) {
+    registry.activity.set_firewall(firewall_name);
+    registry
+        .activity
+        .extend(active.iter().map(|(entry, record)| metrics::BanSnapshot {
+            rule: entry.name.clone(),
+            ip: record.ip,
+            until: record.until,
+        }));
+}
+
+/// Refresh the metrics that follow the state of the handler, and save a snapshot of all metrics.
+fn update_metrics<TR: TargetRepository>(
+    registry: &metrics::Registry,
+    snapshot_path: &Path,
+    handler: &Handler<TR, firewall::Worker>,
+    files: &HashMap<PathBuf, (handler::Entry, handler::State), ahash::RandomState>,
+) {
+    registry.memory.update(&handler.storage, files);
+    registry
+        .firewall_queue
+        .store(handler.firewall.queued(), Ordering::Relaxed);
+    registry
+        .deferred_bans
+        .store(handler.deferred.len(), Ordering::Relaxed);
+    registry.paused.store(handler.paused, Ordering::Relaxed);
+
+    if let Err(e) = metrics::save_snapshot(snapshot_path, registry) {
+        warn!("failed saving metrics to {}: {e}", snapshot_path.display());
+    }
+}
+
+/// Write the reputation of IPs to its file, if it's tracked.
+fn save_reputation<TR: TargetRepository, F: Firewall>(handler: &mut Handler<TR, F>) {
+    if let Some(reputation) = &mut handler.reputation {
+        if let Err(e) = reputation.save(handler.matcher.current_time()) {
+            warn!("failed saving reputation: {e}");
+        }
+    }
+}
+
+/// Start the integrations that follow the blocks and unblocks of the handler.
+fn subscribe_events(
+    events: &mut EventBus,
+    registry: &Arc<metrics::Registry>,
+    enrichment: &settings::Enrichment,
+    geoip: Option<Arc<dyn enrich::Lookup>>,
+    audit_log: &settings::Audit,
+    ecs_log: &settings::Ecs,
+    mqtt: &settings::Mqtt,
+) -> Result<()> {
+    metrics::record_events(registry.clone(), events.subscribe())?;
+
+    let mut enricher = Enricher::new(enrichment);
+    if let Some(database) = geoip {
+        enricher.push(Box::new(database));
+    }
+    if enricher.is_enabled() {
+        enrich::log_events(enricher, events.subscribe())?;
+    }
+
+    if let Some(log) = AuditLog::from_settings(audit_log).context("failed opening audit log")? {
+        audit::log_events(log, events.subscribe())?;
+    }
+
+    if let Some(log) = EcsLog::from_settings(ecs_log).context("failed opening ECS log")? {
+        ecs::log_events(log, events.subscribe())?;
+    }
+
+    if let Some(client) = mqtt::Client::from_settings(mqtt) {
+        mqtt::publish_events(client, events.subscribe())?;
+    }
+
+    Ok(())
+}
+
+/// Sources of blocks that don't come from the log file of a rule.
+struct Builtins {
+    honeypot: Option<Honeypot>,
+    tor: Option<Tor>,
+}
+
+impl Builtins {
+    fn start(honeypot: &settings::Honeypot, tor: &settings::Tor) -> Result<Self> {
+        Ok(Self {
+            honeypot: Honeypot::start(honeypot).context("failed starting honeypot")?,
+            tor: Tor::start(tor).context("failed starting Tor exit list")?,
+        })
+    }
+}
+
+/// Rule that blocked the IPs of the given path in the storage, which is either the log file of a
+/// rule, or the path of a builtin.
+fn find_rule<'a, S: BuildHasher>(
+    files: &'a HashMap<PathBuf, (handler::Entry, handler::State), S>,
+    builtins: &'a Builtins,
+    path: &Path,
+) -> Option<&'a handler::Entry> {
+    files
+        .get(path)
+        .map(|(entry, _)| entry)
+        .or_else(|| builtins.honeypot.as_ref()?.rule(path))
+        .or_else(|| builtins.tor.as_ref()?.rule(path))
+}
+
+/// Reason that the main loop woke up, other than the timeout.
+enum Wakeup<'a> {
+    Shutdown,
+    /// A log file changed.
+    Changed(notifier::Event),
+    /// An IP connected to the honeypot.
+    Connection(&'a Honeypot, IpAddr),
+    /// A new list of Tor exits arrived, that must be blocked.
+    TorExits(&'a Tor, Vec<IpAddr>),
+    /// The verification of a crawler finished in the background.
+    Crawlers,
+}
+
+/// Wait for the next shutdown signal, file change, honeypot connection, list of Tor exits or
+/// crawler verification, for up to a minute.
+fn wait<'a>(
+    shutdown: &Receiver<()>,
+    events: &notifier::Notifier,
+    crawlers: &Receiver<()>,
+    builtins: &'a Builtins,
+) -> Result<Wakeup<'a>, SelectError> {
+    let mut selector = flume::Selector::new()
+        .recv(shutdown, |_| Wakeup::Shutdown)
+        .recv(crawlers, |_| Wakeup::Crawlers)
+        .recv(&events.rx, |event| {
+            event.map_or(Wakeup::Shutdown, |event| {
+                events.follow(&event);
+                Wakeup::Changed(event)
+            })
+        });
+
+    if let Some(honeypot) = &builtins.honeypot {
+        selector = selector.recv(&honeypot.connections, move |ip| {
+            ip.map_or(Wakeup::Shutdown, |ip| Wakeup::Connection(honeypot, ip))
+        });
+    }
+
+    if let Some(tor) = &builtins.tor {
+        selector = selector.recv(&tor.updates, move |exits| {
+            exits.map_or(Wakeup::Shutdown, |exits| Wakeup::TorExits(tor, exits))
+        });
+    }
+
+    selector.wait_timeout(StdDuration::from_secs(60))
+}
+
+/// Run the main blocking loop, until a shutdown signal is received.
+fn run(config: Option<PathBuf>, storage: Option<PathBuf>) -> Result<ExitCode> {
+    let config_path = config
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(settings::DEFAULT_PATH));
+    let settings = settings::load(config)?;
+
+    let shutdown = create_shutdown()?;
+
+    let storage = storage.unwrap_or_else(|| settings.storage.backend.default_path());
+    let _lock = lock::InstanceLock::acquire(&storage)?;
+
+    let (firewall, firewall_name) = start_firewall(&settings)?;
+    let firewall_timeouts = is_ipset(&settings) && settings.ipset.timeouts;
+    let keep_on_shutdown = settings.firewall.keep_on_shutdown;
+
+    let snapshot_path = metrics::snapshot_path(&storage);
+    let storage_path = storage.clone();
+    let max_entries = settings.limits.max_storage_entries;
+    let mut storage = storage::open(settings.storage.backend, Some(storage), max_entries)?;
+
+    let mut cache = handler::RuleCache::default();
+    let mut files = handler::RuleBuilder::new()
+        .rules(settings.rules)
+        .limits(settings.limits)
+        .cache(&mut cache)
+        .build()?;
+    stress_filters(
+        files.values().map(|(entry, _)| entry),
+        settings.limits.max_line_length,
+    );
+
+    let entries = files.values().map(|(entry, _)| entry);
+    let registry = start_metrics(&settings.metrics, &settings.statsd, entries)?;
+    let builtins = Builtins::start(&settings.honeypot, &settings.tor)?;
+    tarpit::start(&settings.tarpit).context("failed starting tarpit")?;
+
+    let active = storage
+        .active_records()?
+        .into_iter()
+        .filter_map(|record| Some((find_rule(&files, &builtins, &record.file)?, record)))
+        .collect::<Vec<_>>();
+    restore_activity(&registry, firewall_name, &active);
+
+    reconcile_firewall(&firewall, &mut storage, &active);
+
+    let geoip = start_geoip(&settings.geoip, settings.firewall.detect_only)?;
+    let mut handler = Handler::builder(storage, firewall)
+        .whitelist(settings.whitelist)
+        .whitelist_local(!settings.block_local)
+        .unblock_delay(Duration::MINUTE)
+        .unblock_jitter(settings.firewall.unblock_jitter)
+        .max_unblocks(settings.firewall.max_unblocks)
+        .max_bans_per_minute(settings.firewall.max_bans_per_minute)
+        .tor_exits(builtins.tor.as_ref().map(|tor| tor.exits.clone()))
+        .firewall_timeouts(firewall_timeouts)
+        .reputation(Reputation::from_settings(
+            settings.reputation,
+            geoip.lookup.clone(),
+        )?)
+        .build();
+
+    subscribe_events(
+        &mut handler.events,
+        &registry,
+        &settings.enrichment,
+        geoip.lookup.clone(),
+        &settings.audit,
+        &settings.ecs,
+        &settings.mqtt,
+    )?;
+
     for (entry, state) in files.values_mut() {
         handler.handle_modified(entry, state)?;
     }
 
+    update_metrics(&registry, &snapshot_path, &handler, &files);
+
     let events = notifier::start(files.keys())?;
+    let crawlers = handler.matcher.crawler_updates();
+    let mut watchdog = Watchdog::new(files.values().map(|(entry, _)| entry));
+    let entries = files.values().map(|(entry, _)| entry);
+    let mut breaker = Breaker::new(&settings.breaker, config_path, entries);
 
     loop {
-        let result = flume::Selector::new()
-            .recv(&shutdown, |_| None)
-            .recv(&events.rx, Result::ok)
-            .wait_timeout(StdDuration::from_secs(60));
-
-        match result {
-            Ok(None) => {
+        match wait(&shutdown, &events, &crawlers, &builtins) {
+            Ok(Wakeup::Shutdown) => {
                 info!("shutting down");
                 break;
             }
-            Ok(Some(event)) => handler.handle_event(&mut files, event)?,
-            Err(SelectError::Timeout) => handler.handle_unblock(&files)?,
+            Ok(Wakeup::Changed(event)) => handler.handle_event(&mut files, event)?,
+            Ok(Wakeup::Connection(honeypot, ip)) => handler.handle_offense(&honeypot.entry, ip)?,
+            Ok(Wakeup::TorExits(tor, exits)) => handler.handle_offenses(&tor.entry, exits)?,
+            Ok(Wakeup::Crawlers) => {
+                handler.release_crawlers(|path| find_rule(&files, &builtins, path));
+            }
+            Err(SelectError::Timeout) => {
+                handler.unblock_with(|path| find_rule(&files, &builtins, path))?;
+                save_reputation(&mut handler);
+                update_metrics(&registry, &snapshot_path, &handler, &files);
+            }
         }
+
+        watchdog.check();
+        check_breaker(&mut breaker, &mut handler, &storage_path);
     }
 
-    handler.firewall.uninstall()?;
+    save_reputation(&mut handler);
+    update_metrics(&registry, &snapshot_path, &handler, &files);
+    if keep_on_shutdown {
+        info!("keeping blocks on the firewall");
+    } else {
+        handler.firewall.uninstall()?;
+    }
+    geoip.uninstall()?;
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Exit code of commands that ran fine, but had a negative result, like a line that wouldn't
+/// lead to a block. Errors exit with 1, and invalid arguments with 2.
+const EXIT_NEGATIVE: u8 = 3;
+
+fn run_command(
+    cmd: Command,
+    config: Option<PathBuf>,
+    storage: Option<PathBuf>,
+    json: bool,
+) -> Result<ExitCode> {
+    match execute(cmd, config, storage, json) {
+        Ok(true) => Ok(ExitCode::SUCCESS),
+        Ok(false) => Ok(ExitCode::from(EXIT_NEGATIVE)),
+        Err(e) if json => {
+            println!("{}", serde_json::json!({ "error": format!("{e:#}") }));
+            Ok(ExitCode::FAILURE)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Run the command and return whether its result was positive.
+fn execute(
+    cmd: Command,
+    config: Option<PathBuf>,
+    storage: Option<PathBuf>,
+    json: bool,
+) -> Result<bool> {
+    if json
+        && matches!(
+            cmd,
+            Command::TestFirewall
+                | Command::Top
+                | Command::Resume
+                | Command::Bench { .. }
+                | Command::Wizard { .. }
+                | Command::Helper
+        )
+    {
+        anyhow::bail!("this command has no JSON output");
+    }
+
+    match cmd {
+        Command::Uninstall => uninstall(config).map(|()| true),
+        Command::TestFirewall => test_firewall(config).map(|()| true),
+        Command::Doctor => doctor(config, storage, json),
+        Command::Check { watch } => check(config, watch, json),
+        Command::Status => status(config, json).map(|()| true),
+        Command::Top => top(config).map(|()| true),
+        Command::Rules => rules(config, storage, json).map(|()| true),
+        Command::Metrics => print_metrics(config, storage, json).map(|()| true),
+        Command::Ban { ips, options } => manual::ban(config, storage, ips, &options, json),
+        Command::Unban {
+            ips,
+            reason,
+            dry_run,
+        } => manual::unban(config, storage, &ips, reason.as_deref(), dry_run, json),
+        Command::Import { file, options } => manual::import(config, storage, &file, &options, json),
+        Command::Resume => resume(config, storage).map(|()| true),
+        Command::Restore { dry_run } => manual::restore(config, storage, dry_run, json),
+        Command::MigrateStorage { from, to, output } => {
+            migrate_storage(storage, from, to, output, json).map(|()| true)
+        }
+        Command::Explain { ip } => explain(config, storage, ip, json),
+        Command::Analyze {
+            rule,
+            line: Some(line),
+            debug,
+            selection,
+        } => {
+            if selection.is_set() {
+                anyhow::bail!("--sample and --since only apply to log files, not a single line");
+            }
+            analyze(config, &rule, &line, json, debug)
+        }
+        Command::Analyze {
+            rule,
+            line: None,
+            selection,
+            ..
+        } => analyze_file(config, &rule, selection, json),
+        Command::Simulate {
+            rule,
+            files,
+            selection,
+        } => simulate(config, rule.as_deref(), files, selection, json),
+        Command::Bench {
+            rule,
+            iterations,
+            file,
+        } => bench(config, rule.as_deref(), iterations, file.as_deref()).map(|()| true),
+        Command::Wizard { file } => rule_wizard(config, file).map(|()| true),
+        Command::Helper => helper(config).map(|()| true),
+    }
 }
 
 fn create_shutdown() -> Result<Receiver<()>> {
@@ -151,70 +925,1599 @@ fn create_shutdown() -> Result<Receiver<()>> {
     Ok(rx)
 }
 
+fn helper(config: Option<PathBuf>) -> Result<()> {
+    let mut settings = settings::load(config)?;
+    let helper = settings
+        .helper
+        .take()
+        .context("the helper needs a [helper] section")?;
+    let socket = &helper.socket;
+    // The helper changes the real firewall, instead of handing the changes to itself.
+    settings
+        .firewall
+        .backends
+        .retain(|backend| *backend != FirewallBackend::Helper);
+
+    let firewall = new_firewall(&settings)?;
+
+    // Others must never reach the socket, not even between binding it and setting its
+    // permissions, which is ensured by the directory.
+    let dir = socket
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o750)
+        .create(dir)
+        .with_context(|| format!("failed creating {}", dir.display()))?;
+    let mode = fs::metadata(dir)?.mode();
+    let others = mode & 0o007;
+    ensure!(
+        others == 0,
+        "the directory {} of the helper socket must not be accessible by others (mode {:o})",
+        dir.display(),
+        mode & 0o777,
+    );
+
+    // A socket of a previous run is left behind if the helper was killed.
+    if let Err(e) = fs::remove_file(socket) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e).with_context(|| format!("failed removing {}", socket.display()));
+        }
+    }
+
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("failed listening on {}", socket.display()))?;
+    // Only the owner and group may send changes, like a dedicated group for veto.
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o660))?;
+
+    info!("helper listening on {}", socket.display());
+    firewall::Helper::new(helper.clone()).serve(&listener, firewall)?;
+
+    Ok(())
+}
+
 fn uninstall(config: Option<PathBuf>) -> Result<()> {
     let settings = settings::load(config)?;
-    firewall::IpSet::new(settings.ipset)?.uninstall()
+    new_firewall(&settings)?.uninstall()?;
+
+    Ok(())
 }
 
-fn analyze(config: Option<PathBuf>, rule: &str, line: &str) -> Result<()> {
-    let mut settings = settings::load(config)?;
-    let entry = handler::prepare_rule(
-        rule.to_owned(),
-        settings.rules.remove(rule).context("rule doesn't exist")?,
-    )?;
-    let matcher = Matcher::new();
+fn doctor(config: Option<PathBuf>, storage: Option<PathBuf>, json: bool) -> Result<bool> {
+    let config = config.unwrap_or_else(|| PathBuf::from(settings::DEFAULT_PATH));
+    let storage = storage.unwrap_or_else(|| PathBuf::from(storage::DEFAULT_PATH));
 
-    let analysis = matcher.find_analyze(&entry, line);
+    let checks = doctor::run(&config, &storage);
+    let errors = checks
+        .iter()
+        .filter(|check| check.status == doctor::Status::Error)
+        .count();
 
-    for (filter, matched) in analysis.matches {
-        println!("Filter: {filter}");
-        if let Some(matched) = matched {
-            println!("  Captures:");
-            let name_len = matched
-                .captures
-                .iter()
-                .map(|c| c.0.len())
-                .max()
-                .unwrap_or_default();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&checks)?);
+        return Ok(errors == 0);
+    }
 
-            for (name, value) in matched.captures {
-                println!("    {:2$}: {}", name, value.unwrap_or_default(), name_len);
-            }
+    for check in &checks {
+        println!("[{:^7}] {}: {}", check.status, check.name, check.message);
+        if let Some(hint) = &check.hint {
+            println!("          hint: {hint}");
+        }
+    }
 
-            println!(
-                "  Time: {}",
-                match matched.time {
-                    Some((time, outdated)) =>
-                        format!("{} {}", time, if outdated { "(outdated)" } else { "" }),
-                    None => "no timetamp found".to_owned(),
-                }
-            );
+    if errors > 0 {
+        eprintln!("found {errors} problem(s) that prevent veto from working");
+    }
+
+    Ok(errors == 0)
+}
+
+fn check(config: Option<PathBuf>, watch: bool, json: bool) -> Result<bool> {
+    let config = config.unwrap_or_else(|| PathBuf::from(settings::DEFAULT_PATH));
+    let valid = print_lint(&config, json)?;
+    if !watch {
+        return Ok(valid);
+    }
+
+    // Editors often replace the file on save instead of writing to it, which ends any watch on the
+    // file itself, so its directory is watched instead.
+    let dir = config
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map_or_else(|| PathBuf::from("."), Path::to_owned);
+    let events = notifier::start(std::iter::once(&dir))?;
 
+    if !json {
+        println!("Watching {} for changes", config.display());
+    }
+
+    let mut valid = valid;
+    while let Ok(event) = events.rx.recv() {
+        if event.path.file_name() != config.file_name()
+            || matches!(event.ty, notifier::EventType::Removed)
+        {
+            continue;
+        }
+
+        // A single save can cause several events, which are handled together.
+        while events
+            .rx
+            .recv_timeout(StdDuration::from_millis(200))
+            .is_ok()
+        {}
+
+        if !json {
+            println!();
+            println!("[{}]", OffsetDateTime::now_utc().format(&Rfc3339)?);
+        }
+        valid = print_lint(&config, json)?;
+    }
+
+    Ok(valid)
+}
+
+/// Validate the configuration and print all problems, returning whether it has no errors.
+fn print_lint(config: &Path, json: bool) -> Result<bool> {
+    let problems = lint::run(config);
+    let valid = problems
+        .iter()
+        .all(|problem| problem.severity != lint::Severity::Error);
+
+    if json {
+        // Compact, so that each run of the watch mode is a single line.
+        println!("{}", serde_json::to_string(&problems)?);
+        return Ok(valid);
+    }
+
+    if problems.is_empty() {
+        println!("{} is valid", config.display());
+    }
+    for problem in &problems {
+        if problem.location.is_empty() {
+            println!("[{:^7}] {}", problem.severity, problem.message);
+        } else {
             println!(
-                "  Host: {}",
-                matched.host.map_or_else(
-                    || "no host found".to_owned(),
-                    |host| match host {
-                        std::net::IpAddr::V4(addr) => format!("IPv4 {addr}"),
-                        std::net::IpAddr::V6(addr) => format!("IPv6 {addr}"),
-                    }
-                )
+                "[{:^7}] {}: {}",
+                problem.severity, problem.location, problem.message
             );
+        }
+    }
 
-            let name_len = matched
-                .blacklists
-                .iter()
-                .map(|b| b.0.len())
-                .max()
-                .unwrap_or_default();
+    Ok(valid)
+}
+
+fn test_firewall(config: Option<PathBuf>) -> Result<()> {
+    let settings = settings::load(config)?;
+    println!("Testing firewall: {}", describe_firewall(&settings));
+
+    let firewall = new_firewall(&settings)?;
+    let mut failed = None;
+
+    firewall::verify(&firewall, |step, outcome| {
+        let status = match outcome {
+            firewall::Outcome::Passed => "ok".to_owned(),
+            firewall::Outcome::Unverified => {
+                "unverified, the firewall can't list its state".to_owned()
+            }
+            firewall::Outcome::Skipped => "skipped".to_owned(),
+            firewall::Outcome::Failed(e) => format!("FAILED: {e}"),
+        };
+        println!("  {:<40} {status}", step.to_string());
+
+        if outcome.is_failed() {
+            failed.get_or_insert(step);
+        }
+    });
+
+    if let Some(step) = failed {
+        anyhow::bail!("firewall test failed at step: {step}");
+    }
+
+    println!("The firewall works as expected");
+    Ok(())
+}
+
+/// Create the configured firewall, which is ipset unless a helper, plugin, shell commands,
+/// blackhole routes, pf, AWS WAF, iptables, `HAProxy`, nginx or tc are set or veto only detects
+/// offending IPs. If several backends are configured, all of them are changed at once.
+fn new_firewall(settings: &settings::Settings) -> Result<Box<dyn Firewall + Send>> {
+    if settings.firewall.detect_only {
+        info!("Running in detection-only mode, no IPs will be blocked");
+        return Ok(Box::new(firewall::Noop));
+    }
+
+    let mut firewalls = settings
+        .firewall_backends()
+        .into_iter()
+        .map(|backend| {
+            Ok((
+                describe_backend(settings, backend),
+                new_backend(settings, backend)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(if firewalls.len() == 1 {
+        firewalls.remove(0).1
+    } else {
+        Box::new(firewall::Multi::new(firewalls))
+    })
+}
+
+/// Create a single firewall from its settings section.
+fn new_backend(
+    settings: &settings::Settings,
+    backend: FirewallBackend,
+) -> Result<Box<dyn Firewall + Send>> {
+    let missing = || format!("the {0} firewall needs a [{0}] section", backend.section());
+
+    Ok(match backend {
+        FirewallBackend::Ipset => Box::new(firewall::IpSet::new(
+            settings.ipset.clone(),
+            settings.rules.values(),
+        )?),
+        FirewallBackend::Plugin => Box::new(firewall::Plugin::new(
+            settings.plugin.as_ref().with_context(missing)?,
+        )?),
+        FirewallBackend::Exec => Box::new(firewall::Exec::new(
+            settings.exec.clone().with_context(missing)?,
+        )),
+        FirewallBackend::Blackhole => Box::new(firewall::Blackhole::new(
+            settings.blackhole.clone().with_context(missing)?,
+        )?),
+        FirewallBackend::Pf => Box::new(firewall::Pf::new(
+            settings.pf.clone().with_context(missing)?,
+        )?),
+        FirewallBackend::AwsWaf => Box::new(firewall::AwsWaf::new(
+            settings.aws_waf.clone().with_context(missing)?,
+        )?),
+        FirewallBackend::Iptables => Box::new(firewall::IpTables::new(
+            settings.iptables.clone().with_context(missing)?,
+        )?),
+        FirewallBackend::Haproxy => Box::new(firewall::HaProxy::new(
+            settings.haproxy.clone().with_context(missing)?,
+        )),
+        FirewallBackend::Nginx => Box::new(firewall::Nginx::new(
+            settings.nginx.clone().with_context(missing)?,
+        )),
+        FirewallBackend::Tc => Box::new(firewall::Tc::new(
+            settings.tc.clone().with_context(missing)?,
+        )?),
+        FirewallBackend::Helper => Box::new(firewall::Helper::new(
+            settings.helper.clone().with_context(missing)?,
+        )),
+    })
+}
+
+/// Whether ipset is one of the configured firewalls.
+fn is_ipset(settings: &settings::Settings) -> bool {
+    !settings.firewall.detect_only
+        && settings
+            .firewall_backends()
+            .contains(&FirewallBackend::Ipset)
+}
+
+/// Create and install the configured firewall. If that fails, veto either refuses to start or
+/// only detects offending IPs from then on, depending on the configured fallback.
+fn start_firewall(settings: &settings::Settings) -> Result<(firewall::Worker, String)> {
+    let result = new_firewall(settings).and_then(|firewall| {
+        firewall.install()?;
+        Ok(firewall)
+    });
+
+    let (firewall, name) = match result {
+        Ok(firewall) => (firewall, describe_firewall(settings)),
+        Err(e) if settings.firewall.fallback == settings::Fallback::DetectOnly => {
+            error!(
+                "failed setting up the firewall, falling back to detection only. \
+                IPs are NOT blocked until veto is restarted with a working firewall: {e:?}"
+            );
+            let firewall: Box<dyn Firewall + Send> = Box::new(firewall::Noop);
+            (firewall, "none (firewall unavailable)".to_owned())
+        }
+        Err(e) => return Err(e.context("failed setting up the firewall")),
+    };
+
+    let firewall = firewall::Worker::new(firewall::RateLimited::new(
+        firewall,
+        settings.firewall.rate_limit,
+    ))?;
+
+    Ok((firewall, name))
+}
+
+/// Short description of the configured firewall, for status displays.
+fn describe_firewall(settings: &settings::Settings) -> String {
+    if settings.firewall.detect_only {
+        return "none (detection only)".to_owned();
+    }
+
+    settings
+        .firewall_backends()
+        .into_iter()
+        .map(|backend| describe_backend(settings, backend))
+        .collect::<Vec<_>>()
+        .join(" + ")
+}
+
+fn describe_backend(settings: &settings::Settings, backend: FirewallBackend) -> String {
+    match backend {
+        FirewallBackend::Ipset => format!("ipset ({:?})", settings.ipset.target),
+        FirewallBackend::Plugin => settings.plugin.as_ref().map_or_else(
+            || "plugin".to_owned(),
+            |plugin| format!("plugin {}", plugin.command.display()),
+        ),
+        FirewallBackend::Exec => "exec (shell commands)".to_owned(),
+        FirewallBackend::Blackhole => "blackhole routes".to_owned(),
+        FirewallBackend::Pf => settings.pf.as_ref().map_or_else(
+            || "pf".to_owned(),
+            |pf| format!("pf (ports {:?})", pf.ports),
+        ),
+        FirewallBackend::AwsWaf => settings.aws_waf.as_ref().map_or_else(
+            || "AWS WAF".to_owned(),
+            |waf| format!("AWS WAF (IP set {})", waf.ipv4.name),
+        ),
+        FirewallBackend::Iptables => settings.iptables.as_ref().map_or_else(
+            || "iptables".to_owned(),
+            |iptables| format!("iptables ({:?})", iptables.reject_with),
+        ),
+        FirewallBackend::Haproxy => settings.haproxy.as_ref().map_or_else(
+            || "HAProxy".to_owned(),
+            |haproxy| format!("HAProxy (ACL {})", haproxy.acl),
+        ),
+        FirewallBackend::Nginx => settings.nginx.as_ref().map_or_else(
+            || "nginx".to_owned(),
+            |nginx| format!("nginx ({})", nginx.file.display()),
+        ),
+        FirewallBackend::Tc => settings.tc.as_ref().map_or_else(
+            || "tc".to_owned(),
+            |tc| format!("tc ({} on {})", tc.rate, tc.interface),
+        ),
+        FirewallBackend::Helper => settings.helper.as_ref().map_or_else(
+            || "helper".to_owned(),
+            |helper| format!("helper ({})", helper.socket.display()),
+        ),
+    }
+}
+
+fn top(config: Option<PathBuf>) -> Result<()> {
+    let settings = settings::load(config)?;
+    let addr = settings
+        .metrics
+        .listen
+        .context("metrics endpoint is not enabled")?;
+
+    top::run(addr)
+}
+
+fn migrate_storage(
+    storage: Option<PathBuf>,
+    from: storage::Backend,
+    to: storage::Backend,
+    output: Option<PathBuf>,
+    json: bool,
+) -> Result<()> {
+    let source = storage.unwrap_or_else(|| from.default_path());
+    let destination = output.unwrap_or_else(|| to.default_path());
+
+    anyhow::ensure!(source.exists(), "no storage found at {}", source.display());
+    anyhow::ensure!(
+        source != destination,
+        "the new storage must be at another location than {}",
+        source.display()
+    );
+
+    let mut target = storage::open(to, Some(destination.clone()), None)?;
+    let existing = target.count();
+    anyhow::ensure!(
+        existing == 0,
+        "the storage at {} already contains {existing} entries",
+        destination.display()
+    );
+
+    let entries = storage::migrate(
+        &storage::open(from, Some(source.clone()), None)?,
+        &mut target,
+    )
+    .context("failed migrating the storage")?;
+
+    // The bincode storage is only fully written once closed, so the count is checked again after
+    // reopening it.
+    drop(target);
+    let found = storage::open(to, Some(destination.clone()), None)?.count();
+    anyhow::ensure!(
+        found == entries,
+        "expected {entries} entries in the new storage, but found {found}"
+    );
+
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({
+                "from": from,
+                "to": to,
+                "source": source,
+                "destination": destination,
+                "entries": entries,
+            })
+        );
+        return Ok(());
+    }
+
+    println!(
+        "Migrated {entries} entries from the {from} storage at {} to the {to} storage at {}",
+        source.display(),
+        destination.display()
+    );
+    println!("Set `backend = \"{to}\"` in the `[storage]` section of the configuration to use it");
+    if destination != to.default_path() {
+        println!("and pass --storage {} to veto", destination.display());
+    }
+
+    Ok(())
+}
+
+/// Block state of an IP, as shown by the `explain` command.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BlockStatus {
+    NeverBlocked,
+    Blocked,
+    /// The block expired, but the IP wasn't unblocked yet.
+    Expired,
+    Unblocked,
+}
+
+impl BlockStatus {
+    const fn describe(self) -> &'static str {
+        match self {
+            Self::NeverBlocked => "never blocked",
+            Self::Blocked => "blocked",
+            Self::Expired => "block expired, waiting to be unblocked",
+            Self::Unblocked => "not blocked anymore",
+        }
+    }
+}
+
+/// Everything known about an IP, as shown by the `explain` command.
+#[derive(Serialize)]
+struct Explanation {
+    ip: IpAddr,
+    /// Whitelisted network that contains the IP.
+    whitelisted_by: Option<IpNetwork>,
+    /// Whether the IP is an address of this host, which is implicitly whitelisted.
+    local: bool,
+    status: BlockStatus,
+    /// Name of the rule that blocked the IP, if it's still configured.
+    rule: Option<String>,
+    /// Log file that the block came from.
+    file: Option<PathBuf>,
+    /// Approximate time of the block, derived from the rule's timeout.
+    #[serde(with = "time::serde::rfc3339::option")]
+    since: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    until: Option<OffsetDateTime>,
+    /// Amount of times the IP was blocked before the current block.
+    previous_blocks: Option<u8>,
+    enrichment: Enrichment,
+}
+
+fn explain(
+    config: Option<PathBuf>,
+    storage: Option<PathBuf>,
+    ip: IpAddr,
+    json: bool,
+) -> Result<bool> {
+    let settings = settings::load(config)?;
+    let record = storage::open(settings.storage.backend, storage, None)?.record(ip)?;
+    let now = OffsetDateTime::now_utc();
+
+    let mut explanation = Explanation {
+        ip,
+        whitelisted_by: settings
+            .whitelist
+            .iter()
+            .find(|wl| wl.contains(ip))
+            .copied(),
+        local: !settings.block_local && LocalAddresses::detect().contains(ip),
+        status: BlockStatus::NeverBlocked,
+        rule: None,
+        file: None,
+        since: None,
+        until: None,
+        previous_blocks: None,
+        enrichment: Enricher::new(&settings.enrichment).enrich(ip),
+    };
+
+    if let Some(record) = record {
+        explanation.status = match (record.active, record.until > now) {
+            (true, true) => BlockStatus::Blocked,
+            (true, false) => BlockStatus::Expired,
+            (false, _) => BlockStatus::Unblocked,
+        };
+
+        // Rules are identified by their resolved log file path in the storage.
+        if let Some((name, rule)) = settings.rules.iter().find(|(_, rule)| {
+            rule.file == record.file
+                || handler::resolve_file(&rule.file).is_ok_and(|f| f == record.file)
+        }) {
+            explanation.rule = Some(name.clone());
+            explanation.since = Some(record.until.saturating_sub(rule.timeout));
+        } else if record.file == Path::new(honeypot::STORAGE_PATH) {
+            explanation.rule = Some(honeypot::RULE_NAME.to_owned());
+            explanation.since = Some(record.until.saturating_sub(settings.honeypot.timeout));
+        } else if record.file == Path::new(tor::STORAGE_PATH) {
+            explanation.rule = Some(tor::RULE_NAME.to_owned());
+            explanation.since = Some(record.until.saturating_sub(settings.tor.timeout));
+        }
+
+        explanation.file = Some(record.file);
+        explanation.until = Some(record.until);
+        explanation.previous_blocks = Some(record.times);
+    }
+
+    let blocked = explanation.status == BlockStatus::Blocked;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&explanation)?);
+    } else {
+        print_explanation(&explanation, now)?;
+    }
+
+    Ok(blocked)
+}
+
+fn print_explanation(explanation: &Explanation, now: OffsetDateTime) -> Result<()> {
+    let Explanation { ip, enrichment, .. } = explanation;
+
+    println!("IP: {ip}");
+
+    if let Some(network) = explanation.whitelisted_by {
+        println!("  Whitelisted by {network}, it's never blocked");
+    }
+
+    if explanation.local {
+        println!("  Address of this host, it's never blocked");
+    }
+
+    if let Some(hostname) = &enrichment.hostname {
+        println!("  Host:     {hostname}");
+    }
+    if let Some(asn) = enrichment.asn {
+        println!(
+            "  Network:  AS{asn} {} ({})",
+            enrichment.network.as_deref().unwrap_or("unknown"),
+            enrichment.country.as_deref().unwrap_or("unknown country"),
+        );
+    }
+    if let Some(abuse) = &enrichment.abuse {
+        println!("  Abuse:    {abuse}");
+    }
+
+    println!("  Status:   {}", explanation.status.describe());
+
+    let (Some(file), Some(until)) = (&explanation.file, explanation.until) else {
+        return Ok(());
+    };
+
+    match (&explanation.rule, explanation.since) {
+        (Some(name), Some(since)) => {
+            println!("  Rule:     {name} ({})", file.display());
+            println!("  Since:    {} (approx.)", since.format(&Rfc3339)?);
+        }
+        _ => println!("  Rule:     unknown ({})", file.display()),
+    }
+
+    let relative = |d: Duration| {
+        humantime::format_duration(StdDuration::from_secs(d.unsigned_abs().as_secs()))
+    };
+    println!(
+        "  Until:    {} ({})",
+        until.format(&Rfc3339)?,
+        if until > now {
+            format!("in {}", relative(until - now))
+        } else {
+            format!("{} ago", relative(now - until))
+        }
+    );
+    println!(
+        "  Previous: blocked {} times before",
+        explanation.previous_blocks.unwrap_or_default()
+    );
+
+    Ok(())
+}
+
+fn status(config: Option<PathBuf>, json: bool) -> Result<()> {
+    let settings = settings::load(config)?;
+    let addr = settings
+        .metrics
+        .listen
+        .context("metrics endpoint is not enabled")?;
+
+    if json {
+        let snapshot = metrics::fetch(addr, "/status.json").context("failed fetching status")?;
+        let snapshot = serde_json::from_str::<metrics::Snapshot>(&snapshot)?;
+        println!("{}", serde_json::to_string_pretty(&snapshot)?);
+        return Ok(());
+    }
+
+    let status = metrics::fetch(addr, "/status").context("failed fetching status")?;
+    print!("{status}");
+
+    Ok(())
+}
+
+/// Settings and statistics of a single rule, as shown by the `rules` command.
+#[derive(Serialize)]
+struct RuleSummary {
+    name: String,
+    file: PathBuf,
+    filters: usize,
+    /// Duration of blocks in seconds.
+    timeout: u64,
+    /// Blocked ports, or all ports if empty.
+    ports: Vec<u16>,
+    protocol: Protocol,
+    /// Lines processed by the running instance, if it's reachable.
+    lines: Option<u64>,
+    /// Matches of the running instance, if it's reachable.
+    matches: Option<u64>,
+    /// IPs that are currently blocked.
+    active_bans: usize,
+    /// All blocks, including repeated ones of the same IP.
+    total_bans: usize,
+}
+
+fn rules(config: Option<PathBuf>, storage: Option<PathBuf>, json: bool) -> Result<()> {
+    let settings = settings::load(config)?;
+    let storage = storage.unwrap_or_else(|| settings.storage.backend.default_path());
+    let records =
+        storage::open(settings.storage.backend, Some(storage.clone()), None)?.records()?;
+    let now = OffsetDateTime::now_utc();
+    let snapshot = running_snapshot(&settings, &storage);
+
+    let mut summaries = settings
+        .rules
+        .iter()
+        .map(|(name, rule)| {
+            let (active_bans, total_bans) = count_bans(&records, &rule.file, now);
+            let counters = snapshot
+                .as_ref()
+                .and_then(|snapshot| snapshot.rules.iter().find(|r| &r.name == name));
+
+            RuleSummary {
+                name: name.clone(),
+                file: rule.file.clone(),
+                filters: rule.filters.len(),
+                timeout: rule.timeout.unsigned_abs().as_secs(),
+                ports: rule.ports.clone(),
+                protocol: rule.protocol,
+                lines: counters.map(|c| c.lines),
+                matches: counters.map(|c| c.matches),
+                active_bans,
+                total_bans,
+            }
+        })
+        .collect::<Vec<_>>();
+    summaries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+        return Ok(());
+    }
+
+    let unknown =
+        |value: Option<u64>| value.map_or_else(|| "unknown".to_owned(), |v| v.to_string());
+
+    for (i, summary) in summaries.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!("Rule: {}", summary.name);
+        println!("  File:     {}", summary.file.display());
+        println!("  Filters:  {}", summary.filters);
+        println!(
+            "  Timeout:  {}",
+            humantime::format_duration(StdDuration::from_secs(summary.timeout))
+        );
+        if summary.ports.is_empty() {
+            println!("  Ports:    all");
+        } else {
+            let ports = summary.ports.iter().map(u16::to_string).collect::<Vec<_>>();
+            println!("  Ports:    {}", ports.join(", "));
+        }
+        println!("  Protocol: {}", summary.protocol);
+        println!("  Lines:    {}", unknown(summary.lines));
+        println!("  Matches:  {}", unknown(summary.matches));
+        println!(
+            "  Bans:     {} active, {} in total",
+            summary.active_bans, summary.total_bans
+        );
+
+        if summary.total_bans == 0 && summary.matches.is_none_or(|matches| matches == 0) {
+            println!("  This rule never blocked an IP, check its filters with `veto analyze`");
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the counters of the running instance, preferably from its metrics endpoint, or otherwise
+/// from the snapshot that it saves next to the storage.
+fn running_snapshot(settings: &settings::Settings, storage: &Path) -> Option<metrics::Snapshot> {
+    if let Some(addr) = settings.metrics.listen {
+        match metrics::fetch(addr, "/status.json")
+            .map_err(anyhow::Error::from)
+            .and_then(|body| serde_json::from_str(&body).map_err(Into::into))
+        {
+            Ok(snapshot) => return Some(snapshot),
+            Err(e) => warn!("failed fetching statistics of the running instance: {e:?}"),
+        }
+    }
+
+    let path = metrics::snapshot_path(storage);
+    let (snapshot, saved) = metrics::load_snapshot(&path)
+        .map_err(|e| {
+            info!(
+                "no statistics of a running instance at {}: {e}",
+                path.display()
+            );
+        })
+        .ok()?;
+
+    // The snapshot is saved every minute, so an older one hints at a stopped instance.
+    if let Ok(age) = saved.elapsed() {
+        if age > StdDuration::from_secs(300) {
+            warn!(
+                "statistics of the running instance are from {} ago, it may have stopped",
+                humantime::format_duration(StdDuration::from_secs(age.as_secs()))
+            );
+        }
+    }
+
+    Some(snapshot)
+}
+
+/// Count the currently active and all ever done bans of the rule that watches the log file.
+fn count_bans(records: &[storage::BanRecord], file: &Path, now: OffsetDateTime) -> (usize, usize) {
+    // Records refer to rules by their resolved log file path.
+    let file = handler::resolve_file(file).unwrap_or_else(|_| file.to_owned());
+
+    records
+        .iter()
+        .filter(|record| record.file == file)
+        .fold((0, 0), |(active, total), record| {
+            (
+                active + usize::from(record.active && record.until > now),
+                total + usize::from(record.times) + 1,
+            )
+        })
+}
+
+fn resume(config: Option<PathBuf>, storage: Option<PathBuf>) -> Result<()> {
+    let settings = settings::load(config)?;
+    let storage = storage.unwrap_or_else(|| settings.storage.backend.default_path());
+
+    breaker::request_resume(&storage).context("failed requesting to resume blocking")?;
+    println!("requested the running instance to resume blocking");
+
+    Ok(())
+}
+
+fn print_metrics(config: Option<PathBuf>, storage: Option<PathBuf>, json: bool) -> Result<()> {
+    let settings = settings::load(config)?;
+    let storage = storage.unwrap_or_else(|| settings.storage.backend.default_path());
+    let records =
+        storage::open(settings.storage.backend, Some(storage.clone()), None)?.records()?;
+    let now = OffsetDateTime::now_utc();
+    let snapshot = running_snapshot(&settings, &storage);
+
+    let mut rules = settings
+        .rules
+        .iter()
+        .map(|(name, rule)| {
+            let (active_bans, total_bans) = count_bans(&records, &rule.file, now);
+            let counters = snapshot
+                .as_ref()
+                .and_then(|snapshot| snapshot.rules.iter().find(|r| &r.name == name));
+
+            metrics::RuleCounters {
+                name: name.clone(),
+                lines: counters.map(|c| c.lines),
+                matches: counters.map(|c| c.matches),
+                active_bans,
+                total_bans,
+            }
+        })
+        .collect::<Vec<_>>();
+    rules.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let counters = metrics::Counters {
+        storage_entries: records.len(),
+        storage_bytes: fs::metadata(&storage).map_or(0, |meta| meta.len()),
+        pending_unblocks: records
+            .iter()
+            .filter(|record| record.active && record.until <= now)
+            .count(),
+        firewall_queue: snapshot.map(|snapshot| snapshot.firewall_queue),
+        rules,
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&counters)?);
+    } else {
+        let mut out = String::new();
+        counters.render_prometheus(&mut out)?;
+        print!("{out}");
+    }
+
+    Ok(())
+}
+
+fn analyze(
+    config: Option<PathBuf>,
+    rule: &str,
+    line: &str,
+    json: bool,
+    debug: bool,
+) -> Result<bool> {
+    let mut settings = settings::load(config)?;
+    let entry = handler::prepare_rule(
+        rule.to_owned(),
+        settings.rules.remove(rule).context("rule doesn't exist")?,
+        &settings.limits,
+        &mut handler::RuleCache::default(),
+    )?;
+    let matcher = Matcher::new();
+
+    let analysis = matcher.find_analyze(&entry, line);
+    let mut last_time = OffsetDateTime::UNIX_EPOCH;
+    let blocked = matcher.find(&entry, &mut last_time, line).is_some();
+    let diagnoses = if debug {
+        entry
+            .rule
+            .filters
+            .iter()
+            .filter_map(|filter| {
+                diagnose::diagnose(&filter.pattern, &entry.rule.host, line)
+                    .map(|diagnosis| (filter.pattern.clone(), diagnosis))
+            })
+            .collect()
+    } else {
+        IndexMap::new()
+    };
+
+    if json {
+        #[derive(Serialize)]
+        struct Output<'a> {
+            #[serde(flatten)]
+            analysis: &'a Analysis,
+            #[serde(skip_serializing_if = "IndexMap::is_empty")]
+            diagnoses: IndexMap<String, Diagnosis>,
+        }
+
+        let output = Output {
+            analysis: &analysis,
+            diagnoses,
+        };
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(blocked);
+    }
+
+    for (i, (filter, matched)) in analysis.matches.into_iter().enumerate() {
+        println!("Filter: {filter}");
+        if let Some(matched) = matched {
+            println!("  Captures:");
+            let name_len = matched
+                .captures
+                .iter()
+                .map(|c| c.0.len())
+                .max()
+                .unwrap_or_default();
+
+            for (name, value) in matched.captures {
+                println!("    {:2$}: {}", name, value.unwrap_or_default(), name_len);
+            }
+
+            println!(
+                "  Time: {}",
+                matched.time.map_or_else(
+                    || "no timetamp found".to_owned(),
+                    |time| format!(
+                        "{} {}",
+                        time.value,
+                        if time.outdated { "(outdated)" } else { "" }
+                    )
+                )
+            );
+
+            println!(
+                "  Host: {}",
+                matched.host.map_or_else(
+                    || "no host found".to_owned(),
+                    |host| match host {
+                        std::net::IpAddr::V4(addr) => format!("IPv4 {addr}"),
+                        std::net::IpAddr::V6(addr) => format!("IPv6 {addr}"),
+                    }
+                )
+            );
+
+            let name_len = matched
+                .blacklists
+                .iter()
+                .map(|b| b.0.len())
+                .max()
+                .unwrap_or_default();
 
             println!("  Blacklists:");
             for (name, pattern) in matched.blacklists {
                 println!("    {name:name_len$}: {pattern}");
             }
+
+            if debug {
+                print_spans(&entry.matchers[i].regex, line);
+            }
         } else {
             println!("  No match");
+
+            if let Some(diagnosis) = diagnoses.get(&filter) {
+                print_diagnosis(diagnosis, line);
+            }
+        }
+    }
+
+    Ok(blocked)
+}
+
+/// Result of matching many lines of a log file, as shown by the `analyze` command.
+#[derive(Default, Serialize)]
+struct FileAnalysis {
+    lines: u64,
+    /// Lines that would lead to a block, regardless of their age.
+    blocks: u64,
+    /// Distinct IPs that would be blocked.
+    hosts: usize,
+    /// Counters of each filter, keyed by its pattern.
+    filters: IndexMap<String, FilterCounts>,
+}
+
+#[derive(Default, Serialize)]
+struct FilterCounts {
+    /// Lines that the pattern matched.
+    matches: u64,
+    /// Matched lines that also contained an IP and hit a blacklist.
+    blacklisted: u64,
+}
+
+fn analyze_file(
+    config: Option<PathBuf>,
+    rule: &str,
+    selection: Selection,
+    json: bool,
+) -> Result<bool> {
+    let mut settings = settings::load(config)?;
+    let max_length = max_line_length(&settings);
+    let entry = handler::prepare_rule(
+        rule.to_owned(),
+        settings.rules.remove(rule).context("rule doesn't exist")?,
+        &settings.limits,
+        &mut handler::RuleCache::default(),
+    )?;
+
+    // Like in simulations, lines of any age are considered.
+    let matcher = Matcher::with_clock(|| OffsetDateTime::UNIX_EPOCH);
+    let locator = TimeLocator::new(&entry);
+    let mut hosts = HashSet::new();
+    let mut result = FileAnalysis::default();
+
+    read_selected(
+        &entry.rule.file,
+        selection,
+        locator.as_ref(),
+        max_length,
+        |line| {
+            result.lines += 1;
+
+            for (pattern, found) in matcher.find_analyze(&entry, line).matches {
+                let counts = result.filters.entry(pattern).or_default();
+                if let Some(found) = found {
+                    counts.matches += 1;
+                    if found.host.is_some() && !found.blacklists.is_empty() {
+                        counts.blacklisted += 1;
+                    }
+                }
+            }
+
+            let mut last_time = OffsetDateTime::UNIX_EPOCH;
+            if let Some(host) = matcher.find(&entry, &mut last_time, line) {
+                result.blocks += 1;
+                hosts.insert(host);
+            }
+        },
+    )
+    .with_context(|| format!("failed reading {}", entry.rule.file.display()))?;
+
+    result.hosts = hosts.len();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+        return Ok(result.blocks > 0);
+    }
+
+    println!("Lines:  {}", result.lines);
+    println!("Blocks: {} ({} IPs)", result.blocks, result.hosts);
+    for (pattern, counts) in &result.filters {
+        println!("Filter: {pattern}");
+        println!("  Matches:     {}", counts.matches);
+        println!("  Blacklisted: {}", counts.blacklisted);
+    }
+
+    Ok(result.blocks > 0)
+}
+
+/// Print the line and mark the span of each capture group below it.
+fn print_spans(regex: &Regex, line: &str) {
+    let Some(caps) = regex.captures(line) else {
+        return;
+    };
+    // Tabs are replaced, so the markers line up with the characters above them.
+    let column = |offset: usize| line[..offset].chars().count();
+
+    println!("  Spans:");
+    println!("    {}", line.replace('\t', " "));
+    for name in regex.capture_names().flatten() {
+        if let Some(m) = caps.name(name) {
+            println!(
+                "    {:start$}{} {name}",
+                "",
+                "^".repeat((column(m.end()) - column(m.start())).max(1)),
+                start = column(m.start()),
+            );
+        }
+    }
+}
+
+/// Print how far the pattern of a non-matching filter got, and which part of it likely failed.
+fn print_diagnosis(diagnosis: &Diagnosis, line: &str) {
+    println!("  Matched up to: {}", diagnosis.matched);
+    println!("  Failed at:     {}", diagnosis.failed);
+    println!("    {}", line.replace('\t', " "));
+    println!(
+        "    {:offset$}^",
+        "",
+        offset = line[..diagnosis.offset].chars().count()
+    );
+}
+
+/// Result of the `simulate` command.
+#[derive(Serialize)]
+struct SimulationReport<'a> {
+    lines: u64,
+    /// Time the replay took, in seconds.
+    elapsed: f64,
+    bans: Vec<SimulationBan<'a>>,
+    rules: Vec<SimulationRule<'a>>,
+}
+
+#[derive(Serialize)]
+struct SimulationBan<'a> {
+    rule: &'a str,
+    ip: IpAddr,
+    #[serde(with = "time::serde::rfc3339")]
+    at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    until: OffsetDateTime,
+    /// Pattern of the matching filter, or [`None`] if a plugin found the IP.
+    filter: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct SimulationRule<'a> {
+    name: &'a str,
+    lines: u64,
+    matches: u64,
+}
+
+fn simulate(
+    config: Option<PathBuf>,
+    rule: Option<&str>,
+    files: Vec<PathBuf>,
+    selection: Selection,
+    json: bool,
+) -> Result<bool> {
+    let mut settings = settings::load(config)?;
+    let mut entries = prepare_rules(&mut settings, rule)?;
+
+    // Either each rule replays its own file, or all rules together replay the given files.
+    let runs = if files.is_empty() {
+        entries
+            .into_iter()
+            .map(|entry| {
+                let file = entry.rule.file.clone();
+                (vec![entry], vec![file])
+            })
+            .collect::<Vec<_>>()
+    } else {
+        vec![(std::mem::take(&mut entries), files)]
+    };
+
+    let max_length = max_line_length(&settings);
+    let mut simulations = Vec::with_capacity(runs.len());
+    for (entries, files) in runs {
+        let locator = entries.iter().find_map(TimeLocator::new);
+        let mut simulation = Simulation::new(entries, settings.whitelist.clone());
+        for file in files {
+            let total = fs::metadata(&file).ok().map(|meta| meta.len());
+            let mut reporter = Reporter::default();
+            let mut processed = 0;
+
+            read_selected(&file, selection, locator.as_ref(), max_length, |line| {
+                simulation.push_line(line);
+
+                processed += line.len() as u64 + 1;
+                if reporter.due() {
+                    info!(
+                        "replaying {}: {}",
+                        file.display(),
+                        Progress {
+                            processed,
+                            total,
+                            matches: simulation.bans().len() as u64,
+                            elapsed: reporter.elapsed(),
+                        }
+                    );
+                }
+            })
+            .with_context(|| format!("failed replaying {}", file.display()))?;
+        }
+        simulations.push(simulation);
+    }
+
+    let lines = simulations.iter().map(Simulation::lines).sum::<u64>();
+    let elapsed = simulations
+        .iter()
+        .map(Simulation::elapsed)
+        .sum::<StdDuration>();
+
+    let mut bans = simulations
+        .iter()
+        .flat_map(|simulation| {
+            simulation.bans().iter().map(move |ban| {
+                let entry = simulation.rules().find(|entry| entry.name == ban.rule);
+                let filter = ban
+                    .filter
+                    .zip(entry)
+                    .map(|(index, entry)| entry.rule.filters[index].pattern.as_str());
+                (ban, filter)
+            })
+        })
+        .collect::<Vec<_>>();
+    bans.sort_by_key(|(ban, _)| ban.at);
+
+    if json {
+        let report = SimulationReport {
+            lines,
+            elapsed: elapsed.as_secs_f64(),
+            bans: bans
+                .iter()
+                .map(|(ban, filter)| SimulationBan {
+                    rule: &ban.rule,
+                    ip: ban.ip,
+                    at: ban.at,
+                    until: ban.until,
+                    filter: *filter,
+                })
+                .collect(),
+            rules: simulations
+                .iter()
+                .flat_map(Simulation::rules)
+                .map(|entry| SimulationRule {
+                    name: &entry.name,
+                    lines: entry.metrics.lines.load(Ordering::Relaxed),
+                    matches: entry.metrics.matches.load(Ordering::Relaxed),
+                })
+                .collect(),
+        };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(!report.bans.is_empty());
+    }
+
+    print_simulation(&simulations, &bans, lines, elapsed)?;
+
+    Ok(!bans.is_empty())
+}
+
+fn print_simulation(
+    simulations: &[Simulation],
+    bans: &[(&SimulatedBan, Option<&str>)],
+    lines: u64,
+    elapsed: StdDuration,
+) -> Result<()> {
+    #[allow(clippy::cast_precision_loss)]
+    let rate = lines as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+
+    println!("Replayed {lines} lines in {elapsed:?} ({rate:.0} lines/s)");
+
+    println!();
+    println!("Blocks: {}", bans.len());
+    for (ban, filter) in bans {
+        let filter = filter.unwrap_or("plugin");
+        println!(
+            "  {}  {}  {} (until {})",
+            ban.at.format(&Rfc3339)?,
+            ban.ip,
+            ban.rule,
+            ban.until.format(&Rfc3339)?,
+        );
+        println!("    Filter: {filter}");
+    }
+
+    for entry in simulations.iter().flat_map(Simulation::rules) {
+        let metrics = &entry.metrics;
+
+        println!();
+        println!("Rule: {}", entry.name);
+        println!("  Lines:   {}", metrics.lines.load(Ordering::Relaxed));
+        println!("  Matches: {}", metrics.matches.load(Ordering::Relaxed));
+
+        for (filter, histogram) in entry.rule.filters.iter().zip(&metrics.filters) {
+            println!("  Filter: {}", filter.pattern);
+            println!(
+                "    Mean: {:?}, p99: {}, samples: {}",
+                histogram.mean(),
+                histogram
+                    .quantile(0.99)
+                    .map_or_else(|| "> 10ms".to_owned(), |d| format!("<= {d:?}")),
+                histogram.count()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn bench(
+    config: Option<PathBuf>,
+    rule: Option<&str>,
+    iterations: u32,
+    file: Option<&Path>,
+) -> Result<()> {
+    let mut settings = settings::load(config)?;
+    let entries = prepare_rules(&mut settings, rule)?;
+    let max_length = max_line_length(&settings);
+
+    let load = |file: &Path| -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        read_lines(file, max_length, |line| lines.push(line.to_owned()))
+            .with_context(|| format!("failed reading {}", file.display()))?;
+        Ok(lines)
+    };
+    let sample = file.map(load).transpose()?;
+
+    for entry in entries {
+        let lines = match &sample {
+            Some(lines) => Cow::Borrowed(lines),
+            None => Cow::Owned(load(&entry.rule.file)?),
+        };
+        let report = veto::bench::run(&entry, &lines, iterations);
+        let slow = report.slow_filters().collect::<Vec<_>>();
+
+        println!("Rule: {}", report.name);
+        println!(
+            "  Lines: {} in {:?} ({:.0} lines/s)",
+            report.lines,
+            report.elapsed,
+            report.lines_per_second()
+        );
+
+        for (i, filter) in report.filters.iter().enumerate() {
+            println!(
+                "  Filter: {}{}",
+                filter.pattern,
+                if slow.contains(&i) { " (SLOW)" } else { "" }
+            );
+            println!(
+                "    Per line: {:?} ({:.0} lines/s), candidates: {}, matches: {}",
+                filter.per_line(),
+                filter.lines_per_second(),
+                filter.candidates,
+                filter.matches
+            );
+        }
+
+        if !slow.is_empty() {
+            println!(
+                "  {} filter(s) at least {}x slower than the others of this rule",
+                slow.len(),
+                veto::bench::SLOW_FACTOR
+            );
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+fn rule_wizard(config: Option<PathBuf>, file: Option<PathBuf>) -> Result<()> {
+    let config = config.unwrap_or_else(|| PathBuf::from(settings::DEFAULT_PATH));
+    let settings = if config.exists() {
+        settings::load(Some(config.clone()))?
+    } else {
+        settings::Settings::default()
+    };
+
+    let file = match file {
+        Some(file) => file,
+        None => prompt("Sample log file", None)?.into(),
+    };
+    let mut lines = Vec::new();
+    read_lines(&file, max_line_length(&settings), |line| {
+        lines.push(line.to_owned());
+    })
+    .with_context(|| format!("failed reading {}", file.display()))?;
+    println!("Read {} lines from {}", lines.len(), file.display());
+
+    let mut suggestions = Vec::<wizard::Suggestion>::new();
+    for line in pick_lines(&lines)? {
+        let Some(mut suggestion) = wizard::suggest(&line) else {
+            println!("No known timestamp and IP found, skipping: {line}");
+            continue;
+        };
+
+        println!();
+        println!("Line:   {line}");
+        println!("Filter: {}", suggestion.pattern);
+        suggestion.blacklist = prompt(
+            &format!("Blacklist entry for the `{}` group", suggestion.group),
+            Some(&suggestion.blacklist),
+        )?;
+
+        let rule = wizard::build_rule(file.clone(), Duration::hours(1), &[suggestion.clone()]);
+        let evaluation = evaluate_rule(rule, &settings, &lines)?;
+        println!(
+            "Matches {} of {} lines, from {} IPs",
+            evaluation.matches, evaluation.lines, evaluation.hosts
+        );
+
+        if !confirm("Use this filter?", true)? {
+            continue;
+        }
+        match suggestions.first() {
+            Some(first) if first.time_format != suggestion.time_format => {
+                println!("The timestamp differs from the previous filters, skipping it");
+            }
+            _ => suggestions.push(suggestion),
+        }
+    }
+
+    if suggestions.is_empty() {
+        println!("No filters picked, nothing to do");
+        return Ok(());
+    }
+
+    let name = loop {
+        let default = file
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned());
+        let name = prompt("Rule name", default.as_deref())?;
+        if !settings.rules.contains_key(&name) {
+            break name;
+        }
+        println!("A rule named `{name}` already exists");
+    };
+    let timeout = prompt("Block duration", Some("1h"))?
+        .parse::<humantime::Duration>()
+        .context("invalid duration")
+        .and_then(|d| Duration::try_from(*d).context("duration too long"))?;
+
+    let rule = wizard::build_rule(file.canonicalize()?, timeout, &suggestions);
+    let toml = settings::rule_to_string(&name, &rule)?;
+    let evaluation = evaluate_rule(rule, &settings, &lines)?;
+
+    println!();
+    println!(
+        "The rule matches {} of {} lines, from {} IPs:",
+        evaluation.matches, evaluation.lines, evaluation.hosts
+    );
+    println!();
+    println!("{toml}");
+
+    if confirm(&format!("Append it to {}?", config.display()), false)? {
+        let mut out = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config)
+            .with_context(|| format!("failed opening {}", config.display()))?;
+        writeln!(out, "\n{toml}")?;
+        println!("Rule written, restart veto to load it");
+    }
+
+    Ok(())
+}
+
+/// Let the user search for lines and pick some of them as examples.
+fn pick_lines(lines: &[String]) -> Result<Vec<String>> {
+    const MAX_RESULTS: usize = 20;
+    let mut picked = Vec::new();
+
+    loop {
+        let search = prompt("Search for example lines (empty to finish)", Some(""))?;
+        if search.is_empty() {
+            return Ok(picked);
+        }
+
+        let found = lines
+            .iter()
+            .filter(|line| line.contains(&search))
+            .take(MAX_RESULTS)
+            .collect::<Vec<_>>();
+        if found.is_empty() {
+            println!("No lines found");
+            continue;
+        }
+
+        for (i, line) in found.iter().enumerate() {
+            println!("{:>3}: {line}", i + 1);
+        }
+
+        let choice = prompt("Lines to use, like 1,3 (empty for none)", Some(""))?;
+        for number in choice.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match number
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| found.get(n.checked_sub(1)?))
+            {
+                Some(line) => picked.push((*line).clone()),
+                None => println!("Ignoring invalid choice `{number}`"),
+            }
+        }
+    }
+}
+
+fn evaluate_rule(
+    rule: settings::Rule,
+    settings: &settings::Settings,
+    lines: &[String],
+) -> Result<wizard::Evaluation> {
+    let entry = handler::prepare_rule(
+        String::new(),
+        rule,
+        &settings.limits,
+        &mut handler::RuleCache::default(),
+    )?;
+
+    Ok(wizard::evaluate(&entry, lines))
+}
+
+/// Ask the user for a line of input, falling back to the default for an empty answer. Without a
+/// default, the question is repeated until an answer is given.
+fn prompt(question: &str, default: Option<&str>) -> Result<String> {
+    let mut stdout = io::stdout().lock();
+    let mut answer = String::new();
+
+    loop {
+        match default {
+            Some(default) if !default.is_empty() => write!(stdout, "{question} [{default}]: ")?,
+            _ => write!(stdout, "{question}: ")?,
         }
+        stdout.flush()?;
+
+        answer.clear();
+        if io::stdin().read_line(&mut answer)? == 0 {
+            anyhow::bail!("no more input");
+        }
+
+        match (answer.trim(), default) {
+            ("", Some(default)) => return Ok(default.to_owned()),
+            ("", None) => {}
+            (answer, _) => return Ok(answer.to_owned()),
+        }
+    }
+}
+
+/// Ask the user a yes or no question.
+fn confirm(question: &str, default: bool) -> Result<bool> {
+    let choices = if default { "Y/n" } else { "y/N" };
+    let answer = prompt(&format!("{question} [{choices}]"), Some(""))?;
+
+    Ok(match answer.to_ascii_lowercase().as_str() {
+        "" => default,
+        answer => answer == "y" || answer == "yes",
+    })
+}
+
+/// Prepare either all rules, or only the one with the given name.
+fn prepare_rules(
+    settings: &mut settings::Settings,
+    rule: Option<&str>,
+) -> Result<Vec<handler::Entry>> {
+    let rules = match rule {
+        Some(name) => vec![(
+            name.to_owned(),
+            settings.rules.remove(name).context("rule doesn't exist")?,
+        )],
+        None => std::mem::take(&mut settings.rules).into_iter().collect(),
+    };
+
+    let mut cache = handler::RuleCache::default();
+    rules
+        .into_iter()
+        .map(|(name, rule)| {
+            handler::prepare_rule(name, rule, &settings.limits, &mut cache).map_err(Into::into)
+        })
+        .collect()
+}
+
+fn max_line_length(settings: &settings::Settings) -> usize {
+    settings
+        .limits
+        .max_line_length
+        .map_or(usize::MAX, NonZeroUsize::get)
+}
+
+/// Pass all lines of a file (or stdin for `-`) to the given function, skipping overly long lines.
+fn read_lines(file: &Path, max_length: usize, f: impl FnMut(&str)) -> Result<()> {
+    read_selected(file, Selection::default(), None, max_length, f)
+}
+
+/// Read the selected part of the file line by line, or stdin if the file is `-`. Seeking to the
+/// start time needs the timestamps of the lines, which the locator finds.
+fn read_selected(
+    file: &Path,
+    selection: Selection,
+    locator: Option<&TimeLocator>,
+    max_length: usize,
+    mut f: impl FnMut(&str),
+) -> Result<()> {
+    let since = match (selection.since, locator) {
+        (Some(since), Some(locator)) => Some((since, locator)),
+        (Some(_), None) => anyhow::bail!("--since needs filters that capture a timestamp"),
+        (None, _) => None,
+    };
+
+    let mut reader: Box<dyn BufRead> = if file == Path::new("-") {
+        Box::new(io::stdin().lock())
+    } else {
+        let mut file = File::open(file)?;
+        if let Some((since, locator)) = since {
+            seek::seek_since(&mut file, locator, since)?;
+        }
+        Box::new(BufReader::new(file))
+    };
+
+    // Stdin can't seek, so older lines are skipped while reading instead.
+    let mut skipping = since;
+    let mut remaining = selection.sample.map_or(usize::MAX, NonZeroUsize::get);
+    let mut buf = Vec::new();
+
+    while remaining > 0 {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+
+        let line = buf.strip_suffix(b"\n").unwrap_or(&buf);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.len() > max_length {
+            continue;
+        }
+        let Some(line) = reader::text(line) else {
+            continue;
+        };
+        let line = String::from_utf8_lossy(line);
+
+        if let Some((since, locator)) = skipping {
+            if locator.find(&line).is_none_or(|time| time < since) {
+                continue;
+            }
+            skipping = None;
+        }
+
+        f(&line);
+        remaining -= 1;
     }
 
     Ok(())
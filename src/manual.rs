@@ -0,0 +1,497 @@
+//! Manual changes to the blocked IPs, like blocking single IPs or importing whole blocklists. Each
+//! change can be shown as dry run first, listing the exact storage changes and firewall commands.
+
+use std::{
+    collections::HashSet,
+    env,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration as StdDuration,
+};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use indexmap::IndexSet;
+use log::warn;
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use veto::{
+    audit::{self, AuditLog},
+    firewall::{self, Action, Firewall, Target},
+    handler, honeypot, metrics,
+    settings::{self, Protocol, Rule, Settings},
+    storage::{self, BanRecord, TargetRepository},
+    tor,
+};
+
+/// Age of the saved metrics, below which an instance is considered to be running. The snapshot is
+/// saved every minute.
+const RUNNING_THRESHOLD: StdDuration = StdDuration::from_secs(120);
+
+/// Options for blocking IPs manually.
+#[derive(Args)]
+pub struct BanOptions {
+    /// Rule to block the IPs for, which decides the blocked ports and the default duration.
+    #[arg(long, short)]
+    rule: String,
+    /// How long to block the IPs, instead of the rule's timeout.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    duration: Option<StdDuration>,
+    /// Why the IPs are blocked, which is recorded in the audit log.
+    #[arg(long)]
+    reason: Option<String>,
+    /// Only show the storage changes and firewall commands, without doing them.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+/// All changes of a manual command, which are either shown or done.
+#[derive(Default, Serialize)]
+struct Plan {
+    /// Whether the changes were done, or only shown as dry run.
+    applied: bool,
+    storage: Vec<StorageChange>,
+    /// Commands that the firewall runs, if it can describe them.
+    firewall: Option<Vec<String>>,
+    skipped: Vec<Skipped>,
+}
+
+/// Change to a single entry of the storage.
+#[derive(Serialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+enum StorageChange {
+    /// New entry for an IP that was never blocked before.
+    Insert {
+        ip: IpAddr,
+        rule: String,
+        #[serde(with = "time::serde::rfc3339")]
+        until: OffsetDateTime,
+    },
+    /// Existing entry, that is blocked again or for a different time.
+    Update {
+        ip: IpAddr,
+        #[serde(with = "time::serde::rfc3339")]
+        until: OffsetDateTime,
+    },
+    Remove {
+        ip: IpAddr,
+    },
+}
+
+/// Input that was left out, together with the reason.
+#[derive(Serialize)]
+struct Skipped {
+    /// Line in the imported file, if the input came from a file.
+    line: Option<usize>,
+    value: String,
+    reason: &'static str,
+}
+
+/// Block the IPs, as if the rule had blocked them.
+pub fn ban(
+    config: Option<PathBuf>,
+    storage: Option<PathBuf>,
+    ips: Vec<IpAddr>,
+    options: &BanOptions,
+    json: bool,
+) -> Result<bool> {
+    let input = ips.into_iter().map(|ip| (None, ip.to_string())).collect();
+    block(config, storage, input, options, "manual ban", json)
+}
+
+/// Block all IPs from a blocklist file, with one IP per line. Empty lines and comments starting
+/// with `#` are ignored, as well as anything after the IP on the same line.
+pub fn import(
+    config: Option<PathBuf>,
+    storage: Option<PathBuf>,
+    file: &Path,
+    options: &BanOptions,
+    json: bool,
+) -> Result<bool> {
+    let reader: Box<dyn BufRead> = if file == Path::new("-") {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(
+            File::open(file).with_context(|| format!("failed opening {}", file.display()))?,
+        ))
+    };
+
+    let mut input = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.with_context(|| format!("failed reading {}", file.display()))?;
+        let content = line.split('#').next().unwrap_or_default();
+
+        if let Some(value) = content.split_whitespace().next() {
+            input.push((Some(i + 1), value.to_owned()));
+        }
+    }
+
+    let reason = format!("imported from {}", file.display());
+    block(config, storage, input, options, &reason, json)
+}
+
+/// Remove the blocks of the IPs from the storage and firewall.
+pub fn unban(
+    config: Option<PathBuf>,
+    storage: Option<PathBuf>,
+    ips: &[IpAddr],
+    reason: Option<&str>,
+    dry_run: bool,
+    json: bool,
+) -> Result<bool> {
+    let settings = settings::load(config)?;
+    let storage = storage.unwrap_or_else(|| settings.storage.backend.default_path());
+    let mut repository = storage::open(settings.storage.backend, Some(storage.clone()), None)?;
+    let records = repository.records()?;
+
+    let mut plan = Plan::default();
+    let mut targets = Vec::new();
+    let mut seen = HashSet::new();
+    let mut audit = Vec::new();
+
+    for ip in ips {
+        if !seen.insert(ip) {
+            plan.skip(None, ip.to_string(), "duplicate");
+            continue;
+        }
+
+        let Some(record) = records.iter().find(|record| record.ip == *ip) else {
+            plan.skip(None, ip.to_string(), "not in the storage");
+            continue;
+        };
+
+        let rule = find_rule(&settings, record);
+
+        plan.storage.push(StorageChange::Remove { ip: *ip });
+        if record.active {
+            let (ports, protocol) = rule.map_or((&[][..], Protocol::Tcp), |(_, rule)| {
+                (&rule.ports, rule.protocol)
+            });
+            targets.push(Target {
+                ip: (*ip).into(),
+                ports,
+                protocol,
+                timeout: None,
+            });
+        }
+
+        audit.push(audit::Entry {
+            at: OffsetDateTime::now_utc(),
+            action: Action::Unblock,
+            ip: *ip,
+            rule: rule.map_or_else(|| unknown_rule(record), |(name, _)| name.clone()),
+            until: None,
+            reason: reason.unwrap_or("manual unban").to_owned(),
+            initiator: initiator(),
+        });
+    }
+
+    let firewall = super::new_firewall(&settings)?;
+    plan.firewall = firewall.describe(Action::Unblock, &targets);
+
+    if !dry_run {
+        warn_running(&storage);
+
+        for change in &plan.storage {
+            if let StorageChange::Remove { ip } = change {
+                repository.remove(*ip)?;
+            }
+        }
+        firewall.unblock_all(&targets)?;
+        write_audit(&settings, &audit)?;
+        plan.applied = true;
+    }
+
+    plan.print(json)?;
+
+    Ok(!plan.storage.is_empty())
+}
+
+/// Block all active IPs of the storage on the firewall again, like after a reboot cleared it.
+pub fn restore(
+    config: Option<PathBuf>,
+    storage: Option<PathBuf>,
+    dry_run: bool,
+    json: bool,
+) -> Result<bool> {
+    let settings = settings::load(config)?;
+    let storage = storage.unwrap_or_else(|| settings.storage.backend.default_path());
+    let records = storage::open(settings.storage.backend, Some(storage), None)?.active_records()?;
+
+    let now = OffsetDateTime::now_utc().replace_nanosecond(0)?;
+    let targets = records
+        .iter()
+        .filter(|record| record.active)
+        .map(|record| {
+            let (ports, protocol) = find_rule(&settings, record)
+                .map_or((&[][..], Protocol::Tcp), |(_, rule)| {
+                    (&rule.ports, rule.protocol)
+                });
+            Target {
+                ip: record.ip.into(),
+                ports,
+                protocol,
+                timeout: firewall::remaining(record.until, now),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let firewall = super::new_firewall(&settings)?;
+    let mut plan = Plan {
+        firewall: firewall.describe(Action::Block, &targets),
+        ..Plan::default()
+    };
+
+    // Only the firewall is changed, so this is fine while veto is running.
+    if !dry_run {
+        firewall.install()?;
+        firewall.block_all(&targets)?;
+        plan.applied = true;
+    }
+
+    plan.print(json)?;
+
+    Ok(!targets.is_empty())
+}
+
+/// Parse the input values as IPs and block them for the rule.
+fn block(
+    config: Option<PathBuf>,
+    storage: Option<PathBuf>,
+    input: Vec<(Option<usize>, String)>,
+    options: &BanOptions,
+    reason: &str,
+    json: bool,
+) -> Result<bool> {
+    let settings = settings::load(config)?;
+    let rule = settings
+        .rules
+        .get(&options.rule)
+        .context("rule doesn't exist")?;
+    let storage = storage.unwrap_or_else(|| settings.storage.backend.default_path());
+    let mut repository = storage::open(settings.storage.backend, Some(storage.clone()), None)?;
+    let known = repository
+        .records()?
+        .into_iter()
+        .map(|record| record.ip)
+        .collect::<HashSet<_>>();
+
+    let now = OffsetDateTime::now_utc().replace_nanosecond(0)?;
+    let until = now.saturating_add(
+        options
+            .duration
+            .map_or(Ok(rule.timeout), time::Duration::try_from)
+            .context("duration is too long")?,
+    );
+    // Records refer to rules by their resolved log file path.
+    let file = handler::resolve_file(&rule.file).unwrap_or_else(|_| rule.file.clone());
+
+    let mut plan = Plan::default();
+    let mut ips = IndexSet::new();
+
+    for (line, value) in input {
+        let ip = match value.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) if value.contains('/') => {
+                plan.skip(line, value, "networks aren't supported");
+                continue;
+            }
+            Err(_) => {
+                plan.skip(line, value, "not an IP address");
+                continue;
+            }
+        };
+
+        if settings.whitelist.iter().any(|wl| wl.contains(ip)) {
+            plan.skip(line, value, "whitelisted");
+        } else if !ips.insert(ip) {
+            plan.skip(line, value, "duplicate");
+        }
+    }
+
+    for ip in &ips {
+        plan.storage.push(if known.contains(ip) {
+            StorageChange::Update { ip: *ip, until }
+        } else {
+            StorageChange::Insert {
+                ip: *ip,
+                rule: options.rule.clone(),
+                until,
+            }
+        });
+    }
+
+    let targets = ips
+        .iter()
+        .map(|ip| Target {
+            ip: (*ip).into(),
+            ports: &rule.ports,
+            protocol: rule.protocol,
+            timeout: firewall::remaining(until, now),
+        })
+        .collect::<Vec<_>>();
+
+    let firewall = super::new_firewall(&settings)?;
+    plan.firewall = firewall.describe(Action::Block, &targets);
+
+    if !options.dry_run {
+        warn_running(&storage);
+
+        for ip in &ips {
+            repository.upsert(*ip, until, &file)?;
+        }
+        firewall.block_all(&targets)?;
+
+        let initiator = initiator();
+        let audit = ips
+            .iter()
+            .map(|ip| audit::Entry {
+                at: now,
+                action: Action::Block,
+                ip: *ip,
+                rule: options.rule.clone(),
+                until: Some(until),
+                reason: options.reason.clone().unwrap_or_else(|| reason.to_owned()),
+                initiator: initiator.clone(),
+            })
+            .collect::<Vec<_>>();
+        write_audit(&settings, &audit)?;
+        plan.applied = true;
+    }
+
+    plan.print(json)?;
+
+    Ok(!ips.is_empty())
+}
+
+/// Find the rule that the record belongs to.
+fn find_rule<'a>(settings: &'a Settings, record: &BanRecord) -> Option<(&'a String, &'a Rule)> {
+    settings
+        .rules
+        .iter()
+        .find(|(_, rule)| handler::resolve_file(&rule.file).is_ok_and(|file| file == record.file))
+}
+
+/// Name for records without a rule in the settings, which are either blocks of the honeypot, the
+/// Tor exit list or of a rule that was removed since.
+fn unknown_rule(record: &BanRecord) -> String {
+    if record.file == Path::new(honeypot::STORAGE_PATH) {
+        honeypot::RULE_NAME.to_owned()
+    } else if record.file == Path::new(tor::STORAGE_PATH) {
+        tor::RULE_NAME.to_owned()
+    } else {
+        "unknown".to_owned()
+    }
+}
+
+/// User that runs the command, as initiator of the audit log entries. For commands that are run
+/// through sudo, it's the user that invoked sudo.
+fn initiator() -> String {
+    let user = env::var("SUDO_USER")
+        .or_else(|_| env::var("USER"))
+        .unwrap_or_else(|_| "unknown".to_owned());
+
+    format!("user:{user}")
+}
+
+/// Append the entries to the audit log, if it's enabled.
+fn write_audit(settings: &Settings, entries: &[audit::Entry]) -> Result<()> {
+    let Some(log) = AuditLog::from_settings(&settings.audit).context("failed opening audit log")?
+    else {
+        return Ok(());
+    };
+
+    for entry in entries {
+        log.write(entry).context("failed writing audit log")?;
+    }
+
+    Ok(())
+}
+
+/// Warn about a running instance, which keeps its own copy of the storage and overwrites any
+/// changes to the storage file with its next save.
+fn warn_running(storage: &Path) {
+    let running = metrics::load_snapshot(&metrics::snapshot_path(storage))
+        .ok()
+        .filter(|(snapshot, _)| {
+            // The final snapshot is saved on shutdown, so check that the process still exists.
+            snapshot.pid.is_none_or(|pid| {
+                cfg!(not(target_os = "linux")) || Path::new("/proc").join(pid.to_string()).exists()
+            })
+        })
+        .and_then(|(_, saved)| saved.elapsed().ok())
+        .is_some_and(|age| age < RUNNING_THRESHOLD);
+
+    if running {
+        warn!(
+            "veto seems to be running, its next save overwrites these storage changes, stop it \
+             first"
+        );
+    }
+}
+
+impl Plan {
+    fn skip(&mut self, line: Option<usize>, value: impl Into<String>, reason: &'static str) {
+        self.skipped.push(Skipped {
+            line,
+            value: value.into(),
+            reason,
+        });
+    }
+
+    fn print(&self, json: bool) -> Result<()> {
+        if json {
+            println!("{}", serde_json::to_string_pretty(self)?);
+            return Ok(());
+        }
+
+        let format = |time: OffsetDateTime| time.format(&Rfc3339).unwrap_or_default();
+
+        println!("Storage changes:");
+        for change in &self.storage {
+            match change {
+                StorageChange::Insert { ip, rule, until } => {
+                    println!("  insert {ip} for rule {rule}, until {}", format(*until));
+                }
+                StorageChange::Update { ip, until } => {
+                    println!("  update {ip}, until {}", format(*until));
+                }
+                StorageChange::Remove { ip } => println!("  remove {ip}"),
+            }
+        }
+        if self.storage.is_empty() {
+            println!("  none");
+        }
+
+        println!("Firewall commands:");
+        match &self.firewall {
+            Some(commands) if commands.is_empty() => println!("  none"),
+            Some(commands) => {
+                for line in commands.iter().flat_map(|command| command.lines()) {
+                    println!("  {line}");
+                }
+            }
+            None => println!("  unknown, the firewall can't describe its commands"),
+        }
+
+        if !self.skipped.is_empty() {
+            println!("Skipped:");
+            for skipped in &self.skipped {
+                match skipped.line {
+                    Some(line) => {
+                        println!("  line {line}: {} ({})", skipped.value, skipped.reason);
+                    }
+                    None => println!("  {} ({})", skipped.value, skipped.reason),
+                }
+            }
+        }
+
+        if !self.applied {
+            println!("Dry run, nothing was changed");
+        }
+
+        Ok(())
+    }
+}
@@ -1,44 +1,77 @@
 #![allow(clippy::inline_always, clippy::option_if_let_else)]
 
-use std::net::IpAddr;
+use std::{net::IpAddr, path::PathBuf, time::Instant};
 
 use aho_corasick::AhoCorasick;
-use regex::Captures;
-use time::{format_description::FormatItem, macros::format_description, OffsetDateTime};
+use flume::Receiver;
+use parking_lot::Mutex;
+use regex::CaptureLocations;
+use serde::Serialize;
+use time::OffsetDateTime;
 
-use crate::{handler::Entry, settings::Rule, IndexMap};
+use crate::{
+    crawler::{Claim, Resolver, Verifier},
+    handler::Entry,
+    host::{HostContext, HostExtractor},
+    settings::Rule,
+    timestamp::{TimeParser, TIME_GROUPS},
+    IndexMap,
+};
 
-const HOST_GROUP: &str = "host";
-const TIME_GROUP: &str = "time";
-const TIME_FORMAT: &[FormatItem<'_>] = format_description!(
-    "[day]/[month repr:short]/[year]:[hour][minute][second] [offset_hour][offset_minute]"
-);
+/// Source of the current time, which can be replaced for testing or replaying old logs.
+pub type Clock = fn() -> OffsetDateTime;
 
 pub struct Matcher {
     now: OffsetDateTime,
+    clock: Clock,
+    /// Verification of crawlers, for rules with a `crawler_group`.
+    crawlers: Verifier,
+    /// Whether lines of IPs that claim to be crawlers are skipped until the verification finished,
+    /// instead of waiting for it.
+    defer_crawlers: bool,
+    /// IPs that claim to be crawlers and are still verified, with the log file of the rule that
+    /// found them.
+    pending_crawlers: Mutex<IndexMap<IpAddr, (Claim, PathBuf)>>,
 }
 
 impl Default for Matcher {
     fn default() -> Self {
-        Self {
-            now: OffsetDateTime::now_utc(),
-        }
+        Self::with_clock(OffsetDateTime::now_utc)
     }
 }
 
-#[derive(Debug, Default)]
+/// Detailed result of matching a single line against all filters of a rule.
+///
+/// The type serializes to a stable layout, so results can be consumed by other tools as JSON.
+#[derive(Debug, Default, Serialize)]
 pub struct Analysis {
+    /// Result of each filter, keyed by its pattern, or [`None`] if it didn't match.
     pub matches: IndexMap<String, Option<Match>>,
 }
 
-#[derive(Debug)]
+/// Information extracted by a single matching filter.
+#[derive(Debug, Serialize)]
 pub struct Match {
-    pub time: Option<(OffsetDateTime, bool)>,
+    /// Timestamp of the line, if it contained one.
+    pub time: Option<MatchTime>,
+    /// Client IP of the line, if it contained a valid one.
     pub host: Option<IpAddr>,
+    /// Content of all named capture groups, or [`None`] for groups that didn't participate.
     pub captures: IndexMap<String, Option<String>>,
+    /// Blacklists that were hit, with the matching word of each.
     pub blacklists: IndexMap<String, String>,
 }
 
+/// Timestamp extracted from a line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct MatchTime {
+    /// The parsed timestamp, serialized in the RFC 3339 format.
+    #[serde(with = "time::serde::rfc3339")]
+    pub value: OffsetDateTime,
+    /// Whether the timestamp is too old for the line to cause a block.
+    pub outdated: bool,
+}
+
 impl Matcher {
     #[must_use]
     pub fn new() -> Self {
@@ -47,43 +80,187 @@ impl Matcher {
 
     // Only used for benchmarks, don't use directly.
     #[must_use]
-    pub const fn with(now: OffsetDateTime) -> Self {
-        Self { now }
+    pub fn with(now: OffsetDateTime) -> Self {
+        Self {
+            now,
+            clock: OffsetDateTime::now_utc,
+            crawlers: Verifier::default(),
+            defer_crawlers: false,
+            pending_crawlers: Mutex::default(),
+        }
+    }
+
+    /// Create a new matcher that takes the current time from the given clock.
+    #[must_use]
+    pub fn with_clock(clock: Clock) -> Self {
+        Self {
+            now: clock(),
+            clock,
+            crawlers: Verifier::default(),
+            defer_crawlers: false,
+            pending_crawlers: Mutex::default(),
+        }
+    }
+
+    /// Replace the resolver that crawlers are verified with, like for testing.
+    #[must_use]
+    pub fn with_resolver(mut self, resolver: Box<dyn Resolver>) -> Self {
+        self.crawlers = Verifier::new(resolver);
+        self
+    }
+
+    /// Don't wait for the verification of IPs that claim to be crawlers. Their lines are skipped,
+    /// and [`Self::unverified_crawlers`] tells once the verification failed, so the handler thread
+    /// is never held up by DNS lookups.
+    #[must_use]
+    pub const fn with_deferred_crawlers(mut self) -> Self {
+        self.defer_crawlers = true;
+        self
+    }
+
+    /// IPs that claimed to be crawlers and failed the verification, since the last call, with the
+    /// log file of the rule that found them. Verified crawlers are dropped, while IPs that are
+    /// still verified are kept for the next call.
+    pub fn unverified_crawlers(&self) -> Vec<(IpAddr, PathBuf)> {
+        let mut unverified = Vec::new();
+
+        self.pending_crawlers.lock().retain(|ip, (claim, file)| {
+            match self.crawlers.status(*ip, *claim) {
+                Some(false) => {
+                    unverified.push((*ip, file.clone()));
+                    false
+                }
+                Some(true) => false,
+                None => true,
+            }
+        });
+
+        unverified
     }
 
+    /// Receiver of a message whenever a verification of a crawler finished.
+    #[must_use]
+    pub fn crawler_updates(&self) -> Receiver<()> {
+        self.crawlers.updates()
+    }
+
+    /// Update the current time that log entries are compared against, to decide whether they're
+    /// outdated.
+    pub fn refresh(&mut self) {
+        self.now = (self.clock)();
+    }
+
+    /// Get the current time from the clock, without updating the time used for matching.
+    #[must_use]
+    pub fn current_time(&self) -> OffsetDateTime {
+        (self.clock)()
+    }
+
+    #[inline]
     pub fn find(
         &self,
         entry: &Entry,
         last_time: &mut OffsetDateTime,
         line: &str,
     ) -> Option<IpAddr> {
-        for matcher in &entry.matchers {
-            if let Some(caps) = matcher.captures(line) {
-                match Self::match_time(&caps) {
-                    Some(time) => {
+        self.find_with(entry, &mut entry.capture_locations(), last_time, line)
+    }
+
+    /// Like [`Self::find`], but with the capture locations of the entry's filters from
+    /// [`Entry::capture_locations`], which are reused for many lines.
+    #[inline]
+    pub fn find_with(
+        &self,
+        entry: &Entry,
+        locations: &mut [CaptureLocations],
+        last_time: &mut OffsetDateTime,
+        line: &str,
+    ) -> Option<IpAddr> {
+        self.detect_with(entry, locations, last_time, line)
+            .map(|detection| detection.host)
+    }
+
+    /// Like [`Self::find`], but also tell which filter caused the match.
+    pub fn detect(
+        &self,
+        entry: &Entry,
+        last_time: &mut OffsetDateTime,
+        line: &str,
+    ) -> Option<Detection> {
+        self.detect_with(entry, &mut entry.capture_locations(), last_time, line)
+    }
+
+    /// Like [`Self::detect`], but with reused capture locations like [`Self::find_with`].
+    pub fn detect_with(
+        &self,
+        entry: &Entry,
+        locations: &mut [CaptureLocations],
+        last_time: &mut OffsetDateTime,
+        line: &str,
+    ) -> Option<Detection> {
+        let sample = entry.metrics.record_line();
+
+        for ((i, filter), locations) in entry.matchers.iter().enumerate().zip(locations) {
+            let start = sample.then(Instant::now);
+
+            let found = filter
+                .is_candidate(line)
+                .then(|| {
+                    filter.captures_with(locations, line, |group| {
+                        let Some(time) = Self::match_time(&*entry.time_parser, group) else {
+                            return Found::Continue;
+                        };
+
                         if self.is_outdated(&entry.rule, *last_time, time) {
-                            break;
+                            return Found::Break;
                         }
 
                         *last_time = time;
-                    }
-                    None => continue,
-                }
 
-                let host = match Self::match_host(&caps) {
-                    Some(host) => host,
-                    None => continue,
-                };
+                        let Some(host) = Self::match_host(&*entry.host_extractor, line, group)
+                        else {
+                            return Found::Continue;
+                        };
 
-                if Self::match_blacklists(&caps, &entry.blacklists)
-                    .next()
-                    .is_some()
-                {
-                    return Some(host);
+                        if Self::match_blacklists(group, &entry.blacklists)
+                            .next()
+                            .is_none()
+                            || self.is_verified_crawler(&entry.rule, host, group)
+                        {
+                            Found::Continue
+                        } else {
+                            Found::Host(host)
+                        }
+                    })
+                })
+                .flatten();
+
+            if let Some(start) = start {
+                entry.metrics.filters[i].record(start.elapsed());
+            }
+
+            match found {
+                Some(Found::Host(host)) => {
+                    entry.metrics.record_match();
+                    return Some(Detection {
+                        host,
+                        filter: Some(i),
+                    });
                 }
+                Some(Found::Break) => return None,
+                Some(Found::Continue) | None => {}
             }
         }
 
+        #[cfg(feature = "wasm")]
+        if let Some(verdict) = entry.plugins.iter().find_map(|plugin| plugin.check(line)) {
+            entry.metrics.record_match();
+            return Some(Detection {
+                host: verdict.host,
+                filter: None,
+            });
+        }
+
         None
     }
 
@@ -91,20 +268,20 @@ impl Matcher {
     pub fn find_analyze(&self, entry: &Entry, line: &str) -> Analysis {
         let mut analysis = Analysis::default();
 
-        for (i, matcher) in entry.matchers.iter().enumerate() {
-            let matcher_name = entry.rule.filters[i].clone();
+        for (i, filter) in entry.matchers.iter().enumerate() {
+            let matcher_name = entry.rule.filters[i].pattern.clone();
 
-            if let Some(caps) = matcher.captures(line) {
-                let time = Self::match_time(&caps).map(|time| {
-                    (
-                        time,
-                        self.is_outdated(&entry.rule, OffsetDateTime::UNIX_EPOCH, time),
-                    )
+            if let Some(caps) = filter.regex.captures(line) {
+                let group = |name: &str| caps.name(name).map(|m| m.as_str());
+
+                let time = Self::match_time(&*entry.time_parser, &group).map(|value| MatchTime {
+                    value,
+                    outdated: self.is_outdated(&entry.rule, OffsetDateTime::UNIX_EPOCH, value),
                 });
 
-                let host = Self::match_host(&caps);
+                let host = Self::match_host(&*entry.host_extractor, line, &group);
 
-                let blacklists = Self::match_blacklists(&caps, &entry.blacklists)
+                let blacklists = Self::match_blacklists(&group, &entry.blacklists)
                     .map(|(bl, p)| (bl.to_owned(), entry.rule.blacklists[bl][p].clone()))
                     .collect();
 
@@ -113,7 +290,8 @@ impl Matcher {
                     Some(Match {
                         time,
                         host,
-                        captures: matcher
+                        captures: filter
+                            .regex
                             .capture_names()
                             .filter_map(|name| {
                                 name.map(|n| {
@@ -129,6 +307,19 @@ impl Matcher {
             }
         }
 
+        #[cfg(feature = "wasm")]
+        for plugin in &entry.plugins {
+            analysis.matches.insert(
+                plugin.path().display().to_string(),
+                plugin.check(line).map(|verdict| Match {
+                    time: None,
+                    host: Some(verdict.host),
+                    captures: IndexMap::default(),
+                    blacklists: IndexMap::default(),
+                }),
+            );
+        }
+
         analysis
     }
 
@@ -138,33 +329,125 @@ impl Matcher {
     }
 
     #[inline(always)]
-    fn match_time(caps: &Captures<'_>) -> Option<OffsetDateTime> {
-        caps.name(TIME_GROUP).and_then(|time| {
-            OffsetDateTime::parse(time.as_str(), TIME_FORMAT)
-                .map(Into::into)
-                .ok()
-        })
+    fn match_time<'l>(
+        parser: &dyn TimeParser,
+        group: &dyn Fn(&str) -> Option<&'l str>,
+    ) -> Option<OffsetDateTime> {
+        TIME_GROUPS
+            .iter()
+            .find_map(|name| group(name))
+            .and_then(|time| parser.parse(time))
     }
 
     #[inline(always)]
-    fn match_host(caps: &Captures<'_>) -> Option<IpAddr> {
-        caps.name(HOST_GROUP)
-            .and_then(|host| host.as_str().parse().ok())
+    fn match_host<'l>(
+        extractor: &dyn HostExtractor,
+        line: &'l str,
+        group: &dyn Fn(&str) -> Option<&'l str>,
+    ) -> Option<IpAddr> {
+        extractor.extract(&HostContext::new(line, group))
     }
 
+    /// Whether the user agent of the line claims to be a crawler, and the IP belongs to it. If
+    /// crawlers are deferred, IPs that are still verified count as crawlers for now, and are
+    /// remembered until the verification finished.
     #[inline(always)]
-    fn match_blacklists<'a>(
-        caps: &'a Captures<'a>,
+    fn is_verified_crawler<'l>(
+        &self,
+        rule: &Rule,
+        host: IpAddr,
+        group: &dyn Fn(&str) -> Option<&'l str>,
+    ) -> bool {
+        let Some(claim) = rule
+            .crawler_group
+            .as_deref()
+            .and_then(group)
+            .and_then(Claim::from_agent)
+        else {
+            return false;
+        };
+
+        if !self.defer_crawlers {
+            return self.crawlers.wait(host, claim);
+        }
+
+        self.crawlers.status(host, claim).unwrap_or_else(|| {
+            self.pending_crawlers
+                .lock()
+                .entry(host)
+                .or_insert_with(|| (claim, rule.file.clone()));
+            true
+        })
+    }
+
+    #[inline(always)]
+    fn match_blacklists<'a, 'l: 'a>(
+        group: &'a dyn Fn(&str) -> Option<&'l str>,
         blacklists: &'a IndexMap<String, AhoCorasick>,
-    ) -> impl Iterator<Item = (&'a str, usize)> + 'a {
+    ) -> impl Iterator<Item = (&'a str, usize)> + use<'a, 'l> {
         blacklists.iter().filter_map(move |(name, blacklist)| {
-            if let Some(value) = caps.name(name) {
+            group(name).and_then(|value| {
                 blacklist
-                    .find(value.as_str())
+                    .find(value)
                     .map(|m| (name.as_str(), m.pattern().as_usize()))
-            } else {
-                None
-            }
+            })
         })
     }
 }
+
+/// A host to block, that was found in a line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Detection {
+    /// Client IP of the line.
+    pub host: IpAddr,
+    /// Index of the filter that matched, or [`None`] if a plugin found the host.
+    pub filter: Option<usize>,
+}
+
+/// Outcome of matching a single filter against a line.
+enum Found {
+    /// A host was found that should be blocked.
+    Host(IpAddr),
+    /// The line didn't match, but the next filter may.
+    Continue,
+    /// The line is outdated and no further filters need to be checked.
+    Break,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use time::macros::datetime;
+
+    use super::*;
+
+    #[test]
+    fn analysis_json() {
+        let analysis = Analysis {
+            matches: IndexMap::from_iter([
+                (
+                    "^<HOST> (?P<path>\\S+)".to_owned(),
+                    Some(Match {
+                        time: Some(MatchTime {
+                            value: datetime!(2023-10-01 12:00 UTC),
+                            outdated: true,
+                        }),
+                        host: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
+                        captures: IndexMap::from_iter([
+                            ("host".to_owned(), Some("10.0.0.1".to_owned())),
+                            ("path".to_owned(), None),
+                        ]),
+                        blacklists: IndexMap::from_iter([("ua".to_owned(), "bot".to_owned())]),
+                    }),
+                ),
+                ("^never$".to_owned(), None),
+            ]),
+        };
+
+        assert_eq!(
+            r#"{"matches":{"^<HOST> (?P<path>\\S+)":{"time":{"value":"2023-10-01T12:00:00Z","outdated":true},"host":"10.0.0.1","captures":{"host":"10.0.0.1","path":null},"blacklists":{"ua":"bot"}},"^never$":null}}"#,
+            serde_json::to_string(&analysis).unwrap()
+        );
+    }
+}
@@ -0,0 +1,955 @@
+//! Runtime statistics about the processed log lines and the cost of each filter, exposed through a
+//! small HTTP endpoint in the Prometheus text format and as a human readable status report.
+//!
+//! The endpoint also serves the current bans as JSON snapshot, for live views like `veto top`. The
+//! same snapshot is saved next to the storage regularly, so counters can be read without the
+//! endpoint as well.
+
+use std::{
+    collections::VecDeque,
+    fmt, fs,
+    io::{self, prelude::*, BufReader},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use flume::Receiver;
+use log::{debug, info};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    events::BlockEvent,
+    handler::{Entry, State},
+    progress::Progress,
+    storage::TargetRepository,
+    Error, HashMap, IndexMap, Result,
+};
+
+/// Amount of the most recent bans that are kept for live views.
+const RECENT_BANS: usize = 50;
+
+/// Only every n-th line is timed, to keep the overhead of measuring low.
+const SAMPLE_RATE: u64 = 64;
+
+/// Upper bounds of the latency histogram buckets, in nanoseconds.
+const BUCKETS: [u64; 13] = [
+    1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000,
+    5_000_000, 10_000_000,
+];
+
+/// Latency histogram with fixed buckets that can be updated concurrently.
+#[derive(Default)]
+pub struct Histogram {
+    /// Counts per bucket, with an additional last bucket for values above all bounds.
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    /// Record a single measurement.
+    pub fn record(&self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = BUCKETS.partition_point(|&bound| bound < nanos);
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// Total amount of measurements.
+    #[must_use]
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Sum of all measurements.
+    #[must_use]
+    pub fn sum(&self) -> Duration {
+        Duration::from_nanos(self.sum.load(Ordering::Relaxed))
+    }
+
+    /// Average of all measurements.
+    #[must_use]
+    pub fn mean(&self) -> Duration {
+        Duration::from_nanos(
+            self.sum
+                .load(Ordering::Relaxed)
+                .checked_div(self.count())
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Upper bound of the bucket that contains the given quantile (from `0.0` to `1.0`), or
+    /// [`None`] if it's above the largest bucket.
+    #[must_use]
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_precision_loss,
+            clippy::cast_sign_loss
+        )]
+        let rank = (self.count() as f64 * quantile).ceil() as u64;
+        let mut seen = 0;
+
+        for (bucket, bound) in self.buckets.iter().zip(BUCKETS) {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= rank {
+                return Some(Duration::from_nanos(bound));
+            }
+        }
+
+        None
+    }
+
+    /// Cumulative counts for each bucket bound, as used by Prometheus.
+    fn cumulative(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.buckets
+            .iter()
+            .zip(BUCKETS)
+            .scan(0, |total, (bucket, bound)| {
+                *total += bucket.load(Ordering::Relaxed);
+                Some((bound, *total))
+            })
+    }
+}
+
+/// Statistics of a single rule.
+pub struct RuleMetrics {
+    /// Total amount of lines that were checked.
+    pub lines: AtomicU64,
+    /// Amount of lines that resulted in a host to block.
+    pub matches: AtomicU64,
+    /// Latency of each filter, in the same order as the rule's filters.
+    pub filters: Vec<Histogram>,
+    /// Bytes of the file's existing content that were scanned at startup.
+    pub scanned: AtomicU64,
+    /// Size of the file's existing content at startup.
+    pub scan_size: AtomicU64,
+    /// Whether the file went without new lines for longer than the rule's stall timeout.
+    pub stalled: AtomicBool,
+}
+
+impl RuleMetrics {
+    #[must_use]
+    pub fn new(filters: usize) -> Self {
+        Self {
+            lines: AtomicU64::new(0),
+            matches: AtomicU64::new(0),
+            filters: (0..filters).map(|_| Histogram::default()).collect(),
+            scanned: AtomicU64::new(0),
+            scan_size: AtomicU64::new(0),
+            stalled: AtomicBool::new(false),
+        }
+    }
+
+    /// Count a new line and tell whether its processing should be timed.
+    #[inline]
+    pub fn record_line(&self) -> bool {
+        self.lines.fetch_add(1, Ordering::Relaxed) % SAMPLE_RATE == 0
+    }
+
+    /// Count a line that resulted in a host to block.
+    #[inline]
+    pub fn record_match(&self) {
+        self.matches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Update the progress through the file's existing content.
+    pub fn record_scan(&self, position: u64, size: u64) {
+        self.scanned.store(position, Ordering::Relaxed);
+        self.scan_size.store(size, Ordering::Relaxed);
+    }
+
+    /// Mark the scan of the file's existing content as done.
+    pub fn finish_scan(&self) {
+        self.scanned
+            .store(self.scan_size.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    /// Progress of the scan over the file's existing content, or [`None`] if it's done.
+    #[must_use]
+    pub fn scan_progress(&self, elapsed: Duration) -> Option<Progress> {
+        let processed = self.scanned.load(Ordering::Relaxed);
+        let total = self.scan_size.load(Ordering::Relaxed);
+
+        (processed < total).then(|| Progress {
+            processed,
+            total: Some(total),
+            matches: self.matches.load(Ordering::Relaxed),
+            elapsed,
+        })
+    }
+}
+
+/// Approximate memory usage of the main components, in bytes.
+#[derive(Default)]
+pub struct MemoryUsage {
+    /// Amount of entries in the storage.
+    pub storage_entries: AtomicUsize,
+    /// Memory used by the storage.
+    pub storage: AtomicUsize,
+    /// Memory used by buffers for reading log files.
+    pub readers: AtomicUsize,
+    /// Memory used by Aho-Corasick automata for prefilters and blacklists.
+    pub automata: AtomicUsize,
+}
+
+impl MemoryUsage {
+    /// Gather the current memory usage from the storage and all rules.
+    pub fn update(
+        &self,
+        storage: &impl TargetRepository,
+        files: &HashMap<PathBuf, (Entry, State)>,
+    ) {
+        let readers = files.values().map(|(_, state)| state.memory_usage()).sum();
+        let automata = files.values().map(|(entry, _)| entry.memory_usage()).sum();
+
+        self.storage_entries
+            .store(storage.count(), Ordering::Relaxed);
+        self.storage
+            .store(storage.memory_usage(), Ordering::Relaxed);
+        self.readers.store(readers, Ordering::Relaxed);
+        self.automata.store(automata, Ordering::Relaxed);
+    }
+
+    fn components(&self) -> [(&'static str, usize); 3] {
+        [
+            ("storage", self.storage.load(Ordering::Relaxed)),
+            ("readers", self.readers.load(Ordering::Relaxed)),
+            ("automata", self.automata.load(Ordering::Relaxed)),
+        ]
+    }
+}
+
+/// Current bans and the firewall in use, kept up to date through the ban events.
+#[derive(Default)]
+pub struct Activity {
+    state: Mutex<ActivityState>,
+}
+
+#[derive(Default)]
+struct ActivityState {
+    firewall: String,
+    bans: IndexMap<IpAddr, BanSnapshot>,
+    recent: VecDeque<RecentBan>,
+}
+
+impl Activity {
+    /// Describe the firewall that is used to block IPs.
+    pub fn set_firewall(&self, description: impl Into<String>) {
+        self.state.lock().firewall = description.into();
+    }
+
+    /// Add bans that were already active at startup.
+    pub fn extend(&self, bans: impl IntoIterator<Item = BanSnapshot>) {
+        self.state
+            .lock()
+            .bans
+            .extend(bans.into_iter().map(|ban| (ban.ip, ban)));
+    }
+
+    /// Apply a ban or unban to the current bans.
+    pub fn record(&self, event: &BlockEvent) {
+        let mut state = self.state.lock();
+
+        match event {
+            BlockEvent::Ban(ban) => {
+                let ban = BanSnapshot {
+                    rule: ban.rule.clone(),
+                    ip: ban.ip,
+                    until: ban.until,
+                };
+
+                state.bans.insert(ban.ip, ban.clone());
+                if state.recent.len() >= RECENT_BANS {
+                    state.recent.pop_back();
+                }
+                state.recent.push_front(RecentBan {
+                    at: OffsetDateTime::now_utc(),
+                    ban,
+                });
+            }
+            BlockEvent::Unban(unban) => {
+                state.bans.shift_remove(&unban.ip);
+            }
+        }
+    }
+}
+
+/// Point-in-time view of a running instance, as served by the metrics endpoint in JSON format.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Snapshot {
+    /// Process ID of the running instance.
+    #[serde(default)]
+    pub pid: Option<u32>,
+    /// Time since veto was started, in seconds.
+    pub uptime: u64,
+    /// Description of the firewall that is used to block IPs.
+    pub firewall: String,
+    /// Amount of entries in the storage.
+    pub storage_entries: usize,
+    /// Block and unblock commands waiting for the firewall.
+    #[serde(default)]
+    pub firewall_queue: usize,
+    /// Counters of each rule.
+    pub rules: Vec<RuleSnapshot>,
+    /// Currently blocked IPs.
+    pub bans: Vec<BanSnapshot>,
+    /// Most recent bans, newest first.
+    pub recent: Vec<RecentBan>,
+}
+
+/// Counters of a single rule.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RuleSnapshot {
+    pub name: String,
+    /// Total amount of lines that were checked.
+    pub lines: u64,
+    /// Amount of lines that resulted in a host to block.
+    pub matches: u64,
+    /// Bytes of the file's existing content that were scanned at startup so far.
+    #[serde(default)]
+    pub scanned: u64,
+    /// Size of the file's existing content at startup.
+    #[serde(default)]
+    pub scan_size: u64,
+    /// Whether the file went without new lines for longer than the rule's stall timeout.
+    #[serde(default)]
+    pub stalled: bool,
+}
+
+/// A single blocked IP.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BanSnapshot {
+    /// Name of the rule that blocked the IP.
+    pub rule: String,
+    pub ip: IpAddr,
+    /// Time until the IP stays blocked.
+    #[serde(with = "time::serde::rfc3339")]
+    pub until: OffsetDateTime,
+}
+
+/// A ban together with the time it happened.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecentBan {
+    #[serde(with = "time::serde::rfc3339")]
+    pub at: OffsetDateTime,
+    #[serde(flatten)]
+    pub ban: BanSnapshot,
+}
+
+/// Collection of the metrics from all rules.
+pub struct Registry {
+    started: Instant,
+    rules: IndexMap<String, (Vec<String>, Arc<RuleMetrics>)>,
+    /// Memory usage, which is updated regularly by the main loop.
+    pub memory: MemoryUsage,
+    /// Current bans, which are updated through [`record_events`].
+    pub activity: Activity,
+    /// Commands waiting for the firewall, which is updated regularly by the main loop.
+    pub firewall_queue: AtomicUsize,
+    /// Blocks held back by the ban limits, which is updated regularly by the main loop.
+    pub deferred_bans: AtomicUsize,
+    /// Whether blocking is paused by the breaker, which is updated regularly by the main loop.
+    pub paused: AtomicBool,
+}
+
+impl Registry {
+    /// Create a new registry from the rule names, their filter patterns and metrics.
+    pub fn new(rules: impl IntoIterator<Item = (String, Vec<String>, Arc<RuleMetrics>)>) -> Self {
+        let mut rules = rules
+            .into_iter()
+            .map(|(name, filters, metrics)| (name, (filters, metrics)))
+            .collect::<IndexMap<_, _>>();
+        rules.sort_keys();
+
+        Self {
+            started: Instant::now(),
+            rules,
+            memory: MemoryUsage::default(),
+            activity: Activity::default(),
+            firewall_queue: AtomicUsize::new(0),
+            deferred_bans: AtomicUsize::new(0),
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    /// Names and metrics of all rules, ordered by name.
+    pub fn rules(&self) -> impl Iterator<Item = (&str, &RuleMetrics)> {
+        self.rules
+            .iter()
+            .map(|(name, (_, metrics))| (name.as_str(), metrics.as_ref()))
+    }
+
+    /// Take a snapshot of the current counters and bans.
+    pub fn snapshot(&self) -> Snapshot {
+        let state = self.activity.state.lock();
+
+        let mut bans = state.bans.values().cloned().collect::<Vec<_>>();
+        bans.sort_by_key(|ban| ban.until);
+
+        Snapshot {
+            pid: Some(std::process::id()),
+            uptime: self.started.elapsed().as_secs(),
+            firewall: state.firewall.clone(),
+            storage_entries: self.memory.storage_entries.load(Ordering::Relaxed),
+            firewall_queue: self.firewall_queue.load(Ordering::Relaxed),
+            rules: self
+                .rules
+                .iter()
+                .map(|(name, (_, metrics))| RuleSnapshot {
+                    name: name.clone(),
+                    lines: metrics.lines.load(Ordering::Relaxed),
+                    matches: metrics.matches.load(Ordering::Relaxed),
+                    scanned: metrics.scanned.load(Ordering::Relaxed),
+                    scan_size: metrics.scan_size.load(Ordering::Relaxed),
+                    stalled: metrics.stalled.load(Ordering::Relaxed),
+                })
+                .collect(),
+            bans,
+            recent: state
+                .recent
+                .iter()
+                .map(|recent| RecentBan {
+                    at: recent.at,
+                    ban: recent.ban.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render_prometheus(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(
+            out,
+            "# HELP veto_uptime_seconds Time since veto was started."
+        )?;
+        writeln!(out, "# TYPE veto_uptime_seconds gauge")?;
+        writeln!(
+            out,
+            "veto_uptime_seconds {}",
+            self.started.elapsed().as_secs_f64()
+        )?;
+
+        writeln!(out, "# HELP veto_storage_entries Entries in the storage.")?;
+        writeln!(out, "# TYPE veto_storage_entries gauge")?;
+        writeln!(
+            out,
+            "veto_storage_entries {}",
+            self.memory.storage_entries.load(Ordering::Relaxed)
+        )?;
+
+        writeln!(
+            out,
+            "# HELP veto_firewall_queue Commands waiting for the firewall."
+        )?;
+        writeln!(out, "# TYPE veto_firewall_queue gauge")?;
+        writeln!(
+            out,
+            "veto_firewall_queue {}",
+            self.firewall_queue.load(Ordering::Relaxed)
+        )?;
+
+        writeln!(
+            out,
+            "# HELP veto_deferred_bans Blocks held back by the ban limits."
+        )?;
+        writeln!(out, "# TYPE veto_deferred_bans gauge")?;
+        writeln!(
+            out,
+            "veto_deferred_bans {}",
+            self.deferred_bans.load(Ordering::Relaxed)
+        )?;
+
+        writeln!(
+            out,
+            "# HELP veto_paused Whether blocking is paused by the breaker."
+        )?;
+        writeln!(out, "# TYPE veto_paused gauge")?;
+        writeln!(
+            out,
+            "veto_paused {}",
+            u8::from(self.paused.load(Ordering::Relaxed))
+        )?;
+
+        writeln!(
+            out,
+            "# HELP veto_memory_bytes Approximate memory usage per component."
+        )?;
+        writeln!(out, "# TYPE veto_memory_bytes gauge")?;
+        for (component, bytes) in self.memory.components() {
+            writeln!(
+                out,
+                "veto_memory_bytes{{component=\"{component}\"}} {bytes}"
+            )?;
+        }
+
+        writeln!(out, "# HELP veto_lines_total Log lines processed per rule.")?;
+        writeln!(out, "# TYPE veto_lines_total counter")?;
+        for (name, (_, metrics)) in &self.rules {
+            let lines = metrics.lines.load(Ordering::Relaxed);
+            writeln!(out, "veto_lines_total{{rule=\"{name}\"}} {lines}")?;
+        }
+
+        writeln!(
+            out,
+            "# HELP veto_matches_total Log lines that matched per rule."
+        )?;
+        writeln!(out, "# TYPE veto_matches_total counter")?;
+        for (name, (_, metrics)) in &self.rules {
+            let matches = metrics.matches.load(Ordering::Relaxed);
+            writeln!(out, "veto_matches_total{{rule=\"{name}\"}} {matches}")?;
+        }
+
+        writeln!(
+            out,
+            "# HELP veto_stalled Whether a rule's file went without new lines for too long."
+        )?;
+        writeln!(out, "# TYPE veto_stalled gauge")?;
+        for (name, (_, metrics)) in &self.rules {
+            let stalled = u8::from(metrics.stalled.load(Ordering::Relaxed));
+            writeln!(out, "veto_stalled{{rule=\"{name}\"}} {stalled}")?;
+        }
+
+        self.render_filter_durations(out)
+    }
+
+    /// Render the sampled latency histograms of all filters.
+    fn render_filter_durations(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(
+            out,
+            "# HELP veto_filter_duration_seconds Sampled time to check a line against a filter."
+        )?;
+        writeln!(out, "# TYPE veto_filter_duration_seconds histogram")?;
+        for (name, (_, metrics)) in &self.rules {
+            for (i, histogram) in metrics.filters.iter().enumerate() {
+                let labels = format!("rule=\"{name}\",filter=\"{i}\"");
+
+                for (bound, count) in histogram.cumulative() {
+                    #[allow(clippy::cast_precision_loss)]
+                    let bound = bound as f64 / 1e9;
+                    writeln!(
+                        out,
+                        "veto_filter_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {count}"
+                    )?;
+                }
+
+                let count = histogram.count();
+                let sum = histogram.sum();
+                writeln!(
+                    out,
+                    "veto_filter_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {count}"
+                )?;
+                writeln!(
+                    out,
+                    "veto_filter_duration_seconds_sum{{{labels}}} {}",
+                    sum.as_secs_f64()
+                )?;
+                writeln!(
+                    out,
+                    "veto_filter_duration_seconds_count{{{labels}}} {count}"
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Render a human readable summary of all metrics.
+    pub fn render_status(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        let uptime = self.started.elapsed();
+        writeln!(
+            out,
+            "Uptime: {}",
+            humantime::format_duration(Duration::from_secs(uptime.as_secs()))
+        )?;
+        if self.paused.load(Ordering::Relaxed) {
+            writeln!(out, "Blocking is PAUSED, resume it with `veto resume`")?;
+        }
+
+        writeln!(out, "Memory (approx.):")?;
+        for (component, bytes) in self.memory.components() {
+            writeln!(out, "  {component:8}: {:.1} KiB", kibibytes(bytes))?;
+        }
+        writeln!(
+            out,
+            "  Storage entries: {}",
+            self.memory.storage_entries.load(Ordering::Relaxed)
+        )?;
+
+        for (name, (filters, metrics)) in &self.rules {
+            let lines = metrics.lines.load(Ordering::Relaxed);
+            #[allow(clippy::cast_precision_loss)]
+            let rate = lines as f64 / uptime.as_secs_f64().max(1.0);
+
+            writeln!(out)?;
+            writeln!(out, "Rule: {name}")?;
+            writeln!(out, "  Lines:   {lines} ({rate:.1}/s)")?;
+            writeln!(
+                out,
+                "  Matches: {}",
+                metrics.matches.load(Ordering::Relaxed)
+            )?;
+            if let Some(progress) = metrics.scan_progress(uptime) {
+                writeln!(out, "  Scan:    {progress}")?;
+            }
+            if metrics.stalled.load(Ordering::Relaxed) {
+                writeln!(out, "  Stalled: no new lines within the stall timeout")?;
+            }
+
+            for (pattern, histogram) in filters.iter().zip(&metrics.filters) {
+                writeln!(out, "  Filter: {pattern}")?;
+                writeln!(
+                    out,
+                    "    Mean: {:?}, p99: {}, samples: {}",
+                    histogram.mean(),
+                    histogram
+                        .quantile(0.99)
+                        .map_or_else(|| "> 10ms".to_owned(), |d| format!("<= {d:?}")),
+                    histogram.count()
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Counters that are gathered without the metrics endpoint, as printed by `veto metrics`.
+///
+/// Bans and the storage size come from the storage, while lines, matches and the firewall queue
+/// are only known if a running instance reported them.
+#[derive(Debug, Serialize)]
+pub struct Counters {
+    /// Amount of entries in the storage.
+    pub storage_entries: usize,
+    /// Size of the storage file, in bytes.
+    pub storage_bytes: u64,
+    /// IPs whose block expired, but that weren't unblocked yet.
+    pub pending_unblocks: usize,
+    /// Block and unblock commands waiting for the firewall.
+    pub firewall_queue: Option<usize>,
+    /// Counters of each rule.
+    pub rules: Vec<RuleCounters>,
+}
+
+/// Counters of a single rule.
+#[derive(Debug, Serialize)]
+pub struct RuleCounters {
+    pub name: String,
+    /// Total amount of lines that were checked.
+    pub lines: Option<u64>,
+    /// Amount of lines that resulted in a host to block.
+    pub matches: Option<u64>,
+    /// Currently blocked IPs.
+    pub active_bans: usize,
+    /// Amount of blocks ever done, including repeated blocks of the same IP.
+    pub total_bans: usize,
+}
+
+impl Counters {
+    /// Render the counters in the Prometheus text exposition format, leaving out unknown ones.
+    pub fn render_prometheus(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        let mut gauge = |name: &str, help: &str, value: Option<usize>| {
+            value.map_or(Ok(()), |value| {
+                writeln!(out, "# HELP {name} {help}")?;
+                writeln!(out, "# TYPE {name} gauge")?;
+                writeln!(out, "{name} {value}")
+            })
+        };
+
+        gauge(
+            "veto_storage_entries",
+            "Entries in the storage.",
+            Some(self.storage_entries),
+        )?;
+        gauge(
+            "veto_storage_size_bytes",
+            "Size of the storage file.",
+            usize::try_from(self.storage_bytes).ok(),
+        )?;
+        gauge(
+            "veto_pending_unblocks",
+            "Expired blocks that weren't unblocked yet.",
+            Some(self.pending_unblocks),
+        )?;
+        gauge(
+            "veto_firewall_queue",
+            "Commands waiting for the firewall.",
+            self.firewall_queue,
+        )?;
+
+        self.render_rules(
+            out,
+            "veto_lines_total",
+            "counter",
+            "Log lines processed per rule.",
+            |r| r.lines,
+        )?;
+        self.render_rules(
+            out,
+            "veto_matches_total",
+            "counter",
+            "Log lines that matched per rule.",
+            |r| r.matches,
+        )?;
+        self.render_rules(
+            out,
+            "veto_active_bans",
+            "gauge",
+            "Currently blocked IPs per rule.",
+            |r| Some(r.active_bans as u64),
+        )?;
+        self.render_rules(
+            out,
+            "veto_bans_total",
+            "counter",
+            "Blocks done per rule.",
+            |r| Some(r.total_bans as u64),
+        )?;
+
+        Ok(())
+    }
+}
+
+impl Counters {
+    /// Render a single metric with a sample for each rule, unless it's unknown for all of them.
+    fn render_rules(
+        &self,
+        out: &mut impl fmt::Write,
+        name: &str,
+        kind: &str,
+        help: &str,
+        value: impl Fn(&RuleCounters) -> Option<u64>,
+    ) -> fmt::Result {
+        if self.rules.iter().all(|rule| value(rule).is_none()) {
+            return Ok(());
+        }
+
+        writeln!(out, "# HELP {name} {help}")?;
+        writeln!(out, "# TYPE {name} {kind}")?;
+        for rule in &self.rules {
+            if let Some(value) = value(rule) {
+                writeln!(out, "{name}{{rule=\"{}\"}} {value}", rule.name)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn kibibytes(bytes: usize) -> f64 {
+    bytes as f64 / 1024.0
+}
+
+/// Start a background thread that keeps the registry's [`Activity`] up to date with the events of
+/// a handler.
+pub fn record_events(registry: Arc<Registry>, events: Receiver<BlockEvent>) -> Result<()> {
+    thread::Builder::new()
+        .name("activity".to_owned())
+        .spawn(move || {
+            for event in events {
+                registry.activity.record(&event);
+            }
+        })?;
+
+    Ok(())
+}
+
+/// Location of the saved [`Snapshot`], next to the storage file at the given location.
+#[must_use]
+pub fn snapshot_path(storage: &Path) -> PathBuf {
+    storage.with_extension("metrics.json")
+}
+
+/// Save a snapshot of the registry to the given file. The file is replaced at once, so readers
+/// never see a partially written snapshot.
+pub fn save_snapshot(path: &Path, registry: &Registry) -> Result<()> {
+    let temp = path.with_extension("tmp");
+    let json = serde_json::to_vec(&registry.snapshot()).map_err(io::Error::from)?;
+
+    fs::write(&temp, json)?;
+    fs::rename(temp, path)?;
+
+    Ok(())
+}
+
+/// Load a snapshot that was saved by a running instance, together with the time it was saved.
+pub fn load_snapshot(path: &Path) -> Result<(Snapshot, SystemTime)> {
+    let modified = fs::metadata(path)?.modified()?;
+    let snapshot = serde_json::from_slice(&fs::read(path)?).map_err(io::Error::from)?;
+
+    Ok((snapshot, modified))
+}
+
+/// Start a background thread that serves the metrics over HTTP on the given address.
+///
+/// The Prometheus metrics are available at `/metrics`, the human readable status at `/status`
+/// and a [`Snapshot`] in JSON format at `/status.json`.
+pub fn serve(addr: SocketAddr, registry: Arc<Registry>) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("serving metrics on {}", addr);
+
+    thread::Builder::new()
+        .name("metrics".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream
+                    .map_err(Into::into)
+                    .and_then(|stream| handle_request(stream, &registry));
+                if let Err(e) = result {
+                    debug!("failed handling metrics request: {:?}", e);
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn handle_request(mut stream: TcpStream, registry: &Registry) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    let mut body = String::new();
+
+    let mut content_type = "text/plain; version=0.0.4";
+
+    let status = match path {
+        "/metrics" => {
+            _ = registry.render_prometheus(&mut body);
+            "200 OK"
+        }
+        "/status" => {
+            _ = registry.render_status(&mut body);
+            "200 OK"
+        }
+        "/status.json" => {
+            body = serde_json::to_string(&registry.snapshot()).map_err(io::Error::from)?;
+            content_type = "application/json";
+            "200 OK"
+        }
+        _ => "404 Not Found",
+    };
+
+    write!(
+        stream,
+        "HTTP/1.0 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    )?;
+
+    Ok(())
+}
+
+/// Request a resource from the metrics endpoint of a running instance and return the body.
+pub fn fetch(addr: SocketAddr, path: &str) -> Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(stream, "GET {path} HTTP/1.0\r\n\r\n")?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| Error::Metrics("invalid response".to_owned()))?;
+    let status = head.lines().next().unwrap_or_default();
+    if !status.contains(" 200 ") {
+        return Err(Error::Metrics(status.to_owned()));
+    }
+
+    Ok(body.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_quantiles() {
+        let histogram = Histogram::default();
+        for micros in [1, 2, 3, 4, 5, 6, 7, 8, 9, 20_000] {
+            histogram.record(Duration::from_micros(micros));
+        }
+
+        assert_eq!(10, histogram.count());
+        assert_eq!(Some(Duration::from_micros(5)), histogram.quantile(0.5));
+        assert_eq!(Some(Duration::from_micros(10)), histogram.quantile(0.9));
+        assert_eq!(None, histogram.quantile(1.0));
+    }
+
+    #[test]
+    fn activity_snapshot() {
+        use time::macros::datetime;
+
+        use crate::events::{BanEvent, UnbanEvent};
+
+        let registry =
+            Registry::new([("web".to_owned(), Vec::new(), Arc::new(RuleMetrics::new(0)))]);
+        registry.activity.set_firewall("ipset");
+
+        let ip = |last| IpAddr::from([10, 0, 0, last]);
+        for last in [1, 2] {
+            registry.activity.record(&BlockEvent::Ban(BanEvent {
+                rule: "web".to_owned(),
+                ip: ip(last),
+                until: datetime!(2023-10-01 13:00 UTC),
+            }));
+        }
+        registry.activity.record(&BlockEvent::Unban(UnbanEvent {
+            rule: "web".to_owned(),
+            ip: ip(1),
+        }));
+
+        let json = serde_json::to_string(&registry.snapshot()).unwrap();
+        let snapshot = serde_json::from_str::<Snapshot>(&json).unwrap();
+
+        assert_eq!("ipset", snapshot.firewall);
+        assert_eq!(
+            vec![ip(2)],
+            snapshot.bans.iter().map(|b| b.ip).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![ip(2), ip(1)],
+            snapshot.recent.iter().map(|r| r.ban.ip).collect::<Vec<_>>()
+        );
+        assert_eq!("web", snapshot.rules[0].name);
+    }
+
+    #[test]
+    fn counters_prometheus() {
+        let counters = Counters {
+            storage_entries: 3,
+            storage_bytes: 120,
+            pending_unblocks: 1,
+            firewall_queue: None,
+            rules: vec![RuleCounters {
+                name: "web".to_owned(),
+                lines: None,
+                matches: None,
+                active_bans: 2,
+                total_bans: 5,
+            }],
+        };
+
+        let mut out = String::new();
+        counters.render_prometheus(&mut out).unwrap();
+
+        assert!(out.contains("veto_storage_entries 3\n"));
+        assert!(out.contains("veto_bans_total{rule=\"web\"} 5\n"));
+        // Counters of the running instance are left out if they're unknown.
+        assert!(!out.contains("veto_firewall_queue"));
+        assert!(!out.contains("veto_lines_total"));
+    }
+}
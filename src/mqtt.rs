@@ -0,0 +1,384 @@
+//! Publishing of blocks and unblocks to an MQTT broker, so home automation like Home Assistant or
+//! router scripts can react to them.
+//!
+//! Only the small part of MQTT 3.1.1 that's needed to publish messages is implemented: connecting,
+//! publishing with `QoS` 0 and keeping the connection alive. Messages are dropped while the broker
+//! is unreachable, as they're only meant for notifications.
+
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, TcpStream, ToSocketAddrs},
+    thread,
+    time::{Duration, Instant},
+};
+
+use flume::{Receiver, RecvTimeoutError};
+use log::{debug, info, warn};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::{events::BlockEvent, settings, Result};
+
+/// Port of the broker, if the address doesn't contain one.
+const DEFAULT_PORT: u16 = 1883;
+/// Maximum time to wait for the broker on connects, writes and replies.
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval that the broker expects messages in, before it drops the connection.
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+/// Minimum time between two connection attempts, so a missing broker doesn't slow down publishing.
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+
+/// A message for a single event, on the topic `<prefix>/<rule>/ban` or `<prefix>/<rule>/unban`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Message {
+    pub topic: String,
+    /// JSON object with the IP, and the time until it stays blocked for bans.
+    pub payload: String,
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    ip: &'a IpAddr,
+    #[serde(
+        with = "time::serde::rfc3339::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    until: Option<OffsetDateTime>,
+}
+
+impl Message {
+    /// Create the message for an event, with topics below the given prefix.
+    #[must_use]
+    pub fn from_event(prefix: &str, event: &BlockEvent) -> Self {
+        let (rule, kind, payload) = match event {
+            BlockEvent::Ban(ban) => (
+                &ban.rule,
+                "ban",
+                Payload {
+                    ip: &ban.ip,
+                    until: Some(ban.until),
+                },
+            ),
+            BlockEvent::Unban(unban) => (
+                &unban.rule,
+                "unban",
+                Payload {
+                    ip: &unban.ip,
+                    until: None,
+                },
+            ),
+        };
+
+        // Wildcards aren't allowed in topics that are published to.
+        let rule = rule.replace(['+', '#'], "_");
+
+        Self {
+            topic: format!("{prefix}/{rule}/{kind}"),
+            payload: serde_json::to_string(&payload).unwrap_or_default(),
+        }
+    }
+}
+
+/// Connection to an MQTT broker, that is established on demand.
+pub struct Client {
+    broker: String,
+    id: String,
+    username: Option<String>,
+    password: Option<String>,
+    /// Prefix of all topics.
+    pub topic: String,
+    stream: Option<TcpStream>,
+    last_attempt: Option<Instant>,
+}
+
+impl Client {
+    /// Create a client from the settings, or return [`None`] if publishing is disabled.
+    #[must_use]
+    pub fn from_settings(settings: &settings::Mqtt) -> Option<Self> {
+        settings.broker.as_ref().map(|broker| Self {
+            broker: broker.clone(),
+            id: settings.client_id.clone(),
+            username: settings.username.clone(),
+            password: settings.password.clone(),
+            topic: settings.topic.trim_end_matches('/').to_owned(),
+            stream: None,
+            last_attempt: None,
+        })
+    }
+
+    /// Publish the message with `QoS` 0, connecting to the broker first if needed. If the
+    /// connection broke, it's established again once.
+    pub fn publish(&mut self, message: &Message) -> io::Result<()> {
+        let packet = publish_packet(&message.topic, message.payload.as_bytes());
+
+        if let Some(stream) = &mut self.stream {
+            if stream.write_all(&packet).is_ok() {
+                return Ok(());
+            }
+            self.stream = None;
+        }
+
+        self.connected()?.write_all(&packet)
+    }
+
+    /// Keep the connection alive while there is nothing to publish.
+    pub fn ping(&mut self) -> io::Result<()> {
+        let Some(stream) = &mut self.stream else {
+            return Ok(());
+        };
+
+        let result = stream.write_all(&[PINGREQ, 0]).and_then(|()| {
+            let mut reply = [0; 2];
+            stream.read_exact(&mut reply)?;
+            expect(reply[0] == PINGRESP, "expected ping response")
+        });
+        if result.is_err() {
+            self.stream = None;
+        }
+
+        result
+    }
+
+    /// The current connection, or a new one if there is none and the last attempt was long
+    /// enough ago.
+    fn connected(&mut self) -> io::Result<&mut TcpStream> {
+        if self.stream.is_none() {
+            if self
+                .last_attempt
+                .is_some_and(|at| at.elapsed() < RECONNECT_DELAY)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "waiting to reconnect",
+                ));
+            }
+
+            self.last_attempt = Some(Instant::now());
+            self.stream = Some(self.connect()?);
+            info!("connected to MQTT broker {}", self.broker);
+        }
+
+        self.stream
+            .as_mut()
+            .ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let addr = if self.broker.contains(':') {
+            self.broker.to_socket_addrs()
+        } else {
+            (self.broker.as_str(), DEFAULT_PORT).to_socket_addrs()
+        }?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for broker"))?;
+
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        stream.write_all(&connect_packet(
+            &self.id,
+            self.username.as_deref(),
+            self.password.as_deref(),
+        ))?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply)?;
+        expect(reply[0] == CONNACK, "expected connect acknowledgement")?;
+        expect(
+            reply[3] == 0,
+            match reply[3] {
+                4 => "broker rejected the username or password",
+                5 => "not authorized by the broker",
+                _ => "broker refused the connection",
+            },
+        )?;
+
+        Ok(stream)
+    }
+}
+
+fn expect(condition: bool, message: &str) -> io::Result<()> {
+    if condition {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData, message))
+    }
+}
+
+fn connect_packet(client_id: &str, username: Option<&str>, password: Option<&str>) -> Vec<u8> {
+    let mut flags = 0x02; // clean session
+    let mut body = Vec::new();
+
+    write_str(&mut body, "MQTT");
+    body.push(4); // protocol level 3.1.1
+
+    if username.is_some() {
+        flags |= 0x80;
+    }
+    if password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+
+    let keep_alive = u16::try_from(KEEP_ALIVE.as_secs()).unwrap_or(u16::MAX);
+    body.extend_from_slice(&keep_alive.to_be_bytes());
+
+    write_str(&mut body, client_id);
+    for value in [username, password].into_iter().flatten() {
+        write_str(&mut body, value);
+    }
+
+    packet(CONNECT, &body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(2 + topic.len() + payload.len());
+    write_str(&mut body, topic);
+    body.extend_from_slice(payload);
+
+    packet(PUBLISH, &body)
+}
+
+/// Prefix the body with the packet type and its length, which is encoded in 7 bits per byte with
+/// the highest bit marking that more bytes follow.
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut length = body.len();
+
+    loop {
+        #[allow(clippy::cast_possible_truncation)]
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+
+        if length == 0 {
+            break;
+        }
+    }
+
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn write_str(buf: &mut Vec<u8>, value: &str) {
+    let value = &value.as_bytes()[..value.len().min(usize::from(u16::MAX))];
+    #[allow(clippy::cast_possible_truncation)]
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+/// Start a background thread that publishes all events of a handler to the broker.
+pub fn publish_events(mut client: Client, events: Receiver<BlockEvent>) -> Result<()> {
+    thread::Builder::new()
+        .name("mqtt".to_owned())
+        .spawn(move || {
+            if let Err(e) = client.connected() {
+                warn!("failed connecting to MQTT broker {}: {e}", client.broker);
+            }
+
+            loop {
+                match events.recv_timeout(KEEP_ALIVE / 2) {
+                    Ok(event) => {
+                        let message = Message::from_event(&client.topic, &event);
+                        if let Err(e) = client.publish(&message) {
+                            warn!("failed publishing to {}: {e}", message.topic);
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        if let Err(e) = client.ping() {
+                            debug!("lost connection to MQTT broker: {e}");
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use time::macros::datetime;
+
+    use super::*;
+    use crate::events::{BanEvent, UnbanEvent};
+
+    #[test]
+    fn messages() {
+        let ip = "10.0.0.1".parse().unwrap();
+        let ban = BlockEvent::Ban(BanEvent {
+            rule: "web+1".to_owned(),
+            ip,
+            until: datetime!(2023-10-01 13:00 UTC),
+        });
+        let unban = BlockEvent::Unban(UnbanEvent {
+            rule: "ssh".to_owned(),
+            ip,
+        });
+
+        assert_eq!(
+            Message {
+                topic: "veto/web_1/ban".to_owned(),
+                payload: r#"{"ip":"10.0.0.1","until":"2023-10-01T13:00:00Z"}"#.to_owned(),
+            },
+            Message::from_event("veto", &ban)
+        );
+        assert_eq!(
+            Message {
+                topic: "veto/ssh/unban".to_owned(),
+                payload: r#"{"ip":"10.0.0.1"}"#.to_owned(),
+            },
+            Message::from_event("veto", &unban)
+        );
+
+        assert_eq!(&[0x30, 0x81, 0x01], &publish_packet("t", &[0; 126])[..3]);
+    }
+
+    #[test]
+    fn publish() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let settings = settings::Mqtt {
+            broker: Some(listener.local_addr().unwrap().to_string()),
+            username: Some("user".to_owned()),
+            password: Some("secret".to_owned()),
+            ..settings::Mqtt::default()
+        };
+
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let expected = connect_packet("veto", Some("user"), Some("secret"));
+            let mut connect = vec![0; expected.len()];
+            stream.read_exact(&mut connect).unwrap();
+            assert_eq!(expected, connect);
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+
+            let expected = publish_packet("veto/web/ban", b"{}");
+            let mut publish = vec![0; expected.len()];
+            stream.read_exact(&mut publish).unwrap();
+            assert_eq!(expected, publish);
+        });
+
+        let mut client = Client::from_settings(&settings).unwrap();
+        client
+            .publish(&Message {
+                topic: "veto/web/ban".to_owned(),
+                payload: "{}".to_owned(),
+            })
+            .unwrap();
+
+        broker.join().unwrap();
+    }
+}
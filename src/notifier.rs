@@ -1,34 +1,79 @@
 use std::path::PathBuf;
 
-use anyhow::Result;
 use flume::{Receiver, Sender};
 use log::{debug, trace, warn};
 use notify::{
     event::{EventKind, ModifyKind},
     RecommendedWatcher, RecursiveMode, Watcher,
 };
+use parking_lot::Mutex;
+
+use crate::Result;
 
 pub fn start<'a>(paths: impl Iterator<Item = &'a PathBuf>) -> Result<Notifier> {
     let (tx, rx) = flume::unbounded();
     let handler = Handler { tx };
 
     let mut watcher = notify::recommended_watcher(move |res| handler.handle(res))?;
+    let paths = paths.cloned().collect::<Vec<_>>();
+
+    for path in &paths {
+        // Files that don't exist yet can't be watched, so their directory is watched instead,
+        // which reports the creation of the file and any later changes to it.
+        let target = match path.parent() {
+            Some(dir) if !path.exists() => dir,
+            _ => path.as_path(),
+        };
+
+        debug!("Start watching {:?}", target);
+        watcher.watch(target, RecursiveMode::NonRecursive)?;
 
-    for path in paths {
-        debug!("Start watching file {:?}", path);
-        watcher.watch(path, RecursiveMode::NonRecursive)?;
+        // Watching a symlink only follows its current target, so its directory is watched as well
+        // to notice when it's pointed to another file.
+        if let Some(dir) = path.parent().filter(|_| path.is_symlink()) {
+            debug!(
+                "Start watching {:?} for changes of the symlink {:?}",
+                dir, path
+            );
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
     }
 
     Ok(Notifier {
         rx,
-        _watcher: watcher,
+        watcher: Mutex::new(watcher),
+        paths,
     })
 }
 
 pub struct Notifier {
     pub rx: Receiver<Event>,
-    // Not used but has to be kept around or otherwise it would be dropped.
-    _watcher: RecommendedWatcher,
+    // Has to be kept around or otherwise it would be dropped.
+    watcher: Mutex<RecommendedWatcher>,
+    /// The watched paths, excluding the directories that are watched on their behalf.
+    paths: Vec<PathBuf>,
+}
+
+impl Notifier {
+    /// Move the watch of a symlink to its new target, after the event reported that the symlink
+    /// was (re-)created. Otherwise, changes are still reported for the previous target only.
+    pub fn follow(&self, event: &Event) {
+        if !matches!(event.ty, EventType::Created)
+            || !event.path.is_symlink()
+            || !self.paths.contains(&event.path)
+        {
+            return;
+        }
+
+        debug!("Following {:?} to its new target", event.path);
+        let result = self
+            .watcher
+            .lock()
+            .watch(&event.path, RecursiveMode::NonRecursive);
+        if let Err(e) = result {
+            warn!("failed watching new target of {:?}: {:?}", event.path, e);
+        }
+    }
 }
 
 pub struct Event {
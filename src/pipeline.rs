@@ -0,0 +1,258 @@
+//! Feeding log lines to veto directly, for applications that produce their own log events and
+//! don't write them to a file first.
+
+use std::net::IpAddr;
+
+use time::OffsetDateTime;
+
+use crate::{
+    firewall::{self, Firewall, Target},
+    handler::{prepare_rule, Entry, Handler, RuleCache},
+    host::HostExtractor,
+    settings::{Limits, Rule},
+    storage::TargetRepository,
+    timestamp::TimeParser,
+    Error, HashMap, Result,
+};
+
+/// A newly blocked IP, as result of a pushed line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ban {
+    /// The blocked IP address.
+    pub ip: IpAddr,
+    /// Time until the IP stays blocked.
+    pub until: OffsetDateTime,
+}
+
+/// Runs lines through the rules and blocks offending IPs, without watching any files.
+///
+/// The `file` of each rule isn't read, but still identifies the rule in the storage, so it should
+/// be unique across all rules.
+pub struct Pipeline<TR, F> {
+    handler: Handler<TR, F>,
+    rules: HashMap<String, (Entry, OffsetDateTime)>,
+    limits: Limits,
+    cache: RuleCache,
+}
+
+impl<TR, F> Pipeline<TR, F>
+where
+    TR: TargetRepository,
+    F: Firewall,
+{
+    pub fn new(handler: Handler<TR, F>) -> Self {
+        Self {
+            handler,
+            rules: HashMap::default(),
+            limits: Limits::default(),
+            cache: RuleCache::default(),
+        }
+    }
+
+    /// Limits for line lengths and regex sizes. No limits are applied by default.
+    #[must_use]
+    pub const fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Add a new rule, replacing any existing one with the same name.
+    pub fn add_rule(&mut self, name: impl Into<String>, rule: Rule) -> Result<()> {
+        let name = name.into();
+        let entry = prepare_rule(name.clone(), rule, &self.limits, &mut self.cache)?;
+
+        self.rules.insert(name, (entry, OffsetDateTime::UNIX_EPOCH));
+
+        Ok(())
+    }
+
+    /// Make a custom time parser available to rules that are added afterwards, under the given
+    /// `time_format` name.
+    pub fn register_time_parser(
+        &mut self,
+        name: impl Into<String>,
+        parser: impl TimeParser + 'static,
+    ) {
+        self.cache.register_time_parser(name, parser);
+    }
+
+    /// Make a custom host extractor available to rules that are added afterwards, under the
+    /// given `host` name.
+    pub fn register_host_extractor(
+        &mut self,
+        name: impl Into<String>,
+        extractor: impl HostExtractor + 'static,
+    ) {
+        self.cache.register_host_extractor(name, extractor);
+    }
+
+    /// Remove a rule, returning whether it existed. IPs that it blocked stay blocked until the
+    /// rule is added again and they're unblocked with [`Self::unblock`].
+    pub fn remove_rule(&mut self, name: &str) -> bool {
+        self.rules.remove(name).is_some()
+    }
+
+    /// Check a single line against the named rule, just like a line of its log file, so it needs
+    /// a timestamp as well. If the line caused a new block, the IP is blocked on the firewall and
+    /// returned.
+    pub fn push_line(&mut self, rule: &str, line: &str) -> Result<Option<Ban>> {
+        let (entry, time) = self
+            .rules
+            .get_mut(rule)
+            .ok_or_else(|| Error::UnknownRule(rule.to_owned()))?;
+
+        if self
+            .limits
+            .max_line_length
+            .is_some_and(|max| line.len() > max.get())
+        {
+            return Ok(None);
+        }
+
+        self.handler.matcher.refresh();
+
+        let Some(ip) = self.handler.matcher.find(entry, time, line) else {
+            return Ok(None);
+        };
+        let Some(until) = self.handler.record_offense(entry, ip)? else {
+            return Ok(None);
+        };
+
+        self.handler.block_all(
+            &entry.name,
+            &[Target {
+                ip: ip.into(),
+                ports: &entry.rule.ports,
+                protocol: entry.rule.protocol,
+                timeout: firewall::remaining(until, self.handler.matcher.current_time()),
+            }],
+        );
+
+        Ok(Some(Ban { ip, until }))
+    }
+
+    /// Unblock all IPs whose block timed out, and block IPs that failed the crawler verification
+    /// in the meantime. This should be called periodically.
+    pub fn unblock(&mut self) -> Result<()> {
+        let rules = &self.rules;
+
+        self.handler.unblock_with(|path| {
+            rules
+                .values()
+                .map(|(entry, _)| entry)
+                .find(|entry| entry.rule.file == path)
+        })
+    }
+
+    /// The handler that manages the storage and firewall.
+    pub const fn handler(&self) -> &Handler<TR, F> {
+        &self.handler
+    }
+
+    /// Mutable access to the handler, for example to update the whitelist.
+    pub const fn handler_mut(&mut self) -> &mut Handler<TR, F> {
+        &mut self.handler
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ipnetwork::IpNetwork;
+    use time::{macros::datetime, Duration};
+
+    use super::*;
+    use crate::{
+        events::{BanEvent, BlockEvent},
+        settings::{Filter, HostSource, Protocol},
+        testing::{MemoryRepository, MockFirewall},
+        IndexMap, IndexSet,
+    };
+
+    #[test]
+    fn push_line() {
+        let mut pipeline = Pipeline::new(
+            Handler::builder(MemoryRepository::new(), MockFirewall::new())
+                .clock(|| datetime!(2023-10-01 12:00 UTC))
+                .build(),
+        );
+
+        pipeline
+            .add_rule(
+                "app",
+                Rule {
+                    file: "app".into(),
+                    filters: vec![Filter {
+                        pattern: r"^\[<TIME>\] <HOST> (?P<event>.+)$".to_owned(),
+                        prefilter: None,
+                    }],
+                    plugins: Vec::new(),
+                    ports: vec![443],
+                    protocol: Protocol::Tcp,
+                    timeout: Duration::hours(1),
+                    blacklists: IndexMap::from_iter([(
+                        "event".to_owned(),
+                        IndexSet::from_iter(["failed login".to_owned()]),
+                    )]),
+                    host: HostSource::Capture,
+                    time_format: None,
+                    stall_timeout: None,
+                    max_bans_per_minute: None,
+                    reputation_timeout: None,
+                    tor_timeout: None,
+                    crawler_group: None,
+                },
+            )
+            .unwrap();
+
+        let events = pipeline.handler_mut().events.subscribe();
+        let ip = "10.0.0.1".parse().unwrap();
+
+        assert_eq!(
+            None,
+            pipeline
+                .push_line("app", "[01/Oct/2023:11:59:00 +0000] 10.0.0.1 logged in")
+                .unwrap()
+        );
+        assert_eq!(
+            Some(Ban {
+                ip,
+                until: datetime!(2023-10-01 13:00 UTC)
+            }),
+            pipeline
+                .push_line("app", "[01/Oct/2023:11:59:00 +0000] 10.0.0.1 failed login")
+                .unwrap()
+        );
+        assert_eq!(
+            None,
+            pipeline
+                .push_line("app", "[01/Oct/2023:11:59:00 +0000] 10.0.0.1 failed login")
+                .unwrap()
+        );
+        assert_eq!(
+            vec![IpNetwork::from(ip)],
+            pipeline
+                .handler()
+                .firewall
+                .blocked()
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            Ok(BlockEvent::Ban(BanEvent {
+                rule: "app".to_owned(),
+                ip,
+                until: datetime!(2023-10-01 13:00 UTC)
+            })),
+            events.try_recv()
+        );
+
+        assert!(matches!(
+            pipeline.push_line(
+                "other",
+                "[01/Oct/2023:11:59:00 +0000] 10.0.0.1 failed login"
+            ),
+            Err(Error::UnknownRule(_))
+        ));
+    }
+}
@@ -0,0 +1,186 @@
+//! Rule plugins implemented as WebAssembly modules, for detection logic that can't be expressed
+//! with regexes, like stateful protocol checks or custom decoders.
+//!
+//! A plugin module must not import anything and export the following items:
+//!
+//! - `memory`: The linear memory that lines and verdicts are exchanged through.
+//! - `alloc(len: i32) -> i32`: Reserve `len` bytes and return a pointer to them. The log line is
+//!   written to this location before each call to `match`.
+//! - `match(ptr: i32, len: i32) -> i64`: Check the UTF-8 encoded line at `ptr` with length `len`. A
+//!   negative value means there is nothing to block. Otherwise, the upper 32 bits are a pointer and
+//!   the lower 32 bits the length of a string in memory, holding the IP address to block.
+//!
+//! The module instance lives as long as its rule, so plugins can keep state between lines in
+//! globals or their memory.
+
+use std::{
+    cell::RefCell,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+use wasmtime::{Engine, Instance, Memory, Module, Store, TypedFunc};
+
+use crate::{Error, Result};
+
+/// Decision of a plugin about a single log line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Verdict {
+    /// The IP address to block.
+    pub host: IpAddr,
+}
+
+/// A loaded plugin module, ready to check log lines.
+pub struct Plugin {
+    path: PathBuf,
+    inner: RefCell<Inner>,
+}
+
+struct Inner {
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    matches: TypedFunc<(i32, i32), i64>,
+}
+
+impl Plugin {
+    /// Load and instantiate the module at the given path, which can be either in the binary or the
+    /// text format.
+    pub fn load(path: &Path) -> Result<Self> {
+        Inner::load(path)
+            .map(|inner| Self {
+                path: path.to_owned(),
+                inner: RefCell::new(inner),
+            })
+            .map_err(|source| Error::Plugin {
+                path: path.to_owned(),
+                source: source.into(),
+            })
+    }
+
+    /// Location of the module this plugin was loaded from.
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Let the plugin check a single line. Any failure of the plugin is logged and treated as if
+    /// there is nothing to block.
+    #[must_use]
+    pub fn check(&self, line: &str) -> Option<Verdict> {
+        self.inner.borrow_mut().check(line).unwrap_or_else(|e| {
+            warn!(
+                "plugin {}: failed checking line: {:?}",
+                self.path.display(),
+                e
+            );
+            None
+        })
+    }
+}
+
+impl Inner {
+    fn load(path: &Path) -> wasmtime::Result<Self> {
+        let engine = Engine::default();
+        let module = Module::from_file(&engine, path)?;
+        let mut store = Store::new(&engine, ());
+        let instance = Instance::new(&mut store, &module, &[])?;
+
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("missing `memory` export"))?;
+        let alloc = instance.get_typed_func(&mut store, "alloc")?;
+        let matches = instance.get_typed_func(&mut store, "match")?;
+
+        Ok(Self {
+            store,
+            memory,
+            alloc,
+            matches,
+        })
+    }
+
+    fn check(&mut self, line: &str) -> wasmtime::Result<Option<Verdict>> {
+        let len = i32::try_from(line.len())?;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory
+            .write(&mut self.store, offset(ptr), line.as_bytes())?;
+
+        let result = self.matches.call(&mut self.store, (ptr, len))?;
+        if result < 0 {
+            return Ok(None);
+        }
+
+        #[allow(clippy::cast_possible_truncation)]
+        let (ptr, len) = (offset((result >> 32) as i32), offset(result as i32));
+        let host = self
+            .memory
+            .data(&self.store)
+            .get(ptr..ptr + len)
+            .ok_or_else(|| wasmtime::Error::msg("verdict out of bounds"))?;
+
+        Ok(Some(Verdict {
+            host: std::str::from_utf8(host)?.parse()?,
+        }))
+    }
+}
+
+/// Turn a 32-bit WebAssembly pointer into an offset into the linear memory.
+#[allow(clippy::cast_sign_loss)]
+const fn offset(ptr: i32) -> usize {
+    ptr as u32 as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// Blocks `10.0.0.1` for the second line starting with `!`.
+    const MODULE: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 0) "10.0.0.1")
+          (global $seen (mut i32) (i32.const 0))
+          (func (export "alloc") (param i32) (result i32)
+            i32.const 1024)
+          (func (export "match") (param $ptr i32) (param $len i32) (result i64)
+            (if (i32.ne (i32.load8_u (local.get $ptr)) (i32.const 33))
+              (then (return (i64.const -1))))
+            (global.set $seen (i32.add (global.get $seen) (i32.const 1)))
+            (if (result i64) (i32.ge_u (global.get $seen) (i32.const 2))
+              (then (i64.const 8))
+              (else (i64.const -1)))))
+    "#;
+
+    #[test]
+    fn stateful_verdict() {
+        let path = std::env::temp_dir().join("veto-test-stateful-verdict.wat");
+        std::fs::write(&path, MODULE).unwrap();
+
+        let plugin = Plugin::load(&path).unwrap();
+        std::fs::remove_file(path).ok();
+
+        assert_eq!(None, plugin.check("ok"));
+        assert_eq!(None, plugin.check("!bad"));
+        assert_eq!(
+            Some(Verdict {
+                host: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))
+            }),
+            plugin.check("!bad")
+        );
+    }
+
+    #[test]
+    fn missing_exports() {
+        let path = std::env::temp_dir().join("veto-test-missing-exports.wat");
+        std::fs::write(&path, "(module)").unwrap();
+
+        let result = Plugin::load(&path);
+        std::fs::remove_file(path).ok();
+
+        assert!(matches!(result, Err(Error::Plugin { .. })));
+    }
+}
@@ -0,0 +1,163 @@
+//! Progress through large amounts of log content, like the existing content of files at startup,
+//! so a long scan can be told apart from a hang.
+
+use std::{
+    fmt::{self, Display},
+    time::{Duration, Instant},
+};
+
+/// Time between two progress reports.
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Snapshot of how far processing got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    /// Amount of bytes processed so far.
+    pub processed: u64,
+    /// Total amount of bytes to process, if known.
+    pub total: Option<u64>,
+    /// Amount of lines that matched so far.
+    pub matches: u64,
+    /// Time since processing started.
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Processed share of the total, from `0.0` to `1.0`.
+    #[must_use]
+    pub fn fraction(&self) -> Option<f64> {
+        #[allow(clippy::cast_precision_loss)]
+        self.total
+            .filter(|&total| total > 0)
+            .map(|total| self.processed.min(total) as f64 / total as f64)
+    }
+
+    /// Estimated time until processing is done, based on the rate so far.
+    #[must_use]
+    pub fn eta(&self) -> Option<Duration> {
+        let fraction = self.fraction().filter(|&f| f > 0.0)?;
+        let total = self.elapsed.as_secs_f64() / fraction;
+
+        Duration::try_from_secs_f64(total - self.elapsed.as_secs_f64()).ok()
+    }
+}
+
+/// Summary like `1.2 GiB of 5.0 GiB (24.0%), 42 matches, about 3m 20s left`.
+impl Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Bytes(self.processed))?;
+
+        if let (Some(total), Some(fraction)) = (self.total, self.fraction()) {
+            write!(f, " of {} ({:.1}%)", Bytes(total), fraction * 100.0)?;
+        }
+
+        write!(f, ", {} matches", self.matches)?;
+
+        if let Some(eta) = self.eta() {
+            write!(
+                f,
+                ", about {} left",
+                humantime::format_duration(Duration::from_secs(eta.as_secs()))
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Byte amount, shown in binary units.
+struct Bytes(u64);
+
+impl Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+        if self.0 < 1024 {
+            return write!(f, "{} B", self.0);
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        let mut value = self.0 as f64 / 1024.0;
+        let mut unit = UNITS[0];
+        for next in &UNITS[1..] {
+            if value < 1024.0 {
+                break;
+            }
+            value /= 1024.0;
+            unit = next;
+        }
+
+        write!(f, "{value:.1} {unit}")
+    }
+}
+
+/// Decides when the next progress report is due, to keep reports at a steady interval.
+#[derive(Debug)]
+pub struct Reporter {
+    started: Instant,
+    last: Instant,
+    interval: Duration,
+}
+
+impl Reporter {
+    #[must_use]
+    pub fn new(interval: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            last: now,
+            interval,
+        }
+    }
+
+    /// Time since the reporter was created.
+    #[must_use]
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Whether the interval passed since the last report. If so, the next report is due after
+    /// another interval.
+    pub fn due(&mut self) -> bool {
+        if self.last.elapsed() < self.interval {
+            return false;
+        }
+
+        self.last = Instant::now();
+        true
+    }
+}
+
+impl Default for Reporter {
+    fn default() -> Self {
+        Self::new(REPORT_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary() {
+        let progress = Progress {
+            processed: 256 * 1024 * 1024,
+            total: Some(1024 * 1024 * 1024),
+            matches: 42,
+            elapsed: Duration::from_secs(60),
+        };
+
+        assert_eq!(Some(Duration::from_secs(180)), progress.eta());
+        assert_eq!(
+            "256.0 MiB of 1.0 GiB (25.0%), 42 matches, about 3m left",
+            progress.to_string()
+        );
+
+        let progress = Progress {
+            processed: 512,
+            total: None,
+            ..progress
+        };
+        assert_eq!("512 B, 42 matches", progress.to_string());
+    }
+}
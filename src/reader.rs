@@ -0,0 +1,296 @@
+//! Line readers for log files, split into a scan over existing content with a large buffer and a
+//! reader that follows newly appended lines afterwards.
+
+use std::{
+    fs::File,
+    io::{prelude::*, BufReader, SeekFrom},
+};
+
+use log::{debug, warn};
+
+use crate::Result;
+
+/// Buffer size for the initial scan, which reads large files in fewer and bigger chunks.
+const SCAN_CAPACITY: usize = 1024 * 1024;
+/// Smallest buffer for the scan, which is the default size of a [`BufReader`].
+const MIN_CAPACITY: usize = 8 * 1024;
+
+/// Source of new lines for a single log file.
+///
+/// Lines are read into a buffer that is reused for every line, so no memory is allocated per line.
+/// Log files are read instead of mapped into memory, as they're truncated by log rotation like
+/// `copytruncate` at any time, which is fine for reads but not for a mapped file.
+pub struct LineReader {
+    reader: BufReader<File>,
+    /// Buffer for the current line, which keeps incomplete lines until the rest of it was written.
+    buf: Vec<u8>,
+    /// Position and length of the content that existed when the file was opened, while it's
+    /// scanned.
+    scan: Option<(usize, usize)>,
+    /// Maximum length of a single line. Any longer lines are skipped.
+    max_length: usize,
+    /// Whether the current line exceeded the maximum length and is skipped until its end.
+    discarding: bool,
+}
+
+impl LineReader {
+    /// Open a file for scanning, starting with the existing content.
+    pub fn open(file: File) -> Result<Self> {
+        let len = usize::try_from(file.metadata()?.len()).unwrap_or(usize::MAX);
+        // Small files don't need the full buffer, which would be allocated for each of them.
+        let capacity = len.clamp(MIN_CAPACITY, SCAN_CAPACITY);
+
+        Ok(Self {
+            scan: Some((0, len)),
+            ..Self::new(BufReader::with_capacity(capacity, file))
+        })
+    }
+
+    /// Create a reader that only follows new lines, starting at the current position of the
+    /// file.
+    #[must_use]
+    pub fn tail(file: File) -> Self {
+        Self::new(BufReader::new(file))
+    }
+
+    const fn new(reader: BufReader<File>) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            scan: None,
+            max_length: usize::MAX,
+            discarding: false,
+        }
+    }
+
+    /// Limit the length of lines, skipping any lines that are longer. This keeps the memory used
+    /// for buffering bounded.
+    #[must_use]
+    pub const fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// Approximate amount of heap memory used by this reader for buffering, in bytes.
+    #[must_use]
+    pub fn memory_usage(&self) -> usize {
+        self.reader.capacity() + self.buf.capacity()
+    }
+
+    /// Position and total length in bytes of the initial scan over the existing content, or
+    /// [`None`] once the scan is done and the reader follows new lines.
+    #[must_use]
+    pub fn scan_progress(&self) -> Option<(usize, usize)> {
+        // Lines that were appended during the scan are read as part of it.
+        self.scan.map(|(position, len)| (position.min(len), len))
+    }
+
+    /// Pass the next line to the given function and return its result, or [`None`] if no more
+    /// lines are available right now.
+    ///
+    /// Once the existing content is scanned, the reader switches over to a smaller buffer that
+    /// follows the file from the end of the last complete line. Only complete lines are passed on
+    /// in either case.
+    ///
+    /// Invalid UTF-8 is replaced with `U+FFFD`, so binary data in a log doesn't stop processing it.
+    /// Lines that are obviously binary are skipped, see [`text`].
+    pub fn next_with<T>(&mut self, f: impl FnOnce(&str) -> T) -> Option<Result<T>> {
+        loop {
+            if self.buf.last() == Some(&b'\n') {
+                self.buf.clear();
+            }
+
+            let limit = self
+                .max_length
+                .saturating_add(1)
+                .saturating_sub(self.buf.len());
+            let read = match self
+                .reader
+                .by_ref()
+                .take(limit as u64)
+                .read_until(b'\n', &mut self.buf)
+            {
+                Ok(read) => read,
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            if let Some((position, _)) = &mut self.scan {
+                *position += read;
+            }
+
+            if self.buf.last() == Some(&b'\n') {
+                if self.discarding {
+                    self.discarding = false;
+                    continue;
+                }
+
+                let line = &self.buf[..self.buf.len() - 1];
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                let Some(line) = text(line) else {
+                    continue;
+                };
+
+                return Some(Ok(f(&String::from_utf8_lossy(line))));
+            }
+
+            if self.buf.len() <= self.max_length {
+                if let Err(e) = self.finish_scan() {
+                    return Some(Err(e));
+                }
+                return None;
+            }
+
+            if !self.discarding {
+                warn!("skipping line exceeding {} bytes", self.max_length);
+                self.discarding = true;
+            }
+
+            self.buf.clear();
+        }
+    }
+
+    /// Swap the large buffer of the scan for a regular one, once all complete lines are read. The
+    /// file continues right after the last read byte, which the incomplete line ends with.
+    fn finish_scan(&mut self) -> Result<()> {
+        let Some((position, _)) = self.scan.take() else {
+            return Ok(());
+        };
+
+        let mut file = self.reader.get_ref().try_clone()?;
+        file.seek(SeekFrom::Start(position as u64))?;
+        self.reader = BufReader::new(file);
+
+        Ok(())
+    }
+}
+
+/// Share of control characters above which a line is considered binary.
+const MAX_CONTROL_RATIO: usize = 8;
+
+/// Get the textual content of a line, or [`None`] if it's binary garbage, which sometimes ends up
+/// in log files after a crash. Such lines only waste time in the filters and can match in bizarre
+/// ways.
+///
+/// Leading NUL bytes are dropped, as file systems fill the unwritten part of a file with them after
+/// a crash, which is then followed by the next regular line. Any other NUL byte, or more than an
+/// eighth of the bytes being control characters, marks the line as binary. Tabs and the escape
+/// character of terminal colors are regular text.
+#[must_use]
+pub fn text(line: &[u8]) -> Option<&[u8]> {
+    let start = line.iter().position(|&b| b != 0).unwrap_or(line.len());
+    let line = &line[start..];
+
+    let controls = line
+        .iter()
+        .filter(|&&b| (b < 0x20 && b != b'\t' && b != 0x1b) || b == 0x7f)
+        .count();
+
+    if line.contains(&0) || controls * MAX_CONTROL_RATIO > line.len() {
+        debug!("skipping binary line of {} bytes", line.len());
+        return None;
+    }
+
+    Some(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    #[test]
+    fn scan_then_tail() {
+        let path = env::temp_dir().join(format!("veto-reader-{}.log", std::process::id()));
+        fs::write(&path, "first\r\nsecond\npart").unwrap();
+
+        let mut reader = LineReader::open(File::open(&path).unwrap()).unwrap();
+        let mut next = || reader.next_with(str::to_owned).transpose().unwrap();
+
+        assert_eq!(Some("first".to_owned()), next());
+        assert_eq!(Some("second".to_owned()), next());
+
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"ial\nthird\n")
+            .unwrap();
+
+        assert_eq!(Some("partial".to_owned()), next());
+        assert_eq!(Some("third".to_owned()), next());
+        assert_eq!(None, next());
+
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"fou").unwrap();
+        assert_eq!(None, next());
+        file.write_all(b"rth\n").unwrap();
+        assert_eq!(Some("fourth".to_owned()), next());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn skip_long_lines() {
+        let path = env::temp_dir().join(format!("veto-reader-long-{}.log", std::process::id()));
+        fs::write(&path, "short\nway too long\nok\n").unwrap();
+
+        let mut reader = LineReader::open(File::open(&path).unwrap())
+            .unwrap()
+            .with_max_length(5);
+        let mut next = || reader.next_with(str::to_owned).transpose().unwrap();
+
+        assert_eq!(Some("short".to_owned()), next());
+        assert_eq!(Some("ok".to_owned()), next());
+
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"too lo").unwrap();
+        assert_eq!(None, next());
+        file.write_all(b"ng\nfine\n").unwrap();
+        assert_eq!(Some("fine".to_owned()), next());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let path = env::temp_dir().join(format!("veto-reader-utf8-{}.log", std::process::id()));
+        fs::write(&path, b"bin\xff\xfe\nnext\n").unwrap();
+
+        let mut reader = LineReader::open(File::open(&path).unwrap()).unwrap();
+        let mut next = || reader.next_with(str::to_owned).transpose().unwrap();
+
+        assert_eq!(Some("bin\u{fffd}\u{fffd}".to_owned()), next());
+        assert_eq!(Some("next".to_owned()), next());
+
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"\xc3(\nafter\n")
+            .unwrap();
+
+        assert_eq!(Some("\u{fffd}(".to_owned()), next());
+        assert_eq!(Some("after".to_owned()), next());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn binary_lines() {
+        assert_eq!(Some(&b"text"[..]), text(b"text"));
+        assert_eq!(Some(&b"after crash"[..]), text(b"\0\0\0after crash"));
+        assert_eq!(Some(&b""[..]), text(b"\0\0"));
+        assert_eq!(
+            Some(&b"\x1b[31mred\x1b[0m\tand tabs"[..]),
+            text(b"\x1b[31mred\x1b[0m\tand tabs")
+        );
+
+        assert_eq!(None, text(b"some\0thing"));
+        assert_eq!(None, text(b"\x01\x02\x03abc"));
+        assert_eq!(
+            Some(&b"one \x07 bell in a longer line"[..]),
+            text(b"one \x07 bell in a longer line")
+        );
+    }
+}
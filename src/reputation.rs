@@ -0,0 +1,355 @@
+//! Reputation of IPs, a single score built from several signals, that rules use to block IPs with
+//! a bad reputation for longer.
+//!
+//! The score adds up the offenses that any rule found, plus the external signals: blocklist files,
+//! DNS-based blocklists and the country from the `GeoIP` database. External signals are only
+//! checked once per TTL, as DNS lookups are slow, and scores without any news for a whole TTL are
+//! forgotten. The scores are written to a file regularly, so they survive restarts.
+
+use std::{
+    fmt::Write,
+    fs::{self, File},
+    io::{self, BufRead, BufReader},
+    net::{IpAddr, ToSocketAddrs},
+    path::Path,
+    sync::Arc,
+};
+
+use ipnetwork::IpNetwork;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::{
+    enrich::{Enrichment, Lookup},
+    settings, HashMap, Result,
+};
+
+/// Cached reputation of a single IP.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Score {
+    pub ip: IpAddr,
+    /// Offenses found by any rule, since the score was created.
+    pub offenses: u32,
+    /// Sum of the external signals at the last check.
+    pub listed: f64,
+    /// Time of the last check of the external signals.
+    #[serde(with = "time::serde::rfc3339")]
+    pub checked: OffsetDateTime,
+    /// Time of the last offense or check, from which on the score expires after the TTL.
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated: OffsetDateTime,
+}
+
+/// Cache of the reputation of all IPs that were seen within the TTL.
+pub struct Reputation {
+    settings: settings::Reputation,
+    blocklist: Vec<IpNetwork>,
+    geoip: Option<Arc<dyn Lookup>>,
+    scores: HashMap<IpAddr, Score>,
+    /// Whether the scores changed since they were last saved.
+    dirty: bool,
+}
+
+impl Reputation {
+    /// Load the blocklists and the scores of the settings, or return [`None`] if the reputation
+    /// is disabled. The `GeoIP` database is used for the countries of the settings.
+    pub fn from_settings(
+        settings: settings::Reputation,
+        geoip: Option<Arc<dyn Lookup>>,
+    ) -> Result<Option<Self>> {
+        if settings.file.is_none() {
+            return Ok(None);
+        }
+
+        if !settings.countries.is_empty() && geoip.is_none() {
+            warn!("reputation by country requires a GeoIP database, the countries are ignored");
+        }
+
+        let mut blocklist = Vec::new();
+        for path in &settings.blocklists {
+            blocklist.extend(load_blocklist(path)?);
+        }
+
+        let mut reputation = Self {
+            settings,
+            blocklist,
+            geoip,
+            scores: HashMap::default(),
+            dirty: false,
+        };
+        reputation.load()?;
+
+        Ok(Some(reputation))
+    }
+
+    /// Load the saved scores. Nothing is loaded if the file doesn't exist yet.
+    fn load(&mut self) -> Result<()> {
+        let Some(path) = &self.settings.file else {
+            return Ok(());
+        };
+
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let scores = serde_json::from_slice::<Vec<Score>>(&data).map_err(io::Error::from)?;
+
+        self.scores = scores.into_iter().map(|score| (score.ip, score)).collect();
+
+        info!("loaded the reputation of {} IPs", self.scores.len());
+
+        Ok(())
+    }
+
+    /// Write all scores to the file, if they changed since the last call. Expired scores are
+    /// dropped first.
+    pub fn save(&mut self, now: OffsetDateTime) -> Result<()> {
+        let Some(path) = &self.settings.file else {
+            return Ok(());
+        };
+
+        let before = self.scores.len();
+        let ttl = self.settings.ttl;
+        self.scores.retain(|_, score| score.updated + ttl > now);
+
+        if !self.dirty && self.scores.len() == before {
+            return Ok(());
+        }
+
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+
+        let temp = path.with_extension("tmp");
+        let json = serde_json::to_vec(&self.scores.values().collect::<Vec<_>>())
+            .map_err(io::Error::from)?;
+
+        fs::write(&temp, json)?;
+        fs::rename(temp, path)?;
+        self.dirty = false;
+
+        Ok(())
+    }
+
+    /// Current score of the IP, checking the external signals if they're unknown or outdated.
+    pub fn score(&mut self, ip: IpAddr, now: OffsetDateTime) -> f64 {
+        let score = self.entry(ip, now).clone();
+        self.value(&score)
+    }
+
+    /// Count a new offense of the IP, and tell whether it has a bad reputation now.
+    pub fn record_offense(&mut self, ip: IpAddr, now: OffsetDateTime) -> bool {
+        let score = self.entry(ip, now);
+        score.offenses = score.offenses.saturating_add(1);
+        score.updated = now;
+
+        let score = score.clone();
+        self.dirty = true;
+        self.is_bad(&score)
+    }
+
+    /// Whether the score reached the threshold of the settings.
+    #[must_use]
+    pub fn is_bad(&self, score: &Score) -> bool {
+        self.value(score) >= self.settings.threshold
+    }
+
+    /// All cached scores, in no particular order.
+    pub fn scores(&self) -> impl Iterator<Item = &Score> {
+        self.scores.values()
+    }
+
+    fn value(&self, score: &Score) -> f64 {
+        f64::from(score.offenses).mul_add(self.settings.offense, score.listed)
+    }
+
+    /// The cached score of the IP, which is created or refreshed as needed.
+    fn entry(&mut self, ip: IpAddr, now: OffsetDateTime) -> &mut Score {
+        let ttl = self.settings.ttl;
+        let expired = self
+            .scores
+            .get(&ip)
+            .is_none_or(|score| score.updated + ttl <= now);
+        let outdated = expired
+            || self
+                .scores
+                .get(&ip)
+                .is_some_and(|score| score.checked + ttl <= now);
+
+        if expired {
+            self.scores.remove(&ip);
+        }
+
+        let listed = outdated.then(|| self.listed(ip));
+        let score = self.scores.entry(ip).or_insert_with(|| Score {
+            ip,
+            offenses: 0,
+            listed: 0.0,
+            checked: now,
+            updated: now,
+        });
+
+        if let Some(listed) = listed {
+            score.listed = listed;
+            score.checked = now;
+            score.updated = now;
+            self.dirty = true;
+        }
+
+        score
+    }
+
+    /// Sum of the external signals for the IP.
+    fn listed(&self, ip: IpAddr) -> f64 {
+        let mut listed = 0.0;
+
+        if self.blocklist.iter().any(|network| network.contains(ip)) {
+            listed += self.settings.blocklist;
+        }
+
+        for zone in &self.settings.dnsbl {
+            if is_dnsbl_listed(ip, zone) {
+                debug!("{ip} is listed on {zone}");
+                listed += self.settings.dnsbl_listed;
+            }
+        }
+
+        if let Some(geoip) = self
+            .geoip
+            .as_ref()
+            .filter(|_| !self.settings.countries.is_empty())
+        {
+            let mut enrichment = Enrichment::default();
+            if geoip.lookup(ip, &mut enrichment).is_ok()
+                && enrichment.country.is_some_and(|country| {
+                    self.settings
+                        .countries
+                        .iter()
+                        .any(|c| c.eq_ignore_ascii_case(&country))
+                })
+            {
+                listed += self.settings.country;
+            }
+        }
+
+        listed
+    }
+}
+
+/// Load a blocklist file with one IP or network per line. Anything after the address, as well as
+/// empty lines and comments starting with `#` or `;` are ignored.
+fn load_blocklist(path: &Path) -> Result<Vec<IpNetwork>> {
+    let file = File::open(path)?;
+    let mut networks = Vec::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let Some(address) = line.split_whitespace().next() else {
+            continue;
+        };
+        if address.starts_with(['#', ';']) {
+            continue;
+        }
+
+        if let Ok(network) = address.parse() {
+            networks.push(network);
+        } else {
+            warn!("skipping invalid address {address} in {}", path.display());
+        }
+    }
+
+    info!("loaded {} networks from {}", networks.len(), path.display());
+
+    Ok(networks)
+}
+
+/// Name to look up for the IP in a DNS-based blocklist. IPv4 addresses are written with their
+/// octets in reverse order, and IPv6 addresses with their nibbles in reverse order.
+fn dnsbl_name(ip: IpAddr, zone: &str) -> String {
+    let mut name = String::new();
+
+    match ip {
+        IpAddr::V4(ip) => {
+            for octet in ip.octets().iter().rev() {
+                _ = write!(name, "{octet}.");
+            }
+        }
+        IpAddr::V6(ip) => {
+            for byte in ip.octets().iter().rev() {
+                _ = write!(name, "{:x}.{:x}.", byte & 0xf, byte >> 4);
+            }
+        }
+    }
+
+    name.push_str(zone.trim_end_matches('.'));
+    name
+}
+
+/// Whether the blocklist lists the IP, which it does by resolving its name to an address in
+/// `127.0.0.0/8`. Failed lookups count as not listed.
+fn is_dnsbl_listed(ip: IpAddr, zone: &str) -> bool {
+    (dnsbl_name(ip, zone).as_str(), 0)
+        .to_socket_addrs()
+        .is_ok_and(|mut addrs| addrs.any(|addr| addr.ip().is_loopback()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, net::Ipv6Addr};
+
+    use time::{macros::datetime, Duration};
+
+    use super::*;
+
+    #[test]
+    fn scores() {
+        let dir = env::temp_dir().join(format!("veto-reputation-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let blocklist = dir.join("blocklist.txt");
+        fs::write(&blocklist, "# comment\n10.0.0.0/8 spam\n\ninvalid\n").unwrap();
+
+        let settings = || settings::Reputation {
+            file: Some(dir.join("reputation.json")),
+            blocklists: vec![blocklist.clone()],
+            ..settings::Reputation::default()
+        };
+        let listed = IpAddr::from([10, 0, 0, 1]);
+        let other = IpAddr::from([192, 168, 0, 1]);
+        let now = datetime!(2023-10-01 12:00 UTC);
+
+        let mut reputation = Reputation::from_settings(settings(), None)
+            .unwrap()
+            .unwrap();
+
+        // Listed IPs reach the threshold with the first offense.
+        assert!(reputation.record_offense(listed, now));
+        assert!(!reputation.record_offense(other, now));
+        assert!(!reputation.record_offense(other, now));
+        assert!(reputation.record_offense(other, now));
+        reputation.save(now).unwrap();
+
+        let mut reputation = Reputation::from_settings(settings(), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(2, reputation.scores().count());
+        assert!((reputation.score(other, now) - 3.0).abs() < f64::EPSILON);
+
+        // Scores are forgotten after the TTL.
+        let later = now + Duration::WEEK;
+        assert!((reputation.score(other, later)).abs() < f64::EPSILON);
+
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(
+            "1.0.0.10.zen.spamhaus.org",
+            dnsbl_name(IpAddr::from([10, 0, 0, 1]), "zen.spamhaus.org.")
+        );
+        assert!(dnsbl_name(
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+            "example.org"
+        )
+        .starts_with("1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2."));
+    }
+}
@@ -0,0 +1,222 @@
+//! Jumping to a point in time within large log files, by bisecting over the timestamps of their
+//! lines instead of reading them from the start.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Seek, SeekFrom},
+    sync::Arc,
+};
+
+use regex::Regex;
+use regex_syntax::hir::{Hir, HirKind};
+use time::OffsetDateTime;
+
+use crate::{
+    handler::{self, Entry},
+    timestamp::{TimeParser, TIME_GROUPS},
+};
+
+/// Maximum amount of lines that are read from a position to find a timestamp, before giving up on
+/// that position.
+const MAX_PROBE_LINES: usize = 1000;
+
+/// Finds the timestamp in any line of a rule's log file, not only in lines that match a filter.
+pub struct TimeLocator {
+    regex: Regex,
+    parser: Arc<dyn TimeParser>,
+}
+
+impl TimeLocator {
+    /// Create a locator from the `time` capture groups of the rule's filters, or return [`None`]
+    /// if none of them captures a timestamp.
+    #[must_use]
+    pub fn new(entry: &Entry) -> Option<Self> {
+        let mut patterns = entry
+            .rule
+            .filters
+            .iter()
+            .filter_map(|filter| {
+                let pattern = handler::expand_pattern(&filter.pattern, &entry.rule.host);
+                time_group(&regex_syntax::parse(&pattern).ok()?).map(ToString::to_string)
+            })
+            .collect::<Vec<_>>();
+        patterns.dedup();
+
+        if patterns.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            regex: Regex::new(&patterns.join("|")).ok()?,
+            parser: Arc::clone(&entry.time_parser),
+        })
+    }
+
+    /// Find and parse the first timestamp in the line.
+    #[must_use]
+    pub fn find(&self, line: &str) -> Option<OffsetDateTime> {
+        self.regex
+            .find_iter(line)
+            .find_map(|m| self.parser.parse(m.as_str()))
+    }
+}
+
+/// Find the sub-pattern of the capture group that contains the timestamp.
+fn time_group(hir: &Hir) -> Option<&Hir> {
+    match hir.kind() {
+        HirKind::Capture(capture)
+            if capture
+                .name
+                .as_deref()
+                .is_some_and(|name| TIME_GROUPS.contains(&name)) =>
+        {
+            Some(&capture.sub)
+        }
+        HirKind::Capture(capture) => time_group(&capture.sub),
+        HirKind::Repetition(repetition) => time_group(&repetition.sub),
+        HirKind::Concat(parts) | HirKind::Alternation(parts) => parts.iter().find_map(time_group),
+        _ => None,
+    }
+}
+
+/// Move the file to the first line with a timestamp at or after `since`, and return its offset.
+///
+/// Lines are expected to be in chronological order, which log files usually are. If all lines are
+/// older, the file is moved to its end.
+pub fn seek_since(
+    file: &mut File,
+    locator: &TimeLocator,
+    since: OffsetDateTime,
+) -> io::Result<u64> {
+    let len = file.metadata()?.len();
+    let (mut low, mut high) = (0, len);
+
+    while low < high {
+        let mid = low + (high - low) / 2;
+
+        match probe(file, locator, mid)? {
+            Some((_, end, time)) if time < since => low = end,
+            Some(_) | None => high = mid,
+        }
+    }
+
+    // Lines without a timestamp right at the found offset still belong to older entries.
+    let offset = probe(file, locator, low)?.map_or(len, |(start, ..)| start);
+    file.seek(SeekFrom::Start(offset))
+}
+
+/// Find the first timestamp in the complete lines at or after the offset, together with the
+/// offsets of the start and end of its line.
+fn probe(
+    file: &mut File,
+    locator: &TimeLocator,
+    offset: u64,
+) -> io::Result<Option<(u64, u64, OffsetDateTime)>> {
+    let mut pos = line_start(file, offset)?;
+    let mut reader = BufReader::new(&mut *file);
+    let mut buf = Vec::new();
+
+    for _ in 0..MAX_PROBE_LINES {
+        buf.clear();
+        let read = reader.read_until(b'\n', &mut buf)?;
+        if read == 0 {
+            break;
+        }
+        let start = pos;
+        pos += read as u64;
+
+        if let Some(time) = locator.find(&String::from_utf8_lossy(&buf)) {
+            return Ok(Some((start, pos, time)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Find the start of the first complete line at or after the offset, and move the file there.
+fn line_start(file: &mut File, offset: u64) -> io::Result<u64> {
+    if offset == 0 {
+        return file.seek(SeekFrom::Start(0));
+    }
+
+    // Starting one byte early finds the line break, if the offset is right at a line start.
+    file.seek(SeekFrom::Start(offset - 1))?;
+    let mut skipped = Vec::new();
+    let read = BufReader::new(&mut *file).read_until(b'\n', &mut skipped)?;
+
+    file.seek(SeekFrom::Start(offset - 1 + read as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fmt::Write, fs, io::Read};
+
+    use time::macros::datetime;
+
+    use super::*;
+    use crate::{handler::RuleCache, settings::Limits, wizard};
+
+    #[test]
+    fn seek() {
+        let path = env::temp_dir().join(format!("veto-seek-{}.log", std::process::id()));
+        let mut content = String::new();
+        for minute in 0..60 {
+            writeln!(
+                content,
+                "10.0.0.1 - - [01/Oct/2020:12:{minute:02}:00 +0000] \"GET /{minute} HTTP/1.1\""
+            )
+            .unwrap();
+            // Lines without a timestamp in between are skipped over.
+            content.push_str("continued\n");
+        }
+        fs::write(&path, &content).unwrap();
+
+        let suggestion = wizard::suggest(content.lines().next().unwrap()).unwrap();
+        let rule = wizard::build_rule(path.clone(), time::Duration::HOUR, &[suggestion]);
+        let entry = handler::prepare_rule(
+            "web".to_owned(),
+            rule,
+            &Limits::default(),
+            &mut RuleCache::default(),
+        )
+        .unwrap();
+        let locator = TimeLocator::new(&entry).unwrap();
+
+        let mut file = File::open(&path).unwrap();
+        let mut rest = String::new();
+
+        let offset = seek_since(&mut file, &locator, datetime!(2020-10-01 12:30:30 UTC)).unwrap();
+        file.read_to_string(&mut rest).unwrap();
+        assert_eq!((content.len() - rest.len()) as u64, offset);
+        assert!(rest.starts_with("10.0.0.1 - - [01/Oct/2020:12:31:00 +0000]"));
+
+        let offset = seek_since(&mut file, &locator, datetime!(2020-10-01 11:00 UTC)).unwrap();
+        assert_eq!(0, offset);
+
+        let offset = seek_since(&mut file, &locator, datetime!(2020-10-01 13:00 UTC)).unwrap();
+        assert_eq!(content.len() as u64, offset);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn named_time_groups() {
+        let line = "2020-10-01T12:00:00+00:00 10.0.0.1 \"GET / HTTP/1.1\"";
+        let suggestion = wizard::suggest(line).unwrap();
+        let rule = wizard::build_rule(
+            "/var/log/app.log".into(),
+            time::Duration::HOUR,
+            &[suggestion],
+        );
+        let entry = handler::prepare_rule(
+            "app".to_owned(),
+            rule,
+            &Limits::default(),
+            &mut RuleCache::default(),
+        )
+        .unwrap();
+
+        let locator = TimeLocator::new(&entry).unwrap();
+        assert_eq!(Some(datetime!(2020-10-01 12:00 UTC)), locator.find(line));
+    }
+}
@@ -1,42 +1,726 @@
 use std::{
     fmt::{self, Display},
     fs,
+    net::{IpAddr, SocketAddr},
+    num::{NonZeroU32, NonZeroUsize},
     path::PathBuf,
 };
 
-use anyhow::{Context, Result};
 use ipnetwork::IpNetwork;
 use log::info;
-use serde::{
-    de::{self, Visitor},
-    Deserialize, Deserializer,
-};
+use serde::{Deserialize, Serialize, Serializer};
 use time::Duration;
 
-use crate::{HashMap, IndexMap, IndexSet};
+pub use crate::storage::Backend;
+use crate::{Error, HashMap, IndexMap, IndexSet, Result};
+
+/// Location of the configuration, if no other one is given.
+pub const DEFAULT_PATH: &str = "/etc/veto/config.toml";
 
 /// Structure holding all application settings.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Settings {
     /// List of IP network masks to ignore.
     #[serde(default)]
     pub whitelist: Vec<IpNetwork>,
+    /// Allow blocking the host's own interface addresses and default gateways, which are
+    /// implicitly whitelisted otherwise.
+    #[serde(default)]
+    pub block_local: bool,
+    /// General settings for the firewall.
+    #[serde(default)]
+    pub firewall: Firewall,
     /// Settings for the ipset firewall.
     #[serde(default)]
     pub ipset: IpSet,
+    /// Settings for an external firewall plugin, which is used instead of ipset if set.
+    pub plugin: Option<Plugin>,
+    /// Settings for running shell commands as firewall, which are used instead of ipset if set.
+    pub exec: Option<Exec>,
+    /// Settings for blocking through blackhole routes, which are used instead of ipset if set.
+    pub blackhole: Option<Blackhole>,
+    /// Settings for the pf firewall of the BSDs, which is used instead of ipset if set.
+    pub pf: Option<Pf>,
+    /// Settings for AWS WAF IP sets, which are used instead of ipset if set.
+    pub aws_waf: Option<AwsWaf>,
+    /// Settings for plain iptables rules per blocked IP, which are used instead of ipset if set.
+    pub iptables: Option<IpTables>,
+    /// Settings for an ACL of `HAProxy`, which is used instead of ipset if set.
+    pub haproxy: Option<HaProxy>,
+    /// Settings for a file of nginx `deny` directives, which is used instead of ipset if set.
+    pub nginx: Option<Nginx>,
+    /// Settings for throttling through traffic control, which is used instead of ipset if set.
+    pub tc: Option<Tc>,
+    /// Settings for a privileged helper that changes the firewall, which is used instead of any
+    /// other firewall if set.
+    pub helper: Option<Helper>,
+    /// Settings for exposing runtime metrics.
+    #[serde(default)]
+    pub metrics: Metrics,
+    /// Settings for sending runtime metrics to a `StatsD` server.
+    #[serde(default)]
+    pub statsd: Statsd,
+    /// Limits to keep the memory usage bounded.
+    #[serde(default)]
+    pub limits: Limits,
+    /// Settings for looking up details about blocked IPs.
+    #[serde(default)]
+    pub enrichment: Enrichment,
+    /// Settings for looking up the country of IPs in a local `GeoIP` database.
+    #[serde(default)]
+    pub geoip: GeoIp,
+    /// Settings for the audit log of all blocks and unblocks.
+    #[serde(default)]
+    pub audit: Audit,
+    /// Settings for exporting blocks and unblocks in the Elastic Common Schema.
+    #[serde(default)]
+    pub ecs: Ecs,
+    /// Settings for publishing blocks and unblocks to an MQTT broker.
+    #[serde(default)]
+    pub mqtt: Mqtt,
+    /// Settings for the storage of blocked IPs.
+    #[serde(default)]
+    pub storage: Storage,
+    /// Settings for pausing blocking when veto looks misconfigured.
+    #[serde(default)]
+    pub breaker: Breaker,
+    /// Settings for the decoy ports, that block every IP connecting to them.
+    #[serde(default)]
+    pub honeypot: Honeypot,
+    /// Settings for the tarpit, that wastes the time of blocked clients.
+    #[serde(default)]
+    pub tarpit: Tarpit,
+    /// Settings for the reputation of IPs, that rules can use to block bad IPs for longer.
+    #[serde(default)]
+    pub reputation: Reputation,
+    /// Settings for the list of Tor exit nodes.
+    #[serde(default)]
+    pub tor: Tor,
     /// List of rules to apply.
     pub rules: HashMap<String, Rule>,
 }
 
+impl Settings {
+    /// Firewalls that are used, which are either the configured `backends`, or the first one
+    /// with a settings section, in the order helper, plugin, exec, blackhole, pf, AWS WAF,
+    /// iptables, `HAProxy`, nginx, tc and ipset.
+    #[must_use]
+    pub fn firewall_backends(&self) -> Vec<FirewallBackend> {
+        if !self.firewall.backends.is_empty() {
+            return self.firewall.backends.clone();
+        }
+
+        let backend = [
+            FirewallBackend::Helper,
+            FirewallBackend::Plugin,
+            FirewallBackend::Exec,
+            FirewallBackend::Blackhole,
+            FirewallBackend::Pf,
+            FirewallBackend::AwsWaf,
+            FirewallBackend::Iptables,
+            FirewallBackend::Haproxy,
+            FirewallBackend::Nginx,
+            FirewallBackend::Tc,
+        ]
+        .into_iter()
+        .find(|backend| backend.is_configured(self))
+        .unwrap_or(FirewallBackend::Ipset);
+
+        vec![backend]
+    }
+}
+
+/// Structure holding limits that bound the memory usage. No limits are applied if not set.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct Limits {
+    /// Maximum amount of entries in the storage. Once reached, the longest expired entries are
+    /// evicted to make room for new ones.
+    pub max_storage_entries: Option<NonZeroUsize>,
+    /// Maximum length of a single log line in bytes. Longer lines are skipped.
+    pub max_line_length: Option<NonZeroUsize>,
+    /// Maximum size of a single compiled filter regex in bytes.
+    pub regex_size_limit: Option<NonZeroUsize>,
+}
+
+/// Structure holding settings for the metrics endpoint.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Metrics {
+    /// Address to serve metrics on over HTTP. The endpoint is disabled if not set.
+    pub listen: Option<SocketAddr>,
+}
+
+/// Structure holding settings for sending the runtime metrics to a `StatsD` server. Sending is
+/// disabled if no address is set.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Statsd {
+    /// Address of the server, like `localhost:8125`.
+    pub address: Option<String>,
+    /// Prefix of all metric names.
+    #[serde(default = "default_statsd_prefix")]
+    pub prefix: String,
+    /// Send rule and filter names as `DogStatsD` tags, instead of as part of the metric names.
+    #[serde(default)]
+    pub tags: bool,
+    /// Time between two sends of the metrics.
+    #[serde(default = "default_statsd_interval", with = "human_duration")]
+    pub interval: Duration,
+}
+
+impl Default for Statsd {
+    fn default() -> Self {
+        Self {
+            address: None,
+            prefix: default_statsd_prefix(),
+            tags: false,
+            interval: default_statsd_interval(),
+        }
+    }
+}
+
+fn default_statsd_prefix() -> String {
+    "veto".to_owned()
+}
+
+const fn default_statsd_interval() -> Duration {
+    Duration::seconds(10)
+}
+
+/// Structure holding settings for the storage of blocked IPs.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Storage {
+    /// Implementation that keeps the entries. Switching it requires migrating the existing
+    /// entries with `veto migrate-storage`.
+    #[serde(default)]
+    pub backend: Backend,
+}
+
+/// Structure holding settings for the audit log, that records every block and unblock, whether it
+/// was done by a rule or manually. The log is disabled if no file is set.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Audit {
+    /// File to append the entries to. It's never truncated or pruned by veto.
+    pub file: Option<PathBuf>,
+    /// Write each entry as JSON object instead of human readable text.
+    #[serde(default)]
+    pub json: bool,
+}
+
+/// Structure holding settings for exporting blocks and unblocks as JSON lines in the Elastic Common
+/// Schema, for shipping them with Filebeat. The export is disabled if no file is set.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Ecs {
+    /// File to append the documents to. It's never truncated or pruned by veto.
+    pub file: Option<PathBuf>,
+}
+
+/// Structure holding settings for publishing blocks and unblocks to an MQTT broker. Publishing is
+/// disabled if no broker is set.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Mqtt {
+    /// Address of the broker, like `localhost:1883`. The port defaults to 1883.
+    pub broker: Option<String>,
+    /// Prefix of the topics, which are followed by the rule name and `ban` or `unban`.
+    #[serde(default = "default_mqtt_topic")]
+    pub topic: String,
+    /// Identifier of the client towards the broker.
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Default for Mqtt {
+    fn default() -> Self {
+        Self {
+            broker: None,
+            topic: default_mqtt_topic(),
+            client_id: default_mqtt_client_id(),
+            username: None,
+            password: None,
+        }
+    }
+}
+
+fn default_mqtt_topic() -> String {
+    "veto".to_owned()
+}
+
+fn default_mqtt_client_id() -> String {
+    "veto".to_owned()
+}
+
+/// Structure holding settings for the breaker, that pauses blocking if veto looks like it would
+/// block the wrong IPs, until it's resumed with `veto resume`. It's disabled by default.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Breaker {
+    /// Check for the conditions that pause blocking.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Maximum share of lines in percent, over all rules, that may match within a minute.
+    #[serde(default = "default_max_match_percent")]
+    pub max_match_percent: u8,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_match_percent: default_max_match_percent(),
+        }
+    }
+}
+
+const fn default_max_match_percent() -> u8 {
+    50
+}
+
+/// Structure holding settings for looking up details about blocked IPs, like their host name or
+/// network. All lookups are disabled by default, as they contact external servers.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Enrichment {
+    /// Resolve the host name of IPs through reverse DNS.
+    #[serde(default)]
+    pub rdns: bool,
+    /// Look up the country, network and abuse contact of IPs through whois.
+    #[serde(default)]
+    pub whois: bool,
+    /// How long the details about an IP are kept, before looking them up again.
+    #[serde(default = "default_cache_ttl", with = "human_duration")]
+    pub cache_ttl: Duration,
+    /// Maximum time to wait for a single lookup.
+    #[serde(default = "default_lookup_timeout", with = "human_duration")]
+    pub timeout: Duration,
+}
+
+impl Default for Enrichment {
+    fn default() -> Self {
+        Self {
+            rdns: false,
+            whois: false,
+            cache_ttl: default_cache_ttl(),
+            timeout: default_lookup_timeout(),
+        }
+    }
+}
+
+const fn default_cache_ttl() -> Duration {
+    Duration::DAY
+}
+
+const fn default_lookup_timeout() -> Duration {
+    Duration::seconds(5)
+}
+
+/// Structure holding settings for the local `GeoIP` database, that the country of blocked IPs is
+/// looked up in. Lookups are disabled if no file is set.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GeoIp {
+    /// Location of the database in the `MaxMind` DB format.
+    pub file: Option<PathBuf>,
+    /// Provider to download the database from, and keep it up to date. The file is only loaded
+    /// if not set, like when it's managed by another tool.
+    pub provider: Option<GeoIpProvider>,
+    /// License key for downloads from `MaxMind`.
+    pub license_key: Option<String>,
+    /// Edition of the database, which defaults to the free country database of the provider.
+    pub edition: Option<String>,
+    /// Age of the database after which a new one is downloaded.
+    #[serde(default = "default_geoip_refresh", with = "human_duration")]
+    pub refresh: Duration,
+    /// Two-letter codes of the only countries that may connect to the allowed ports.
+    #[serde(default)]
+    pub allowed_countries: Vec<String>,
+    /// Ports that only the allowed countries can connect to. Nothing is enforced if not set.
+    #[serde(default)]
+    pub allowed_ports: Vec<u16>,
+}
+
+impl Default for GeoIp {
+    fn default() -> Self {
+        Self {
+            file: None,
+            provider: None,
+            license_key: None,
+            edition: None,
+            refresh: default_geoip_refresh(),
+            allowed_countries: Vec::new(),
+            allowed_ports: Vec::new(),
+        }
+    }
+}
+
+const fn default_geoip_refresh() -> Duration {
+    Duration::WEEK
+}
+
+/// Source of `GeoIP` databases.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GeoIpProvider {
+    /// The `GeoLite2` or `GeoIP2` databases of `MaxMind`, which require a license key.
+    MaxMind,
+    /// The free lite databases of DB-IP.
+    DbIp,
+}
+
+impl GeoIpProvider {
+    /// Edition of the free country database.
+    #[must_use]
+    pub const fn default_edition(self) -> &'static str {
+        match self {
+            Self::MaxMind => "GeoLite2-Country",
+            Self::DbIp => "country-lite",
+        }
+    }
+}
+
+/// Structure holding settings that apply to any firewall.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Firewall {
+    /// Maximum amount of firewall commands to run per second. Any further commands are queued
+    /// until the limit allows them to run. No limit is applied if not set.
+    pub rate_limit: Option<NonZeroU32>,
+    /// Maximum random duration added to the timeout of each block, to spread out the unblocking
+    /// of many IPs that were blocked at the same time.
+    #[serde(default, with = "human_duration")]
+    pub unblock_jitter: Duration,
+    /// Maximum amount of IPs to unblock at once. Any remaining IPs are unblocked in the next run.
+    /// No limit is applied if not set.
+    pub max_unblocks: Option<NonZeroUsize>,
+    /// Maximum amount of IPs to block per minute over all rules. Any further blocks are queued
+    /// until the limit allows them, which protects against a broken filter suddenly blocking lots
+    /// of legitimate clients. No limit is applied if not set.
+    pub max_bans_per_minute: Option<NonZeroUsize>,
+    /// Only detect offending IPs, without blocking them on the firewall. Blocks are still recorded
+    /// and published as events.
+    #[serde(default)]
+    pub detect_only: bool,
+    /// What to do if the firewall can't be set up, like when its tools are missing or veto lacks
+    /// the permissions to use them.
+    #[serde(default)]
+    pub fallback: Fallback,
+    /// Firewalls to run at the same time, like ipset together with AWS WAF. Each one needs its
+    /// own settings section, except for ipset. If empty, a single firewall is selected by the
+    /// sections that are set.
+    #[serde(default)]
+    pub backends: Vec<FirewallBackend>,
+    /// Keep all blocks on the firewall when shutting down, instead of removing them. The next start
+    /// removes any blocks that expired in the meantime.
+    #[serde(default)]
+    pub keep_on_shutdown: bool,
+}
+
+/// Firewall implementation, that is configured by the settings section of the same name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FirewallBackend {
+    Ipset,
+    Plugin,
+    Exec,
+    Blackhole,
+    Pf,
+    AwsWaf,
+    Iptables,
+    Haproxy,
+    Nginx,
+    Tc,
+    Helper,
+}
+
+impl FirewallBackend {
+    /// Name of the settings section.
+    #[must_use]
+    pub const fn section(self) -> &'static str {
+        match self {
+            Self::Ipset => "ipset",
+            Self::Plugin => "plugin",
+            Self::Exec => "exec",
+            Self::Blackhole => "blackhole",
+            Self::Pf => "pf",
+            Self::AwsWaf => "aws_waf",
+            Self::Iptables => "iptables",
+            Self::Haproxy => "haproxy",
+            Self::Nginx => "nginx",
+            Self::Tc => "tc",
+            Self::Helper => "helper",
+        }
+    }
+
+    /// Whether the settings section is present. The ipset settings all have defaults, so they're
+    /// always present.
+    #[must_use]
+    pub const fn is_configured(self, settings: &Settings) -> bool {
+        match self {
+            Self::Ipset => true,
+            Self::Plugin => settings.plugin.is_some(),
+            Self::Exec => settings.exec.is_some(),
+            Self::Blackhole => settings.blackhole.is_some(),
+            Self::Pf => settings.pf.is_some(),
+            Self::AwsWaf => settings.aws_waf.is_some(),
+            Self::Iptables => settings.iptables.is_some(),
+            Self::Haproxy => settings.haproxy.is_some(),
+            Self::Nginx => settings.nginx.is_some(),
+            Self::Tc => settings.tc.is_some(),
+            Self::Helper => settings.helper.is_some(),
+        }
+    }
+}
+
+/// Behavior for a firewall that can't be set up at startup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fallback {
+    /// Refuse to start.
+    #[default]
+    Abort,
+    /// Keep running in detection-only mode, where blocks are still recorded and published as
+    /// events, but not enforced.
+    DetectOnly,
+}
+
 /// Structure holding settings specific to the ipset firewall.
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct IpSet {
     /// Target to send matched IPs to in **iptables**.
     pub target: IptablesTarget,
+    /// Add IPs with the timeout of their block, so the kernel unblocks them by itself, even if
+    /// veto stops unexpectedly.
+    #[serde(default)]
+    pub timeouts: bool,
+    /// Chains of the `filter` table that the rules are inserted into. The `Redirect` and `RawDrop`
+    /// targets always use the `PREROUTING` chain of the `nat` or `raw` table instead.
+    #[serde(default = "default_ipset_chains")]
+    pub chains: Vec<String>,
+    /// Initial hash size of new sets, which the kernel grows as needed. Uses the default of ipset
+    /// if not set.
+    pub hashsize: Option<NonZeroU32>,
+    /// Maximum amount of entries per set, which can't be exceeded. Uses the default of ipset, 65536
+    /// entries, if not set.
+    pub maxelem: Option<NonZeroU32>,
+}
+
+impl Default for IpSet {
+    fn default() -> Self {
+        Self {
+            target: IptablesTarget::default(),
+            timeouts: false,
+            chains: default_ipset_chains(),
+            hashsize: None,
+            maxelem: None,
+        }
+    }
+}
+
+fn default_ipset_chains() -> Vec<String> {
+    vec!["INPUT".to_owned(), "FORWARD".to_owned()]
+}
+
+/// Structure holding settings for an external firewall plugin, that is controlled with a
+/// line-based JSON protocol over its standard input and output.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Plugin {
+    /// Program to run.
+    pub command: PathBuf,
+    /// Arguments passed to the program.
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// Structure holding the shell commands of the exec firewall. The placeholders `{ip}` and
+/// `{ports}` are replaced with the IP and comma separated ports of each target.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Exec {
+    /// Command to run once at startup.
+    pub install: Option<String>,
+    /// Command to run once at shutdown, which should unblock all IPs.
+    pub uninstall: Option<String>,
+    /// Command to block a single target.
+    pub block: String,
+    /// Command to unblock a single target.
+    pub unblock: String,
+}
+
+/// Structure holding settings specific to the blackhole firewall, which adds a route for each
+/// blocked IP that drops all traffic to it.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct Blackhole {
+    /// Routing table to add the routes to. The main table is used if not set.
+    pub table: Option<u32>,
+}
+
+/// Structure holding settings specific to the iptables firewall, which inserts a rule for each
+/// blocked IP into its own chain.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct IpTables {
+    /// How rejected packets are answered, which is what blocked clients get to see.
+    #[serde(default)]
+    pub reject_with: RejectWith,
+}
+
+/// Answer to packets that are rejected by iptables.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RejectWith {
+    /// Reset TCP connections, as if the port was closed. Packets of other protocols are answered
+    /// with an ICMP port unreachable error instead.
+    #[default]
+    TcpReset,
+    /// ICMP port unreachable error, as if no service listened on the port.
+    IcmpPortUnreachable,
+    /// ICMP administratively prohibited error, which tells that a firewall rejected the packet.
+    IcmpAdminProhibited,
+}
+
+impl RejectWith {
+    /// Name of the type for the `--reject-with` option, which differs between IPv4 and IPv6 for
+    /// the ICMP errors.
+    #[must_use]
+    pub const fn name(self, v6: bool) -> &'static str {
+        match (self, v6) {
+            (Self::TcpReset, _) => "tcp-reset",
+            (Self::IcmpPortUnreachable, false) => "icmp-port-unreachable",
+            (Self::IcmpPortUnreachable, true) => "icmp6-port-unreachable",
+            (Self::IcmpAdminProhibited, false) => "icmp-admin-prohibited",
+            (Self::IcmpAdminProhibited, true) => "icmp6-adm-prohibited",
+        }
+    }
+}
+
+/// Structure holding settings specific to the `HAProxy` firewall, which adds blocked IPs to an ACL
+/// through the runtime API.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HaProxy {
+    /// Unix socket of the runtime API, which `HAProxy` opens with the `stats socket` setting. It
+    /// needs the `admin` level to change ACLs.
+    #[serde(default = "default_haproxy_socket")]
+    pub socket: PathBuf,
+    /// ACL to add the blocked IPs to, referenced by the file that `HAProxy` loaded it from.
+    pub acl: String,
+}
+
+fn default_haproxy_socket() -> PathBuf {
+    PathBuf::from("/run/haproxy/admin.sock")
+}
+
+/// Structure holding settings specific to the nginx firewall, which writes a `deny` directive for
+/// each blocked IP into a file.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Nginx {
+    /// File with the directives, which the nginx configuration includes.
+    pub file: PathBuf,
+    /// Shell command that reloads nginx after the file changed.
+    #[serde(default = "default_nginx_reload")]
+    pub reload: String,
+}
+
+fn default_nginx_reload() -> String {
+    "nginx -s reload".to_owned()
+}
+
+/// Structure holding settings specific to the tc firewall, which throttles blocked IPs with a slow
+/// traffic control class instead of blocking them.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Tc {
+    /// Network interface that the traffic to blocked IPs goes out of.
+    pub interface: String,
+    /// Bandwidth that all blocked IPs share, in the format of tc like `64kbit`.
+    #[serde(default = "default_tc_rate")]
+    pub rate: String,
+}
+
+fn default_tc_rate() -> String {
+    "64kbit".to_owned()
+}
+
+/// Structure holding settings for the privileged helper, that changes the firewall on behalf of an
+/// unprivileged veto.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Helper {
+    /// Unix socket that the helper listens on.
+    #[serde(default = "default_helper_socket")]
+    pub socket: PathBuf,
+    /// User that veto runs as, either by name or ID. The helper only answers this user and root.
+    #[serde(default = "default_helper_user")]
+    pub user: String,
+    /// Let veto install and uninstall the firewall through the helper. Otherwise the helper
+    /// installs it when it starts, and refuses these requests.
+    #[serde(default)]
+    pub allow_install: bool,
+}
+
+fn default_helper_socket() -> PathBuf {
+    PathBuf::from("/run/veto/helper.sock")
+}
+
+fn default_helper_user() -> String {
+    "veto".to_owned()
+}
+
+/// Structure holding settings specific to the pf firewall of FreeBSD and OpenBSD.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Pf {
+    /// Ports that blocked IPs can't connect to anymore. All ports are blocked if empty.
+    #[serde(default = "default_pf_ports")]
+    pub ports: Vec<u16>,
+}
+
+impl Default for Pf {
+    fn default() -> Self {
+        Self {
+            ports: default_pf_ports(),
+        }
+    }
+}
+
+fn default_pf_ports() -> Vec<u16> {
+    vec![80, 443]
+}
+
+/// Structure holding settings for blocking IPs at the edge, through IP sets of AWS WAF that are
+/// changed with the AWS CLI.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AwsWaf {
+    /// Whether the IP sets belong to regional resources or to `CloudFront` distributions.
+    #[serde(default)]
+    pub scope: WafScope,
+    /// Region of the IP sets, which must be `us-east-1` for `CloudFront`. The default region of
+    /// the AWS CLI is used if not set.
+    pub region: Option<String>,
+    /// IP set for blocked IPv4 addresses.
+    pub ipv4: WafIpSet,
+    /// IP set for blocked IPv6 addresses. IPv6 addresses aren't blocked if not set.
+    pub ipv6: Option<WafIpSet>,
+}
+
+/// Scope of AWS WAF resources.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum WafScope {
+    /// Resources like load balancers and API gateways.
+    #[default]
+    Regional,
+    /// `CloudFront` distributions.
+    Cloudfront,
+}
+
+impl Display for WafScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Regional => "REGIONAL",
+            Self::Cloudfront => "CLOUDFRONT",
+        })
+    }
+}
+
+/// Reference to an existing IP set of AWS WAF.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WafIpSet {
+    pub name: String,
+    pub id: String,
 }
 
 /// Different targets that a matched IP can be send to in iptables.
-#[derive(Copy, Clone, Debug, Deserialize)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub enum IptablesTarget {
     /// Drop the packets, making the server look as it would not exist.
     Drop,
@@ -48,19 +732,30 @@ pub enum IptablesTarget {
     /// **Note**: For this target to work, the iptables addons need to be installed on the system
     /// (`xtables-addons-dkms` package on Debian).
     Tarpit,
+    /// Redirect new connections to a local port, usually the one of veto's own tarpit, which
+    /// works without any kernel addons.
+    Redirect(u16),
+    /// Drop new connections of blocked IPs above the given amount per minute, instead of all of
+    /// them. This throttles clients without fully locking out legitimate users that share an IP,
+    /// like behind a NAT.
+    RateLimit(NonZeroU32),
+    /// Drop the packets in the `raw` table, before connection tracking sees them. This saves
+    /// resources on hosts that get scanned a lot, as blocked packets never create any connection
+    /// state.
+    RawDrop,
 }
 
 impl IptablesTarget {
     #[must_use]
-    pub const fn to_args(self) -> &'static [&'static str] {
-        match self {
-            Self::Drop => &["DROP"],
-            Self::Reject => &["REJECT"],
-            Self::Tarpit => &["TARPIT", "--tarpit"],
-        }
+    pub fn to_args(self) -> Vec<String> {
+        self.to_string()
+            .split_whitespace()
+            .map(ToOwned::to_owned)
+            .collect()
     }
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for IptablesTarget {
     fn default() -> Self {
         Self::Drop
@@ -68,28 +763,288 @@ impl Default for IptablesTarget {
 }
 
 impl Display for IptablesTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // The limit and the table are set apart from the jump.
+            Self::Drop | Self::RateLimit(_) | Self::RawDrop => f.write_str("DROP"),
+            Self::Reject => f.write_str("REJECT"),
+            Self::Tarpit => f.write_str("TARPIT --tarpit"),
+            Self::Redirect(port) => write!(f, "REDIRECT --to-ports {port}"),
+        }
+    }
+}
+
+/// Protocol that blocks apply to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// TCP connections, like the ones of web servers.
+    #[default]
+    Tcp,
+    /// UDP packets, like the ones of DNS, SIP or `WireGuard`.
+    Udp,
+    /// All protocols. Ports don't apply to them, so all ports are blocked.
+    All,
+}
+
+impl Display for Protocol {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str(match self {
-            Self::Drop => "DROP",
-            Self::Reject => "REJECT",
-            Self::Tarpit => "TARPIT --tarpit",
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+            Self::All => "all",
         })
     }
 }
 
+/// Structure holding settings for the tarpit, a service that keeps connections open for as long
+/// as possible, by sending an endless response very slowly. It's disabled if no port is set.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Tarpit {
+    /// Port to listen on, which the `Redirect` target of the ipset firewall should point to.
+    pub port: Option<u16>,
+    /// Time between two lines of the response.
+    #[serde(default = "default_tarpit_delay", with = "human_duration")]
+    pub delay: Duration,
+    /// Maximum amount of connections that are kept open at once. Any further connections are
+    /// closed right away.
+    #[serde(default = "default_tarpit_max_clients")]
+    pub max_clients: usize,
+}
+
+impl Default for Tarpit {
+    fn default() -> Self {
+        Self {
+            port: None,
+            delay: default_tarpit_delay(),
+            max_clients: default_tarpit_max_clients(),
+        }
+    }
+}
+
+const fn default_tarpit_delay() -> Duration {
+    Duration::seconds(10)
+}
+
+const fn default_tarpit_max_clients() -> usize {
+    4096
+}
+
+/// Structure holding settings for the reputation of IPs, a score built from several signals that
+/// rules can use to block bad IPs for longer. It's disabled if no file is set.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Reputation {
+    /// Location where the scores are kept across restarts.
+    pub file: Option<PathBuf>,
+    /// Time that a score stays cached after the last offense or check of the IP.
+    #[serde(default = "default_reputation_ttl", with = "human_duration")]
+    pub ttl: Duration,
+    /// Score from which on an IP has a bad reputation.
+    #[serde(default = "default_reputation_threshold")]
+    pub threshold: f64,
+    /// Score added for each offense that any rule found.
+    #[serde(default = "default_reputation_offense")]
+    pub offense: f64,
+    /// Files with one IP or network per line, like downloaded blocklists.
+    #[serde(default)]
+    pub blocklists: Vec<PathBuf>,
+    /// Score added if the IP is on any of the blocklists.
+    #[serde(default = "default_reputation_listed")]
+    pub blocklist: f64,
+    /// Zones of DNS-based blocklists, like `zen.spamhaus.org`.
+    #[serde(default)]
+    pub dnsbl: Vec<String>,
+    /// Score added for each DNS-based blocklist that lists the IP.
+    #[serde(default = "default_reputation_listed")]
+    pub dnsbl_listed: f64,
+    /// Two-letter codes of countries that IPs get a worse reputation for. Requires the `geoip`
+    /// settings.
+    #[serde(default)]
+    pub countries: Vec<String>,
+    /// Score added if the IP is located in any of the countries.
+    #[serde(default = "default_reputation_offense")]
+    pub country: f64,
+}
+
+impl Default for Reputation {
+    fn default() -> Self {
+        Self {
+            file: None,
+            ttl: default_reputation_ttl(),
+            threshold: default_reputation_threshold(),
+            offense: default_reputation_offense(),
+            blocklists: Vec::new(),
+            blocklist: default_reputation_listed(),
+            dnsbl: Vec::new(),
+            dnsbl_listed: default_reputation_listed(),
+            countries: Vec::new(),
+            country: default_reputation_offense(),
+        }
+    }
+}
+
+const fn default_reputation_ttl() -> Duration {
+    Duration::WEEK
+}
+
+const fn default_reputation_threshold() -> f64 {
+    3.0
+}
+
+const fn default_reputation_offense() -> f64 {
+    1.0
+}
+
+const fn default_reputation_listed() -> f64 {
+    2.0
+}
+
+/// Structure holding settings for the list of Tor exit nodes, that is downloaded regularly, so
+/// exits can be treated differently from other IPs. Requires the `tor` feature.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Tor {
+    /// Whether to download the list of exit nodes.
+    #[serde(default)]
+    pub enabled: bool,
+    /// How exit nodes are treated.
+    #[serde(default)]
+    pub policy: TorPolicy,
+    /// Location of the list, with one IP per line.
+    #[serde(default = "default_tor_url")]
+    pub url: String,
+    /// Time between two downloads of the list.
+    #[serde(default = "default_tor_refresh", with = "human_duration")]
+    pub refresh: Duration,
+    /// Timeout duration on the blocklist, for exits that are blocked by the `block` policy.
+    #[serde(default = "default_tor_timeout", with = "human_duration")]
+    pub timeout: Duration,
+}
+
+impl Default for Tor {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            policy: TorPolicy::default(),
+            url: default_tor_url(),
+            refresh: default_tor_refresh(),
+            timeout: default_tor_timeout(),
+        }
+    }
+}
+
+fn default_tor_url() -> String {
+    "https://check.torproject.org/torbulkexitlist".to_owned()
+}
+
+const fn default_tor_refresh() -> Duration {
+    Duration::HOUR
+}
+
+const fn default_tor_timeout() -> Duration {
+    Duration::DAY
+}
+
+/// Treatment of Tor exit nodes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TorPolicy {
+    /// Exits are blocked like any other IP, but with the `tor_timeout` of a rule if it has one.
+    #[default]
+    Rules,
+    /// All exits are blocked right away, without waiting for any offense.
+    Block,
+    /// Exits are never blocked.
+    Exempt,
+}
+
+/// Structure holding settings for the honeypot, that listens on decoy ports and blocks every IP
+/// that connects to them. It's disabled if no ports are set.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Honeypot {
+    /// Ports to listen on, which must not be used by any real service.
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    /// Address to listen on. Defaults to all IPv4 and IPv6 addresses.
+    pub address: Option<IpAddr>,
+    /// Timeout duration on the blocklist.
+    #[serde(default = "default_honeypot_timeout", with = "human_duration")]
+    pub timeout: Duration,
+}
+
+impl Default for Honeypot {
+    fn default() -> Self {
+        Self {
+            ports: Vec::new(),
+            address: None,
+            timeout: default_honeypot_timeout(),
+        }
+    }
+}
+
+const fn default_honeypot_timeout() -> Duration {
+    Duration::DAY
+}
+
 /// A rule describes the file to track with filters and blacklists to detect malicious accesses.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Rule {
     /// The file to track for changes and scan for access logs.
     pub file: PathBuf,
-    /// List of regex filters to extract information.
-    pub filters: Vec<String>,
+    /// WebAssembly modules that check each line in addition to the filters, for detection logic
+    /// that regexes can't express. Requires the `wasm` feature.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub plugins: Vec<PathBuf>,
     /// Ports to block in case a malicious access was found.
     #[serde(default)]
     pub ports: Vec<u16>,
+    /// Protocol to block the ports for.
+    #[serde(default)]
+    pub protocol: Protocol,
     /// Timeout duration on the blocklist.
-    #[serde(deserialize_with = "human_duration")]
+    #[serde(with = "human_duration")]
     pub timeout: Duration,
+    /// Where to take the client IP of a matching line from.
+    #[serde(default)]
+    pub host: HostSource,
+    /// Format of the timestamp in the `time` group of the filters, either the name of a registered
+    /// parser or a custom format description. Defaults to the nginx format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_format: Option<String>,
+    /// Time without any new lines in the file, after which the rule is reported as stalled. Not
+    /// checked if not set.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "human_duration::option"
+    )]
+    pub stall_timeout: Option<Duration>,
+    /// Maximum amount of IPs that this rule blocks per minute. Any further blocks are queued until
+    /// the limit allows them. No limit is applied if not set.
+    pub max_bans_per_minute: Option<NonZeroUsize>,
+    /// Timeout duration on the blocklist for IPs with a bad reputation, in place of the regular
+    /// timeout. Requires the `reputation` settings.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "human_duration::option"
+    )]
+    pub reputation_timeout: Option<Duration>,
+    /// Timeout duration on the blocklist for Tor exit nodes, in place of the regular timeout.
+    /// Requires the `tor` settings.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "human_duration::option"
+    )]
+    pub tor_timeout: Option<Duration>,
+    /// Name of the capture group with the user agent. Matches that claim to come from a search
+    /// engine crawler are only blocked, if the IP fails the crawler's DNS verification.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crawler_group: Option<String>,
+    // The remaining fields can turn into tables, which must come last in TOML.
+    /// List of regex filters to extract information.
+    #[serde(default, serialize_with = "serialize_filters")]
+    pub filters: Vec<Filter>,
     /// Blacklisted words that trigger a block.
     ///
     /// The key is the name of a regex catch group within the `filters` property thus the blacklist
@@ -100,44 +1055,400 @@ pub struct Rule {
     pub blacklists: IndexMap<String, IndexSet<String>>,
 }
 
+/// Source of the client IP for a matching log line.
+///
+/// It's written as a single string, like `leading` or `json:client.ip`, where the part after
+/// the colon configures the source.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum HostSource {
+    /// Extract the IP with the `<HOST>` placeholder of the filter regex.
+    #[default]
+    Capture,
+    /// Take the IP from the start of the line, up to the first space. The `<HOST>` placeholder
+    /// only skips over the IP in this case, which is a lot cheaper than capturing it.
+    Leading,
+    /// Extract the IP with a named capture group of the filter regex, written as `group:<name>`.
+    Group(String),
+    /// Take the IP from a whitespace separated column of the line, counting from 1, written as
+    /// `column:<number>`.
+    Column(NonZeroUsize),
+    /// Take the IP from a field of lines that are JSON objects, written as `json:<path>`, where
+    /// nested fields are separated by dots.
+    Json(String),
+    /// Take the IP from an extension key of lines in the Common Event Format, written as
+    /// `cef:<key>`.
+    Cef(String),
+    /// Use an extractor that was registered by a library user under this name.
+    Custom(String),
+}
+
+impl Display for HostSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Capture => f.write_str("capture"),
+            Self::Leading => f.write_str("leading"),
+            Self::Group(name) => write!(f, "group:{name}"),
+            Self::Column(column) => write!(f, "column:{column}"),
+            Self::Json(path) => write!(f, "json:{path}"),
+            Self::Cef(key) => write!(f, "cef:{key}"),
+            Self::Custom(name) => f.write_str(name),
+        }
+    }
+}
+
+impl TryFrom<String> for HostSource {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let Some((kind, arg)) = value.split_once(':') else {
+            return Ok(match value.as_str() {
+                "capture" => Self::Capture,
+                "leading" => Self::Leading,
+                _ => Self::Custom(value),
+            });
+        };
+
+        if arg.is_empty() {
+            return Err(format!("missing argument for host source '{kind}'"));
+        }
+
+        Ok(match kind {
+            "group" => Self::Group(arg.to_owned()),
+            "column" => Self::Column(
+                arg.parse()
+                    .map_err(|e| format!("invalid host column '{arg}': {e}"))?,
+            ),
+            "json" => Self::Json(arg.to_owned()),
+            "cef" => Self::Cef(arg.to_owned()),
+            _ => return Err(format!("unknown host source '{kind}'")),
+        })
+    }
+}
+
+impl From<HostSource> for String {
+    fn from(value: HostSource) -> Self {
+        value.to_string()
+    }
+}
+
+/// A single regex filter of a rule, with an optional literal to quickly skip non-matching lines.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(from = "FilterRepr", into = "FilterRepr")]
+pub struct Filter {
+    /// Regex pattern that extracts information from a log line.
+    pub pattern: String,
+    /// Literal string that must be contained in a line for the pattern to possibly match. If not
+    /// set, required literals are extracted from the pattern automatically.
+    pub prefilter: Option<String>,
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+/// Filters can be either a plain pattern string or a table with further settings.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum FilterRepr {
+    Plain(String),
+    Full {
+        pattern: String,
+        prefilter: Option<String>,
+    },
+}
+
+/// Serialize all filters in the same form, as TOML doesn't allow arrays that mix plain values and
+/// tables. Only if any filter has further settings, all are serialized as tables.
+fn serialize_filters<S>(filters: &[Filter], serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    if filters.iter().all(|f| f.prefilter.is_none()) {
+        serializer.collect_seq(filters.iter().map(|f| &f.pattern))
+    } else {
+        serializer.collect_seq(filters.iter().map(|f| FilterRepr::Full {
+            pattern: f.pattern.clone(),
+            prefilter: f.prefilter.clone(),
+        }))
+    }
+}
+
+impl From<Filter> for FilterRepr {
+    fn from(value: Filter) -> Self {
+        match value.prefilter {
+            None => Self::Plain(value.pattern),
+            prefilter => Self::Full {
+                pattern: value.pattern,
+                prefilter,
+            },
+        }
+    }
+}
+
+impl From<FilterRepr> for Filter {
+    fn from(value: FilterRepr) -> Self {
+        match value {
+            FilterRepr::Plain(pattern) => Self {
+                pattern,
+                prefilter: None,
+            },
+            FilterRepr::Full { pattern, prefilter } => Self { pattern, prefilter },
+        }
+    }
+}
+
 /// Load the application settings from the given path or the OS-specific default location otherwise.
 pub fn load(path: Option<PathBuf>) -> Result<Settings> {
-    let path = path.unwrap_or_else(|| PathBuf::from("/etc/veto/config.toml"));
+    let path = path.unwrap_or_else(|| PathBuf::from(DEFAULT_PATH));
 
     info!("Attempting to load settings from {:?}", path);
 
-    let content = fs::read(path).context("Failed reading settings file")?;
+    let content = fs::read(&path).map_err(|source| Error::ReadSettings { path, source })?;
 
     basic_toml::from_slice(&content).map_err(Into::into)
 }
 
-/// Parse a human representation like `2h 15m` into a [`Duration`].
+/// Serialize the settings into the TOML format, that [`load`] reads.
+pub fn to_string(settings: &Settings) -> Result<String> {
+    basic_toml::to_string(settings).map_err(Into::into)
+}
+
+/// Serialize a single rule into the TOML format, as tables that can be appended to an existing
+/// configuration.
+pub fn rule_to_string(name: &str, rule: &Rule) -> Result<String> {
+    #[derive(Serialize)]
+    struct Rules<'a> {
+        rules: HashMap<&'a str, &'a Rule>,
+    }
+
+    basic_toml::to_string(&Rules {
+        rules: HashMap::from_iter([(name, rule)]),
+    })
+    .map_err(Into::into)
+}
+
+/// Conversion between a human representation like `2h 15m` and a [`Duration`].
 ///
-/// It can be used with serde by specifying `#[serde(deserialize_with = "human_duration")]` on a
-/// property within a struct.
-fn human_duration<'de, D>(deserializer: D) -> std::result::Result<Duration, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    struct DurationVisitor;
+/// It can be used with serde by specifying `#[serde(with = "human_duration")]` on a property
+/// within a struct.
+mod human_duration {
+    use std::fmt;
+
+    use serde::{
+        de::{self, Visitor},
+        ser, Deserializer, Serializer,
+    };
+    use time::Duration;
+
+    pub fn serialize<S>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let duration = std::time::Duration::try_from(*value)
+            .map_err(|_| ser::Error::custom("negative durations are not supported"))?;
+
+        serializer.collect_str(&humantime::format_duration(duration))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct DurationVisitor;
 
-    impl<'de> Visitor<'de> for DurationVisitor {
-        type Value = Duration;
+        #[allow(clippy::elidable_lifetime_names)]
+        impl<'de> Visitor<'de> for DurationVisitor {
+            type Value = Duration;
 
-        fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
-            formatter.write_str("a duration")
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                formatter.write_str("a duration")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                humantime::parse_duration(v)
+                    .ok()
+                    .and_then(|d| Duration::try_from(d).ok())
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Str(v), &self))
+            }
         }
 
-        fn visit_str<E>(self, v: &str) -> std::result::Result<Self::Value, E>
+        deserializer.deserialize_str(DurationVisitor)
+    }
+
+    /// The same conversion for optional durations, which are left out if not set.
+    pub mod option {
+        use serde::{Deserializer, Serializer};
+        use time::Duration;
+
+        #[allow(clippy::ref_option)]
+        pub fn serialize<S>(value: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
         where
-            E: de::Error,
+            S: Serializer,
         {
-            humantime::parse_duration(v)
-                .ok()
-                .and_then(|d| Duration::try_from(d).ok())
-                .ok_or_else(|| E::invalid_value(de::Unexpected::Str(v), &self))
+            match value {
+                Some(value) => super::serialize(value, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            super::deserialize(deserializer).map(Some)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_sample() {
+        let settings = basic_toml::from_str::<Settings>(include_str!("../sample.toml")).unwrap();
+        let serialized = to_string(&settings).unwrap();
+        let reparsed = basic_toml::from_str::<Settings>(&serialized).unwrap();
+
+        assert_eq!(serialized, to_string(&reparsed).unwrap());
+        assert_eq!(settings.rules["web"].timeout, reparsed.rules["web"].timeout);
+    }
+
+    #[test]
+    fn roundtrip_filters() {
+        let rule = Rule {
+            file: PathBuf::from("/var/log/app.log"),
+            filters: vec![
+                Filter {
+                    pattern: "^<HOST> failed$".to_owned(),
+                    prefilter: None,
+                },
+                Filter {
+                    pattern: "^<HOST> denied$".to_owned(),
+                    prefilter: Some("denied".to_owned()),
+                },
+            ],
+            plugins: Vec::new(),
+            ports: vec![22],
+            protocol: Protocol::Tcp,
+            timeout: Duration::hours(2),
+            blacklists: IndexMap::default(),
+            host: HostSource::Leading,
+            time_format: None,
+            stall_timeout: None,
+            max_bans_per_minute: None,
+            reputation_timeout: None,
+            tor_timeout: None,
+            crawler_group: None,
+        };
+        let settings = Settings {
+            rules: HashMap::from_iter([("app".to_owned(), rule)]),
+            ..Settings::default()
+        };
+
+        let serialized = to_string(&settings).unwrap();
+        let reparsed = basic_toml::from_str::<Settings>(&serialized).unwrap();
+        let rule = &reparsed.rules["app"];
+
+        assert_eq!(settings.rules["app"].filters, rule.filters);
+        assert_eq!(Duration::hours(2), rule.timeout);
+        assert_eq!(HostSource::Leading, rule.host);
+    }
+
+    #[test]
+    fn append_rule() {
+        let sample = include_str!("../sample.toml");
+        let rule = Rule {
+            file: PathBuf::from("/var/log/auth.log"),
+            filters: vec![Filter {
+                pattern: "^<HOST> failed$".to_owned(),
+                prefilter: None,
+            }],
+            plugins: Vec::new(),
+            ports: vec![22],
+            protocol: Protocol::Tcp,
+            timeout: Duration::hours(1),
+            blacklists: IndexMap::from_iter([(
+                "message".to_owned(),
+                IndexSet::from_iter(["failed".to_owned()]),
+            )]),
+            host: HostSource::Capture,
+            time_format: Some("syslog".to_owned()),
+            stall_timeout: Some(Duration::hours(6)),
+            max_bans_per_minute: None,
+            reputation_timeout: None,
+            tor_timeout: None,
+            crawler_group: None,
+        };
+
+        let appended = format!("{sample}\n{}", rule_to_string("ssh", &rule).unwrap());
+        let settings = basic_toml::from_str::<Settings>(&appended).unwrap();
+
+        assert!(settings.rules.contains_key("web"));
+        assert_eq!(rule.blacklists, settings.rules["ssh"].blacklists);
+        assert_eq!(rule.time_format, settings.rules["ssh"].time_format);
+        assert_eq!(rule.stall_timeout, settings.rules["ssh"].stall_timeout);
+    }
+
+    #[test]
+    fn human_durations() {
+        #[derive(Debug, Deserialize, Serialize)]
+        struct Wrapper {
+            #[serde(with = "human_duration")]
+            value: Duration,
         }
+
+        let parse = |v: &str| {
+            basic_toml::from_str::<Wrapper>(&format!("value = \"{v}\""))
+                .map(|w| w.value)
+                .ok()
+        };
+
+        assert_eq!(Some(Duration::minutes(135)), parse("2h 15m"));
+        assert_eq!(Some(Duration::ZERO), parse("0s"));
+        // Too long for a signed duration, but still valid for the unsigned one it's parsed into.
+        assert_eq!(None, parse(&format!("{}s", u64::MAX)));
+        assert_eq!(None, parse("-1h"));
+
+        assert_eq!(
+            "value = \"1h 30m\"\n",
+            basic_toml::to_string(&Wrapper {
+                value: Duration::minutes(90)
+            })
+            .unwrap()
+        );
+        assert!(basic_toml::to_string(&Wrapper {
+            value: Duration::minutes(-1)
+        })
+        .is_err());
     }
 
-    deserializer.deserialize_str(DurationVisitor)
+    #[test]
+    fn host_sources() {
+        for (text, source) in [
+            ("capture", HostSource::Capture),
+            ("leading", HostSource::Leading),
+            ("group:client", HostSource::Group("client".to_owned())),
+            (
+                "column:3",
+                HostSource::Column(NonZeroUsize::new(3).unwrap()),
+            ),
+            ("json:client.ip", HostSource::Json("client.ip".to_owned())),
+            ("cef:src", HostSource::Cef("src".to_owned())),
+            ("mine", HostSource::Custom("mine".to_owned())),
+        ] {
+            assert_eq!(Ok(source.clone()), HostSource::try_from(text.to_owned()));
+            assert_eq!(text, source.to_string());
+        }
+
+        assert!(HostSource::try_from("column:0".to_owned()).is_err());
+        assert!(HostSource::try_from("json:".to_owned()).is_err());
+        assert!(HostSource::try_from("other:x".to_owned()).is_err());
+    }
 }
@@ -0,0 +1,212 @@
+//! Replaying complete log files through rules, to see which IPs would have been blocked without
+//! touching the firewall. This is meant for tuning rules against recorded logs.
+
+use std::{
+    net::IpAddr,
+    time::{Duration as StdDuration, Instant},
+};
+
+use ipnetwork::IpNetwork;
+use regex::CaptureLocations;
+use time::OffsetDateTime;
+
+use crate::{handler::Entry, matcher::Matcher, HashMap};
+
+/// A block that would have happened during the replay.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SimulatedBan {
+    /// Name of the rule that matched.
+    pub rule: String,
+    pub ip: IpAddr,
+    /// Timestamp of the line that caused the block.
+    pub at: OffsetDateTime,
+    /// Time until the IP would have been blocked.
+    pub until: OffsetDateTime,
+    /// Index of the filter that matched, or [`None`] if a plugin found the IP.
+    pub filter: Option<usize>,
+}
+
+/// Runs lines through rules like the daemon, but takes the current time from the lines themselves.
+///
+/// Lines are never considered too old, so logs of any age can be replayed. Blocks expire once a
+/// line after their timeout is seen, which allows the same IP to be blocked again. Timing
+/// statistics of each filter are collected in the [`Entry::metrics`] of the rules.
+pub struct Simulation {
+    /// Rules with the reusable capture locations of their filters, and the time of their last line.
+    rules: Vec<(Entry, Vec<CaptureLocations>, OffsetDateTime)>,
+    whitelist: Vec<IpNetwork>,
+    matcher: Matcher,
+    blocked: HashMap<IpAddr, OffsetDateTime>,
+    bans: Vec<SimulatedBan>,
+    lines: u64,
+    elapsed: StdDuration,
+}
+
+impl Simulation {
+    #[must_use]
+    pub fn new(rules: Vec<Entry>, whitelist: Vec<IpNetwork>) -> Self {
+        Self {
+            rules: rules
+                .into_iter()
+                .map(|entry| {
+                    let locations = entry.capture_locations();
+                    (entry, locations, OffsetDateTime::UNIX_EPOCH)
+                })
+                .collect(),
+            whitelist,
+            // A clock far in the past never considers lines outdated.
+            matcher: Matcher::with_clock(|| OffsetDateTime::UNIX_EPOCH),
+            blocked: HashMap::default(),
+            bans: Vec::new(),
+            lines: 0,
+            elapsed: StdDuration::ZERO,
+        }
+    }
+
+    /// Check a single line against all rules, and return the block it caused, if any.
+    pub fn push_line(&mut self, line: &str) -> Option<&SimulatedBan> {
+        let start = Instant::now();
+        self.lines += 1;
+
+        let mut found = None;
+
+        for (entry, locations, last_time) in &mut self.rules {
+            let Some(detection) = self.matcher.detect_with(entry, locations, last_time, line)
+            else {
+                continue;
+            };
+            if self.whitelist.iter().any(|wl| wl.contains(detection.host)) {
+                continue;
+            }
+
+            let at = *last_time;
+            if self
+                .blocked
+                .get(&detection.host)
+                .is_some_and(|&until| until > at)
+            {
+                continue;
+            }
+
+            let until = at.saturating_add(entry.rule.timeout);
+            self.blocked.insert(detection.host, until);
+            found = Some(SimulatedBan {
+                rule: entry.name.clone(),
+                ip: detection.host,
+                at,
+                until,
+                filter: detection.filter,
+            });
+            break;
+        }
+
+        self.elapsed += start.elapsed();
+
+        let ban = found?;
+        self.bans.push(ban);
+        self.bans.last()
+    }
+
+    /// Rules that the lines are checked against.
+    pub fn rules(&self) -> impl Iterator<Item = &Entry> {
+        self.rules.iter().map(|(entry, _, _)| entry)
+    }
+
+    /// All blocks so far, in the order they happened.
+    #[must_use]
+    pub fn bans(&self) -> &[SimulatedBan] {
+        &self.bans
+    }
+
+    /// Total amount of lines that were pushed.
+    #[must_use]
+    pub const fn lines(&self) -> u64 {
+        self.lines
+    }
+
+    /// Time spent on checking lines, excluding the time to read them.
+    #[must_use]
+    pub const fn elapsed(&self) -> StdDuration {
+        self.elapsed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{macros::datetime, Duration};
+
+    use super::*;
+    use crate::{
+        handler::{prepare_rule, RuleCache},
+        settings::{Filter, HostSource, Limits, Protocol, Rule},
+        IndexMap, IndexSet,
+    };
+
+    #[test]
+    fn replay() {
+        let entry = prepare_rule(
+            "web".to_owned(),
+            Rule {
+                file: "web".into(),
+                filters: vec![
+                    Filter {
+                        pattern: r#"^<HOST> - - \[<TIME>\] "POST"#.to_owned(),
+                        prefilter: None,
+                    },
+                    Filter {
+                        pattern: r#"^<HOST> - - \[<TIME>\] "GET (?P<path>\S+)"#.to_owned(),
+                        prefilter: None,
+                    },
+                ],
+                plugins: Vec::new(),
+                ports: Vec::new(),
+                protocol: Protocol::Tcp,
+                timeout: Duration::hours(1),
+                blacklists: IndexMap::from_iter([(
+                    "path".to_owned(),
+                    IndexSet::from_iter(["/wp-login.php".to_owned()]),
+                )]),
+                host: HostSource::Capture,
+                time_format: None,
+                stall_timeout: None,
+                max_bans_per_minute: None,
+                reputation_timeout: None,
+                tor_timeout: None,
+                crawler_group: None,
+            },
+            &Limits::default(),
+            &mut RuleCache::default(),
+        )
+        .unwrap();
+
+        let mut simulation = Simulation::new(vec![entry], vec!["10.0.0.2/32".parse().unwrap()]);
+
+        for line in [
+            r#"10.0.0.1 - - [01/Oct/2020:12:00:00 +0000] "GET /wp-login.php""#,
+            r#"10.0.0.1 - - [01/Oct/2020:12:30:00 +0000] "GET /wp-login.php""#,
+            r#"10.0.0.2 - - [01/Oct/2020:12:40:00 +0000] "GET /wp-login.php""#,
+            r#"10.0.0.1 - - [01/Oct/2020:12:50:00 +0000] "GET /""#,
+            r#"10.0.0.1 - - [01/Oct/2020:13:10:00 +0000] "GET /wp-login.php""#,
+        ] {
+            simulation.push_line(line);
+        }
+
+        let ip = "10.0.0.1".parse().unwrap();
+        let ban = |at: OffsetDateTime| SimulatedBan {
+            rule: "web".to_owned(),
+            ip,
+            at,
+            until: at + Duration::hours(1),
+            filter: Some(1),
+        };
+
+        assert_eq!(5, simulation.lines());
+        assert_eq!(
+            [
+                ban(datetime!(2020-10-01 12:00 UTC)),
+                ban(datetime!(2020-10-01 13:10 UTC))
+            ],
+            simulation.bans()
+        );
+    }
+}
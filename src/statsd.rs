@@ -0,0 +1,251 @@
+//! Push-based export of the metrics to a `StatsD` server, for monitoring stacks like Datadog or
+//! Telegraf that don't scrape the Prometheus endpoint.
+//!
+//! Counters are sent as the increase since the last flush, and filter latencies as the mean of
+//! the sampled lines within the flush interval. With tags enabled, rule and filter names are sent
+//! as `DogStatsD` tags instead of being part of the metric name.
+
+use std::{
+    fmt::Write,
+    net::UdpSocket,
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::Duration,
+};
+
+use log::{debug, info};
+
+use crate::{
+    metrics::{Registry, RuleMetrics},
+    settings, Result,
+};
+
+/// Maximum size of a single datagram, that fits into the common MTU of 1500 bytes.
+const MAX_PACKET: usize = 1432;
+
+/// Counters of a rule at the last flush.
+#[derive(Default)]
+struct Totals {
+    lines: u64,
+    matches: u64,
+    /// Amount and sum of the latency samples of each filter.
+    filters: Vec<(u64, Duration)>,
+}
+
+/// Formats the metrics of a registry in the `StatsD` line protocol.
+pub struct Emitter {
+    prefix: String,
+    tags: bool,
+    last: Vec<Totals>,
+}
+
+impl Emitter {
+    #[must_use]
+    pub fn new(settings: &settings::Statsd) -> Self {
+        Self {
+            prefix: settings.prefix.trim_end_matches('.').to_owned(),
+            tags: settings.tags,
+            last: Vec::new(),
+        }
+    }
+
+    /// Render all metrics as `StatsD` lines, remembering the counters for the next call.
+    pub fn render(&mut self, registry: &Registry) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        let gauges = [
+            (
+                "storage_entries",
+                registry.memory.storage_entries.load(Ordering::Relaxed),
+            ),
+            (
+                "firewall_queue",
+                registry.firewall_queue.load(Ordering::Relaxed),
+            ),
+            (
+                "deferred_bans",
+                registry.deferred_bans.load(Ordering::Relaxed),
+            ),
+            (
+                "paused",
+                usize::from(registry.paused.load(Ordering::Relaxed)),
+            ),
+        ];
+        for (name, value) in gauges {
+            lines.push(format!("{}.{name}:{value}|g", self.prefix));
+        }
+
+        let rules = registry.rules().collect::<Vec<_>>();
+        self.last.resize_with(rules.len(), Totals::default);
+
+        for ((name, metrics), last) in rules.into_iter().zip(&mut self.last) {
+            let current = totals(metrics);
+            let name = sanitize(name);
+
+            let mut metric = |metric: &str, value: &str, kind: &str, filter: Option<usize>| {
+                let mut line = self.prefix.clone();
+                if self.tags {
+                    _ = write!(line, ".{metric}:{value}|{kind}|#rule:{name}");
+                    if let Some(filter) = filter {
+                        _ = write!(line, ",filter:{filter}");
+                    }
+                } else {
+                    _ = write!(line, ".rules.{name}");
+                    if let Some(filter) = filter {
+                        _ = write!(line, ".filters.{filter}");
+                    }
+                    _ = write!(line, ".{metric}:{value}|{kind}");
+                }
+                lines.push(line);
+            };
+
+            metric(
+                "lines",
+                &current.lines.saturating_sub(last.lines).to_string(),
+                "c",
+                None,
+            );
+            metric(
+                "matches",
+                &current.matches.saturating_sub(last.matches).to_string(),
+                "c",
+                None,
+            );
+            metric(
+                "stalled",
+                &u8::from(metrics.stalled.load(Ordering::Relaxed)).to_string(),
+                "g",
+                None,
+            );
+
+            for (i, &(count, sum)) in current.filters.iter().enumerate() {
+                let (last_count, last_sum) = last.filters.get(i).copied().unwrap_or_default();
+                let samples = count.saturating_sub(last_count);
+                if samples == 0 {
+                    continue;
+                }
+
+                let mean =
+                    sum.saturating_sub(last_sum) / u32::try_from(samples).unwrap_or(u32::MAX);
+                #[allow(clippy::cast_precision_loss)]
+                let millis = mean.as_nanos() as f64 / 1e6;
+                metric("filter_duration", &millis.to_string(), "ms", Some(i));
+            }
+
+            *last = current;
+        }
+
+        lines
+    }
+}
+
+fn totals(metrics: &RuleMetrics) -> Totals {
+    Totals {
+        lines: metrics.lines.load(Ordering::Relaxed),
+        matches: metrics.matches.load(Ordering::Relaxed),
+        filters: metrics
+            .filters
+            .iter()
+            .map(|histogram| (histogram.count(), histogram.sum()))
+            .collect(),
+    }
+}
+
+/// Replace characters that have a meaning in the line protocol.
+fn sanitize(name: &str) -> String {
+    name.replace(['.', ':', '|', '@', '#', ',', ' '], "_")
+}
+
+/// Join the lines into as few datagrams as possible, without splitting any line.
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets = Vec::<String>::new();
+
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= MAX_PACKET => {
+                packet.push('\n');
+                packet.push_str(line);
+            }
+            _ => packets.push(line.clone()),
+        }
+    }
+
+    packets
+}
+
+/// Start a background thread that sends the metrics of the registry to the `StatsD` server
+/// regularly. Nothing is started if no server is configured.
+pub fn start(settings: &settings::Statsd, registry: Arc<Registry>) -> Result<()> {
+    let Some(address) = settings.address.clone() else {
+        return Ok(());
+    };
+
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    socket.connect(&address)?;
+    info!("sending metrics to StatsD at {}", address);
+
+    let mut emitter = Emitter::new(settings);
+    let interval = settings.interval.unsigned_abs().max(Duration::from_secs(1));
+
+    thread::Builder::new()
+        .name("statsd".to_owned())
+        .spawn(move || loop {
+            thread::sleep(interval);
+
+            for packet in packets(&emitter.render(&registry)) {
+                if let Err(e) = socket.send(packet.as_bytes()) {
+                    debug!("failed sending metrics to StatsD: {e}");
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Arc::new(RuleMetrics::new(1));
+        let registry = Registry::new([("web.1".to_owned(), vec![String::new()], metrics.clone())]);
+
+        metrics.record_line();
+        metrics.record_line();
+        metrics.filters[0].record(Duration::from_micros(10));
+        metrics.filters[0].record(Duration::from_micros(30));
+
+        let mut emitter = Emitter::new(&settings::Statsd::default());
+        let lines = emitter.render(&registry);
+
+        assert!(lines.contains(&"veto.storage_entries:0|g".to_owned()));
+        assert!(lines.contains(&"veto.rules.web_1.lines:2|c".to_owned()));
+        assert!(lines.contains(&"veto.rules.web_1.filters.0.filter_duration:0.02|ms".to_owned()));
+
+        // Counters only contain the increase since the last flush.
+        metrics.record_line();
+        let lines = emitter.render(&registry);
+
+        assert!(lines.contains(&"veto.rules.web_1.lines:1|c".to_owned()));
+        assert!(!lines.iter().any(|line| line.contains("filter_duration")));
+
+        let mut emitter = Emitter::new(&settings::Statsd {
+            tags: true,
+            ..settings::Statsd::default()
+        });
+        let lines = emitter.render(&registry);
+
+        assert!(lines.contains(&"veto.lines:3|c|#rule:web_1".to_owned()));
+        assert!(lines.contains(&"veto.filter_duration:0.02|ms|#rule:web_1,filter:0".to_owned()));
+    }
+
+    #[test]
+    fn split_packets() {
+        let lines = vec!["a".repeat(1000), "b".repeat(400), "c".repeat(100)];
+        let packets = packets(&lines);
+
+        assert_eq!(2, packets.len());
+        assert_eq!(1000 + 1 + 400, packets[0].len());
+    }
+}
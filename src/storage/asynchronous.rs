@@ -0,0 +1,80 @@
+use std::{net::IpAddr, path::Path};
+
+use async_trait::async_trait;
+use time::OffsetDateTime;
+
+use super::{BanRecord, TargetRepository};
+use crate::Result;
+
+/// Async variant of the [`TargetRepository`] trait, for repositories that are backed by a network
+/// service and shouldn't block a thread while waiting for a response.
+#[async_trait]
+pub trait AsyncTargetRepository: Send + Sync {
+    /// Insert a new entry or update an existing one. See [`TargetRepository::upsert`].
+    async fn upsert(&mut self, ip: IpAddr, until: OffsetDateTime, file: &Path) -> Result<bool>;
+
+    /// Remove an entry by its IP address. See [`TargetRepository::remove`].
+    async fn remove(&mut self, ip: IpAddr) -> Result<()>;
+
+    /// Iterate over all active entries. See [`TargetRepository::iter_active`].
+    async fn iter_active(
+        &self,
+        f: &mut (dyn for<'p> FnMut(IpAddr, &'p Path) -> Result<()> + Send),
+    ) -> Result<()>;
+
+    /// Iterate over all outdated but still active entries. See
+    /// [`TargetRepository::iter_outdated`].
+    async fn iter_outdated(
+        &self,
+        f: &mut (dyn for<'p> FnMut(IpAddr, &'p Path) -> Result<bool> + Send),
+    ) -> Result<()>;
+
+    /// Owned snapshot of all entries. See [`TargetRepository::records`].
+    async fn records(&self) -> Result<Vec<BanRecord>>;
+
+    /// Total amount of entries in the repository.
+    async fn count(&self) -> usize;
+}
+
+/// Adapter that turns any [`TargetRepository`] into an [`AsyncTargetRepository`].
+///
+/// Operations are run directly on the calling task, as the default repository only works on
+/// memory and never blocks for long. Repositories doing slow I/O should implement the async trait
+/// themselves instead.
+pub struct FromSync<T>(pub T);
+
+#[async_trait]
+impl<T> AsyncTargetRepository for FromSync<T>
+where
+    T: TargetRepository + Send + Sync,
+{
+    async fn upsert(&mut self, ip: IpAddr, until: OffsetDateTime, file: &Path) -> Result<bool> {
+        self.0.upsert(ip, until, file)
+    }
+
+    async fn remove(&mut self, ip: IpAddr) -> Result<()> {
+        self.0.remove(ip)
+    }
+
+    async fn iter_active(
+        &self,
+        f: &mut (dyn for<'p> FnMut(IpAddr, &'p Path) -> Result<()> + Send),
+    ) -> Result<()> {
+        self.0.iter_active(f)
+    }
+
+    async fn iter_outdated(
+        &self,
+        f: &mut (dyn for<'p> FnMut(IpAddr, &'p Path) -> Result<bool> + Send),
+    ) -> Result<()> {
+        self.0.iter_outdated(f)
+    }
+
+    async fn records(&self) -> Result<Vec<BanRecord>> {
+        self.0.records()
+    }
+
+    async fn count(&self) -> usize {
+        self.0.count()
+    }
+}
@@ -2,90 +2,109 @@ use std::{
     fs,
     fs::File,
     hash::Hash,
-    io::{prelude::*, BufReader, BufWriter},
+    io::{prelude::*, BufReader, BufWriter, IntoInnerError},
     ops::Drop,
     path::{Path, PathBuf},
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use ahash::RandomState;
-use anyhow::Result;
+use bincode::Options;
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
-use flume::Sender;
-use log::{debug, error};
+use flume::{Receiver, RecvTimeoutError, Sender};
+use log::{debug, error, warn};
 use parking_lot::RwLock;
 use serde::{de::DeserializeOwned, Serialize};
 
-use crate::HashMap;
+use crate::{HashMap, Result};
 
+/// Minimum interval between two saves to disk.
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// In-memory key-value database that persists its content to disk.
+///
+/// Persistence is done by a separate writer thread that keeps its own copy of the data, fed by the
+/// changes of each modification. That way, saving never holds a lock on the map that is used for
+/// lookups and updates.
 pub struct MemoryDatabase<K, V> {
-    map: Arc<RwLock<HashMap<K, V>>>,
-    dirty: Arc<AtomicBool>,
+    map: RwLock<HashMap<K, V>>,
     handle: Option<JoinHandle<()>>,
-    stop: Sender<()>,
+    deltas: Option<Sender<Delta<K, V>>>,
+}
+
+/// Single change to the database, sent to the writer thread.
+enum Delta<K, V> {
+    Upsert(K, V),
+    Remove(K),
 }
 
 impl<K, V> MemoryDatabase<K, V>
 where
-    K: Eq + Hash + Serialize + DeserializeOwned + Send + Sync + 'static,
-    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+    K: Clone + Eq + Hash + Serialize + DeserializeOwned + Send + Sync + 'static,
+    V: Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
 {
     pub fn new(path: Option<PathBuf>) -> Self {
         let location = super::get_location(path);
-        let map = Arc::new(RwLock::new(File::open(&location).map_or_else(
+        let map = File::open(&location).map_or_else(
             |_| HashMap::with_hasher(RandomState::new()),
-            |f| bincode::deserialize_from(GzDecoder::new(BufReader::new(f))).unwrap_or_default(),
-        )));
-        let dirty = Arc::new(AtomicBool::new(false));
-
-        let map2 = map.clone();
-        let dirty2 = dirty.clone();
-
-        let (stop_tx, stop_rx) = flume::bounded(0);
-
-        let handle = thread::spawn(move || loop {
-            match stop_rx.recv_timeout(Duration::from_millis(500)) {
-                Err(_) => break,
-                Ok(()) => {
-                    if dirty2.load(Ordering::Relaxed) {
-                        if let Err(e) = save(&location, &map2.read()) {
-                            error!("Failed saving storage: {:?}", e);
-                        }
-
-                        dirty2.store(false, Ordering::Relaxed);
-                    }
-                }
-            }
-        });
+            |f| {
+                load(f).unwrap_or_else(|e| {
+                    warn!(
+                        "failed loading storage from {:?}, starting empty: {}",
+                        location, e
+                    );
+                    HashMap::default()
+                })
+            },
+        );
+
+        let (tx, rx) = flume::unbounded();
+        let copy = map.clone();
+
+        let handle = thread::spawn(move || write_loop(&location, copy, &rx));
 
         Self {
-            map,
-            dirty,
+            map: RwLock::new(map),
             handle: Some(handle),
-            stop: stop_tx,
+            deltas: Some(tx),
         }
     }
 
-    pub fn get(&self, f: impl Fn(&HashMap<K, V>) -> Result<()>) -> Result<()> {
+    pub fn get<T>(&self, f: impl FnOnce(&HashMap<K, V>) -> T) -> T {
         f(&self.map.read())
     }
 
-    pub fn get_mut(&self, mut f: impl FnMut(&mut HashMap<K, V>) -> Result<bool>) -> Result<()> {
-        if f(&mut self.map.write())? {
-            self.dirty.store(true, Ordering::Relaxed);
+    /// Modify the map with the given function, which returns the keys of all entries that it
+    /// changed, so they can be persisted.
+    pub fn get_mut(&self, mut f: impl FnMut(&mut HashMap<K, V>) -> Result<Vec<K>>) -> Result<()> {
+        let mut map = self.map.write();
+        let changed = f(&mut map)?;
+
+        if let Some(deltas) = &self.deltas {
+            for key in changed {
+                let delta = match map.get(&key) {
+                    Some(value) => Delta::Upsert(key, value.clone()),
+                    None => Delta::Remove(key),
+                };
+
+                if deltas.send(delta).is_err() {
+                    error!("storage writer stopped unexpectedly");
+                    break;
+                }
+            }
         }
+
+        drop(map);
+
         Ok(())
     }
 }
 
 impl<K, V> Drop for MemoryDatabase<K, V> {
     fn drop(&mut self) {
-        self.stop.send(()).ok();
+        // Closing the channel tells the writer to do a last save and stop.
+        self.deltas.take();
 
         if let Some(handle) = self.handle.take() {
             handle.join().unwrap();
@@ -98,6 +117,78 @@ impl<K, V> Drop for MemoryDatabase<K, V> {
     }
 }
 
+/// Apply incoming changes to the writer's copy of the map and save it, until the channel is
+/// closed.
+///
+/// The writer sleeps until a change arrives, so it doesn't wake up at all on idle systems. After a
+/// change, it collects further changes until the save interval passed since the last save and then
+/// persists all of them at once. Any pending changes are saved once the channel is closed.
+fn write_loop<K, V>(location: &Path, mut map: HashMap<K, V>, rx: &Receiver<Delta<K, V>>)
+where
+    K: Eq + Hash + Serialize,
+    V: Serialize,
+{
+    let mut last_save = Instant::now();
+
+    while let Ok(delta) = rx.recv() {
+        apply(&mut map, delta);
+
+        let deadline = last_save + SAVE_INTERVAL;
+        let closed = loop {
+            match rx.recv_deadline(deadline) {
+                Ok(delta) => apply(&mut map, delta),
+                Err(RecvTimeoutError::Timeout) => break false,
+                Err(RecvTimeoutError::Disconnected) => break true,
+            }
+        };
+
+        if let Err(e) = save(location, &map) {
+            error!("Failed saving storage: {:?}", e);
+        }
+
+        last_save = Instant::now();
+
+        if closed {
+            break;
+        }
+    }
+}
+
+fn apply<K, V>(map: &mut HashMap<K, V>, delta: Delta<K, V>)
+where
+    K: Eq + Hash,
+{
+    match delta {
+        Delta::Upsert(key, value) => {
+            map.insert(key, value);
+        }
+        Delta::Remove(key) => {
+            map.remove(&key);
+        }
+    }
+}
+
+/// Read the saved map from a file.
+///
+/// The whole content is decompressed first, to limit the decoding to its size. Otherwise, a
+/// corrupted length in the file would make the decoder allocate that much memory upfront, which
+/// aborts the process instead of failing.
+fn load<K, V>(file: File) -> Result<HashMap<K, V>>
+where
+    K: Eq + Hash + DeserializeOwned,
+    V: DeserializeOwned,
+{
+    let mut data = Vec::new();
+    GzDecoder::new(BufReader::new(file)).read_to_end(&mut data)?;
+
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(data.len() as u64)
+        .deserialize(&data)
+        .map_err(Into::into)
+}
+
 fn save<K, V>(location: &Path, map: &HashMap<K, V>) -> Result<()>
 where
     K: Eq + Hash + Serialize,
@@ -112,7 +203,10 @@ where
     let mut file = GzEncoder::new(file, Compression::default());
 
     bincode::serialize_into(&mut file, map)?;
-    file.finish()?.into_inner()?.flush()?;
+    file.finish()?
+        .into_inner()
+        .map_err(IntoInnerError::into_error)?
+        .flush()?;
 
     Ok(())
 }
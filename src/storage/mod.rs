@@ -1,38 +1,147 @@
 use std::{
+    fmt::{self, Display},
+    mem,
     net::IpAddr,
+    num::NonZeroUsize,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
-use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
 
+#[cfg(feature = "async")]
+pub use self::asynchronous::{AsyncTargetRepository, FromSync};
 use self::memory::MemoryDatabase;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteStorage;
+use crate::{Error, HashMap, Result};
 
+#[cfg(feature = "async")]
+mod asynchronous;
 mod memory;
+#[cfg(feature = "sqlite")]
+mod sqlite;
 
 /// Repository that keeps information about all IPs that have ever been blocked by the application.
 /// It helps to determine when to remove items from the blocklist again and holds basic statistics.
+///
+/// The trait is object safe, so implementations can be selected at runtime as a
+/// `Box<dyn TargetRepository>`.
 pub trait TargetRepository {
     /// Insert a new entry into the repository or update it if it already exists.
     fn upsert(&mut self, ip: IpAddr, until: OffsetDateTime, file: &Path) -> Result<bool>;
 
+    /// Insert the entry of the record as is, replacing any existing entry of the same IP. Unlike
+    /// [`Self::upsert`], the state and block count are taken over from the record, which allows to
+    /// copy entries between repositories.
+    fn restore(&mut self, record: BanRecord) -> Result<()>;
+
     /// Remove an entry by its IP address from the repository.
     fn remove(&mut self, ip: IpAddr) -> Result<()>;
 
     /// Iterate over all active entries, not modifying there status in any way.
-    fn iter_active<F>(&self, f: F) -> Result<()>
-    where
-        F: Fn(IpAddr, &Path) -> Result<()>;
+    fn iter_active(&self, f: &mut dyn FnMut(IpAddr, &Path) -> Result<()>) -> Result<()>;
 
     /// Iterate over all outdated but still active entries. The outcome of the given function tells
     /// whether an entry should be marked as inactive.
-    fn iter_outdated<F>(&self, f: F) -> Result<()>
-    where
-        F: Fn(IpAddr, &Path) -> Result<bool>;
+    fn iter_outdated(&self, f: &mut dyn FnMut(IpAddr, &Path) -> Result<bool>) -> Result<()>;
+
+    /// Owned snapshot of all entries, in no particular order.
+    fn records(&self) -> Result<Vec<BanRecord>>;
+
+    /// Owned snapshot of the entry of a single IP, if it's in the repository.
+    fn record(&self, ip: IpAddr) -> Result<Option<BanRecord>> {
+        Ok(self.records()?.into_iter().find(|r| r.ip == ip))
+    }
+
+    /// Owned snapshot of all active entries, the same ones that [`Self::iter_active`] visits.
+    fn active_records(&self) -> Result<Vec<BanRecord>> {
+        let now = OffsetDateTime::now_utc();
+        let mut records = self.records()?;
+        records.retain(|r| r.until >= now);
+        Ok(records)
+    }
+
+    /// Owned snapshot of all outdated but still active entries, the same ones that
+    /// [`Self::iter_outdated`] visits. Unlike the latter, this doesn't modify any entries.
+    fn outdated_records(&self) -> Result<Vec<BanRecord>> {
+        let now = OffsetDateTime::now_utc();
+        let mut records = self.records()?;
+        records.retain(|r| r.until < now && r.active);
+        Ok(records)
+    }
+
+    /// Total amount of entries in the repository.
+    fn count(&self) -> usize;
+
+    /// Approximate amount of memory used by all entries, in bytes.
+    fn memory_usage(&self) -> usize;
+}
+
+/// Information about a single IP in a [`TargetRepository`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BanRecord {
+    /// The blocked IP address.
+    pub ip: IpAddr,
+    /// Location of the log file that the block came from.
+    pub file: PathBuf,
+    /// Time until the IP is blocked, serialized in the RFC 3339 format.
+    #[serde(with = "time::serde::rfc3339")]
+    pub until: OffsetDateTime,
+    /// Whether the IP is still expected to be on the blocklist, even if its block expired.
+    pub active: bool,
+    /// Amount of times the IP was blocked before the current block.
+    pub times: u8,
 }
 
-#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+impl<T: TargetRepository + ?Sized> TargetRepository for Box<T> {
+    fn upsert(&mut self, ip: IpAddr, until: OffsetDateTime, file: &Path) -> Result<bool> {
+        (**self).upsert(ip, until, file)
+    }
+
+    fn restore(&mut self, record: BanRecord) -> Result<()> {
+        (**self).restore(record)
+    }
+
+    fn remove(&mut self, ip: IpAddr) -> Result<()> {
+        (**self).remove(ip)
+    }
+
+    fn iter_active(&self, f: &mut dyn FnMut(IpAddr, &Path) -> Result<()>) -> Result<()> {
+        (**self).iter_active(f)
+    }
+
+    fn iter_outdated(&self, f: &mut dyn FnMut(IpAddr, &Path) -> Result<bool>) -> Result<()> {
+        (**self).iter_outdated(f)
+    }
+
+    fn records(&self) -> Result<Vec<BanRecord>> {
+        (**self).records()
+    }
+
+    fn record(&self, ip: IpAddr) -> Result<Option<BanRecord>> {
+        (**self).record(ip)
+    }
+
+    fn active_records(&self) -> Result<Vec<BanRecord>> {
+        (**self).active_records()
+    }
+
+    fn outdated_records(&self) -> Result<Vec<BanRecord>> {
+        (**self).outdated_records()
+    }
+
+    fn count(&self) -> usize {
+        (**self).count()
+    }
+
+    fn memory_usage(&self) -> usize {
+        (**self).memory_usage()
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 struct Entry {
     /// Location of the log file that this entry came from.
     file: PathBuf,
@@ -61,16 +170,62 @@ impl Entry {
 }
 
 /// An implementation of [`TargetRepository`] that keeps all information in a in-memory hash map and
-/// periodically saves the state to disk.
-struct HashMapStorage(MemoryDatabase<IpAddr, Entry>);
+/// saves the state to disk in the background.
+struct HashMapStorage {
+    db: MemoryDatabase<IpAddr, Entry>,
+    max_entries: Option<NonZeroUsize>,
+}
+
+impl HashMapStorage {
+    /// Remove expired and inactive entries, that have been expired the longest, until there is
+    /// room for at least one more entry. Nothing is removed if that doesn't make enough room.
+    fn evict(map: &mut HashMap<IpAddr, Entry>, max_entries: usize) -> Result<Vec<IpAddr>> {
+        let excess = (map.len() + 1).saturating_sub(max_entries);
+        if excess == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut candidates = map
+            .iter()
+            .filter(|(_, e)| !e.active)
+            .map(|(ip, e)| (e.until, *ip))
+            .collect::<Vec<_>>();
+        if candidates.len() < excess {
+            return Err(Error::StorageFull(max_entries));
+        }
+
+        candidates.sort_unstable();
+
+        let evicted = candidates
+            .into_iter()
+            .take(excess)
+            .map(|(_, ip)| ip)
+            .collect::<Vec<_>>();
+
+        for ip in &evicted {
+            map.remove(ip);
+        }
+
+        Ok(evicted)
+    }
+}
 
 impl TargetRepository for HashMapStorage {
     fn upsert(&mut self, ip: IpAddr, until: OffsetDateTime, file: &Path) -> Result<bool> {
         let mut exists = true;
+        let max_entries = self.max_entries;
+
+        self.db.get_mut(|map| {
+            let mut changed = match max_entries {
+                Some(max) if !map.contains_key(&ip) => Self::evict(map, max.get())?,
+                _ => Vec::new(),
+            };
 
-        self.0.get_mut(|map| {
             map.entry(ip)
                 .and_modify(|e| {
+                    if !e.active {
+                        e.times = e.times.saturating_add(1);
+                    }
                     e.until = until;
                     e.active = true;
                 })
@@ -78,44 +233,56 @@ impl TargetRepository for HashMapStorage {
                     exists = false;
                     Entry::new(file.to_owned(), until)
                 });
-            Ok(true)
+
+            changed.push(ip);
+            Ok(changed)
         })?;
 
         Ok(exists)
     }
 
+    fn restore(&mut self, record: BanRecord) -> Result<()> {
+        self.db.get_mut(|map| {
+            map.insert(
+                record.ip,
+                Entry {
+                    file: record.file.clone(),
+                    until: record.until,
+                    active: record.active,
+                    times: record.times,
+                },
+            );
+            Ok(vec![record.ip])
+        })
+    }
+
     fn remove(&mut self, ip: IpAddr) -> Result<()> {
-        self.0.get_mut(|map| Ok(map.remove(&ip).is_some()))
+        self.db.get_mut(|map| {
+            map.remove(&ip);
+            Ok(vec![ip])
+        })
     }
 
-    fn iter_active<F>(&self, f: F) -> Result<()>
-    where
-        F: Fn(IpAddr, &Path) -> Result<()>,
-    {
+    fn iter_active(&self, f: &mut dyn FnMut(IpAddr, &Path) -> Result<()>) -> Result<()> {
         let now = OffsetDateTime::now_utc();
 
-        self.0.get(|map| {
+        self.db.get(|map| {
             for (k, v) in map.iter().filter(|(_, v)| v.until >= now) {
                 f(*k, &v.file)?;
             }
             Ok(())
-        })?;
-
-        Ok(())
+        })
     }
 
-    fn iter_outdated<F>(&self, f: F) -> Result<()>
-    where
-        F: Fn(IpAddr, &Path) -> Result<bool>,
-    {
+    fn iter_outdated(&self, f: &mut dyn FnMut(IpAddr, &Path) -> Result<bool>) -> Result<()> {
         let now = OffsetDateTime::now_utc();
 
-        self.0.get_mut(|map| {
-            let mut changed = false;
+        self.db.get_mut(|map| {
+            let mut changed = Vec::new();
             for (k, v) in map.iter_mut().filter(|(_, v)| v.until < now && v.active) {
                 if f(*k, &v.file)? {
                     v.active = false;
-                    changed = true;
+                    changed.push(*k);
                 }
             }
             Ok(changed)
@@ -123,15 +290,333 @@ impl TargetRepository for HashMapStorage {
 
         Ok(())
     }
+
+    fn records(&self) -> Result<Vec<BanRecord>> {
+        Ok(self.db.get(|map| {
+            map.iter()
+                .map(|(ip, e)| BanRecord {
+                    ip: *ip,
+                    file: e.file.clone(),
+                    until: e.until,
+                    active: e.active,
+                    times: e.times,
+                })
+                .collect()
+        }))
+    }
+
+    fn record(&self, ip: IpAddr) -> Result<Option<BanRecord>> {
+        Ok(self.db.get(|map| {
+            map.get(&ip).map(|e| BanRecord {
+                ip,
+                file: e.file.clone(),
+                until: e.until,
+                active: e.active,
+                times: e.times,
+            })
+        }))
+    }
+
+    fn count(&self) -> usize {
+        self.db.get(HashMap::len)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.db.get(|map| {
+            map.capacity() * mem::size_of::<(IpAddr, Entry)>()
+                + map
+                    .values()
+                    .map(|e| e.file.as_os_str().len())
+                    .sum::<usize>()
+        })
+    }
 }
 
-/// Create a new [`TargetRepository`] with the default implementation.
+/// Create a new [`TargetRepository`] with the default implementation, optionally limited to a
+/// maximum amount of entries.
 #[must_use]
-pub fn new_storage(path: Option<PathBuf>) -> impl TargetRepository {
-    HashMapStorage(MemoryDatabase::new(path))
+pub fn new_storage(
+    path: Option<PathBuf>,
+    max_entries: Option<NonZeroUsize>,
+) -> impl TargetRepository {
+    HashMapStorage {
+        db: MemoryDatabase::new(path),
+        max_entries,
+    }
+}
+
+/// Location of the storage file, if no other one is given.
+pub const DEFAULT_PATH: &str = "/var/lib/veto/storage.bin";
+
+/// Location of the `SQLite` database, if no other one is given.
+pub const DEFAULT_SQLITE_PATH: &str = "/var/lib/veto/storage.db";
+
+/// Available implementations of the [`TargetRepository`], that [`open`] can create.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// All entries in memory, saved to disk as compressed bincode in the background.
+    #[default]
+    Bincode,
+    /// All entries in a `SQLite` database. Requires the `sqlite` feature.
+    Sqlite,
+}
+
+impl Backend {
+    /// Location of the storage, if no other one is given.
+    #[must_use]
+    pub fn default_path(self) -> PathBuf {
+        PathBuf::from(match self {
+            Self::Bincode => DEFAULT_PATH,
+            Self::Sqlite => DEFAULT_SQLITE_PATH,
+        })
+    }
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Bincode => "bincode",
+            Self::Sqlite => "sqlite",
+        })
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bincode" => Ok(Self::Bincode),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err(format!("unknown storage backend '{s}'")),
+        }
+    }
+}
+
+/// Open the storage of the given backend, at the given location or the backend's default one,
+/// optionally limited to a maximum amount of entries.
+pub fn open(
+    backend: Backend,
+    path: Option<PathBuf>,
+    max_entries: Option<NonZeroUsize>,
+) -> Result<Box<dyn TargetRepository + Send>> {
+    match backend {
+        Backend::Bincode => Ok(Box::new(new_storage(path, max_entries))),
+        #[cfg(feature = "sqlite")]
+        Backend::Sqlite => Ok(Box::new(SqliteStorage::open(
+            &path.unwrap_or_else(|| backend.default_path()),
+            max_entries,
+        )?)),
+        #[cfg(not(feature = "sqlite"))]
+        Backend::Sqlite => Err(Error::UnsupportedStorage(backend)),
+    }
+}
+
+/// Copy all entries from one repository into another, returning the amount of copied entries.
+///
+/// Afterwards, the entries of both repositories are compared, to make sure nothing got lost.
+pub fn migrate(from: &dyn TargetRepository, to: &mut dyn TargetRepository) -> Result<usize> {
+    let mut records = from.records()?;
+    let count = records.len();
+
+    for record in records.iter().cloned() {
+        to.restore(record)?;
+    }
+
+    let mut copied = to.records()?;
+    records.sort_by_key(|r| r.ip);
+    copied.sort_by_key(|r| r.ip);
+
+    if records != copied {
+        return Err(Error::Migration {
+            expected: count,
+            found: copied.len(),
+        });
+    }
+
+    Ok(count)
 }
 
 /// Determine the location of a file for persistence.
 fn get_location(path: Option<PathBuf>) -> PathBuf {
-    path.unwrap_or_else(|| PathBuf::from("/var/lib/veto/storage.bin"))
+    path.unwrap_or_else(|| PathBuf::from(DEFAULT_PATH))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn records() {
+        let path = env::temp_dir().join(format!("veto-storage-{}.bin", std::process::id()));
+        let mut storage = new_storage(Some(path.clone()), None);
+        let now = OffsetDateTime::now_utc();
+        let file = Path::new("/var/log/access.log");
+
+        let active = "10.0.0.1".parse().unwrap();
+        let outdated = "10.0.0.2".parse().unwrap();
+        storage
+            .upsert(active, now + Duration::hours(1), file)
+            .unwrap();
+        storage
+            .upsert(outdated, now - Duration::hours(1), file)
+            .unwrap();
+
+        let ips = |records: Vec<BanRecord>| records.into_iter().map(|r| r.ip).collect::<Vec<_>>();
+
+        assert_eq!(2, storage.records().unwrap().len());
+        assert_eq!(vec![active], ips(storage.active_records().unwrap()));
+        assert_eq!(vec![outdated], ips(storage.outdated_records().unwrap()));
+
+        storage.iter_outdated(&mut |_, _| Ok(true)).unwrap();
+        storage
+            .upsert(outdated, now + Duration::hours(1), file)
+            .unwrap();
+
+        let record = storage.record(outdated).unwrap().unwrap();
+        assert!(record.active);
+        assert_eq!(1, record.times);
+        assert_eq!(None, storage.record("10.0.0.3".parse().unwrap()).unwrap());
+
+        drop(storage);
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn full_storage() {
+        let mut backends = vec![(Backend::Bincode, "bin")];
+        if cfg!(feature = "sqlite") {
+            backends.push((Backend::Sqlite, "db"));
+        }
+
+        for (backend, ext) in backends {
+            let path = env::temp_dir().join(format!("veto-full-{}.{ext}", std::process::id()));
+            let mut storage = open(backend, Some(path.clone()), NonZeroUsize::new(2)).unwrap();
+            let now = OffsetDateTime::now_utc();
+            let file = Path::new("/var/log/access.log");
+            let ip = |last: u8| IpAddr::from([10, 0, 0, last]);
+
+            storage
+                .upsert(ip(1), now + Duration::hours(1), file)
+                .unwrap();
+            storage
+                .upsert(ip(2), now - Duration::hours(1), file)
+                .unwrap();
+            assert!(matches!(
+                storage.upsert(ip(3), now + Duration::hours(1), file),
+                Err(Error::StorageFull(2))
+            ));
+            assert_eq!(2, storage.count());
+            assert_eq!(None, storage.record(ip(3)).unwrap());
+
+            // Existing entries can still be updated.
+            assert!(storage
+                .upsert(ip(1), now + Duration::hours(2), file)
+                .unwrap());
+
+            // Once an entry is no longer active, it makes room for the new one.
+            storage.iter_outdated(&mut |_, _| Ok(true)).unwrap();
+            assert!(!storage
+                .upsert(ip(3), now + Duration::hours(1), file)
+                .unwrap());
+            assert_eq!(None, storage.record(ip(2)).unwrap());
+            assert_eq!(2, storage.count());
+
+            drop(storage);
+            fs::remove_file(path).ok();
+        }
+    }
+
+    #[test]
+    fn corrupted_file() {
+        use std::io::Write;
+
+        let path = env::temp_dir().join(format!("veto-corrupted-{}.bin", std::process::id()));
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        // Map with a single entry, whose file path claims to be exabytes long.
+        encoder.write_all(&1_u64.to_le_bytes()).unwrap();
+        encoder.write_all(&[0, 0, 0, 0, 10, 0, 0, 1]).unwrap();
+        encoder.write_all(&(u64::MAX >> 4).to_le_bytes()).unwrap();
+        fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        let storage = new_storage(Some(path.clone()), None);
+        assert_eq!(0, storage.count());
+
+        drop(storage);
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn migrate_records() {
+        let path = env::temp_dir().join(format!("veto-migrate-{}.bin", std::process::id()));
+        let mut from = crate::testing::MemoryRepository::new();
+        let until = OffsetDateTime::now_utc().replace_nanosecond(0).unwrap();
+        let file = Path::new("/var/log/access.log");
+
+        for ip in ["10.0.0.1", "10.0.0.2", "2001:db8::1"] {
+            from.upsert(ip.parse().unwrap(), until, file).unwrap();
+        }
+        from.iter_outdated(&mut |_, _| Ok(true)).unwrap();
+        from.upsert(
+            "10.0.0.1".parse().unwrap(),
+            until + Duration::hours(1),
+            file,
+        )
+        .unwrap();
+
+        let mut to = open(Backend::Bincode, Some(path.clone()), None).unwrap();
+        assert_eq!(3, migrate(&from, &mut to).unwrap());
+        drop(to);
+
+        let reopened = open(Backend::Bincode, Some(path.clone()), None).unwrap();
+        let record = reopened
+            .record("10.0.0.1".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(1, record.times);
+        assert_eq!(3, reopened.count());
+
+        drop(reopened);
+        fs::remove_file(path).ok();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_storage() {
+        let path = env::temp_dir().join(format!("veto-storage-{}.db", std::process::id()));
+        let mut storage = open(Backend::Sqlite, Some(path.clone()), None).unwrap();
+        let now = OffsetDateTime::now_utc().replace_nanosecond(0).unwrap();
+        let file = Path::new("/var/log/access.log");
+
+        let active = "10.0.0.1".parse().unwrap();
+        let outdated = "2001:db8::1".parse().unwrap();
+        assert!(!storage
+            .upsert(active, now + Duration::hours(1), file)
+            .unwrap());
+        assert!(!storage
+            .upsert(outdated, now - Duration::hours(1), file)
+            .unwrap());
+
+        storage.iter_outdated(&mut |_, _| Ok(true)).unwrap();
+        assert!(storage
+            .upsert(outdated, now + Duration::hours(1), file)
+            .unwrap());
+
+        let record = storage.record(outdated).unwrap().unwrap();
+        assert!(record.active);
+        assert_eq!(1, record.times);
+        assert_eq!(now + Duration::hours(1), record.until);
+        assert_eq!(2, storage.active_records().unwrap().len());
+
+        storage.remove(active).unwrap();
+        assert_eq!(1, storage.count());
+
+        drop(storage);
+        fs::remove_file(path).ok();
+    }
 }
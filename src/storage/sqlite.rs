@@ -0,0 +1,250 @@
+use std::{
+    net::IpAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use time::OffsetDateTime;
+
+use super::{BanRecord, TargetRepository};
+use crate::{Error, Result};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS bans (
+        ip     TEXT PRIMARY KEY NOT NULL,
+        file   TEXT NOT NULL,
+        until  INTEGER NOT NULL,
+        active INTEGER NOT NULL,
+        times  INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS bans_until ON bans (until);
+";
+
+/// An implementation of [`TargetRepository`] that keeps all entries in a `SQLite` database.
+///
+/// Unlike the default storage, every change is written to disk right away and entries aren't kept
+/// in memory, which suits large amounts of entries better and allows to inspect them with other
+/// tools.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+    max_entries: Option<NonZeroUsize>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path, max_entries: Option<NonZeroUsize>) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            max_entries,
+        })
+    }
+
+    /// Remove expired and inactive entries, that have been expired the longest, until there is
+    /// room for at least one more entry. Nothing is removed if that doesn't make enough room.
+    fn evict(conn: &Connection, max_entries: usize) -> Result<()> {
+        let count = conn.query_row("SELECT COUNT(*) FROM bans", [], |row| row.get::<_, i64>(0))?;
+        let excess = (usize::try_from(count).unwrap_or_default() + 1).saturating_sub(max_entries);
+        if excess == 0 {
+            return Ok(());
+        }
+
+        let inactive = conn.query_row("SELECT COUNT(*) FROM bans WHERE active = 0", [], |row| {
+            row.get::<_, i64>(0)
+        })?;
+        if usize::try_from(inactive).unwrap_or_default() < excess {
+            return Err(Error::StorageFull(max_entries));
+        }
+
+        conn.execute(
+            "DELETE FROM bans WHERE ip IN (
+                SELECT ip FROM bans WHERE active = 0 ORDER BY until LIMIT ?1
+            )",
+            [i64::try_from(excess).unwrap_or(i64::MAX)],
+        )?;
+
+        Ok(())
+    }
+
+    /// Run the function with the connection, while holding its lock.
+    fn with_conn<T>(&self, f: impl FnOnce(&Connection) -> Result<T>) -> Result<T> {
+        f(&self.conn.lock())
+    }
+
+    /// Call the function for each IP and file of the entries that the query selects, which takes
+    /// the current time as only parameter.
+    fn query_files(
+        &self,
+        query: &str,
+        f: &mut dyn FnMut(IpAddr, &Path) -> Result<()>,
+    ) -> Result<()> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(query)?;
+            let mut rows = stmt.query([OffsetDateTime::now_utc().unix_timestamp()])?;
+
+            while let Some(row) = rows.next()? {
+                let ip = parse_ip(&row.get::<_, String>(0)?)?;
+                let file = PathBuf::from(row.get::<_, String>(1)?);
+                f(ip, &file)?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl TargetRepository for SqliteStorage {
+    fn upsert(&mut self, ip: IpAddr, until: OffsetDateTime, file: &Path) -> Result<bool> {
+        let conn = self.conn.get_mut();
+        let tx = conn.transaction()?;
+        let key = ip.to_string();
+
+        let exists = tx
+            .query_row("SELECT 1 FROM bans WHERE ip = ?1", [&key], |_| Ok(()))
+            .optional()?
+            .is_some();
+
+        if exists {
+            tx.execute(
+                "UPDATE bans SET
+                    times = CASE WHEN active THEN times ELSE MIN(times + 1, 255) END,
+                    until = ?2,
+                    active = 1
+                WHERE ip = ?1",
+                params![key, until.unix_timestamp()],
+            )?;
+        } else {
+            if let Some(max) = self.max_entries {
+                Self::evict(&tx, max.get())?;
+            }
+
+            tx.execute(
+                "INSERT INTO bans (ip, file, until, active, times) VALUES (?1, ?2, ?3, 1, 0)",
+                params![key, file.to_string_lossy(), until.unix_timestamp()],
+            )?;
+        }
+
+        tx.commit()?;
+
+        Ok(exists)
+    }
+
+    fn restore(&mut self, record: BanRecord) -> Result<()> {
+        self.conn.get_mut().execute(
+            "INSERT OR REPLACE INTO bans (ip, file, until, active, times)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                record.ip.to_string(),
+                record.file.to_string_lossy(),
+                record.until.unix_timestamp(),
+                record.active,
+                record.times,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn remove(&mut self, ip: IpAddr) -> Result<()> {
+        self.conn
+            .get_mut()
+            .execute("DELETE FROM bans WHERE ip = ?1", [ip.to_string()])?;
+
+        Ok(())
+    }
+
+    fn iter_active(&self, f: &mut dyn FnMut(IpAddr, &Path) -> Result<()>) -> Result<()> {
+        self.query_files("SELECT ip, file FROM bans WHERE until >= ?1", f)
+    }
+
+    fn iter_outdated(&self, f: &mut dyn FnMut(IpAddr, &Path) -> Result<bool>) -> Result<()> {
+        let mut unblocked = Vec::new();
+
+        self.query_files(
+            "SELECT ip, file FROM bans WHERE until < ?1 AND active",
+            &mut |ip, file| {
+                if f(ip, file)? {
+                    unblocked.push(ip);
+                }
+                Ok(())
+            },
+        )?;
+
+        self.with_conn(|conn| {
+            let tx = conn.unchecked_transaction()?;
+            for ip in unblocked {
+                tx.execute("UPDATE bans SET active = 0 WHERE ip = ?1", [ip.to_string()])?;
+            }
+            tx.commit().map_err(Into::into)
+        })
+    }
+
+    fn records(&self) -> Result<Vec<BanRecord>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT ip, file, until, active, times FROM bans")?;
+            let mut rows = stmt.query([])?;
+            let mut records = Vec::new();
+
+            while let Some(row) = rows.next()? {
+                records.push(to_record(row)?);
+            }
+
+            Ok(records)
+        })
+    }
+
+    fn record(&self, ip: IpAddr) -> Result<Option<BanRecord>> {
+        self.with_conn(|conn| {
+            let mut stmt =
+                conn.prepare("SELECT ip, file, until, active, times FROM bans WHERE ip = ?1")?;
+            let mut rows = stmt.query([ip.to_string()])?;
+
+            rows.next()?.map(to_record).transpose()
+        })
+    }
+
+    fn count(&self) -> usize {
+        self.with_conn(|conn| {
+            conn.query_row("SELECT COUNT(*) FROM bans", [], |row| row.get::<_, i64>(0))
+                .map_err(Into::into)
+        })
+        .map_or_else(
+            |e| {
+                warn!("failed counting storage entries: {e}");
+                0
+            },
+            |count| usize::try_from(count).unwrap_or_default(),
+        )
+    }
+
+    fn memory_usage(&self) -> usize {
+        // Entries live on disk, only SQLite's page cache is kept in memory.
+        0
+    }
+}
+
+fn to_record(row: &Row<'_>) -> Result<BanRecord> {
+    let until =
+        OffsetDateTime::from_unix_timestamp(row.get(2)?).map_err(|e| Error::Other(Box::new(e)))?;
+
+    Ok(BanRecord {
+        ip: parse_ip(&row.get::<_, String>(0)?)?,
+        file: PathBuf::from(row.get::<_, String>(1)?),
+        until,
+        active: row.get(3)?,
+        times: row.get(4)?,
+    })
+}
+
+fn parse_ip(value: &str) -> Result<IpAddr> {
+    value.parse().map_err(|e| Error::Other(Box::new(e)))
+}
@@ -0,0 +1,219 @@
+//! Following a single log file, including its rotation, independently of any rules.
+
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use flume::RecvTimeoutError;
+use log::debug;
+
+use crate::{
+    notifier::{self, EventType, Notifier},
+    reader::LineReader,
+    Error, Result,
+};
+
+/// Reads all lines of a file and follows any lines that are appended afterwards.
+///
+/// If the file is rotated, reading continues at the start of the new file. Notifications about
+/// changes either come from the own watcher that [`Self::watch`] sets up, or from a shared
+/// [`Notifier`], whose events are passed to [`Self::handle`].
+pub struct Tailer {
+    path: PathBuf,
+    lines: Option<LineReader>,
+    max_length: usize,
+    notifier: Option<Notifier>,
+}
+
+impl Tailer {
+    /// Open the file, starting with its existing content.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let lines = Some(LineReader::open(open_file(&path)?)?);
+
+        Ok(Self {
+            path,
+            lines,
+            max_length: usize::MAX,
+            notifier: None,
+        })
+    }
+
+    /// Follow a file that may not exist yet. Until it's created and reported through
+    /// [`Self::handle`], no lines are yielded, and it's read from the start afterwards. Any other
+    /// error than a missing file is still returned.
+    pub fn open_or_pending(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let lines = match File::open(&path) {
+            Ok(file) => Some(LineReader::open(file)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(source) => return Err(Error::LogFile { path, source }),
+        };
+
+        Ok(Self {
+            path,
+            lines,
+            max_length: usize::MAX,
+            notifier: None,
+        })
+    }
+
+    /// Limit the length of lines, skipping any lines that are longer.
+    #[must_use]
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self.lines = self.lines.map(|lines| lines.with_max_length(max_length));
+        self
+    }
+
+    /// Watch the file for changes with an own watcher, which is needed for [`Self::wait`].
+    pub fn watch(mut self) -> Result<Self> {
+        self.notifier = Some(notifier::start(std::iter::once(&self.path))?);
+        Ok(self)
+    }
+
+    /// Path of the followed file.
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the file doesn't exist (anymore), and lines are only yielded once it's created.
+    #[must_use]
+    pub const fn is_pending(&self) -> bool {
+        self.lines.is_none()
+    }
+
+    /// Approximate amount of heap memory used for reading lines, in bytes.
+    #[must_use]
+    pub fn memory_usage(&self) -> usize {
+        self.lines.as_ref().map_or(0, LineReader::memory_usage)
+    }
+
+    /// Position and total length in bytes of the initial scan over the file's existing content,
+    /// or [`None`] once the scan is done.
+    #[must_use]
+    pub fn scan_progress(&self) -> Option<(usize, usize)> {
+        self.lines.as_ref()?.scan_progress()
+    }
+
+    /// Pass the next line to the given function and return its result, or [`None`] if no more
+    /// lines are available right now.
+    pub fn next_with<T>(&mut self, f: impl FnOnce(&str) -> T) -> Option<Result<T>> {
+        self.lines.as_mut()?.next_with(f)
+    }
+
+    /// Apply a change of the file, as reported by a [`Notifier`]. A removed file stops yielding
+    /// lines until it's created again, and is then read from the start.
+    pub fn handle(&mut self, ty: &EventType) -> Result<()> {
+        match ty {
+            EventType::Modified => {}
+            EventType::Removed => {
+                debug!("{:?} removed", self.path);
+                self.lines = None;
+            }
+            EventType::Created => {
+                debug!("{:?} created", self.path);
+                self.lines =
+                    Some(LineReader::tail(open_file(&self.path)?).with_max_length(self.max_length));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wait up to the timeout for the file to change, and return whether new lines may be
+    /// available. Without an own watcher, this waits for the full timeout and polls the file.
+    pub fn wait(&mut self, timeout: Duration) -> Result<bool> {
+        let Some(notifier) = &self.notifier else {
+            thread::sleep(timeout);
+            return Ok(true);
+        };
+
+        // A missing file is watched through its directory, which reports other files as well.
+        let rx = notifier.rx.clone();
+        let event = match rx.recv_timeout(timeout) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => return Ok(false),
+        };
+
+        for event in std::iter::once(event).chain(rx.try_iter()) {
+            if event.path == self.path {
+                if let Some(notifier) = &self.notifier {
+                    notifier.follow(&event);
+                }
+                self.handle(&event.ty)?;
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+fn open_file(path: &Path) -> Result<File> {
+    File::open(path).map_err(|source| Error::LogFile {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, io::Write};
+
+    use super::*;
+
+    #[test]
+    fn rotation() {
+        let path = env::temp_dir().join(format!("veto-tailer-{}.log", std::process::id()));
+        fs::write(&path, "first\n").unwrap();
+
+        let mut tailer = Tailer::open(&path).unwrap();
+        let next = |tailer: &mut Tailer| tailer.next_with(str::to_owned).transpose().unwrap();
+
+        assert_eq!(Some("first".to_owned()), next(&mut tailer));
+        assert_eq!(None, next(&mut tailer));
+
+        fs::remove_file(&path).unwrap();
+        tailer.handle(&EventType::Removed).unwrap();
+        assert_eq!(None, next(&mut tailer));
+
+        fs::write(&path, "").unwrap();
+        tailer.handle(&EventType::Created).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"second\n")
+            .unwrap();
+
+        assert!(tailer.wait(Duration::ZERO).unwrap());
+        assert_eq!(Some("second".to_owned()), next(&mut tailer));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn pending() {
+        let path = env::temp_dir().join(format!("veto-tailer-pending-{}.log", std::process::id()));
+        fs::remove_file(&path).ok();
+
+        let mut tailer = Tailer::open_or_pending(&path).unwrap();
+        assert!(tailer.is_pending());
+        assert!(tailer.next_with(str::to_owned).is_none());
+
+        fs::write(&path, "first\n").unwrap();
+        tailer.handle(&EventType::Created).unwrap();
+        assert!(!tailer.is_pending());
+        assert_eq!(
+            Some("first".to_owned()),
+            tailer.next_with(str::to_owned).transpose().unwrap()
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+}
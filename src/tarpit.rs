@@ -0,0 +1,191 @@
+//! Tarpit in the style of endlessh, that keeps connections of blocked clients open for as long as
+//! possible, to waste the time of attackers without any kernel addons.
+//!
+//! Clients get an HTTP status line, followed by an endless response header that is sent one short
+//! line at a time. HTTP clients keep waiting for the end of the header, and SSH clients ignore all
+//! lines before the version banner, which never arrives. Blocked IPs are sent here by the
+//! `Redirect` target of the ipset firewall.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use flume::{Receiver, RecvTimeoutError, Sender};
+use log::{debug, info};
+
+use crate::{honeypot, settings, Result};
+
+/// First line sent to each client.
+const STATUS_LINE: &[u8] = b"HTTP/1.1 200 OK\r\n";
+
+/// A client that is kept in the tarpit.
+struct Client {
+    stream: TcpStream,
+    ip: IpAddr,
+    since: Instant,
+    /// Time that the next line is due.
+    next: Instant,
+}
+
+/// Start listening on the port of the settings, if one is set.
+pub fn start(settings: &settings::Tarpit) -> Result<()> {
+    let Some(port) = settings.port else {
+        return Ok(());
+    };
+
+    let listener = honeypot::bind(None, port)?;
+    let (tx, rx) = flume::bounded(settings.max_clients.max(1));
+
+    thread::Builder::new()
+        .name("tarpit-accept".to_owned())
+        .spawn(move || accept(&listener, &tx))?;
+
+    let delay = settings
+        .delay
+        .unsigned_abs()
+        .max(Duration::from_millis(100));
+    let max_clients = settings.max_clients;
+
+    thread::Builder::new()
+        .name("tarpit".to_owned())
+        .spawn(move || drip(&rx, delay, max_clients))?;
+
+    info!("tarpit listening on port {port}");
+
+    Ok(())
+}
+
+/// Accept connections and pass them on, until the receiver is gone.
+fn accept(listener: &TcpListener, clients: &Sender<TcpStream>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if clients.send(stream).is_err() {
+                    break;
+                }
+            }
+            Err(e) => debug!("failed accepting tarpit connection: {e}"),
+        }
+    }
+}
+
+/// Send a line to each client whenever its delay passed, until the clients disconnect. All clients
+/// have the same delay, so they're always ordered by the time of their next line.
+fn drip(connections: &Receiver<TcpStream>, delay: Duration, max_clients: usize) {
+    let mut clients = VecDeque::<Client>::new();
+
+    loop {
+        // Without any clients there is nothing to send, until a new one connects.
+        let received = clients.front().map_or_else(
+            || {
+                connections
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected)
+            },
+            |client| connections.recv_deadline(client.next),
+        );
+
+        match received {
+            Ok(stream) => {
+                if clients.len() < max_clients {
+                    clients.extend(admit(stream, delay));
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        let now = Instant::now();
+        while clients.front().is_some_and(|client| client.next <= now) {
+            let Some(mut client) = clients.pop_front() else {
+                break;
+            };
+
+            match send_line(&mut client.stream) {
+                Ok(()) => {
+                    client.next = now + delay;
+                    clients.push_back(client);
+                }
+                Err(e) => debug!(
+                    "{} left the tarpit after {:?}: {e}",
+                    client.ip,
+                    client.since.elapsed()
+                ),
+            }
+        }
+    }
+}
+
+/// Prepare a new client and send it the status line.
+fn admit(mut stream: TcpStream, delay: Duration) -> Option<Client> {
+    let ip = stream.peer_addr().ok()?.ip().to_canonical();
+
+    // Clients that don't read anything simply fill up the send buffer, and writes must not block
+    // the other clients then.
+    stream.set_nonblocking(true).ok()?;
+    stream.write_all(STATUS_LINE).ok()?;
+
+    debug!("{ip} entered the tarpit");
+
+    let now = Instant::now();
+    Some(Client {
+        stream,
+        ip,
+        since: now,
+        next: now + delay,
+    })
+}
+
+/// Send a random header line. A full send buffer isn't an error, as the client is still there.
+fn send_line(stream: &mut TcpStream) -> io::Result<()> {
+    match stream.write(random_line().as_bytes()) {
+        Ok(0) => Err(io::ErrorKind::WriteZero.into()),
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Header line with a random name and value, so clients can't detect a repeating pattern.
+fn random_line() -> String {
+    // Each new instance is seeded differently, which makes the hash a cheap random number.
+    let random = ahash::RandomState::new().hash_one(0);
+
+    format!("X-{:08x}: {:08x}\r\n", random >> 32, random & 0xffff_ffff)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        net::Ipv4Addr,
+    };
+
+    use super::*;
+
+    #[test]
+    fn drip_lines() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = flume::bounded(1);
+        thread::spawn(move || accept(&listener, &tx));
+        thread::spawn(move || drip(&rx, Duration::from_millis(10), 1));
+
+        let mut reader = BufReader::new(TcpStream::connect(addr).unwrap());
+        let mut line = String::new();
+
+        reader.read_line(&mut line).unwrap();
+        assert_eq!("HTTP/1.1 200 OK\r\n", line);
+
+        for _ in 0..3 {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            assert!(line.starts_with("X-"), "{line:?}");
+            assert!(line.ends_with("\r\n"));
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! Test doubles for the firewall and storage, to test rules and integrations without touching the
+//! system's firewall or the disk.
+
+use std::{
+    mem,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use ipnetwork::IpNetwork;
+use parking_lot::Mutex;
+use time::OffsetDateTime;
+
+use crate::{
+    firewall::{Firewall, Target},
+    storage::{BanRecord, TargetRepository},
+    HashMap, IndexSet, Result,
+};
+
+/// Single call that was made to the [`MockFirewall`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FirewallCall {
+    Install,
+    Uninstall,
+    Block { ip: IpNetwork, ports: Vec<u16> },
+    Unblock { ip: IpNetwork, ports: Vec<u16> },
+}
+
+/// Firewall that records all calls instead of running any commands.
+#[derive(Debug, Default)]
+pub struct MockFirewall {
+    calls: Mutex<Vec<FirewallCall>>,
+}
+
+impl MockFirewall {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All calls made so far, in order.
+    #[must_use]
+    pub fn calls(&self) -> Vec<FirewallCall> {
+        self.calls.lock().clone()
+    }
+
+    /// Take all calls made so far, clearing the record.
+    #[must_use]
+    pub fn take_calls(&self) -> Vec<FirewallCall> {
+        mem::take(&mut *self.calls.lock())
+    }
+
+    /// Networks that are currently blocked, in the order they were blocked.
+    #[must_use]
+    pub fn blocked(&self) -> IndexSet<IpNetwork> {
+        let mut blocked = IndexSet::default();
+
+        for call in &*self.calls.lock() {
+            match call {
+                FirewallCall::Block { ip, .. } => {
+                    blocked.insert(*ip);
+                }
+                FirewallCall::Unblock { ip, .. } => {
+                    blocked.shift_remove(ip);
+                }
+                FirewallCall::Install | FirewallCall::Uninstall => {}
+            }
+        }
+
+        blocked
+    }
+
+    fn record(&self, call: FirewallCall) {
+        self.calls.lock().push(call);
+    }
+}
+
+impl Firewall for MockFirewall {
+    fn install(&self) -> Result<()> {
+        self.record(FirewallCall::Install);
+        Ok(())
+    }
+
+    fn uninstall(&self) -> Result<()> {
+        self.record(FirewallCall::Uninstall);
+        Ok(())
+    }
+
+    fn block(&self, target: &Target<'_>) -> Result<()> {
+        self.record(FirewallCall::Block {
+            ip: target.ip,
+            ports: target.ports.to_owned(),
+        });
+        Ok(())
+    }
+
+    fn unblock(&self, target: &Target<'_>) -> Result<()> {
+        self.record(FirewallCall::Unblock {
+            ip: target.ip,
+            ports: target.ports.to_owned(),
+        });
+        Ok(())
+    }
+
+    fn is_installed(&self) -> Result<Option<bool>> {
+        let installed = self.calls.lock().iter().rev().find_map(|call| match call {
+            FirewallCall::Install => Some(true),
+            FirewallCall::Uninstall => Some(false),
+            FirewallCall::Block { .. } | FirewallCall::Unblock { .. } => None,
+        });
+        Ok(Some(installed.unwrap_or_default()))
+    }
+
+    fn is_blocked(&self, target: &Target<'_>) -> Result<Option<bool>> {
+        Ok(Some(self.blocked().contains(&target.ip)))
+    }
+
+    fn list_blocked(&self) -> Result<Option<Vec<IpNetwork>>> {
+        Ok(Some(self.blocked().into_iter().collect()))
+    }
+}
+
+/// Entry of the [`MemoryRepository`].
+#[derive(Clone, Debug)]
+struct Record {
+    file: PathBuf,
+    until: OffsetDateTime,
+    active: bool,
+    times: u8,
+}
+
+/// Repository that only keeps its entries in memory, without any background threads or file I/O.
+#[derive(Debug, Default)]
+pub struct MemoryRepository {
+    entries: Mutex<HashMap<IpAddr, Record>>,
+}
+
+impl MemoryRepository {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Timestamp until when the IP is blocked, if it's in the repository.
+    #[must_use]
+    pub fn until(&self, ip: IpAddr) -> Option<OffsetDateTime> {
+        self.entries.lock().get(&ip).map(|r| r.until)
+    }
+}
+
+impl TargetRepository for MemoryRepository {
+    fn upsert(&mut self, ip: IpAddr, until: OffsetDateTime, file: &Path) -> Result<bool> {
+        let entries = self.entries.get_mut();
+        let times = entries
+            .get(&ip)
+            .map(|r| r.times.saturating_add(u8::from(!r.active)));
+
+        entries.insert(
+            ip,
+            Record {
+                file: file.to_owned(),
+                until,
+                active: true,
+                times: times.unwrap_or_default(),
+            },
+        );
+
+        Ok(times.is_some())
+    }
+
+    fn restore(&mut self, record: BanRecord) -> Result<()> {
+        self.entries.get_mut().insert(
+            record.ip,
+            Record {
+                file: record.file,
+                until: record.until,
+                active: record.active,
+                times: record.times,
+            },
+        );
+        Ok(())
+    }
+
+    fn remove(&mut self, ip: IpAddr) -> Result<()> {
+        self.entries.get_mut().remove(&ip);
+        Ok(())
+    }
+
+    fn iter_active(&self, f: &mut dyn FnMut(IpAddr, &Path) -> Result<()>) -> Result<()> {
+        let now = OffsetDateTime::now_utc();
+
+        for (ip, record) in self.entries.lock().iter().filter(|(_, r)| r.until >= now) {
+            f(*ip, &record.file)?;
+        }
+
+        Ok(())
+    }
+
+    fn iter_outdated(&self, f: &mut dyn FnMut(IpAddr, &Path) -> Result<bool>) -> Result<()> {
+        let now = OffsetDateTime::now_utc();
+
+        for (ip, record) in self
+            .entries
+            .lock()
+            .iter_mut()
+            .filter(|(_, r)| r.until < now && r.active)
+        {
+            if f(*ip, &record.file)? {
+                record.active = false;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn records(&self) -> Result<Vec<BanRecord>> {
+        Ok(self
+            .entries
+            .lock()
+            .iter()
+            .map(|(ip, r)| BanRecord {
+                ip: *ip,
+                file: r.file.clone(),
+                until: r.until,
+                active: r.active,
+                times: r.times,
+            })
+            .collect())
+    }
+
+    fn count(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.entries.lock().capacity() * mem::size_of::<(IpAddr, Record)>()
+    }
+}
@@ -0,0 +1,290 @@
+//! Parsers for the timestamps of log lines, which are selected per rule with its `time_format`.
+
+use std::{fmt, sync::Arc};
+
+use time::{
+    format_description::{self, well_known, FormatItem, OwnedFormatItem},
+    macros::format_description,
+    Duration, OffsetDateTime, PrimitiveDateTime,
+};
+
+use crate::{Error, HashMap, Result};
+
+/// Name of the time format that is used if a rule doesn't set one.
+pub const DEFAULT_FORMAT: &str = "nginx";
+
+/// Names of the capture groups that can contain the timestamp of a line.
+///
+/// Next to the plain `time` group, the `<TIME_RFC2822>` and `<TIME_RFC3339>` placeholders capture
+/// into groups named after their format.
+pub const TIME_GROUPS: [&str; 3] = ["time", "time_rfc2822", "time_rfc3339"];
+
+/// Parser for the timestamp that was captured from a log line.
+pub trait TimeParser: Send + Sync {
+    /// Parse the captured timestamp, or return [`None`] if it's not valid.
+    fn parse(&self, input: &str) -> Option<OffsetDateTime>;
+}
+
+impl<F> TimeParser for F
+where
+    F: Fn(&str) -> Option<OffsetDateTime> + Send + Sync,
+{
+    fn parse(&self, input: &str) -> Option<OffsetDateTime> {
+        self(input)
+    }
+}
+
+/// Timestamps of the common and combined log formats, as used by nginx and Apache, like
+/// `17/Jul/2020:04:02:12 +0000`.
+pub struct Nginx;
+
+impl TimeParser for Nginx {
+    fn parse(&self, input: &str) -> Option<OffsetDateTime> {
+        const FORMAT: &[FormatItem<'_>] = format_description!(
+            "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] [offset_hour \
+             sign:mandatory][offset_minute]"
+        );
+
+        OffsetDateTime::parse(input, FORMAT).ok()
+    }
+}
+
+/// Timestamps in the [RFC 2822](https://datatracker.ietf.org/doc/html/rfc2822) format, like
+/// `Fri, 17 Jul 2020 04:02:12 +0000`.
+pub struct Rfc2822;
+
+impl TimeParser for Rfc2822 {
+    fn parse(&self, input: &str) -> Option<OffsetDateTime> {
+        OffsetDateTime::parse(input, &well_known::Rfc2822).ok()
+    }
+}
+
+/// Timestamps in the [RFC 3339](https://datatracker.ietf.org/doc/html/rfc3339) format, like
+/// `2020-07-17T04:02:12+00:00`.
+pub struct Rfc3339;
+
+impl TimeParser for Rfc3339 {
+    fn parse(&self, input: &str) -> Option<OffsetDateTime> {
+        OffsetDateTime::parse(input, &well_known::Rfc3339).ok()
+    }
+}
+
+/// Timestamps of the traditional syslog format, like `Jul 17 04:02:12`.
+///
+/// They carry neither a year nor an offset, so they're taken as UTC in the current year. Times
+/// that would lie in the future belong to the previous year, which happens around new year.
+pub struct Syslog;
+
+impl TimeParser for Syslog {
+    fn parse(&self, input: &str) -> Option<OffsetDateTime> {
+        const FORMAT: &[FormatItem<'_>] = format_description!(
+            "[year] [month repr:short] [day padding:space] [hour]:[minute]:[second]"
+        );
+
+        let now = OffsetDateTime::now_utc();
+        let parse = |year: i32| {
+            PrimitiveDateTime::parse(&format!("{year} {input}"), FORMAT)
+                .ok()
+                .map(PrimitiveDateTime::assume_utc)
+        };
+
+        parse(now.year()).and_then(|time| {
+            if time > now + Duration::DAY {
+                parse(now.year() - 1)
+            } else {
+                Some(time)
+            }
+        })
+    }
+}
+
+/// Unix timestamps in seconds, with optional fractional seconds, like `1594958532.123`.
+pub struct Epoch;
+
+impl TimeParser for Epoch {
+    fn parse(&self, input: &str) -> Option<OffsetDateTime> {
+        let (secs, fraction) = input.split_once('.').unwrap_or((input, ""));
+        let time = OffsetDateTime::from_unix_timestamp(secs.parse().ok()?).ok()?;
+
+        if fraction.is_empty() {
+            return Some(time);
+        }
+
+        if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        // The fraction extends the seconds away from zero, so it goes backwards before the epoch.
+        let nanos = Duration::nanoseconds(format!("{fraction:0<9}").parse().ok()?);
+        if secs.starts_with('-') {
+            time.checked_sub(nanos)
+        } else {
+            time.checked_add(nanos)
+        }
+    }
+}
+
+/// Timestamps in a custom format, described in the [format description] syntax of the `time`
+/// crate. If the format has no offset, times are taken as UTC.
+///
+/// [format description]: https://time-rs.github.io/book/api/format-description.html
+pub struct Custom(OwnedFormatItem);
+
+impl Custom {
+    pub fn new(description: &str) -> Result<Self> {
+        format_description::parse_owned::<2>(description)
+            .map(Self)
+            .map_err(|source| Error::TimeFormat {
+                format: description.to_owned(),
+                source: Some(source),
+            })
+    }
+}
+
+impl TimeParser for Custom {
+    fn parse(&self, input: &str) -> Option<OffsetDateTime> {
+        OffsetDateTime::parse(input, &self.0).ok().or_else(|| {
+            PrimitiveDateTime::parse(input, &self.0)
+                .ok()
+                .map(PrimitiveDateTime::assume_utc)
+        })
+    }
+}
+
+/// Registry of named time parsers, that rules refer to by their `time_format`.
+///
+/// It contains the built-in parsers `nginx`, `rfc2822`, `rfc3339`, `syslog` and `epoch` by
+/// default. Any format that isn't registered, but contains a `[`, is taken as [`Custom`] format
+/// description.
+#[derive(Clone)]
+pub struct TimeParsers {
+    parsers: HashMap<String, Arc<dyn TimeParser>>,
+}
+
+impl Default for TimeParsers {
+    fn default() -> Self {
+        let builtin: [(&str, Arc<dyn TimeParser>); 5] = [
+            (DEFAULT_FORMAT, Arc::new(Nginx)),
+            ("rfc2822", Arc::new(Rfc2822)),
+            ("rfc3339", Arc::new(Rfc3339)),
+            ("syslog", Arc::new(Syslog)),
+            ("epoch", Arc::new(Epoch)),
+        ];
+
+        Self {
+            parsers: builtin
+                .into_iter()
+                .map(|(name, parser)| (name.to_owned(), parser))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Debug for TimeParsers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.parsers.keys()).finish()
+    }
+}
+
+impl TimeParsers {
+    /// Add a parser under the given name, replacing any existing one.
+    pub fn register(&mut self, name: impl Into<String>, parser: impl TimeParser + 'static) {
+        self.parsers.insert(name.into(), Arc::new(parser));
+    }
+
+    /// Get the parser for a time format, compiling custom format descriptions on first use.
+    pub fn get(&mut self, format: &str) -> Result<Arc<dyn TimeParser>> {
+        if let Some(parser) = self.parsers.get(format) {
+            return Ok(Arc::clone(parser));
+        }
+
+        if !format.contains('[') {
+            return Err(Error::TimeFormat {
+                format: format.to_owned(),
+                source: None,
+            });
+        }
+
+        let parser = Arc::new(Custom::new(format)?) as Arc<dyn TimeParser>;
+        self.parsers.insert(format.to_owned(), Arc::clone(&parser));
+
+        Ok(parser)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::datetime;
+
+    use super::*;
+
+    #[test]
+    fn builtin() {
+        let mut parsers = TimeParsers::default();
+        let mut parse = |format: &str, input: &str| parsers.get(format).unwrap().parse(input);
+
+        let expected = Some(datetime!(2020-07-17 04:02:12 UTC));
+
+        assert_eq!(expected, parse("nginx", "17/Jul/2020:04:02:12 +0000"));
+        assert_eq!(
+            expected,
+            parse("rfc2822", "Fri, 17 Jul 2020 04:02:12 +0000")
+        );
+        assert_eq!(expected, parse("rfc3339", "2020-07-17T04:02:12+00:00"));
+        assert_eq!(expected, parse("epoch", "1594958532"));
+        assert_eq!(
+            Some(datetime!(2020-07-17 04:02:12.5 UTC)),
+            parse("epoch", "1594958532.5")
+        );
+        assert_eq!(None, parse("epoch", "1594958532.-5"));
+        assert_eq!(
+            Some(datetime!(1969-12-31 23:59:58.5 UTC)),
+            parse("epoch", "-1.5")
+        );
+        assert_eq!(
+            Some(datetime!(1969-12-31 23:59:59.5 UTC)),
+            parse("epoch", "-0.5")
+        );
+        assert_eq!(None, parse("epoch", "-377705116800.5"));
+        assert_eq!(None, parse("epoch", "99999999999999999999"));
+    }
+
+    #[test]
+    fn syslog() {
+        let now = OffsetDateTime::now_utc();
+        let time = Syslog.parse("Jan  2 03:04:05").unwrap();
+
+        assert!(time <= now + Duration::DAY);
+        assert!(time > now - Duration::days(367));
+        assert_eq!((time::Month::January, 2), (time.month(), time.day()));
+    }
+
+    #[test]
+    fn custom() {
+        let mut parsers = TimeParsers::default();
+        parsers.register("fixed", |_: &str| Some(OffsetDateTime::UNIX_EPOCH));
+
+        assert_eq!(
+            Some(OffsetDateTime::UNIX_EPOCH),
+            parsers.get("fixed").unwrap().parse("anything")
+        );
+        assert_eq!(
+            Some(datetime!(2020-07-17 04:02:12 UTC)),
+            parsers
+                .get("[year]-[month]-[day] [hour]:[minute]:[second]")
+                .unwrap()
+                .parse("2020-07-17 04:02:12")
+        );
+        assert!(matches!(
+            parsers.get("unknown"),
+            Err(Error::TimeFormat { source: None, .. })
+        ));
+        assert!(matches!(
+            parsers.get("[invalid]"),
+            Err(Error::TimeFormat {
+                source: Some(_),
+                ..
+            })
+        ));
+    }
+}
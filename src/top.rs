@@ -0,0 +1,261 @@
+//! Live dashboard of a running instance, that polls the JSON status of its metrics endpoint.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration as StdDuration, Instant},
+};
+
+use anyhow::{Context, Result};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Modifier, Style, Stylize},
+    text::Line,
+    widgets::{Block, Paragraph, Row, Table},
+    DefaultTerminal, Frame,
+};
+use time::{macros::format_description, OffsetDateTime};
+use veto::metrics::{self, Snapshot};
+
+/// Interval between two requests to the running instance.
+const REFRESH: StdDuration = StdDuration::from_secs(1);
+
+/// Run the dashboard until the user quits it.
+pub fn run(addr: SocketAddr) -> Result<()> {
+    let mut terminal = ratatui::init();
+    let result = Top::new(addr).run(&mut terminal);
+    ratatui::restore();
+
+    result
+}
+
+struct Top {
+    addr: SocketAddr,
+    snapshot: Option<Snapshot>,
+    /// Lines and matches per second of each rule, since the previous snapshot.
+    rates: HashMap<String, (f64, f64)>,
+    fetched: Option<Instant>,
+    error: Option<String>,
+}
+
+impl Top {
+    fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            snapshot: None,
+            rates: HashMap::new(),
+            fetched: None,
+            error: None,
+        }
+    }
+
+    fn run(mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        loop {
+            if self
+                .fetched
+                .is_none_or(|fetched| fetched.elapsed() >= REFRESH)
+            {
+                self.refresh();
+            }
+
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if !event::poll(StdDuration::from_millis(250))? {
+                continue;
+            }
+
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press
+                    && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+                {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Fetch a new snapshot, keeping the previous one around if that fails.
+    fn refresh(&mut self) {
+        let now = Instant::now();
+        let result = metrics::fetch(self.addr, "/status.json")
+            .context("failed fetching status")
+            .and_then(|body| serde_json::from_str::<Snapshot>(&body).context("invalid status"));
+
+        let snapshot = match result {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                self.error = Some(format!("{e:#}"));
+                return;
+            }
+        };
+
+        if let (Some(previous), Some(fetched)) = (&self.snapshot, self.fetched) {
+            let elapsed = now.duration_since(fetched).as_secs_f64().max(f64::EPSILON);
+            let previous = previous
+                .rules
+                .iter()
+                .map(|rule| (rule.name.as_str(), (rule.lines, rule.matches)))
+                .collect::<HashMap<_, _>>();
+
+            self.rates = snapshot
+                .rules
+                .iter()
+                .filter_map(|rule| {
+                    let (lines, matches) = previous.get(rule.name.as_str())?;
+                    #[allow(clippy::cast_precision_loss)]
+                    let rate = |now: u64, before: u64| now.saturating_sub(before) as f64 / elapsed;
+
+                    Some((
+                        rule.name.clone(),
+                        (rate(rule.lines, *lines), rate(rule.matches, *matches)),
+                    ))
+                })
+                .collect();
+        }
+
+        self.snapshot = Some(snapshot);
+        self.fetched = Some(now);
+        self.error = None;
+    }
+
+    fn draw(&self, frame: &mut Frame<'_>) {
+        let [header, rules, bottom, footer] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Length(
+                self.snapshot
+                    .as_ref()
+                    .map_or(0, |s| u16::try_from(s.rules.len()).unwrap_or(u16::MAX))
+                    .saturating_add(3)
+                    .min(12),
+            ),
+            Constraint::Min(5),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [bans, recent] =
+            Layout::horizontal([Constraint::Percentage(55), Constraint::Percentage(45)])
+                .areas(bottom);
+
+        let footer_text = self.error.as_ref().map_or_else(
+            || Line::from(format!("Connected to {} - press q to quit", self.addr)).dim(),
+            |e| Line::from(format!("Error: {e}")).red(),
+        );
+        frame.render_widget(Paragraph::new(footer_text), footer);
+
+        let Some(snapshot) = &self.snapshot else {
+            frame.render_widget(
+                Paragraph::new("Waiting for the first status...").block(Block::bordered()),
+                header,
+            );
+            return;
+        };
+
+        Self::draw_header(frame, snapshot, header);
+        self.draw_rules(frame, snapshot, rules);
+        Self::draw_bans(frame, snapshot, bans);
+        Self::draw_recent(frame, snapshot, recent);
+    }
+
+    fn draw_header(frame: &mut Frame<'_>, snapshot: &Snapshot, area: Rect) {
+        let uptime = humantime::format_duration(StdDuration::from_secs(snapshot.uptime));
+        let text = format!(
+            "Uptime: {uptime}   Firewall: {}   Active bans: {}   Storage entries: {}",
+            snapshot.firewall,
+            snapshot.bans.len(),
+            snapshot.storage_entries,
+        );
+
+        frame.render_widget(
+            Paragraph::new(text).block(Block::bordered().title(" veto ")),
+            area,
+        );
+    }
+
+    fn draw_rules(&self, frame: &mut Frame<'_>, snapshot: &Snapshot, area: Rect) {
+        let rows = snapshot.rules.iter().map(|rule| {
+            let (lines, matches) = self.rates.get(&rule.name).copied().unwrap_or_default();
+            Row::new([
+                rule.name.clone(),
+                rule.lines.to_string(),
+                format!("{lines:.1}"),
+                rule.matches.to_string(),
+                format!("{matches:.1}"),
+            ])
+        });
+
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Fill(1),
+                Constraint::Length(12),
+                Constraint::Length(10),
+                Constraint::Length(10),
+                Constraint::Length(10),
+            ],
+        )
+        .header(header_row([
+            "Rule",
+            "Lines",
+            "Lines/s",
+            "Matches",
+            "Matches/s",
+        ]))
+        .block(Block::bordered().title(" Rules "));
+
+        frame.render_widget(table, area);
+    }
+
+    fn draw_bans(frame: &mut Frame<'_>, snapshot: &Snapshot, area: Rect) {
+        let now = OffsetDateTime::now_utc();
+        let rows = snapshot.bans.iter().map(|ban| {
+            let remaining = StdDuration::try_from(ban.until - now).map_or_else(
+                |_| "expiring".to_owned(),
+                |d| humantime::format_duration(StdDuration::from_secs(d.as_secs())).to_string(),
+            );
+
+            Row::new([ban.ip.to_string(), ban.rule.clone(), remaining])
+        });
+
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(40),
+                Constraint::Fill(1),
+                Constraint::Fill(1),
+            ],
+        )
+        .header(header_row(["IP", "Rule", "Remaining"]))
+        .block(Block::bordered().title(" Bans "));
+
+        frame.render_widget(table, area);
+    }
+
+    fn draw_recent(frame: &mut Frame<'_>, snapshot: &Snapshot, area: Rect) {
+        let format = format_description!("[hour]:[minute]:[second]");
+        let rows = snapshot.recent.iter().map(|recent| {
+            Row::new([
+                recent.at.format(format).unwrap_or_default(),
+                recent.ban.ip.to_string(),
+                recent.ban.rule.clone(),
+            ])
+        });
+
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(8),
+                Constraint::Length(40),
+                Constraint::Fill(1),
+            ],
+        )
+        .header(header_row(["UTC", "IP", "Rule"]))
+        .block(Block::bordered().title(" Recent matches "));
+
+        frame.render_widget(table, area);
+    }
+}
+
+fn header_row<const N: usize>(titles: [&'static str; N]) -> Row<'static> {
+    Row::new(titles).style(Style::new().add_modifier(Modifier::BOLD))
+}
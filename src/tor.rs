@@ -0,0 +1,221 @@
+//! List of Tor exit nodes, that is downloaded regularly, so rules can treat exits differently.
+//!
+//! Depending on the policy, exits are blocked with the `tor_timeout` of the rules, blocked right
+//! away under the rule name [`RULE_NAME`] without waiting for an offense, or never blocked at all.
+//! Downloads require the `tor` feature.
+
+use std::{collections::HashSet, net::IpAddr, path::Path, sync::Arc};
+
+use flume::Receiver;
+use parking_lot::RwLock;
+
+use crate::{
+    handler::{prepare_builtin, Entry},
+    settings::{self, TorPolicy},
+    Result,
+};
+
+/// Name of the rule that blocks exits with the `block` policy, in logs and events.
+pub const RULE_NAME: &str = "tor";
+/// Identifies the blocks of exits in the storage, in place of the log file of a rule.
+pub const STORAGE_PATH: &str = "<tor>";
+
+/// Current list of exits, that is shared with the downloading thread.
+#[derive(Clone, Default)]
+pub struct ExitList {
+    exits: Arc<RwLock<HashSet<IpAddr>>>,
+    policy: TorPolicy,
+}
+
+impl ExitList {
+    #[must_use]
+    pub fn new(policy: TorPolicy) -> Self {
+        Self {
+            exits: Arc::default(),
+            policy,
+        }
+    }
+
+    /// Whether the IP is a known exit.
+    #[must_use]
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.exits.read().contains(&ip)
+    }
+
+    /// Whether the IP is a known exit, that must not be blocked.
+    #[must_use]
+    pub fn is_exempt(&self, ip: IpAddr) -> bool {
+        self.policy == TorPolicy::Exempt && self.contains(ip)
+    }
+
+    /// Replace the list with a newly downloaded one.
+    pub fn replace(&self, exits: HashSet<IpAddr>) {
+        *self.exits.write() = exits;
+    }
+
+    /// Amount of known exits.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.exits.read().len()
+    }
+
+    /// Whether no exits are known, like before the first download.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.exits.read().is_empty()
+    }
+}
+
+/// Downloads of the exit list, and the rule that blocks exits with the `block` policy.
+pub struct Tor {
+    pub exits: ExitList,
+    /// Rule that blocks all exits, with the timeout of the settings.
+    pub entry: Entry,
+    /// New lists after each download, which are only sent with the `block` policy.
+    pub updates: Receiver<Vec<IpAddr>>,
+}
+
+impl Tor {
+    /// Start downloading the list in the background, or return [`None`] if it's disabled.
+    pub fn start(settings: &settings::Tor) -> Result<Option<Self>> {
+        if !settings.enabled {
+            return Ok(None);
+        }
+
+        let exits = ExitList::new(settings.policy);
+        let entry = prepare_builtin(RULE_NAME, STORAGE_PATH, settings.timeout)?;
+        let updates = download::start(settings, exits.clone())?;
+
+        Ok(Some(Self {
+            exits,
+            entry,
+            updates,
+        }))
+    }
+
+    /// The rule for blocked exits, if the path identifies its blocks in the storage.
+    #[must_use]
+    pub fn rule(&self, path: &Path) -> Option<&Entry> {
+        (self.entry.rule.file == path).then_some(&self.entry)
+    }
+}
+
+/// Parse an exit list with one IP per line. Lines of the detailed list in the format
+/// `ExitAddress <ip> <date>` work as well, and anything else is skipped.
+#[must_use]
+pub fn parse(list: &str) -> HashSet<IpAddr> {
+    list.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match fields.next()? {
+                "ExitAddress" => fields.next()?.parse().ok(),
+                field => field.parse().ok(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(feature = "tor")]
+mod download {
+    use std::{io::Read, net::IpAddr, thread, time::Duration};
+
+    use flume::Receiver;
+    use log::{info, warn};
+
+    use super::{parse, ExitList};
+    use crate::{
+        settings::{self, TorPolicy},
+        Result,
+    };
+
+    /// Maximum time for a single download.
+    const TIMEOUT: Duration = Duration::from_secs(60);
+    /// Maximum size of the list, well above its usual size of a few dozen kilobytes.
+    const MAX_SIZE: u64 = 16 * 1024 * 1024;
+    /// Minimum time between two downloads, which is also the time until a failed one is retried.
+    const MIN_REFRESH: Duration = Duration::from_secs(300);
+
+    /// Start a background thread that downloads the list regularly.
+    pub fn start(settings: &settings::Tor, exits: ExitList) -> Result<Receiver<Vec<IpAddr>>> {
+        let (tx, rx) = flume::bounded(1);
+        let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+        let url = settings.url.clone();
+        let refresh = settings.refresh.unsigned_abs().max(MIN_REFRESH);
+        let block = settings.policy == TorPolicy::Block;
+
+        thread::Builder::new()
+            .name("tor".to_owned())
+            .spawn(move || loop {
+                match download(&agent, &url) {
+                    Ok(list) => {
+                        let list = parse(&list);
+                        info!("loaded {} Tor exit nodes", list.len());
+
+                        if block && tx.send(list.iter().copied().collect()).is_err() {
+                            break;
+                        }
+                        exits.replace(list);
+                        thread::sleep(refresh);
+                    }
+                    Err(e) => {
+                        warn!("failed downloading Tor exit list: {e}");
+                        thread::sleep(MIN_REFRESH);
+                    }
+                }
+            })?;
+
+        Ok(rx)
+    }
+
+    fn download(agent: &ureq::Agent, url: &str) -> Result<String> {
+        let mut list = String::new();
+        agent
+            .get(url)
+            .call()?
+            .into_reader()
+            .take(MAX_SIZE)
+            .read_to_string(&mut list)?;
+
+        Ok(list)
+    }
+}
+
+#[cfg(not(feature = "tor"))]
+mod download {
+    use std::net::IpAddr;
+
+    use flume::Receiver;
+
+    use super::ExitList;
+    use crate::{settings, Error, Result};
+
+    pub fn start(_: &settings::Tor, _: ExitList) -> Result<Receiver<Vec<IpAddr>>> {
+        Err(Error::UnsupportedTor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_list() {
+        let exits = parse(
+            "ExitNode 0011BD2485AD45D984EC4159C88FC066E5E3300E\n\
+             Published 2023-10-01 09:58:30\n\
+             ExitAddress 162.247.74.201 2023-10-01 10:04:38\n\
+             185.220.101.1\n\
+             2001:db8::1\n",
+        );
+
+        assert_eq!(3, exits.len());
+        assert!(exits.contains(&IpAddr::from([162, 247, 74, 201])));
+        assert!(exits.contains(&IpAddr::from([185, 220, 101, 1])));
+
+        let list = ExitList::new(TorPolicy::Exempt);
+        list.replace(exits);
+        assert!(list.is_exempt([185, 220, 101, 1].into()));
+        assert!(!list.is_exempt([10, 0, 0, 1].into()));
+        assert!(!ExitList::new(TorPolicy::Rules).is_exempt([185, 220, 101, 1].into()));
+    }
+}
@@ -0,0 +1,161 @@
+//! Detection of log files that stopped receiving new lines, which is often the first sign that
+//! log rotation isn't followed anymore or that the wrong file is watched.
+
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{handler::Entry, metrics::RuleMetrics};
+
+/// Keeps track of the last time each rule with a stall timeout saw a new line.
+pub struct Watchdog {
+    sources: Vec<Source>,
+}
+
+struct Source {
+    rule: String,
+    timeout: Duration,
+    metrics: Arc<RuleMetrics>,
+    /// Amount of lines at the last check.
+    lines: u64,
+    /// Last time the amount of lines changed.
+    active: Instant,
+}
+
+impl Watchdog {
+    /// Watch all entries that have a stall timeout, starting from now.
+    pub fn new<'a>(entries: impl IntoIterator<Item = &'a Entry>) -> Self {
+        Self::new_at(entries, Instant::now())
+    }
+
+    fn new_at<'a>(entries: impl IntoIterator<Item = &'a Entry>, now: Instant) -> Self {
+        let sources = entries
+            .into_iter()
+            .filter_map(|entry| {
+                let timeout = entry
+                    .rule
+                    .stall_timeout
+                    .filter(|t| t.is_positive())
+                    .and_then(|t| Duration::try_from(t).ok())?;
+
+                Some(Source {
+                    rule: entry.name.clone(),
+                    timeout,
+                    metrics: entry.metrics.clone(),
+                    lines: entry.metrics.lines.load(Ordering::Relaxed),
+                    active: now,
+                })
+            })
+            .collect();
+
+        Self { sources }
+    }
+
+    /// Check all rules for new lines, and report the ones that went silent for longer than their
+    /// stall timeout, or started receiving lines again. Each stall is reported only once.
+    pub fn check(&mut self) {
+        self.check_at(Instant::now());
+    }
+
+    fn check_at(&mut self, now: Instant) {
+        for source in &mut self.sources {
+            let lines = source.metrics.lines.load(Ordering::Relaxed);
+            let stalled = source.metrics.stalled.load(Ordering::Relaxed);
+
+            if lines != source.lines {
+                source.lines = lines;
+                source.active = now;
+
+                if stalled {
+                    source.metrics.stalled.store(false, Ordering::Relaxed);
+                    info!("rule {}: receiving new lines again", source.rule);
+                }
+            } else if !stalled && now.saturating_duration_since(source.active) >= source.timeout {
+                source.metrics.stalled.store(true, Ordering::Relaxed);
+                warn!(
+                    "rule {}: no new lines for {}, check that the right file is watched and that \
+                     rotation is followed",
+                    source.rule,
+                    humantime::format_duration(source.timeout)
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        handler::{self, RuleCache},
+        settings::{HostSource, Limits, Protocol, Rule},
+        IndexMap,
+    };
+
+    #[test]
+    fn stall_and_recovery() {
+        let rule = Rule {
+            stall_timeout: Some(time::Duration::minutes(10)),
+            ..basic_rule()
+        };
+        let watched = handler::prepare_rule(
+            "web".to_owned(),
+            rule,
+            &Limits::default(),
+            &mut RuleCache::default(),
+        )
+        .unwrap();
+        let unwatched = handler::prepare_rule(
+            "ssh".to_owned(),
+            basic_rule(),
+            &Limits::default(),
+            &mut RuleCache::default(),
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new_at([&watched, &unwatched], start);
+        assert_eq!(1, watchdog.sources.len());
+
+        let stalled = || watched.metrics.stalled.load(Ordering::Relaxed);
+        let minutes = |m: u64| start + Duration::from_secs(m * 60);
+
+        watchdog.check_at(minutes(5));
+        assert!(!stalled());
+
+        // New lines push the stall further out.
+        watched.metrics.record_line();
+        watchdog.check_at(minutes(9));
+        watchdog.check_at(minutes(15));
+        assert!(!stalled());
+
+        watchdog.check_at(minutes(19));
+        assert!(stalled());
+
+        watched.metrics.record_line();
+        watchdog.check_at(minutes(20));
+        assert!(!stalled());
+    }
+
+    fn basic_rule() -> Rule {
+        Rule {
+            file: "/var/log/test.log".into(),
+            plugins: Vec::new(),
+            ports: Vec::new(),
+            protocol: Protocol::Tcp,
+            timeout: time::Duration::HOUR,
+            host: HostSource::Capture,
+            time_format: None,
+            stall_timeout: None,
+            max_bans_per_minute: None,
+            reputation_timeout: None,
+            tor_timeout: None,
+            crawler_group: None,
+            filters: Vec::new(),
+            blacklists: IndexMap::default(),
+        }
+    }
+}
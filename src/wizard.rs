@@ -0,0 +1,315 @@
+//! Building filters from example log lines, by replacing the parts that veto knows, like the
+//! client IP and the timestamp, with placeholders.
+
+use std::{collections::HashSet, ops::Range, path::PathBuf};
+
+use regex::Regex;
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    handler::{self, Entry},
+    matcher::Matcher,
+    settings::{Filter, HostSource, Protocol, Rule},
+    IndexMap, IndexSet,
+};
+
+/// Capture group for the request path of HTTP logs.
+const PATH_GROUP: &str = "path";
+/// Capture group for the free text before the client IP of other logs.
+const MESSAGE_GROUP: &str = "message";
+
+/// Timestamp formats that are recognized in lines, with their replacement in the filter and the
+/// matching `time_format` setting.
+const TIMES: [(&str, &str, Option<&str>); 4] = [
+    ("<TIME>", "<TIME>", None),
+    ("<TIME_RFC3339>", "<TIME_RFC3339>", Some("rfc3339")),
+    ("<TIME_RFC2822>", "<TIME_RFC2822>", Some("rfc2822")),
+    (SYSLOG_TIME, SYSLOG_TIME, Some("syslog")),
+];
+
+const SYSLOG_TIME: &str = r"(?P<time>[A-Z][a-z]{2} [ 0-9][0-9] [0-9]{2}:[0-9]{2}:[0-9]{2})";
+
+/// A filter that was built from an example line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Suggestion {
+    pub pattern: String,
+    /// Value for the rule's `time_format` setting, that fits the timestamp of the line.
+    pub time_format: Option<String>,
+    /// Capture group that the blacklist applies to.
+    pub group: String,
+    /// Suggested blacklist entry, taken from the group's content in the example line.
+    pub blacklist: String,
+}
+
+/// Build a filter for the line, or return [`None`] if it doesn't contain both a known timestamp
+/// and a client IP.
+#[must_use]
+pub fn suggest(line: &str) -> Option<Suggestion> {
+    let mut parts = Vec::<(Range<usize>, String)>::new();
+
+    let (time, time_format) = TIMES.iter().find_map(|(pattern, replacement, format)| {
+        let found = Regex::new(&expand(pattern)).ok()?.find(line)?;
+        Some(((found.range(), (*replacement).to_owned()), *format))
+    })?;
+    parts.push(time);
+
+    let host = Regex::new(&expand("<HOST>"))
+        .ok()?
+        .find_iter(line)
+        .find(|m| {
+            !overlaps(&parts, &m.range())
+                && is_word(line, &m.range())
+                && m.as_str().parse::<std::net::IpAddr>().is_ok()
+        })?;
+    parts.push((host.range(), "<HOST>".to_owned()));
+
+    let request = Regex::new(&format!(r"{} (\S+)(?: (HTTP/\S+))?", expand("<METHOD>")))
+        .ok()?
+        .captures_iter(line)
+        .find(|caps| {
+            caps.get(0)
+                .is_some_and(|m| !overlaps(&parts, &m.range()) && is_word(line, &m.range()))
+        });
+
+    let (group, blacklist) = if let Some(caps) = request {
+        let method = caps.name("method")?;
+        let path = caps.get(2)?;
+
+        parts.push((method.range(), "<METHOD>".to_owned()));
+        parts.push((path.range(), format!(r"(?P<{PATH_GROUP}>\S+)")));
+        if let Some(version) = caps.get(3) {
+            parts.push((version.range(), "<VERSION>".to_owned()));
+        }
+
+        (PATH_GROUP, path.as_str().to_owned())
+    } else {
+        // Capture the text right before the IP, which usually describes what happened.
+        parts.sort_by_key(|(range, _)| range.start);
+        let start = parts
+            .iter()
+            .map(|(range, _)| range.end)
+            .filter(|&end| end <= host.start())
+            .max()
+            .unwrap_or_default();
+        let text = &line[start..host.start()];
+        let trimmed = text.trim();
+        if trimmed.is_empty() {
+            return None;
+        }
+
+        let offset = start + (text.len() - text.trim_start().len());
+        parts.push((
+            offset..offset + trimmed.len(),
+            format!("(?P<{MESSAGE_GROUP}>.+)"),
+        ));
+
+        (MESSAGE_GROUP, describing_words(trimmed))
+    };
+
+    parts.sort_by_key(|(range, _)| range.start);
+
+    let mut pattern = String::from("^");
+    let mut pos = 0;
+    for (range, replacement) in parts {
+        pattern.push_str(&generalize(&line[pos..range.start]));
+        pattern.push_str(&replacement);
+        pos = range.end;
+    }
+
+    Some(Suggestion {
+        pattern,
+        time_format: time_format.map(ToOwned::to_owned),
+        group: group.to_owned(),
+        blacklist,
+    })
+}
+
+fn expand(pattern: &str) -> String {
+    handler::expand_pattern(pattern, &HostSource::Capture)
+}
+
+fn overlaps(parts: &[(Range<usize>, String)], range: &Range<usize>) -> bool {
+    parts
+        .iter()
+        .any(|(other, _)| other.start < range.end && range.start < other.end)
+}
+
+/// Check that the range isn't surrounded by letters or digits, so it's not part of a longer word.
+fn is_word(line: &str, range: &Range<usize>) -> bool {
+    let before = line[..range.start].chars().next_back();
+    let after = line[range.end..].chars().next();
+
+    !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+}
+
+/// Turn literal text between known parts into a regex. Words with digits, like process IDs or
+/// ports, likely differ between lines and match any non-whitespace instead.
+fn generalize(text: &str) -> String {
+    text.split(' ')
+        .map(|word| {
+            if word.bytes().any(|b| b.is_ascii_digit()) {
+                r"\S+".to_owned()
+            } else {
+                escape(word)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Escape regex meta characters, but keep characters like `-` as is, which only have a meaning
+/// within classes.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if r"\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Pick the longest run of plain words from a message, which likely describes the event without
+/// any variable parts like process names or IDs.
+fn describing_words(message: &str) -> String {
+    message
+        .split(' ')
+        .collect::<Vec<_>>()
+        .split(|word| word.is_empty() || !word.chars().all(char::is_alphabetic))
+        .max_by_key(|run| run.iter().map(|word| word.len()).sum::<usize>())
+        .filter(|run| !run.is_empty())
+        .map_or_else(|| message.to_owned(), |run| run.join(" "))
+}
+
+/// Combine the suggestions into a rule. All suggestions must use the same time format, which is
+/// taken from the first one.
+#[must_use]
+pub fn build_rule(file: PathBuf, timeout: Duration, suggestions: &[Suggestion]) -> Rule {
+    let mut filters = IndexSet::new();
+    let mut blacklists = IndexMap::<String, IndexSet<String>>::default();
+
+    for suggestion in suggestions {
+        filters.insert(suggestion.pattern.clone());
+        blacklists
+            .entry(suggestion.group.clone())
+            .or_default()
+            .insert(suggestion.blacklist.clone());
+    }
+
+    Rule {
+        file,
+        plugins: Vec::new(),
+        ports: Vec::new(),
+        protocol: Protocol::Tcp,
+        timeout,
+        host: HostSource::Capture,
+        time_format: suggestions.first().and_then(|s| s.time_format.clone()),
+        stall_timeout: None,
+        max_bans_per_minute: None,
+        reputation_timeout: None,
+        tor_timeout: None,
+        crawler_group: None,
+        filters: filters
+            .into_iter()
+            .map(|pattern| Filter {
+                pattern,
+                prefilter: None,
+            })
+            .collect(),
+        blacklists,
+    }
+}
+
+/// Result of running a rule against sample lines.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Evaluation {
+    pub lines: usize,
+    /// Amount of lines that would lead to a block.
+    pub matches: usize,
+    /// Amount of distinct IPs that would be blocked.
+    pub hosts: usize,
+}
+
+/// Count the lines that the rule would block an IP for. Lines are checked independently of each
+/// other and regardless of their age.
+#[must_use]
+pub fn evaluate(entry: &Entry, lines: &[String]) -> Evaluation {
+    let matcher = Matcher::with_clock(|| OffsetDateTime::UNIX_EPOCH);
+    let mut hosts = HashSet::new();
+    let mut count = 0;
+
+    for line in lines {
+        let mut last_time = OffsetDateTime::UNIX_EPOCH;
+        if let Some(host) = matcher.find(entry, &mut last_time, line) {
+            count += 1;
+            hosts.insert(host);
+        }
+    }
+
+    Evaluation {
+        lines: lines.len(),
+        matches: count,
+        hosts: hosts.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{handler::RuleCache, settings::Limits};
+
+    #[test]
+    fn suggest_http() {
+        let line = r#"10.0.0.1 - - [01/Oct/2020:12:00:00 +0000] "GET /wp-login.php HTTP/1.1" 404 153 "-" "curl/8.0""#;
+
+        assert_eq!(
+            Some(Suggestion {
+                pattern: r#"^<HOST> - - \[<TIME>\] "<METHOD> (?P<path>\S+) <VERSION>"#.to_owned(),
+                time_format: None,
+                group: "path".to_owned(),
+                blacklist: "/wp-login.php".to_owned(),
+            }),
+            suggest(line)
+        );
+    }
+
+    #[test]
+    fn suggest_syslog() {
+        let line =
+            "Oct  1 12:00:00 host sshd[1234]: Failed password for root from 10.0.0.1 port 22";
+        let suggestion = suggest(line).unwrap();
+
+        assert_eq!(
+            r"^(?P<time>[A-Z][a-z]{2} [ 0-9][0-9] [0-9]{2}:[0-9]{2}:[0-9]{2}) (?P<message>.+) <HOST>",
+            suggestion.pattern
+        );
+        assert_eq!(Some("syslog"), suggestion.time_format.as_deref());
+        assert_eq!("Failed password for root from", suggestion.blacklist);
+
+        let rule = build_rule("auth.log".into(), Duration::hours(1), &[suggestion]);
+        let entry = handler::prepare_rule(
+            "ssh".to_owned(),
+            rule,
+            &Limits::default(),
+            &mut RuleCache::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            Evaluation {
+                lines: 3,
+                matches: 2,
+                hosts: 2
+            },
+            evaluate(
+                &entry,
+                &[
+                    line.to_owned(),
+                    line.replace("10.0.0.1", "10.0.0.2"),
+                    line.replace("Failed", "Accepted"),
+                ]
+            )
+        );
+    }
+}